@@ -1,8 +1,19 @@
-use bookfactory::epub::{build_epub2, zip_with_epub_mimetype};
-use bookfactory::toml::{parse_config, Recipe};
+use bookfactory::epub::{
+    build_epub2, epub2_config_schema, validate_epub, zip_with_epub_mimetype, BuildReport, Severity,
+};
+use bookfactory::import::{import_plain_text, write_imported_book, PlainTextImportSettings};
+use bookfactory::init::{write_scaffold, ScaffoldSettings, SCAFFOLD_FORMATS};
+use bookfactory::lsp::run_language_server;
+use bookfactory::toml::{
+    build_config_file_schema, migrate_config_file, parse_config, Recipe, CURRENT_SCHEMA_VERSION,
+};
+use bookfactory::zip::CompressedFileCache;
 
 use argh::FromArgs;
-use std::fs::write;
+use std::fs::{read, read_to_string, write};
+use std::io::{stdin, stdout, IsTerminal, Write};
+use std::path::PathBuf;
+use std::process::exit;
 
 //////////////
 //   Args   //
@@ -12,7 +23,7 @@ use std::fs::write;
 #[derive(FromArgs)]
 #[argh(subcommand, name = "build")]
 struct Build {
-    /// output path
+    /// output path; {{name}} and {{format}} are replaced by the recipe's name and format
     #[argh(positional)]
     out_path: String,
     /// input config file
@@ -21,6 +32,15 @@ struct Build {
     /// recipe to build from config file
     #[argh(positional)]
     recipe_name: String,
+    /// directory to resolve outside paths against (defaults to the recipe's base_dir, or else the config file's directory)
+    #[argh(option)]
+    base_dir: Option<String>,
+    /// reject keys the recipe format doesn't recognize instead of ignoring them
+    #[argh(switch)]
+    strict: bool,
+    /// build variant, selecting content tagged with only_in or except_in
+    #[argh(option)]
+    variant: Option<String>,
 }
 
 /// Zip input paths with epub mimetype
@@ -35,10 +55,120 @@ struct ZipEpub {
     in_paths: Vec<String>,
 }
 
+/// Import a plain-text book and write an epub2 recipe for it
+#[derive(FromArgs)]
+#[argh(subcommand, name = "import")]
+struct Import {
+    /// input plain-text file
+    #[argh(positional)]
+    in_path: String,
+    /// output directory for the recipe and chapter files
+    #[argh(positional)]
+    out_dir: String,
+    /// name of the generated recipe
+    #[argh(option, default = "String::from(\"book\")")]
+    recipe_name: String,
+    /// book title (defaults to the Project Gutenberg header's title)
+    #[argh(option)]
+    title: Option<String>,
+    /// book author (defaults to the Project Gutenberg header's author)
+    #[argh(option)]
+    author: Option<String>,
+    /// book language (defaults to the Project Gutenberg header's language)
+    #[argh(option)]
+    language: Option<String>,
+    /// regex matching chapter heading lines; replaces the defaults, may be repeated
+    #[argh(option)]
+    chapter_pattern: Vec<String>,
+    /// regex matching scene break lines; replaces the defaults, may be repeated
+    #[argh(option)]
+    scene_break_pattern: Vec<String>,
+    /// regex matching the last line of the front-matter boilerplate; replaces the defaults, may be repeated
+    #[argh(option)]
+    boilerplate_header_end: Vec<String>,
+    /// regex matching the first line of the end-matter boilerplate; replaces the defaults, may be repeated
+    #[argh(option)]
+    boilerplate_footer_start: Vec<String>,
+    /// keep license boilerplate instead of stripping it
+    #[argh(switch)]
+    keep_boilerplate: bool,
+    /// leave quotes, dashes and ellipses as they are in the input
+    #[argh(switch)]
+    no_typography: bool,
+}
+
+/// Create a config file, sample chapter, stylesheet and cover for a new book, then build it
+#[derive(FromArgs)]
+#[argh(subcommand, name = "init")]
+struct Init {
+    /// output directory (defaults to the current directory)
+    #[argh(positional, default = "String::from(\".\")")]
+    out_dir: String,
+    /// name of the generated recipe
+    #[argh(option, default = "String::from(\"book\")")]
+    recipe_name: String,
+    /// book title; prompted for if absent and run interactively
+    #[argh(option)]
+    title: Option<String>,
+    /// book author; prompted for if absent and run interactively
+    #[argh(option)]
+    author: Option<String>,
+    /// book language, e.g. en; prompted for if absent and run interactively
+    #[argh(option)]
+    language: Option<String>,
+    /// output format; prompted for if absent and run interactively
+    #[argh(option)]
+    format: Option<String>,
+    /// don't prompt, using defaults for anything not given as a flag
+    #[argh(switch)]
+    no_input: bool,
+}
+
+/// Rewrite config files' recipes to the current schema version, keeping comments and ordering
+#[derive(FromArgs)]
+#[argh(subcommand, name = "migrate")]
+struct Migrate {
+    /// config files to migrate
+    #[argh(positional)]
+    config_files: Vec<String>,
+    /// print the changes as a diff instead of writing them
+    #[argh(switch)]
+    dry_run: bool,
+}
+
+/// Write a JSON Schema describing config files, for editor completion and validation
+#[derive(FromArgs)]
+#[argh(subcommand, name = "schema")]
+struct Schema {
+    /// output path
+    #[argh(positional)]
+    out_path: String,
+}
+
+/// Check an epub against the container, package and NCX rules, printing each problem found
+#[derive(FromArgs)]
+#[argh(subcommand, name = "validate")]
+struct Validate {
+    /// epub file to check
+    #[argh(positional)]
+    epub_file: String,
+}
+
+/// Run a language server over stdio, giving editors diagnostics, completion and go-to-definition for config files
+#[derive(FromArgs)]
+#[argh(subcommand, name = "lsp")]
+struct Lsp {}
+
 #[derive(FromArgs)]
 #[argh(subcommand)]
 enum Subcommand {
     Build(Build),
+    Import(Import),
+    Init(Init),
+    Lsp(Lsp),
+    Migrate(Migrate),
+    Schema(Schema),
+    Validate(Validate),
     ZipEpub(ZipEpub),
 }
 
@@ -65,32 +195,91 @@ fn get_format(recipe: &Recipe) -> Format {
     }
 }
 
+fn get_extension(format: &str) -> &str {
+    match format {
+        "epub2" => "epub",
+        _ => format,
+    }
+}
+
+fn build_recipe(
+    recipe: &Recipe,
+    file_cache: &mut CompressedFileCache,
+) -> Result<(Vec<u8>, BuildReport), String> {
+    match get_format(recipe) {
+        Format::Epub2 => build_epub2(recipe, file_cache),
+        Format::Unrecognized => Err(format!(
+            "Format {} not recognized in recipe {}",
+            recipe.format, recipe.name
+        )),
+    }
+}
+
+fn print_build_report(report: &BuildReport) {
+    // Stdout is left for the outcome of the build
+    for note in &report.notes {
+        eprintln!("{}", note);
+    }
+    for warning in &report.warnings {
+        eprintln!("{}", warning);
+    }
+}
+
 fn build(args: Build) -> Result<(), String> {
-    let recipes = parse_config(&args.config_file)?;
+    // A recipe with several formats parses into one recipe per format
+    let recipes: Vec<Recipe> = parse_config(&args.config_file)?
+        .into_iter()
+        .filter(|recipe| recipe.name == args.recipe_name)
+        .collect();
+    if recipes.is_empty() {
+        return Err(format!(
+            "Recipe {} not found in file {}.",
+            args.recipe_name, args.config_file
+        ));
+    }
+    if recipes.len() > 1 && !args.out_path.contains("{format}") {
+        return Err(format!(
+            "Recipe {} has {} formats, so the output path must contain {{format}}, e.g. {{name}}-{{format}}.epub.",
+            args.recipe_name,
+            recipes.len()
+        ));
+    }
 
-    let file = match recipes
-        .iter()
-        .find(|recipe| recipe.name == args.recipe_name)
-    {
-        None => {
-            return Err(format!(
-                "Recipe {} not found in file {}.",
-                args.recipe_name, args.config_file
-            ))
+    let format_count = recipes.len();
+    let mut file_cache = CompressedFileCache::new();
+    let mut failures = Vec::new();
+    for mut recipe in recipes {
+        if let Some(base_dir) = &args.base_dir {
+            recipe.base_dir = PathBuf::from(base_dir);
         }
-        Some(recipe) => match get_format(recipe) {
-            Format::Epub2 => build_epub2(recipe).unwrap(),
-            Format::Unrecognized => {
-                return Err(format!(
-                    "Format {} not recognized in recipe {}",
-                    recipe.format, recipe.name
-                ))
-            }
-        },
-    };
-    write(args.out_path, file).map_err(|e| e.to_string())?;
+        recipe.strict = args.strict;
+        recipe.variant = args.variant.clone();
+        let out_path = args
+            .out_path
+            .replace("{name}", &recipe.name)
+            .replace("{format}", &recipe.format);
 
-    Ok(())
+        let result = build_recipe(&recipe, &mut file_cache).and_then(|(file, report)| {
+            print_build_report(&report);
+            write(&out_path, file).map_err(|e| e.to_string())
+        });
+        match (result, format_count) {
+            (Err(e), 1) => return Err(e),
+            (Err(e), _) => failures.push(format!("Format {} failed:\n{}", recipe.format, e)),
+            (Ok(()), 1) => (),
+            (Ok(()), _) => println!("Built format {} as {}.", recipe.format, out_path),
+        }
+    }
+
+    match failures.is_empty() {
+        true => Ok(()),
+        false => Err(format!(
+            "{} of {} formats failed.\n{}",
+            failures.len(),
+            format_count,
+            failures.join("\n")
+        )),
+    }
 }
 
 fn zip_epub(args: ZipEpub) -> Result<(), String> {
@@ -100,14 +289,239 @@ fn zip_epub(args: ZipEpub) -> Result<(), String> {
     Ok(())
 }
 
+fn import(args: Import) -> Result<(), String> {
+    let mut settings = PlainTextImportSettings {
+        title: args.title,
+        author: args.author,
+        language: args.language,
+        strip_boilerplate: !args.keep_boilerplate,
+        typography: !args.no_typography,
+        ..Default::default()
+    };
+    if !args.chapter_pattern.is_empty() {
+        settings.chapter_patterns = args.chapter_pattern;
+    }
+    if !args.scene_break_pattern.is_empty() {
+        settings.scene_break_patterns = args.scene_break_pattern;
+    }
+    if !args.boilerplate_header_end.is_empty() {
+        settings.boilerplate_header_end_patterns = args.boilerplate_header_end;
+    }
+    if !args.boilerplate_footer_start.is_empty() {
+        settings.boilerplate_footer_start_patterns = args.boilerplate_footer_start;
+    }
+
+    let text = read_to_string(&args.in_path).map_err(|e| e.to_string())?;
+    let book = import_plain_text(&text, &settings)?;
+    let config_path = write_imported_book(&book, &args.out_dir, &args.recipe_name)?;
+    println!(
+        "Imported {} chapters into recipe {} in {}.",
+        book.chapters.len(),
+        args.recipe_name,
+        config_path.display()
+    );
+
+    Ok(())
+}
+
+fn prompt(question: &str, default: &str) -> Result<String, String> {
+    match default.is_empty() {
+        true => print!("{}: ", question),
+        false => print!("{} [{}]: ", question, default),
+    }
+    stdout().flush().map_err(|e| e.to_string())?;
+    let mut answer = String::new();
+    stdin().read_line(&mut answer).map_err(|e| e.to_string())?;
+    match answer.trim() {
+        "" => Ok(String::from(default)),
+        answer => Ok(String::from(answer)),
+    }
+}
+
+fn init(args: Init) -> Result<(), String> {
+    // Prompts only fill in what the flags left out, and are skipped when input is piped
+    let interactive = !args.no_input && stdin().is_terminal();
+    let defaults = ScaffoldSettings::default();
+    let ask = |value: Option<String>, question: &str, default: &str| match value {
+        Some(value) => Ok(value),
+        None if interactive => prompt(question, default),
+        None => Ok(String::from(default)),
+    };
+    let title = ask(args.title, "Title", &defaults.title)?;
+    let author = ask(args.author, "Author", "")?;
+    let language = ask(args.language, "Language", &defaults.language)?;
+    let format = ask(
+        args.format,
+        &format!("Format ({})", SCAFFOLD_FORMATS.join(", ")),
+        &defaults.format,
+    )?;
+    let settings = ScaffoldSettings {
+        recipe_name: args.recipe_name,
+        title,
+        author: (!author.is_empty()).then_some(author),
+        language,
+        format,
+    };
+
+    let config_path = write_scaffold(&settings, &args.out_dir)?;
+    println!("Created {}.", config_path.display());
+    let out_path = PathBuf::from(&args.out_dir).join(format!(
+        "{}.{}",
+        settings.recipe_name,
+        get_extension(&settings.format)
+    ));
+    build(Build {
+        out_path: out_path.display().to_string(),
+        config_file: config_path.display().to_string(),
+        recipe_name: settings.recipe_name,
+        base_dir: None,
+        strict: true,
+        variant: None,
+    })?;
+    println!("Built {}.", out_path.display());
+
+    Ok(())
+}
+
+fn migrate(args: Migrate) -> Result<(), String> {
+    if args.config_files.is_empty() {
+        return Err(String::from("No config files given to migrate."));
+    }
+    // Every file is checked before any is written, so that one bad file doesn't leave a set of
+    // configs half-migrated
+    let mut migrated_files = Vec::new();
+    for config_file in &args.config_files {
+        migrated_files.push(migrate_config_file(config_file, true)?);
+    }
+
+    for migrated_file in migrated_files {
+        if migrated_file.recipes.is_empty() {
+            println!(
+                "{} is already at schema version {}.",
+                migrated_file.path.display(),
+                CURRENT_SCHEMA_VERSION
+            );
+        } else if args.dry_run {
+            print!("{}", migrated_file.diff);
+        } else {
+            migrate_config_file(&migrated_file.path, false)?;
+            println!(
+                "Migrated recipe{} {} in {} to schema version {}.",
+                if migrated_file.recipes.len() == 1 {
+                    ""
+                } else {
+                    "s"
+                },
+                migrated_file.recipes.join(", "),
+                migrated_file.path.display(),
+                CURRENT_SCHEMA_VERSION
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn validate(args: Validate) -> Result<(), String> {
+    let epub =
+        read(&args.epub_file).map_err(|e| format!("Failed to read {}: {}", args.epub_file, e))?;
+    let issues = validate_epub(&epub);
+    for issue in &issues {
+        println!("{}", issue);
+    }
+
+    let error_count = issues
+        .iter()
+        .filter(|issue| issue.severity == Severity::Error)
+        .count();
+    let warning_count = issues.len() - error_count;
+    match error_count {
+        0 => {
+            println!(
+                "{} passed validation with {} warning{}.",
+                args.epub_file,
+                warning_count,
+                if warning_count == 1 { "" } else { "s" }
+            );
+            Ok(())
+        }
+        _ => Err(format!(
+            "{} failed validation with {} error{} and {} warning{}.",
+            args.epub_file,
+            error_count,
+            if error_count == 1 { "" } else { "s" },
+            warning_count,
+            if warning_count == 1 { "" } else { "s" }
+        )),
+    }
+}
+
+fn schema(args: Schema) -> Result<(), String> {
+    let schema = build_config_file_schema(vec![("epub2", epub2_config_schema())])?;
+    write(args.out_path, schema).map_err(|e| e.to_string())?;
+
+    Ok(())
+}
+
 fn main() {
     let args: Args = argh::from_env();
-    let result = match args.subcommand {
-        Subcommand::Build(command) => build(command),
-        Subcommand::ZipEpub(command) => zip_epub(command),
+    if let Subcommand::Lsp(_) = args.subcommand {
+        // Stdout carries the protocol, so nothing else may be printed to it
+        if let Err(e) = run_language_server() {
+            eprintln!("Language server stopped:\n{}", e);
+            exit(1);
+        }
+        return;
+    }
+    let (result, success_message, failure_message) = match args.subcommand {
+        Subcommand::Build(command) => (
+            build(command),
+            "Book built successfully.",
+            "Error encountered during build:",
+        ),
+        Subcommand::Import(command) => (
+            import(command),
+            "Book imported successfully.",
+            "Error encountered during import:",
+        ),
+        Subcommand::Init(command) => (
+            init(command),
+            "Book created successfully.",
+            "Error encountered while creating the book:",
+        ),
+        Subcommand::Lsp(_) => return,
+        Subcommand::Migrate(command) => {
+            let success_message = match command.dry_run {
+                true => "Dry run finished; no files were changed.",
+                false => "Config files migrated successfully.",
+            };
+            (
+                migrate(command),
+                success_message,
+                "Error encountered during migration:",
+            )
+        }
+        Subcommand::Schema(command) => (
+            schema(command),
+            "Schema written successfully.",
+            "Error encountered while writing the schema:",
+        ),
+        Subcommand::Validate(command) => (
+            validate(command),
+            "Validation finished.",
+            "Error encountered during validation:",
+        ),
+        Subcommand::ZipEpub(command) => (
+            zip_epub(command),
+            "Book built successfully.",
+            "Error encountered during build:",
+        ),
     };
     match result {
-        Ok(_) => println!("Book built successfully."),
-        Err(e) => println!("Error encountered during build:\n{}", e),
+        Ok(_) => println!("{}", success_message),
+        Err(e) => {
+            println!("{}\n{}", failure_message, e);
+            exit(1);
+        }
     }
 }
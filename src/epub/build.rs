@@ -1,16 +1,26 @@
+use crate::epub::validate::ValidationIssue;
 use crate::epub::zip::add_epub_mimetype;
-use crate::zip::zip_path;
+use crate::zip::{zip_path, CompressedFileCache};
 
 use std::io::Cursor;
 use zip::write::ZipWriter;
 
+// What a build found worth telling the user about without stopping
+#[derive(Default)]
+pub struct BuildReport {
+    pub warnings: Vec<ValidationIssue>,
+    // Anything else worth knowing about the built book
+    pub notes: Vec<String>,
+}
+
 pub fn zip_with_epub_mimetype(in_paths: Vec<String>) -> Result<Vec<u8>, String> {
     let mut file_buffer = Vec::<u8>::new();
     let mut zip_file = ZipWriter::new(Cursor::new(&mut file_buffer));
 
+    let mut file_cache = CompressedFileCache::new();
     add_epub_mimetype(&mut zip_file)?;
     for path in in_paths {
-        zip_path(&mut zip_file, path, None::<String>)?;
+        zip_path(&mut zip_file, path, None::<String>, &mut file_cache)?;
     }
 
     zip_file.finish().map_err(|e| e.to_string())?;
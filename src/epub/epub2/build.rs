@@ -1,156 +1,83 @@
-use crate::epub::epub2::config::{parse_epub2_recipe, Metadata, PageTarget};
+use crate::epub::build::BuildReport;
+use crate::epub::epub2::config::parse_epub2_recipe;
+use crate::epub::epub2::fallbacks::check_fallbacks;
+use crate::epub::epub2::helpers::{get_ncx_id, get_opf_path, get_safe_id};
+use crate::epub::epub2::ids::{check_ids, get_opf_ids};
+use crate::epub::epub2::images::check_images;
+use crate::epub::epub2::orphans::{find_orphans, report_orphans};
+use crate::epub::epub2::paths::{check_paths, get_ncx_path};
 use crate::epub::epub2::{
     container::build_container_xml, ncx::build_ncx_xml, opf::build_opf_xml_and_get_metadata,
 };
+use crate::epub::validate::check_built_epub;
 use crate::epub::zip::add_epub_mimetype;
+use crate::helpers::{fixed_clean, path_to_string};
 use crate::toml::Recipe;
-use crate::zip::{zip_buffer, zip_path};
+use crate::zip::{zip_buffer, zip_path, CompressedFileCache};
 
+use std::collections::HashSet;
 use std::io::Cursor;
 use std::mem::drop;
 use std::path::{Path, PathBuf};
 use zip::write::ZipWriter;
 
-fn check_no_id_collisions(ids: &Vec<String>) -> Result<(), String> {
-    let mut ids_as_str: Vec<&str> = ids.iter().map(|id| id.as_ref()).collect();
-    ids_as_str.sort();
-    ids_as_str.dedup();
-
-    match ids_as_str.len() == ids.len() {
-        true => Ok(()),
-        false => Err(String::from(
-            "Attempted to use multiple copies of the same ID in the same file.",
-        )),
-    }
-}
-
-fn get_safe_uid(opf_ids: &Vec<String>) -> String {
-    let mut tentative_id = String::from("BookId");
-    let mut number_to_append = 1;
-    while opf_ids.contains(&tentative_id) {
-        tentative_id = format!("{}_{}", "BookId", number_to_append);
-        number_to_append += 1;
-    }
-    tentative_id
-}
-
-fn check_no_duplicate_inside_paths(inside_paths: &Vec<PathBuf>) -> Result<(), String> {
-    let mut paths_as_str = inside_paths
-        .iter()
-        .map(|path| match path.to_str() {
-            None => Err(format!("Invalid non-UTF-8 path: {:?}", path)),
-            Some(in_str) => Ok(in_str),
-        })
-        .collect::<Result<Vec<&str>, String>>()?;
-    paths_as_str.sort();
-    paths_as_str.dedup_by(|a, b| a.eq_ignore_ascii_case(b));
-
-    match paths_as_str.len() == inside_paths.len() {
-        true => Ok(()),
-        false => Err(String::from("Attempted to store multiple files at the same inside path, or at inside paths differing only by case.")),
-    }
-}
-
-fn check_inside_path_is_valid(inside_path: &PathBuf) -> Result<(), String> {
-    match inside_path.file_name() {
-        None => return Err(format!("Invalid path ending in '..': {:?}", inside_path)),
-        Some(filename) => match filename.to_str() {
-            None => return Err(format!("Invalid non-UTF-8 filename: {:?}", filename)),
-            Some(filename_str) => {
-                if filename_str.len() > 255 {
-                    return Err(format!(
-                        "Invalid filename of length >255 bytes: {}",
-                        filename_str
-                    ));
-                }
-            }
-        },
-    };
-
-    match inside_path.to_str() {
-        None => return Err(format!("Invalid non-UTF-8 path: {:?}", inside_path)),
-        Some(path_str) => {
-            if path_str.len() > 65535 {
-                return Err(format!("Invalid path of length >65535 bytes: {}", path_str));
-            }
-        }
-    };
-
-    for component in inside_path.iter() {
-        match component.to_str() {
-            None => {
-                return Err(format!(
-                    "Invalid non-UTF-8 path component (you shouldn't ever see this error): {:?}",
-                    component
-                ))
-            }
-            Some(component_str) => {
-                match component_str.find(&['/', '"', '*', ':', '<', '>', '?', '\\'][..]) {
-                    None => (),
-                    Some(index) => {
-                        return Err(format!(
-                            "Path {:?} contains invalid character '{}'.",
-                            inside_path,
-                            component_str.get(index..index + 1).unwrap()
-                        ))
-                    }
-                };
-                if component_str.ends_with('.') && component_str != "." && component_str != ".." {
-                    // This is a bit crude/awkward; plausibly there's a more elegant way to do this check
-                    return Err(format!("Path {:?} ends with '.'.", inside_path));
-                }
-            }
-        };
-    }
-
-    Ok(())
+fn get_safe_uid(opf_ids: &[String]) -> String {
+    get_safe_id("BookId", opf_ids)
 }
 
-pub fn build_epub2(recipe: &Recipe) -> Result<Vec<u8>, String> {
+pub fn build_epub2(
+    recipe: &Recipe,
+    file_cache: &mut CompressedFileCache,
+) -> Result<(Vec<u8>, BuildReport), String> {
     // Parse recipe into build config and various derivatives thereof
-    let config = parse_epub2_recipe(recipe)?;
-    let (add_opf_to_rootfiles, opf_path) = match &config.rootfiles {
-        None => (true, "OEBPS/content.opf"),
-        Some(rootfiles_vec) => match rootfiles_vec
-            .iter()
-            .find(|rootfile| &rootfile.media_type == "application/oebps-package+xml")
-        {
-            None => (true, "OEBPS/content.opf"),
-            Some(rootfile) => (false, rootfile.path.as_ref()),
-        },
+    let mut config = parse_epub2_recipe(recipe)?;
+    let mut report = BuildReport::default();
+    let prune_orphans = config.prune_orphans == Some(true);
+    let (orphan_ids, reported_references): (HashSet<String>, HashSet<(String, String)>) = {
+        let orphan_report = find_orphans(&config)?;
+        report_orphans(&orphan_report, prune_orphans, &mut report);
+        (
+            orphan_report
+                .orphans
+                .iter()
+                .map(|item| item.id.clone())
+                .collect(),
+            orphan_report
+                .unlisted_references
+                .iter()
+                .map(|reference| (reference.item_path.clone(), reference.path.clone()))
+                .collect(),
+        )
     };
+    if prune_orphans {
+        config
+            .manifest
+            .retain(|item| !orphan_ids.contains(&item.id));
+    }
+    let (add_opf_to_rootfiles, opf_path) = get_opf_path(&config);
     let opf_parent_dir = match Path::new(opf_path).parent() {
         None => Path::new(""),
         Some(parent) => parent,
     };
-    let (ncx_id, ncx_path_from_opf) = match &config.ncx_meta {
-        Some(meta) => {
-            let id = match &meta.manifest_id {
-                Some(id) => id,
-                None => "ncx",
-            };
-            let path = match &meta.manifest_path_from_opf {
-                Some(path) => path,
-                None => "toc.ncx",
-            };
-            (id, path)
-        }
-        None => ("ncx", "toc.ncx"),
-    };
-    let mut ncx_path = PathBuf::from(opf_parent_dir);
-    ncx_path.push(ncx_path_from_opf);
+    let ncx_id = get_ncx_id(&config);
+    let (ncx_path_from_opf, ncx_path) = get_ncx_path(&config);
 
     // Set up zip file
     let mut epub_file_buffer = Vec::<u8>::new();
     let mut zip_file = ZipWriter::new(Cursor::new(&mut epub_file_buffer));
 
     // Validate paths
-    let mut outside_and_inside_paths = Vec::new();
+    check_paths(&config)?;
+    let mut outside_and_inside_paths: Vec<(&str, PathBuf)> = Vec::new();
+    let mut lenient_inside_paths = HashSet::new();
 
     for item in &config.manifest {
         let mut item_inside_path = PathBuf::new();
         item_inside_path.push(opf_parent_dir);
         item_inside_path.push(&item.inside_path_from_opf);
+        if item.lenient == Some(true) {
+            lenient_inside_paths.insert(path_to_string(fixed_clean(&item_inside_path))?);
+        }
         outside_and_inside_paths.push((item.outside_path.as_ref(), item_inside_path));
     }
 
@@ -163,54 +90,23 @@ pub fn build_epub2(recipe: &Recipe) -> Result<Vec<u8>, String> {
         }
     }
 
-    let mut inside_paths: Vec<PathBuf> = outside_and_inside_paths
-        .iter()
-        .map(|(_outside, inside): &(&str, PathBuf)| inside.clone())
-        .collect();
-    inside_paths.append(&mut vec![
-        PathBuf::from("META-INF/container.xml"),
-        PathBuf::from(&opf_path),
-        PathBuf::from(&ncx_path),
-    ]);
-
-    check_no_duplicate_inside_paths(&inside_paths)?;
-    for inside_path in inside_paths {
-        check_inside_path_is_valid(&inside_path)?;
-    }
-
     // Validate IDs
-    let mut opf_ids: Vec<String> = config.manifest.iter().map(|item| item.id.clone()).collect();
-    opf_ids.push(String::from(ncx_id));
-    if let Some(metadata) = &config.metadata {
-        opf_ids.append(
-            &mut metadata
-                .iter()
-                .filter_map(|item| match item {
-                    Metadata::DcMetadata { id, .. } => id.clone(),
-                    Metadata::CustomMetadata { .. } => None,
-                })
-                .collect(),
-        );
-    }
+    check_ids(&config)?;
+    let safe_uid = get_safe_uid(&get_opf_ids(&config));
 
-    check_no_id_collisions(&opf_ids)?;
-    let safe_uid = get_safe_uid(&opf_ids);
-
-    if let Some(list) = &config.pagelist {
-        let ncx_ids = list
-            .iter()
-            .map(|target| match target {
-                PageTarget::WithSimpleLabel { id, .. } => id.clone(),
-                PageTarget::WithComplexLabels { id, .. } => id.clone(),
-            })
-            .collect();
-        check_no_id_collisions(&ncx_ids)?;
-    }
+    // Validate fallbacks, which the spine and NCX follow, and images
+    check_fallbacks(&config)?;
+    check_images(&config, &mut report)?;
 
     // Generate non-preexisting files
     let container_xml = build_container_xml(&config, add_opf_to_rootfiles)?;
-    let (opf_xml, uid, title, first_linear_spine_href) =
-        build_opf_xml_and_get_metadata(&config, &ncx_id, &ncx_path_from_opf, &safe_uid)?;
+    let (opf_xml, uid, title, first_linear_spine_href) = build_opf_xml_and_get_metadata(
+        &config,
+        &ncx_id,
+        &ncx_path_from_opf,
+        &safe_uid,
+        &mut report,
+    )?;
     let ncx_xml = build_ncx_xml(
         &config,
         &PathBuf::from(opf_parent_dir),
@@ -223,7 +119,7 @@ pub fn build_epub2(recipe: &Recipe) -> Result<Vec<u8>, String> {
     // Zip up all files
     add_epub_mimetype(&mut zip_file)?;
     for (outside_path, inside_path) in outside_and_inside_paths {
-        zip_path(&mut zip_file, outside_path, Some(inside_path))?;
+        zip_path(&mut zip_file, outside_path, Some(inside_path), file_cache)?;
     }
     zip_buffer(
         &mut zip_file,
@@ -236,6 +132,11 @@ pub fn build_epub2(recipe: &Recipe) -> Result<Vec<u8>, String> {
     // Wrap up and return
     zip_file.finish().map_err(|e| e.to_string())?;
     drop(zip_file);
+    report.warnings.extend(check_built_epub(
+        &epub_file_buffer,
+        lenient_inside_paths,
+        &reported_references,
+    )?);
 
-    Ok(epub_file_buffer)
+    Ok((epub_file_buffer, report))
 }
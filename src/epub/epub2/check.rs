@@ -0,0 +1,372 @@
+use crate::epub::epub2::config::{
+    epub2_config_schema, parse_epub2_recipe, Epub2Config, ManifestItem, Metadata,
+};
+use crate::epub::epub2::fallbacks::find_fallback_problems;
+use crate::epub::epub2::helpers::get_manifest_item;
+use crate::epub::epub2::ids::{find_id_problems, IdEntry};
+use crate::epub::epub2::images::find_image_report;
+use crate::epub::epub2::metadata::find_metadata_problems;
+use crate::epub::epub2::orphans::{describe_orphan, describe_unlisted_reference, find_orphans};
+use crate::epub::epub2::paths::{find_path_problems, PathEntry};
+use crate::epub::epub2::spine::find_spine_report;
+use crate::epub::epub2::variants::is_excluded_reference;
+use crate::epub::validate::find_document_ids;
+use crate::toml::{find_schema_problems, PathSegment, Recipe, RecipeProblem};
+
+use std::collections::{HashMap, HashSet};
+use std::fs::read;
+use toml::Value;
+
+fn key(name: &str) -> PathSegment {
+    PathSegment::Key(String::from(name))
+}
+
+fn error_at(path: Vec<PathSegment>, message: String) -> RecipeProblem {
+    RecipeProblem {
+        path,
+        message,
+        is_warning: false,
+    }
+}
+
+fn warning_at(path: Vec<PathSegment>, message: String) -> RecipeProblem {
+    RecipeProblem {
+        path,
+        message,
+        is_warning: true,
+    }
+}
+
+fn get_array<'a>(value: &'a Value, name: &str) -> &'a [Value] {
+    match value.get(name) {
+        Some(Value::Array(array)) => array,
+        _ => &[],
+    }
+}
+
+fn config_metadata(config: &Epub2Config) -> &[Metadata] {
+    config.metadata.as_deref().unwrap_or(&[])
+}
+
+fn get_manifest_item_path(recipe: &Recipe, item: &ManifestItem, field: &str) -> Vec<PathSegment> {
+    // Items expanded from a glob point at the glob entry, which doesn't have the item's fields
+    let mut path = vec![key("manifest"), PathSegment::Index(item.entry_index)];
+    let entry = get_array(&recipe.recipe, "manifest").get(item.entry_index);
+    if entry.and_then(|entry| entry.get(field)).is_some() {
+        path.push(key(field));
+    }
+    path
+}
+
+///////////////
+//   Paths   //
+///////////////
+
+fn check_paths(recipe: &Recipe, config: &Epub2Config, problems: &mut Vec<RecipeProblem>) {
+    for problem in find_path_problems(config) {
+        let path = match problem.entry {
+            PathEntry::Container => Vec::new(),
+            PathEntry::Opf => {
+                match get_array(&recipe.recipe, "rootfiles")
+                    .iter()
+                    .position(|rootfile| {
+                        rootfile.get("media_type").and_then(Value::as_str)
+                            == Some("application/oebps-package+xml")
+                    }) {
+                    Some(index) => vec![
+                        key("rootfiles"),
+                        PathSegment::Index(index),
+                        key(problem.field),
+                    ],
+                    None => Vec::new(),
+                }
+            }
+            PathEntry::Ncx => vec![key("ncx_meta"), key(problem.field)],
+            PathEntry::ManifestItem(item) => get_manifest_item_path(recipe, item, problem.field),
+            PathEntry::NonmanifestFile(index) => vec![
+                key("nonmanifest_files"),
+                PathSegment::Index(index),
+                key(problem.field),
+            ],
+        };
+        problems.push(error_at(path, problem.message));
+    }
+}
+
+/////////////
+//   Ids   //
+/////////////
+
+fn check_ids(recipe: &Recipe, config: &Epub2Config, problems: &mut Vec<RecipeProblem>) {
+    for problem in find_id_problems(config) {
+        let path = match problem.entry {
+            IdEntry::ManifestItem(item) => get_manifest_item_path(recipe, item, "id"),
+            IdEntry::Metadata(index) => vec![
+                key("metadata"),
+                PathSegment::Index(config.metadata_entry_indices[index]),
+                key("id"),
+            ],
+            IdEntry::PageTarget(index) => vec![
+                key("pagelist"),
+                PathSegment::Index(config.pagelist_entry_indices[index]),
+                key("id"),
+            ],
+        };
+        problems.push(error_at(path, problem.message));
+    }
+}
+
+fn check_reference(
+    config: &Epub2Config,
+    reference: &Value,
+    path: Vec<PathSegment>,
+    problems: &mut Vec<RecipeProblem>,
+) {
+    // References to items left out of the build variant are dropped rather than reported
+    if let Value::String(reference) = reference {
+        if get_manifest_item(config, reference).is_none()
+            && !is_excluded_reference(config, reference)
+        {
+            problems.push(error_at(
+                path,
+                format!(
+                    "Idref {} not found in manifest as either an id or an inside path.",
+                    reference
+                ),
+            ));
+        }
+    }
+}
+
+// Ids of the XHTML documents which fragments point into, by outside path, read as they're needed
+type DocumentIds = HashMap<String, Option<HashSet<String>>>;
+
+fn check_fragment(
+    config: &Epub2Config,
+    entry: &Value,
+    path: &[PathSegment],
+    document_ids: &mut DocumentIds,
+    problems: &mut Vec<RecipeProblem>,
+) {
+    let (idref, fragment) = match (entry.get("idref"), entry.get("fragment")) {
+        (Some(Value::String(idref)), Some(Value::String(fragment))) => (idref, fragment),
+        _ => return,
+    };
+    let item = match get_manifest_item(config, idref) {
+        Some(item) if item.media_type == "application/xhtml+xml" => item,
+        _ => return,
+    };
+    let ids = document_ids
+        .entry(item.outside_path.clone())
+        .or_insert_with(|| {
+            read(&item.outside_path)
+                .ok()
+                .and_then(|bytes| find_document_ids(&bytes))
+        });
+    if let Some(ids) = ids {
+        if !ids.contains(fragment) {
+            problems.push(error_at(
+                [path, &[key("fragment")]].concat(),
+                format!(
+                    "Fragment {} doesn't match any id in {}.",
+                    fragment, item.outside_path
+                ),
+            ));
+        }
+    }
+}
+
+fn check_target(
+    config: &Epub2Config,
+    entry: &Value,
+    path: &[PathSegment],
+    document_ids: &mut DocumentIds,
+    problems: &mut Vec<RecipeProblem>,
+) {
+    // Checks an entry's idref, and its fragment if it has one
+    if let Some(idref) = entry.get("idref") {
+        check_reference(config, idref, [path, &[key("idref")]].concat(), problems);
+        check_fragment(config, entry, path, document_ids, problems);
+    }
+}
+
+fn check_navpoint_references(
+    config: &Epub2Config,
+    navpoints: &[Value],
+    path: &[PathSegment],
+    document_ids: &mut DocumentIds,
+    problems: &mut Vec<RecipeProblem>,
+) {
+    for (index, navpoint) in navpoints.iter().enumerate() {
+        let mut navpoint_path = path.to_vec();
+        navpoint_path.push(PathSegment::Index(index));
+        check_target(config, navpoint, &navpoint_path, document_ids, problems);
+        navpoint_path.push(key("children"));
+        check_navpoint_references(
+            config,
+            get_array(navpoint, "children"),
+            &navpoint_path,
+            document_ids,
+            problems,
+        );
+    }
+}
+
+fn check_references(recipe: &Recipe, config: &Epub2Config, problems: &mut Vec<RecipeProblem>) {
+    // Walks the recipe itself rather than the config, whose lists have been filtered by variant;
+    // the spine is checked with the build's own spine checks
+    let mut document_ids = DocumentIds::new();
+    for (index, reference) in get_array(&recipe.recipe, "guide").iter().enumerate() {
+        let path = [key("guide"), PathSegment::Index(index)];
+        check_target(config, reference, &path, &mut document_ids, problems);
+    }
+
+    check_navpoint_references(
+        config,
+        get_array(&recipe.recipe, "navmap"),
+        &[key("navmap")],
+        &mut document_ids,
+        problems,
+    );
+
+    for (index, target) in get_array(&recipe.recipe, "pagelist").iter().enumerate() {
+        let path = [key("pagelist"), PathSegment::Index(index)];
+        check_target(config, target, &path, &mut document_ids, problems);
+    }
+
+    for (list_index, navlist) in get_array(&recipe.recipe, "navlists").iter().enumerate() {
+        for (index, target) in get_array(navlist, "list").iter().enumerate() {
+            let path = [
+                key("navlists"),
+                PathSegment::Index(list_index),
+                key("list"),
+                PathSegment::Index(index),
+            ];
+            check_target(config, target, &path, &mut document_ids, problems);
+        }
+    }
+}
+
+//////////////////
+//   Metadata   //
+//////////////////
+
+fn check_metadata(config: &Epub2Config, problems: &mut Vec<RecipeProblem>) {
+    for (index, problem) in find_metadata_problems(config_metadata(config)) {
+        let path = vec![
+            key("metadata"),
+            PathSegment::Index(config.metadata_entry_indices[index]),
+            key(problem.field),
+        ];
+        problems.push(match problem.is_warning {
+            true => warning_at(path, problem.message),
+            false => error_at(path, problem.message),
+        });
+    }
+}
+
+///////////////////
+//   Fallbacks   //
+///////////////////
+
+fn check_fallbacks(recipe: &Recipe, config: &Epub2Config, problems: &mut Vec<RecipeProblem>) {
+    for (item, problem) in find_fallback_problems(config) {
+        let field = match problem.is_style {
+            true => "fallback_style",
+            false => "fallback",
+        };
+        problems.push(error_at(
+            get_manifest_item_path(recipe, item, field),
+            problem.message,
+        ));
+    }
+}
+
+////////////////
+//   Images   //
+////////////////
+
+fn check_images(recipe: &Recipe, config: &Epub2Config, problems: &mut Vec<RecipeProblem>) {
+    for (item, message) in find_image_report(config).problems {
+        problems.push(error_at(
+            get_manifest_item_path(recipe, item, "outside_path"),
+            message,
+        ));
+    }
+}
+
+///////////////
+//   Spine   //
+///////////////
+
+fn check_spine(config: &Epub2Config, problems: &mut Vec<RecipeProblem>) {
+    for problem in find_spine_report(config).problems {
+        let mut path = vec![key("spine")];
+        if let Some(index) = problem.index {
+            path.push(PathSegment::Index(config.spine_entry_indices[index]));
+        }
+        if let Some(field) = problem.field {
+            path.push(key(field));
+        }
+        problems.push(error_at(path, problem.message));
+    }
+}
+
+/////////////////
+//   Orphans   //
+/////////////////
+
+fn check_orphans(recipe: &Recipe, config: &Epub2Config, problems: &mut Vec<RecipeProblem>) {
+    // Orphans aren't worth a warning when the build is already leaving them out
+    let report = match find_orphans(config) {
+        Ok(report) => report,
+        Err(e) => {
+            problems.push(error_at(Vec::new(), e));
+            return;
+        }
+    };
+    for reference in &report.unlisted_references {
+        problems.push(warning_at(
+            get_manifest_item_path(recipe, reference.item, "outside_path"),
+            describe_unlisted_reference(reference),
+        ));
+    }
+    if config.prune_orphans != Some(true) {
+        for item in &report.orphans {
+            problems.push(warning_at(
+                get_manifest_item_path(recipe, item, "outside_path"),
+                describe_orphan(item),
+            ));
+        }
+    }
+}
+
+/////////////////////
+//   Entry Point   //
+/////////////////////
+
+pub(crate) fn find_epub2_problems(recipe: &Recipe) -> (Vec<RecipeProblem>, Option<Epub2Config>) {
+    // Collects every problem the build would stop at, for editors to show all at once
+    let mut problems = find_schema_problems(recipe, &epub2_config_schema(), true);
+    if problems.iter().any(|problem| !problem.is_warning) {
+        return (problems, None);
+    }
+    let config = match parse_epub2_recipe(recipe) {
+        Ok(config) => config,
+        Err(e) => {
+            problems.push(error_at(Vec::new(), e));
+            return (problems, None);
+        }
+    };
+
+    check_paths(recipe, &config, &mut problems);
+    check_ids(recipe, &config, &mut problems);
+    check_references(recipe, &config, &mut problems);
+    check_metadata(&config, &mut problems);
+    check_fallbacks(recipe, &config, &mut problems);
+    check_spine(&config, &mut problems);
+    check_images(recipe, &config, &mut problems);
+    check_orphans(recipe, &config, &mut problems);
+
+    (problems, Some(config))
+}
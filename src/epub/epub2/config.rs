@@ -1,15 +1,36 @@
-use crate::toml::Recipe;
+use crate::epub::epub2::manifest::{
+    expand_manifest_entries, generate_manifest_ids, resolve_media_types,
+};
+use crate::epub::epub2::variants::{filter_by_variant, remove_excluded_manifest_items};
+use crate::helpers::path_to_string;
+use crate::toml::{check_recipe_against_schema, Recipe};
 
+use schemars::schema::RootSchema;
+use schemars::{schema_for, JsonSchema};
 use serde::Deserialize;
+use std::collections::HashMap;
+
+//////////////////
+//   Variants   //
+//////////////////
+
+#[derive(Clone, Default, Deserialize, JsonSchema)]
+pub(crate) struct VariantTags {
+    /// Variants to include this in, leaving it out of all others and of builds without a variant
+    pub(crate) only_in: Option<Vec<String>>,
+    /// Variants to leave this out of
+    pub(crate) except_in: Option<Vec<String>>,
+}
 
 ///////////////////
 //   Container   //
 ///////////////////
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 pub(crate) struct Rootfile {
+    /// Path of the rootfile inside the container
     pub(crate) path: String,
-    #[serde(rename = "media-type")]
+    /// Media type of the rootfile
     pub(crate) media_type: String,
 }
 
@@ -17,62 +38,150 @@ pub(crate) struct Rootfile {
 //   OPF   //
 /////////////
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 #[serde(untagged)]
 pub(crate) enum Metadata {
+    /// Dublin Core element, e.g. title, creator or identifier
     DcMetadata {
         // Core
+        /// Dublin Core element name, without the dc: prefix
         name: String,
+        /// Element content
         content: String,
 
         // Attributes
-        id: Option<String>,     // For use with dc:identifier
-        scheme: Option<String>, // opf:scheme
-        #[serde(rename = "file-as")]
-        file_as: Option<String>, // opf:file-as
-        role: Option<String>,   // opf:role
-        event: Option<String>,  // opf:event
-        lang: Option<String>,   // xml:lang
+        /// Element id, for use with dc:identifier
+        id: Option<String>,
+        /// opf:scheme, e.g. ISBN
+        scheme: Option<String>,
+        /// opf:file-as, the sortable form of a name
+        file_as: Option<String>,
+        /// opf:role, a MARC relator code such as aut
+        role: Option<String>,
+        /// opf:event, e.g. publication
+        event: Option<String>,
+        /// xml:lang
+        lang: Option<String>,
+
+        /// Build variants the entry belongs to
+        #[serde(flatten)]
+        variants: VariantTags,
     },
+    /// Custom <meta> element
     CustomMetadata {
+        /// Meta name
         #[serde(rename = "custom_name")]
         name: String,
+        /// Meta content
         content: String,
+        /// Build variants the entry belongs to
+        #[serde(flatten)]
+        variants: VariantTags,
     },
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 pub(crate) struct ManifestItem {
     // Core
+    /// Path of the file on disk, relative to the recipe's base_dir
     pub(crate) outside_path: String,
+    /// Path of the file in the book, relative to the OPF
     pub(crate) inside_path_from_opf: String,
-    #[serde(rename = "media-type")]
+    /// Media type; inferred from the file extension if absent
+    #[serde(rename = "media_type")]
+    pub(crate) declared_media_type: Option<String>,
+    #[serde(skip)]
     pub(crate) media_type: String,
+    /// Manifest id; generated from the file name if absent
+    #[serde(rename = "id")]
+    pub(crate) declared_id: Option<String>,
+    #[serde(skip)]
     pub(crate) id: String,
+    // Index of the manifest entry, item or glob, which the item came from
+    #[serde(skip)]
+    pub(crate) entry_index: usize,
 
     // Fallback
+    /// Id of the item to use for reading systems which don't support this one
     pub(crate) fallback: Option<String>,
-    #[serde(rename = "fallback-style")]
+    /// Id of a CSS item to use when the item's inline XML islands aren't supported
     pub(crate) fallback_style: Option<String>,
-    #[serde(rename = "required-namespace")]
+    /// Namespace of the item's XML islands
     pub(crate) required_namespace: Option<String>,
-    #[serde(rename = "required-modules")]
+    /// Modules of the required namespace which the item uses
     pub(crate) required_modules: Option<String>,
+
+    /// Report malformed XHTML and markup outside OPS 2.0.1 in the item as warnings instead of
+    /// failing the build
+    pub(crate) lenient: Option<bool>,
+
+    /// Build variants the entry belongs to
+    #[serde(flatten)]
+    pub(crate) variants: VariantTags,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub(crate) struct ManifestGlob {
+    /// Glob pattern matching files on disk, relative to the recipe's base_dir
+    pub(crate) glob: String,
+    /// Directory in the book, relative to the OPF, that matched files are placed under
+    pub(crate) inside_dir_from_opf: Option<String>,
+    /// Media type for every matched file; inferred per file if absent
+    pub(crate) media_type: Option<String>,
+    /// Prefix for the generated manifest ids
+    pub(crate) id_prefix: Option<String>,
+    /// Report malformed XHTML and markup outside OPS 2.0.1 in matched files as warnings instead of
+    /// failing the build
+    pub(crate) lenient: Option<bool>,
+    /// Build variants the entry belongs to
+    #[serde(flatten)]
+    pub(crate) variants: VariantTags,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub(crate) enum ManifestEntry {
+    /// A single file
+    Item(ManifestItem),
+    /// Every file matching a glob pattern
+    Glob(ManifestGlob),
+}
+
+#[derive(Deserialize, JsonSchema)]
 #[serde(untagged)]
 pub(crate) enum Itemref {
+    /// Manifest id or inside path
     RawIdref(String),
-    CookedIdref { idref: String, linear: Option<bool> },
+    CookedIdref {
+        /// Manifest id or inside path
+        idref: String,
+        /// Whether the item is part of the main reading order
+        linear: Option<bool>,
+        /// Build variants the entry belongs to
+        #[serde(flatten)]
+        variants: VariantTags,
+    },
+    Glob {
+        /// Glob pattern of a manifest glob entry, adding every file it matched
+        glob: String,
+        /// Whether the items are part of the main reading order
+        linear: Option<bool>,
+        /// Build variants the entry belongs to
+        #[serde(flatten)]
+        variants: VariantTags,
+    },
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 pub(crate) struct Reference {
+    /// Guide reference type, e.g. cover, toc or text
     #[serde(rename = "type")]
     pub(crate) reference_type: String,
+    /// Reference title
     pub(crate) title: Option<String>,
+    /// Manifest id or inside path of the referenced item
     pub(crate) idref: String,
+    /// Fragment identifier within the referenced item, without the '#'
     pub(crate) fragment: Option<String>,
 }
 
@@ -80,82 +189,130 @@ pub(crate) struct Reference {
 //   NCX   //
 /////////////
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 pub(crate) struct NcxMeta {
+    /// Manifest id of the NCX; defaults to ncx
     pub(crate) manifest_id: Option<String>,
+    /// Path of the NCX relative to the OPF; defaults to toc.ncx
     pub(crate) manifest_path_from_opf: Option<String>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 pub(crate) struct NavLabel {
+    /// Label text
     pub(crate) label: String,
+    /// xml:lang of the label
     pub(crate) lang: Option<String>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 #[serde(untagged)]
 pub(crate) enum NavPoint {
+    /// Labelled with a single label
     WithSimpleLabel {
+        /// Label text
         label: String,
+        /// Manifest id or inside path of the target item
         idref: String,
+        /// Fragment identifier within the target item, without the '#'
         fragment: Option<String>,
+        /// Nested navigation points
         children: Option<Vec<NavPoint>>,
+        /// Build variants the entry belongs to
+        #[serde(flatten)]
+        variants: VariantTags,
     },
+    /// Labelled in several languages
     WithComplexLabels {
+        /// Label in each of several languages
         labels: Vec<NavLabel>,
+        /// Manifest id or inside path of the target item
         idref: String,
+        /// Fragment identifier within the target item, without the '#'
         fragment: Option<String>,
+        /// Nested navigation points
         children: Option<Vec<NavPoint>>,
+        /// Build variants the entry belongs to
+        #[serde(flatten)]
+        variants: VariantTags,
     },
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 #[serde(untagged)]
 pub(crate) enum PageTarget {
+    /// Labelled with a single label
     WithSimpleLabel {
+        /// Label text
         label: String,
+        /// NCX id of the page target
         id: String,
+        /// Page type: front, normal or special
         #[serde(rename = "type")]
         target_type: String,
+        /// Page number value
         value: Option<String>,
+        /// Manifest id or inside path of the target item
         idref: String,
+        /// Fragment identifier within the target item, without the '#'
         fragment: Option<String>,
     },
+    /// Labelled in several languages
     WithComplexLabels {
+        /// Label in each of several languages
         labels: Vec<NavLabel>,
+        /// NCX id of the page target
         id: String,
+        /// Page type: front, normal or special
         #[serde(rename = "type")]
         target_type: String,
+        /// Page number value
         value: Option<String>,
+        /// Manifest id or inside path of the target item
         idref: String,
+        /// Fragment identifier within the target item, without the '#'
         fragment: Option<String>,
     },
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 #[serde(untagged)]
 pub(crate) enum NavTarget {
+    /// Labelled with a single label
     WithSimpleLabel {
+        /// Label text
         label: String,
+        /// Manifest id or inside path of the target item
         idref: String,
+        /// Fragment identifier within the target item, without the '#'
         fragment: Option<String>,
     },
+    /// Labelled in several languages
     WithComplexLabels {
+        /// Label in each of several languages
         labels: Vec<NavLabel>,
+        /// Manifest id or inside path of the target item
         idref: String,
+        /// Fragment identifier within the target item, without the '#'
         fragment: Option<String>,
     },
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 #[serde(untagged)]
 pub(crate) enum NavList {
+    /// Labelled with a single label
     WithSimpleLabel {
+        /// Label text
         label: String,
+        /// Targets in the list
         list: Vec<NavTarget>,
     },
+    /// Labelled in several languages
     WithComplexLabels {
+        /// Label in each of several languages
         labels: Vec<NavLabel>,
+        /// Targets in the list
         list: Vec<NavTarget>,
     },
 }
@@ -164,43 +321,106 @@ pub(crate) enum NavList {
 //   Miscellaneous   //
 ///////////////////////
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 pub(crate) struct NonmanifestFile {
+    /// Path of the file on disk, relative to the recipe's base_dir
     pub(crate) outside_path: String,
+    /// Path of the file in the book, relative to the container root
     pub(crate) inside_path: String,
 }
 
+#[derive(Deserialize, JsonSchema)]
+pub(crate) struct ImageLimits {
+    /// Largest allowed area of a raster image, in millions of pixels
+    pub(crate) max_megapixels: Option<f64>,
+    /// Largest allowed image file, in KiB
+    pub(crate) max_file_size_kib: Option<u64>,
+    /// Narrowest allowed raster cover image, in pixels
+    pub(crate) min_cover_width: Option<u32>,
+    /// Shortest allowed raster cover image, in pixels
+    pub(crate) min_cover_height: Option<u32>,
+}
+
 ////////////////////////////
 //   Main Config Struct   //
 ////////////////////////////
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 pub(crate) struct Epub2Config {
     // Container
+    /// Extra rootfiles listed in META-INF/container.xml
     pub(crate) rootfiles: Option<Vec<Rootfile>>,
 
     // OPF
+    /// Publication metadata
     pub(crate) metadata: Option<Vec<Metadata>>,
+    // Index of the recipe entry which each metadata item came from, as variants leave some out
+    #[serde(skip)]
+    pub(crate) metadata_entry_indices: Vec<usize>,
+    /// Files included in the book
+    #[serde(rename = "manifest")]
+    pub(crate) manifest_entries: Vec<ManifestEntry>,
+    #[serde(skip)]
     pub(crate) manifest: Vec<ManifestItem>,
+    #[serde(skip)]
+    pub(crate) manifest_glob_paths: HashMap<String, Vec<String>>,
+    // Manifest items left out of the build variant
+    #[serde(skip)]
+    pub(crate) excluded_manifest: Vec<ManifestItem>,
+    /// Reading order
     pub(crate) spine: Option<Vec<Itemref>>,
+    // Index of the recipe entry which each spine entry came from, as variants leave some out
+    #[serde(skip)]
+    pub(crate) spine_entry_indices: Vec<usize>,
+    /// Structural components such as the cover and table of contents
     pub(crate) guide: Option<Vec<Reference>>,
 
     // NCX
+    /// Location of the NCX
     pub(crate) ncx_meta: Option<NcxMeta>,
+    /// Table of contents
     pub(crate) navmap: Option<Vec<NavPoint>>,
+    /// Print page locations
     pub(crate) pagelist: Option<Vec<PageTarget>>,
+    // Index of the recipe entry which each page target came from, as variants leave some out
+    #[serde(skip)]
+    pub(crate) pagelist_entry_indices: Vec<usize>,
+    /// Additional navigation lists, e.g. of illustrations
     pub(crate) navlists: Option<Vec<NavList>>,
 
     // Miscellaneous
+    /// Files stored in the container outside the manifest
     pub(crate) nonmanifest_files: Option<Vec<NonmanifestFile>>,
+    /// Leave manifest items which nothing references out of the book
+    pub(crate) prune_orphans: Option<bool>,
+    /// Limits on images, such as those retailers enforce, which the build checks
+    pub(crate) image_limits: Option<ImageLimits>,
+}
+
+pub fn epub2_config_schema() -> RootSchema {
+    schema_for!(Epub2Config)
 }
 
 pub(crate) fn parse_epub2_recipe(recipe: &Recipe) -> Result<Epub2Config, String> {
-    let config = recipe
+    check_recipe_against_schema(recipe, &epub2_config_schema())?;
+    let mut config: Epub2Config = recipe
         .recipe
         .clone()
         .try_into()
-        .map_err(|s| s.to_string())?;
+        .map_err(|e: toml::de::Error| recipe.annotate_error(e.to_string()))?;
+
+    expand_manifest_entries(&mut config, &recipe.base_dir)?;
+    // Ids are generated before variant filtering so that they're the same in every variant
+    generate_manifest_ids(&mut config);
+    let variant = recipe.variant.as_deref();
+    remove_excluded_manifest_items(&mut config, variant, &recipe.name)?;
+    filter_by_variant(&mut config, variant);
+    resolve_media_types(&mut config)?;
+    if let Some(nonmanifest_files) = &mut config.nonmanifest_files {
+        for file in nonmanifest_files {
+            file.outside_path = path_to_string(recipe.base_dir.join(&file.outside_path))?;
+        }
+    }
 
     Ok(config)
 }
@@ -0,0 +1,58 @@
+use crate::epub::epub2::config::{Epub2Config, ManifestItem};
+use crate::epub::fallbacks::{FallbackGraph, FallbackItem, FallbackProblem};
+
+fn get_fallback_items(config: &Epub2Config) -> Vec<FallbackItem<'_>> {
+    config
+        .manifest
+        .iter()
+        .map(|item| FallbackItem {
+            id: &item.id,
+            media_type: &item.media_type,
+            fallback: item.fallback.as_deref(),
+            fallback_style: item.fallback_style.as_deref(),
+        })
+        .collect()
+}
+
+pub(crate) fn get_fallback_chain<'a>(
+    config: &'a Epub2Config,
+    item: &ManifestItem,
+) -> Vec<&'a ManifestItem> {
+    // The item followed by every item its fallbacks lead to
+    let index = match config.manifest.iter().position(|other| other.id == item.id) {
+        Some(index) => index,
+        None => return Vec::new(),
+    };
+    let items = get_fallback_items(config);
+    FallbackGraph::new(&items)
+        .get_chain(index)
+        .items
+        .into_iter()
+        .map(|index| &config.manifest[index])
+        .collect()
+}
+
+pub(crate) fn find_fallback_problems(
+    config: &Epub2Config,
+) -> Vec<(&ManifestItem, FallbackProblem)> {
+    let items = get_fallback_items(config);
+    FallbackGraph::new(&items)
+        .find_problems()
+        .into_iter()
+        .map(|problem| (&config.manifest[problem.index], problem))
+        .collect()
+}
+
+pub(crate) fn check_fallbacks(config: &Epub2Config) -> Result<(), String> {
+    let problems: Vec<String> = find_fallback_problems(config)
+        .into_iter()
+        .map(|(_, problem)| format!("  {}", problem.message))
+        .collect();
+    match problems.is_empty() {
+        true => Ok(()),
+        false => Err(format!(
+            "Manifest fallbacks have problems:\n{}",
+            problems.join("\n")
+        )),
+    }
+}
@@ -1,13 +1,59 @@
-use crate::epub::epub2::config::Epub2Config;
+use crate::epub::epub2::config::{Epub2Config, ManifestItem, Metadata};
+use crate::epub::epub2::fallbacks::get_fallback_chain;
+use crate::helpers::fixed_clean;
+
+pub(crate) fn get_manifest_item<'a>(
+    config: &'a Epub2Config,
+    reference: &str,
+) -> Option<&'a ManifestItem> {
+    // References may be either manifest ids or inside paths from the OPF
+    config
+        .manifest
+        .iter()
+        .find(|item| item.id == reference)
+        .or_else(|| {
+            let reference_path = fixed_clean(reference);
+            config
+                .manifest
+                .iter()
+                .find(|item| fixed_clean(&item.inside_path_from_opf) == reference_path)
+        })
+}
+
+pub(crate) fn is_spine_item(config: &Epub2Config, item: &ManifestItem) -> bool {
+    // The spine may only hold XHTML and DTBook documents, or items which fall back to them
+    get_fallback_chain(config, item).iter().any(|item| {
+        item.media_type == "application/xhtml+xml" || item.media_type == "application/x-dtbook+xml"
+    })
+}
+
+pub(crate) fn get_cover_item(config: &Epub2Config) -> Option<&ManifestItem> {
+    // The cover image is the item which a cover meta names
+    config
+        .metadata
+        .iter()
+        .flatten()
+        .find_map(|item| match item {
+            Metadata::CustomMetadata { name, content, .. } if name == "cover" => {
+                get_manifest_item(config, content)
+            }
+            _ => None,
+        })
+}
 
 pub(crate) fn get_path_from_idref(
     config: &Epub2Config,
     idref: &str,
     fragment: Option<&String>,
 ) -> Result<String, String> {
-    let manifest_item = match config.manifest.iter().find(|item| &item.id == idref) {
+    let manifest_item = match get_manifest_item(config, idref) {
         Some(item) => item,
-        None => return Err(format!("Idref {} not found in manifest.", idref)),
+        None => {
+            return Err(format!(
+                "Idref {} not found in manifest as either an id or an inside path.",
+                idref
+            ))
+        }
     };
     match fragment {
         None => Ok(manifest_item.inside_path_from_opf.clone()),
@@ -17,3 +63,54 @@ pub(crate) fn get_path_from_idref(
         )),
     }
 }
+
+pub(crate) fn get_opf_path(config: &Epub2Config) -> (bool, &str) {
+    // Whether the OPF still needs adding to the container's rootfiles, and its inside path
+    match &config.rootfiles {
+        None => (true, "OEBPS/content.opf"),
+        Some(rootfiles_vec) => match rootfiles_vec
+            .iter()
+            .find(|rootfile| &rootfile.media_type == "application/oebps-package+xml")
+        {
+            None => (true, "OEBPS/content.opf"),
+            Some(rootfile) => (false, rootfile.path.as_ref()),
+        },
+    }
+}
+
+pub(crate) fn get_ncx_id(config: &Epub2Config) -> &str {
+    match &config.ncx_meta {
+        Some(meta) => match &meta.manifest_id {
+            Some(id) => id,
+            None => "ncx",
+        },
+        None => "ncx",
+    }
+}
+
+pub(crate) fn get_safe_id(base: &str, ids: &[String]) -> String {
+    let mut tentative_id = String::from(base);
+    let mut number_to_append = 1;
+    while ids.contains(&tentative_id) {
+        tentative_id = format!("{}_{}", base, number_to_append);
+        number_to_append += 1;
+    }
+    tentative_id
+}
+
+pub(crate) fn make_ncname(name: &str) -> String {
+    let mut ncname: String = name
+        .chars()
+        .map(
+            |c| match c.is_alphanumeric() || c == '_' || c == '-' || c == '.' {
+                true => c,
+                false => '_',
+            },
+        )
+        .collect();
+    match ncname.chars().next() {
+        Some(first) if first.is_alphabetic() || first == '_' => (),
+        _ => ncname.insert(0, '_'),
+    }
+    ncname
+}
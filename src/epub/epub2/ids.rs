@@ -0,0 +1,79 @@
+use crate::epub::epub2::config::{Epub2Config, ManifestItem, Metadata, PageTarget};
+use crate::epub::epub2::helpers::get_ncx_id;
+
+pub(crate) enum IdEntry<'a> {
+    ManifestItem(&'a ManifestItem),
+    // Indices into the metadata and page list
+    Metadata(usize),
+    PageTarget(usize),
+}
+
+pub(crate) struct IdProblem<'a> {
+    pub(crate) entry: IdEntry<'a>,
+    pub(crate) message: String,
+}
+
+pub(crate) fn get_opf_ids(config: &Epub2Config) -> Vec<String> {
+    // Every id declared in the OPF, the NCX's manifest id first
+    let mut ids = vec![String::from(get_ncx_id(config))];
+    ids.extend(config.manifest.iter().map(|item| item.id.clone()));
+    ids.extend(
+        config
+            .metadata
+            .iter()
+            .flatten()
+            .filter_map(|item| match item {
+                Metadata::DcMetadata { id, .. } => id.clone(),
+                Metadata::CustomMetadata { .. } => None,
+            }),
+    );
+    ids
+}
+
+pub(crate) fn find_id_problems(config: &Epub2Config) -> Vec<IdProblem<'_>> {
+    // The NCX's id comes first, so that it's the recipe's own entries which are reported
+    let mut problems = Vec::new();
+    let mut opf_ids = vec![get_ncx_id(config)];
+    let mut push_opf_id = |entry, id| match opf_ids.contains(&id) {
+        true => problems.push(IdProblem {
+            entry,
+            message: format!("Id {} is used more than once in the OPF.", id),
+        }),
+        false => opf_ids.push(id),
+    };
+    for item in &config.manifest {
+        push_opf_id(IdEntry::ManifestItem(item), item.id.as_str());
+    }
+    for (index, item) in config.metadata.iter().flatten().enumerate() {
+        if let Metadata::DcMetadata { id: Some(id), .. } = item {
+            push_opf_id(IdEntry::Metadata(index), id.as_str());
+        }
+    }
+
+    let mut ncx_ids: Vec<&str> = Vec::new();
+    for (index, target) in config.pagelist.iter().flatten().enumerate() {
+        let id = match target {
+            PageTarget::WithSimpleLabel { id, .. } => id,
+            PageTarget::WithComplexLabels { id, .. } => id,
+        };
+        match ncx_ids.contains(&id.as_str()) {
+            true => problems.push(IdProblem {
+                entry: IdEntry::PageTarget(index),
+                message: format!("Id {} is used more than once in the NCX.", id),
+            }),
+            false => ncx_ids.push(id),
+        }
+    }
+    problems
+}
+
+pub(crate) fn check_ids(config: &Epub2Config) -> Result<(), String> {
+    let problems: Vec<String> = find_id_problems(config)
+        .into_iter()
+        .map(|problem| format!("  {}", problem.message))
+        .collect();
+    match problems.is_empty() {
+        true => Ok(()),
+        false => Err(format!("Ids have problems:\n{}", problems.join("\n"))),
+    }
+}
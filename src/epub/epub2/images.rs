@@ -0,0 +1,146 @@
+use crate::epub::build::BuildReport;
+use crate::epub::epub2::config::{Epub2Config, ImageLimits, ManifestItem};
+use crate::epub::epub2::helpers::get_cover_item;
+use crate::epub::media_type::{
+    is_raster_image, read_image_dimensions, signature_for_media_type, sniff_content,
+};
+use crate::helpers::format_size;
+
+use std::fs::read;
+
+pub(crate) struct ImageReport<'a> {
+    // Pixel dimensions of every raster image whose header could be read
+    pub(crate) dimensions: Vec<(&'a ManifestItem, u32, u32)>,
+    pub(crate) problems: Vec<(&'a ManifestItem, String)>,
+}
+
+fn find_item_problems<'a>(
+    item: &'a ManifestItem,
+    is_cover: bool,
+    limits: Option<&ImageLimits>,
+    report: &mut ImageReport<'a>,
+) {
+    // Unreadable files are reported when the book is zipped up
+    let contents = match read(&item.outside_path) {
+        Ok(contents) => contents,
+        Err(_) => return,
+    };
+    let name = format!("Image {} ({})", item.id, item.outside_path);
+
+    if let Some(max_kib) = limits.and_then(|limits| limits.max_file_size_kib) {
+        if contents.len() as u64 > max_kib * 1024 {
+            report.problems.push((
+                item,
+                format!(
+                    "{} is {}, over the limit of {}.",
+                    name,
+                    format_size(contents.len() as u64),
+                    format_size(max_kib * 1024)
+                ),
+            ));
+        }
+    }
+
+    // Vector images have no pixel dimensions, being drawn at whatever size is needed
+    let expected_signature = match signature_for_media_type(&item.media_type) {
+        Some(signature) if is_raster_image(signature) => signature,
+        _ => return,
+    };
+    let dimensions = match sniff_content(&contents) {
+        Some(signature) if !signature.media_types.contains(&item.media_type.as_ref()) => {
+            report.problems.push((
+                item,
+                format!(
+                    "{} has media type {}, but its header is a {} header.",
+                    name, item.media_type, signature.description
+                ),
+            ));
+            return;
+        }
+        Some(signature) => read_image_dimensions(signature, &contents),
+        None => None,
+    };
+    let (width, height) = match dimensions {
+        Some(dimensions) => dimensions,
+        None => {
+            report.problems.push((
+                item,
+                format!(
+                    "{} has a {} header which is truncated or corrupt, so its dimensions can't be read.",
+                    name, expected_signature.description
+                ),
+            ));
+            return;
+        }
+    };
+    report.dimensions.push((item, width, height));
+
+    let limits = match limits {
+        Some(limits) => limits,
+        None => return,
+    };
+    if let Some(max_megapixels) = limits.max_megapixels {
+        let megapixels = width as f64 * height as f64 / 1_000_000.0;
+        if megapixels > max_megapixels {
+            report.problems.push((
+                item,
+                format!(
+                    "{} is {}x{} pixels, which is {:.2} megapixels, over the limit of {}.",
+                    name, width, height, megapixels, max_megapixels
+                ),
+            ));
+        }
+    }
+    if is_cover {
+        let min_width = limits.min_cover_width.unwrap_or(0);
+        let min_height = limits.min_cover_height.unwrap_or(0);
+        if width < min_width || height < min_height {
+            report.problems.push((
+                item,
+                format!(
+                    "Cover image {} ({}) is {}x{} pixels, under the minimum of {}x{}.",
+                    item.id, item.outside_path, width, height, min_width, min_height
+                ),
+            ));
+        }
+    }
+}
+
+pub(crate) fn find_image_report(config: &Epub2Config) -> ImageReport<'_> {
+    // Every image's header is decoded; only the comparisons depend on the recipe's image_limits
+    let cover_id = get_cover_item(config).map(|item| &item.id);
+    let mut report = ImageReport {
+        dimensions: Vec::new(),
+        problems: Vec::new(),
+    };
+    for item in &config.manifest {
+        if item.media_type.starts_with("image/") {
+            let is_cover = cover_id == Some(&item.id);
+            find_item_problems(item, is_cover, config.image_limits.as_ref(), &mut report);
+        }
+    }
+    report
+}
+
+pub(crate) fn check_images(
+    config: &Epub2Config,
+    build_report: &mut BuildReport,
+) -> Result<(), String> {
+    // Every problem is listed at once, since fixing images usually means a trip to an editor
+    let report = find_image_report(config);
+    for (item, width, height) in &report.dimensions {
+        build_report.notes.push(format!(
+            "Image {} ({}) is {}x{} pixels.",
+            item.id, item.outside_path, width, height
+        ));
+    }
+    let problems: Vec<String> = report
+        .problems
+        .into_iter()
+        .map(|(_, problem)| format!("  {}", problem))
+        .collect();
+    match problems.is_empty() {
+        true => Ok(()),
+        false => Err(format!("Images have problems:\n{}", problems.join("\n"))),
+    }
+}
@@ -0,0 +1,227 @@
+use crate::epub::epub2::config::{
+    Epub2Config, ManifestEntry, ManifestGlob, ManifestItem, Metadata,
+};
+use crate::epub::epub2::helpers::{get_ncx_id, get_safe_id, make_ncname};
+use crate::epub::media_type::{
+    media_type_from_extension, signature_for_media_type, sniff_content, starts_with_markup,
+};
+use crate::helpers::{fixed_clean, natural_cmp, path_to_string};
+
+use glob::{glob, Pattern};
+use std::fs::File;
+use std::io::Read;
+use std::mem::take;
+use std::path::{Component, Path, PathBuf};
+
+fn get_literal_base(pattern: &str) -> PathBuf {
+    let pattern_path = Path::new(pattern);
+    let mut base = PathBuf::new();
+    for component in pattern_path.parent().unwrap_or(Path::new("")).components() {
+        if let Component::Normal(part) = component {
+            if part.to_string_lossy().contains(&['*', '?', '['][..]) {
+                break;
+            }
+        }
+        base.push(component);
+    }
+    base
+}
+
+fn expand_manifest_glob(
+    manifest_glob: &ManifestGlob,
+    base_dir: &Path,
+    entry_index: usize,
+) -> Result<Vec<ManifestItem>, String> {
+    let base = get_literal_base(&manifest_glob.glob);
+    let full_base = base_dir.join(&base);
+    let full_pattern =
+        match Path::new(&manifest_glob.glob).is_absolute() || base_dir.as_os_str().is_empty() {
+            true => manifest_glob.glob.clone(),
+            false => format!(
+                "{}/{}",
+                Pattern::escape(&path_to_string(base_dir)?),
+                manifest_glob.glob
+            ),
+        };
+
+    let mut matches = Vec::new();
+    for entry in glob(&full_pattern)
+        .map_err(|e| format!("Invalid glob pattern '{}': {}", manifest_glob.glob, e))?
+    {
+        let path = entry.map_err(|e| e.to_string())?;
+        if path.is_file() {
+            let relative_path = path_to_string(path.strip_prefix(&full_base).unwrap_or(&path))?;
+            matches.push((path, relative_path));
+        }
+    }
+    if matches.is_empty() {
+        return Err(format!(
+            "Glob pattern '{}' in manifest matched no files in {}.",
+            manifest_glob.glob,
+            base_dir.display()
+        ));
+    }
+    matches.sort_by(|(_, a), (_, b)| natural_cmp(a, b));
+
+    let inside_dir = match &manifest_glob.inside_dir_from_opf {
+        Some(dir) => PathBuf::from(dir),
+        None if base.is_absolute() => PathBuf::new(),
+        None => base.clone(),
+    };
+    let id_prefix = manifest_glob.id_prefix.as_deref().unwrap_or("");
+
+    matches
+        .iter()
+        .map(|(path, relative_path)| {
+            let relative_stem = path_to_string(Path::new(relative_path).with_extension(""))?;
+            Ok(ManifestItem {
+                outside_path: path_to_string(path)?,
+                inside_path_from_opf: path_to_string(fixed_clean(inside_dir.join(relative_path)))?,
+                declared_media_type: manifest_glob.media_type.clone(),
+                media_type: String::new(),
+                declared_id: None,
+                // Starting point for the generated id; see generate_manifest_ids
+                id: make_ncname(&format!("{}{}", id_prefix, relative_stem)),
+                entry_index,
+                fallback: None,
+                fallback_style: None,
+                required_namespace: None,
+                required_modules: None,
+                lenient: manifest_glob.lenient,
+                variants: manifest_glob.variants.clone(),
+            })
+        })
+        .collect()
+}
+
+pub(crate) fn expand_manifest_entries(
+    config: &mut Epub2Config,
+    base_dir: &Path,
+) -> Result<(), String> {
+    for (entry_index, entry) in take(&mut config.manifest_entries).into_iter().enumerate() {
+        match entry {
+            ManifestEntry::Item(mut item) => {
+                item.outside_path = path_to_string(base_dir.join(&item.outside_path))?;
+                item.entry_index = entry_index;
+                config.manifest.push(item);
+            }
+            ManifestEntry::Glob(manifest_glob) => {
+                let items = expand_manifest_glob(&manifest_glob, base_dir, entry_index)?;
+                config.manifest_glob_paths.insert(
+                    manifest_glob.glob.clone(),
+                    items
+                        .iter()
+                        .map(|item| item.inside_path_from_opf.clone())
+                        .collect(),
+                );
+                config.manifest.extend(items);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_file_head(path: &str) -> Result<Option<Vec<u8>>, String> {
+    if !Path::new(path).is_file() {
+        return Ok(None);
+    }
+    // Markup is read in full, since the prolog before the first element can be any length
+    let mut head = Vec::new();
+    File::open(path)
+        .and_then(|mut file| {
+            (&mut file).take(1024).read_to_end(&mut head)?;
+            match starts_with_markup(&head) {
+                true => file.read_to_end(&mut head),
+                false => Ok(0),
+            }
+        })
+        .map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    Ok(Some(head))
+}
+
+fn resolve_media_type(item: &ManifestItem) -> Result<String, String> {
+    let sniffed = match read_file_head(&item.outside_path)? {
+        Some(head) => sniff_content(&head),
+        None => None,
+    };
+
+    let (media_type, source) = match &item.declared_media_type {
+        Some(declared) => (declared.as_ref(), "declared media type"),
+        None => match media_type_from_extension(&item.outside_path)
+            .or_else(|| media_type_from_extension(&item.inside_path_from_opf))
+        {
+            Some(from_extension) => (from_extension, "extension-inferred media type"),
+            None => match sniffed {
+                Some(signature) => return Ok(String::from(signature.media_types[0])),
+                None => {
+                    return Err(format!(
+                        "Couldn't infer media type of manifest item {} ({}); please specify its media_type.",
+                        item.id, item.outside_path
+                    ))
+                }
+            },
+        },
+    };
+
+    match (sniffed, signature_for_media_type(media_type)) {
+        (Some(signature), _) if !signature.media_types.contains(&media_type) => Err(format!(
+            "Manifest item {} ({}) has {} {}, but its content is a {} ({}).",
+            item.id,
+            item.outside_path,
+            source,
+            media_type,
+            signature.description,
+            signature.media_types.join(" or ")
+        )),
+        // Missing files are reported when the book is zipped up
+        (None, Some(expected_signature)) if Path::new(&item.outside_path).is_file() => {
+            Err(format!(
+                "Manifest item {} ({}) has {} {}, but its content is not a {}.",
+                item.id, item.outside_path, source, media_type, expected_signature.description
+            ))
+        }
+        _ => Ok(String::from(media_type)),
+    }
+}
+
+pub(crate) fn resolve_media_types(config: &mut Epub2Config) -> Result<(), String> {
+    for item in config.manifest.iter_mut() {
+        item.media_type = resolve_media_type(item)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn generate_manifest_ids(config: &mut Epub2Config) {
+    let mut taken_ids: Vec<String> = config
+        .manifest
+        .iter()
+        .filter_map(|item| item.declared_id.clone())
+        .collect();
+    taken_ids.push(String::from(get_ncx_id(config)));
+    if let Some(metadata) = &config.metadata {
+        taken_ids.extend(metadata.iter().filter_map(|item| match item {
+            Metadata::DcMetadata { id, .. } => id.clone(),
+            Metadata::CustomMetadata { .. } => None,
+        }));
+    }
+
+    for item in config.manifest.iter_mut() {
+        item.id = match &item.declared_id {
+            Some(id) => id.clone(),
+            None => {
+                let base = match item.id.is_empty() {
+                    true => make_ncname(
+                        &Path::new(&item.inside_path_from_opf)
+                            .file_stem()
+                            .unwrap_or_default()
+                            .to_string_lossy(),
+                    ),
+                    false => item.id.clone(),
+                };
+                let id = get_safe_id(&base, &taken_ids);
+                taken_ids.push(id.clone());
+                id
+            }
+        };
+    }
+}
@@ -0,0 +1,550 @@
+use crate::epub::build::BuildReport;
+use crate::epub::epub2::config::Metadata;
+use crate::epub::validate::ValidationIssue;
+
+// MARC relator codes, which OPF 2.0 takes opf:role values from, including a few obsolete ones
+// still found in older books
+const MARC_RELATORS: &[&str] = &[
+    "abr", "acp", "act", "adi", "adp", "aft", "anl", "anm", "ann", "ant", "ape", "apl", "app",
+    "aqt", "arc", "ard", "arr", "art", "asg", "asn", "ato", "att", "auc", "aud", "aui", "aus",
+    "aut", "bdd", "bjd", "bkd", "bkp", "blw", "bnd", "bpd", "brd", "brl", "bsl", "cas", "ccp",
+    "chr", "clb", "cli", "cll", "clr", "clt", "cmm", "cmp", "cmt", "cnd", "cng", "cns", "coe",
+    "col", "com", "con", "cor", "cos", "cot", "cou", "cov", "cpc", "cpe", "cph", "cpl", "cpt",
+    "cre", "crp", "crr", "crt", "csl", "csp", "cst", "ctb", "cte", "ctg", "ctr", "cts", "ctt",
+    "cur", "cwt", "dbp", "dfd", "dfe", "dft", "dgg", "dgs", "dis", "dln", "dnc", "dnr", "dpc",
+    "dpt", "drm", "drt", "dsr", "dst", "dtc", "dte", "dtm", "dto", "dub", "edc", "edm", "edt",
+    "egr", "elg", "elt", "eng", "enj", "etr", "evp", "exp", "fac", "fds", "fld", "flm", "fmd",
+    "fmk", "fmo", "fmp", "fnd", "fpy", "frg", "gis", "grt", "his", "hnr", "hst", "ill", "ilu",
+    "ins", "inv", "isb", "itr", "ive", "ivr", "jud", "jug", "lbr", "lbt", "ldr", "led", "lee",
+    "lel", "len", "let", "lgd", "lie", "lil", "lit", "lsa", "lse", "lso", "ltg", "lyr", "mcp",
+    "mdc", "med", "mfp", "mfr", "mod", "mon", "mrb", "mrk", "msd", "mte", "mtk", "mus", "nrt",
+    "opn", "org", "orm", "osp", "oth", "own", "pan", "pat", "pbd", "pbl", "pdr", "pfr", "pht",
+    "plt", "pma", "pmn", "pop", "ppm", "ppt", "pra", "prc", "prd", "pre", "prf", "prg", "prm",
+    "prn", "pro", "prp", "prs", "prt", "prv", "pta", "pte", "ptf", "pth", "ptt", "pup", "rbr",
+    "rcd", "rce", "rcp", "rdd", "red", "ren", "res", "rev", "rpc", "rps", "rpt", "rpy", "rse",
+    "rsg", "rsp", "rsr", "rst", "rth", "rtm", "sad", "sce", "scl", "scr", "sds", "sec", "sgd",
+    "sgn", "sht", "sll", "sng", "spk", "spn", "spy", "srv", "std", "stg", "stl", "stm", "stn",
+    "str", "tcd", "tch", "ths", "tld", "tlp", "trc", "trl", "tyd", "tyg", "uvp", "vac", "vdg",
+    "voc", "wac", "wal", "wam", "wat", "wdc", "wde", "win", "wit", "wpr", "wst",
+];
+
+pub(crate) struct MetadataProblem {
+    // Recipe key of the offending value
+    pub(crate) field: &'static str,
+    pub(crate) message: String,
+    pub(crate) is_warning: bool,
+}
+
+////////////////////
+//   Validation   //
+////////////////////
+
+fn is_language_tag(tag: &str) -> bool {
+    // Checks the shape of a BCP 47 tag subtag by subtag, with the primary language limited to
+    // ISO 639's two and three letter codes, since there's no registry to check longer ones against
+    let subtags: Vec<&str> = tag.split('-').collect();
+    if subtags
+        .iter()
+        .any(|subtag| subtag.is_empty() || subtag.len() > 8)
+        || !subtags
+            .iter()
+            .all(|subtag| subtag.chars().all(|c| c.is_ascii_alphanumeric()))
+    {
+        return false;
+    }
+    let is_alpha = |subtag: &str| subtag.chars().all(|c| c.is_ascii_alphabetic());
+    let is_digit = |subtag: &str| subtag.chars().all(|c| c.is_ascii_digit());
+
+    let mut rest = subtags.iter().peekable();
+    match rest.next() {
+        // Private use and grandfathered irregular tags
+        Some(&"x") | Some(&"X") => return subtags.len() > 1,
+        Some(&"i") | Some(&"I") => return subtags.len() > 1,
+        Some(language) if (2..=3).contains(&language.len()) && is_alpha(language) => (),
+        _ => return false,
+    }
+    // Extended language subtags, then script and region
+    for _ in 0..3 {
+        if rest
+            .next_if(|subtag| subtag.len() == 3 && is_alpha(subtag))
+            .is_none()
+        {
+            break;
+        }
+    }
+    rest.next_if(|subtag| subtag.len() == 4 && is_alpha(subtag));
+    rest.next_if(|subtag| {
+        (subtag.len() == 2 && is_alpha(subtag)) || (subtag.len() == 3 && is_digit(subtag))
+    });
+    // Variants, then extensions and private use, each introduced by a singleton
+    while rest
+        .next_if(|subtag| {
+            (5..=8).contains(&subtag.len())
+                || (subtag.len() == 4 && subtag.starts_with(|c: char| c.is_ascii_digit()))
+        })
+        .is_some()
+    {}
+    while let Some(singleton) = rest.next() {
+        if singleton.len() != 1 {
+            return false;
+        }
+        let is_private_use = singleton.eq_ignore_ascii_case("x");
+        let minimum_length = if is_private_use { 1 } else { 2 };
+        if rest
+            .next_if(|subtag| subtag.len() >= minimum_length)
+            .is_none()
+        {
+            return false;
+        }
+        while rest
+            .next_if(|subtag| {
+                subtag.len() >= minimum_length && (is_private_use || subtag.len() > 1)
+            })
+            .is_some()
+        {}
+    }
+    true
+}
+
+fn parse_number(text: &str, digits: usize, range: std::ops::RangeInclusive<u32>) -> Option<u32> {
+    if text.len() != digits || !text.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    text.parse().ok().filter(|number| range.contains(number))
+}
+
+fn is_w3cdtf_time(time: &str) -> bool {
+    // hh:mm, hh:mm:ss or hh:mm:ss.s, followed by a time zone
+    let (time, zone) = match time.strip_suffix('Z') {
+        Some(time) => (time, None),
+        None => match time.rfind(['+', '-']) {
+            Some(index) => (&time[..index], Some(&time[index + 1..])),
+            None => return false,
+        },
+    };
+    if let Some(zone) = zone {
+        match zone.split_once(':') {
+            Some((hours, minutes))
+                if parse_number(hours, 2, 0..=23).is_some()
+                    && parse_number(minutes, 2, 0..=59).is_some() => {}
+            _ => return false,
+        }
+    }
+    let (time, fraction) = match time.split_once('.') {
+        Some((time, fraction)) => (time, Some(fraction)),
+        None => (time, None),
+    };
+    if fraction.is_some_and(|fraction| {
+        fraction.is_empty() || !fraction.chars().all(|c| c.is_ascii_digit())
+    }) {
+        return false;
+    }
+    let parts: Vec<&str> = time.split(':').collect();
+    match parts.as_slice() {
+        [hours, minutes] if fraction.is_none() => {
+            parse_number(hours, 2, 0..=23).is_some() && parse_number(minutes, 2, 0..=59).is_some()
+        }
+        [hours, minutes, seconds] => {
+            parse_number(hours, 2, 0..=23).is_some()
+                && parse_number(minutes, 2, 0..=59).is_some()
+                && parse_number(seconds, 2, 0..=59).is_some()
+        }
+        _ => false,
+    }
+}
+
+fn is_w3cdtf_date(date: &str) -> bool {
+    // YYYY, YYYY-MM or YYYY-MM-DD, optionally followed by T and a time
+    let (date, time) = match date.split_once('T') {
+        Some((date, time)) => (date, Some(time)),
+        None => (date, None),
+    };
+    let parts: Vec<&str> = date.split('-').collect();
+    let (year, month, day) = match parts.as_slice() {
+        [year] => (year, None, None),
+        [year, month] => (year, Some(month), None),
+        [year, month, day] => (year, Some(month), Some(day)),
+        _ => return false,
+    };
+    let year = match parse_number(year, 4, 0..=9999) {
+        Some(year) => year,
+        None => return false,
+    };
+    let month = match month.map(|month| parse_number(month, 2, 1..=12)) {
+        Some(Some(month)) => month,
+        Some(None) => return false,
+        None => return time.is_none(),
+    };
+    let is_leap_year = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days_in_month = match month {
+        2 if is_leap_year => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    match day.map(|day| parse_number(day, 2, 1..=days_in_month)) {
+        Some(Some(_)) => match time {
+            Some(time) => is_w3cdtf_time(time),
+            None => true,
+        },
+        Some(None) => false,
+        None => time.is_none(),
+    }
+}
+
+fn get_check_digit_problem(kind: &str, value: &str) -> Option<String> {
+    // The digits, with any hyphens or spaces removed, must be the right length and end in the
+    // check digit computed from the others
+    let characters: Vec<char> = value
+        .chars()
+        .filter(|c| *c != '-' && *c != ' ')
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    let digit = |c: &char| c.to_digit(10);
+    let (body, check) = characters.split_at(characters.len().saturating_sub(1));
+    let body: Option<Vec<u32>> = body.iter().map(digit).collect();
+    let check = match check.first() {
+        Some('X') => Some(10),
+        Some(c) => digit(c),
+        None => None,
+    };
+    let (body, check) = match (body, check) {
+        (Some(body), Some(check)) => (body, check),
+        _ => return Some(format!("isn't a valid {}, which must be all digits", kind)),
+    };
+
+    let expected =
+        match (kind, body.len()) {
+            ("ISBN", 9) => {
+                let sum: u32 = body
+                    .iter()
+                    .enumerate()
+                    .map(|(index, digit)| (10 - index as u32) * digit)
+                    .sum();
+                (11 - sum % 11) % 11
+            }
+            ("ISBN", 12) if check < 10 => {
+                let sum: u32 = body
+                    .iter()
+                    .enumerate()
+                    .map(|(index, digit)| if index % 2 == 0 { *digit } else { 3 * digit })
+                    .sum();
+                (10 - sum % 10) % 10
+            }
+            ("ISSN", 7) => {
+                let sum: u32 = body
+                    .iter()
+                    .enumerate()
+                    .map(|(index, digit)| (8 - index as u32) * digit)
+                    .sum();
+                (11 - sum % 11) % 11
+            }
+            ("ISBN", 12) => return Some(String::from(
+                "isn't a valid ISBN-13, which always ends in a digit; only ISBN-10s may end in X",
+            )),
+            ("ISBN", _) => {
+                return Some(String::from(
+                    "isn't a valid ISBN, which has 10 or 13 digits",
+                ))
+            }
+            _ => return Some(format!("isn't a valid {}, which has 8 digits", kind)),
+        };
+    match expected == check {
+        true => None,
+        false => Some(format!(
+            "has an invalid {} check digit; it should end in {}",
+            kind,
+            match expected {
+                10 => 'X',
+                _ => char::from_digit(expected, 10).unwrap_or('?'),
+            }
+        )),
+    }
+}
+
+fn get_identifier_problem(scheme: Option<&str>, content: &str) -> Option<String> {
+    // Identifiers are ISBNs or ISSNs when their scheme says so, or when they're written as URNs
+    let lowercase = content.trim().to_ascii_lowercase();
+    for kind in ["ISBN", "ISSN"] {
+        let prefix = format!("urn:{}:", kind.to_ascii_lowercase());
+        if let Some(value) = lowercase.strip_prefix(&prefix) {
+            return get_check_digit_problem(kind, value);
+        }
+        if scheme.is_some_and(|scheme| scheme.eq_ignore_ascii_case(kind)) {
+            let value = lowercase
+                .strip_prefix(&format!("{}:", kind.to_ascii_lowercase()))
+                .unwrap_or(&lowercase);
+            return get_check_digit_problem(kind, value.trim());
+        }
+    }
+    None
+}
+
+fn find_dc_metadata_problems(
+    name: &str,
+    content: &str,
+    scheme: Option<&str>,
+    role: Option<&str>,
+    lang: Option<&str>,
+) -> Vec<MetadataProblem> {
+    let entry = format!("dc:{} \"{}\"", name, content);
+    let mut problems = Vec::new();
+    match name {
+        "language" if !is_language_tag(content) => problems.push(MetadataProblem {
+            field: "content",
+            message: format!(
+                "{} isn't a BCP 47 language tag, such as en or en-GB.",
+                entry
+            ),
+            is_warning: false,
+        }),
+        "date" if !is_w3cdtf_date(content) => problems.push(MetadataProblem {
+            field: "content",
+            message: format!(
+                "{} isn't a W3CDTF date, such as 2021, 2021-03 or 2021-03-14.",
+                entry
+            ),
+            is_warning: false,
+        }),
+        "identifier" => {
+            if let Some(problem) = get_identifier_problem(scheme, content) {
+                problems.push(MetadataProblem {
+                    field: "content",
+                    message: format!("{} {}.", entry, problem),
+                    is_warning: false,
+                });
+            }
+        }
+        _ => (),
+    }
+    // Only creators and contributors have roles written out; OPF 2.0 allows extensions after oth.
+    if let Some(role) = role.filter(|_| name == "creator" || name == "contributor") {
+        if !MARC_RELATORS.contains(&role) && !role.starts_with("oth.") {
+            problems.push(MetadataProblem {
+                field: "role",
+                message: format!(
+                    "{} has role {}, which isn't a MARC relator code, such as aut, edt or ill.",
+                    entry, role
+                ),
+                is_warning: true,
+            });
+        }
+    }
+    if let Some(lang) = lang.filter(|lang| !is_language_tag(lang)) {
+        problems.push(MetadataProblem {
+            field: "lang",
+            message: format!(
+                "{} has lang {}, which isn't a BCP 47 language tag, such as en or en-GB.",
+                entry, lang
+            ),
+            is_warning: false,
+        });
+    }
+    problems
+}
+
+pub(crate) fn find_metadata_problems(metadata: &[Metadata]) -> Vec<(usize, MetadataProblem)> {
+    // Problems with each item's values, by index in the metadata list; custom metadata isn't
+    // ours to check
+    let mut problems = Vec::new();
+    for (index, item) in metadata.iter().enumerate() {
+        if let Metadata::DcMetadata {
+            name,
+            content,
+            scheme,
+            role,
+            lang,
+            ..
+        } = item
+        {
+            problems.extend(
+                find_dc_metadata_problems(
+                    name,
+                    content,
+                    scheme.as_deref(),
+                    role.as_deref(),
+                    lang.as_deref(),
+                )
+                .into_iter()
+                .map(|problem| (index, problem)),
+            );
+        }
+    }
+    problems
+}
+
+pub(crate) fn check_metadata_values(
+    metadata: &[Metadata],
+    build_report: &mut BuildReport,
+) -> Result<(), String> {
+    // Warnings go in the build report, and errors are all reported together
+    let mut errors = Vec::new();
+    for (_, problem) in find_metadata_problems(metadata) {
+        match problem.is_warning {
+            true => build_report
+                .warnings
+                .push(ValidationIssue::warning(problem.message)),
+            false => errors.push(format!("  {}", problem.message)),
+        }
+    }
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(format!(
+            "Metadata has invalid values:\n{}",
+            errors.join("\n")
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn isbn_10_check_digits() {
+        assert_eq!(get_check_digit_problem("ISBN", "0306406152"), None);
+        assert_eq!(get_check_digit_problem("ISBN", "0-306-40615-2"), None);
+        assert_eq!(get_check_digit_problem("ISBN", "080442957x"), None);
+        assert_eq!(
+            get_check_digit_problem("ISBN", "0306406153").as_deref(),
+            Some("has an invalid ISBN check digit; it should end in 2")
+        );
+        assert_eq!(
+            get_check_digit_problem("ISBN", "0804429570").as_deref(),
+            Some("has an invalid ISBN check digit; it should end in X")
+        );
+    }
+
+    #[test]
+    fn isbn_13_check_digits() {
+        assert_eq!(get_check_digit_problem("ISBN", "9780306406157"), None);
+        assert_eq!(get_check_digit_problem("ISBN", "978 0 306 40615 7"), None);
+        assert_eq!(
+            get_check_digit_problem("ISBN", "9780306406158").as_deref(),
+            Some("has an invalid ISBN check digit; it should end in 7")
+        );
+        assert_eq!(
+            get_check_digit_problem("ISBN", "978030640615X").as_deref(),
+            Some("isn't a valid ISBN-13, which always ends in a digit; only ISBN-10s may end in X")
+        );
+    }
+
+    #[test]
+    fn isbn_lengths_and_characters() {
+        assert_eq!(
+            get_check_digit_problem("ISBN", "03064061").as_deref(),
+            Some("isn't a valid ISBN, which has 10 or 13 digits")
+        );
+        assert_eq!(
+            get_check_digit_problem("ISBN", "97803064061570").as_deref(),
+            Some("isn't a valid ISBN, which has 10 or 13 digits")
+        );
+        assert_eq!(
+            get_check_digit_problem("ISBN", "03X6406152").as_deref(),
+            Some("isn't a valid ISBN, which must be all digits")
+        );
+        assert_eq!(
+            get_check_digit_problem("ISBN", "").as_deref(),
+            Some("isn't a valid ISBN, which must be all digits")
+        );
+    }
+
+    #[test]
+    fn issn_check_digits() {
+        assert_eq!(get_check_digit_problem("ISSN", "0378-5955"), None);
+        assert_eq!(get_check_digit_problem("ISSN", "1000-002x"), None);
+        assert_eq!(
+            get_check_digit_problem("ISSN", "0378-5954").as_deref(),
+            Some("has an invalid ISSN check digit; it should end in 5")
+        );
+        assert_eq!(
+            get_check_digit_problem("ISSN", "1000-0020").as_deref(),
+            Some("has an invalid ISSN check digit; it should end in X")
+        );
+        assert_eq!(
+            get_check_digit_problem("ISSN", "0378-595").as_deref(),
+            Some("isn't a valid ISSN, which has 8 digits")
+        );
+    }
+
+    #[test]
+    fn identifiers_are_checked_by_urn_or_scheme() {
+        assert_eq!(get_identifier_problem(None, "urn:isbn:9780306406157"), None);
+        assert!(get_identifier_problem(None, "urn:isbn:9780306406158").is_some());
+        assert!(get_identifier_problem(None, "URN:ISSN:0378-5954").is_some());
+        assert!(get_identifier_problem(Some("isbn"), "ISBN: 9780306406158").is_some());
+        assert_eq!(get_identifier_problem(None, "9780306406158"), None);
+        assert_eq!(get_identifier_problem(Some("UUID"), "urn:uuid:1234"), None);
+    }
+
+    #[test]
+    fn w3cdtf_dates() {
+        for date in [
+            "2021",
+            "2021-03",
+            "2021-03-14",
+            "2020-02-29",
+            "2000-02-29",
+            "2021-03-14T10:30Z",
+            "2021-03-14T10:30:15+01:00",
+            "2021-03-14T10:30:15.25-05:30",
+        ] {
+            assert!(is_w3cdtf_date(date), "{}", date);
+        }
+        for date in [
+            "",
+            "21",
+            "2021-3",
+            "2021-13",
+            "2021-03-32",
+            "2021-02-29",
+            "1900-02-29",
+            "2021-03-14-01",
+            "2021T10:30Z",
+            "2021-03T10:30Z",
+            "2021-03-14T10:30",
+            "2021-03-14T24:00Z",
+            "2021-03-14T10:30.5Z",
+            "2021-03-14T10:30:15.Z",
+            "2021-03-14T10:30:15+1:00",
+            "March 14, 2021",
+        ] {
+            assert!(!is_w3cdtf_date(date), "{}", date);
+        }
+    }
+
+    #[test]
+    fn bcp_47_language_tags() {
+        for tag in [
+            "en",
+            "EN-gb",
+            "fil",
+            "zh-yue",
+            "zh-Hant-TW",
+            "sr-Latn-RS",
+            "es-419",
+            "de-CH-1996",
+            "sl-rozaj-biske",
+            "en-a-bbb-x-ccc",
+            "x-whatever",
+            "i-klingon",
+        ] {
+            assert!(is_language_tag(tag), "{}", tag);
+        }
+        for tag in [
+            "",
+            "e",
+            "english",
+            "en_GB",
+            "en--GB",
+            "en-",
+            "123",
+            "x",
+            "en-a",
+            "en-a-b",
+            "en-GB-toolongsubtag",
+        ] {
+            assert!(!is_language_tag(tag), "{}", tag);
+        }
+    }
+}
@@ -1,7 +1,22 @@
+mod check;
 mod config;
 mod container;
+mod fallbacks;
 mod helpers;
+mod ids;
+mod images;
+mod manifest;
+mod metadata;
 mod ncx;
 mod opf;
+mod orphans;
+mod paths;
+mod spine;
+mod variants;
 
 pub(crate) mod build;
+
+pub(crate) use check::find_epub2_problems;
+pub use config::epub2_config_schema;
+pub(crate) use config::Epub2Config;
+pub(crate) use helpers::get_manifest_item;
@@ -1,6 +1,6 @@
 use crate::epub::epub2::config;
 use crate::epub::epub2::config::Epub2Config;
-use crate::epub::epub2::helpers::get_path_from_idref;
+use crate::epub::epub2::helpers::{get_path_from_idref, get_safe_id};
 use crate::helpers::fixed_clean;
 
 use common_path::common_path;
@@ -11,6 +11,12 @@ use yaserde_derive::YaSerialize;
 //   Multi-Use   //
 ///////////////////
 
+#[derive(YaSerialize)]
+struct Content {
+    #[yaserde(attribute)]
+    src: String,
+}
+
 #[derive(YaSerialize)]
 struct NavLabel {
     #[yaserde(attribute, rename = "xml:lang")]
@@ -53,10 +59,14 @@ struct DocTitle {
 
 #[derive(YaSerialize)]
 struct NavPoint {
+    #[yaserde(attribute)]
+    id: String,
+    #[yaserde(attribute, rename = "playOrder")]
+    play_order: String,
     #[yaserde(child, rename = "navLabel")]
     navlabel: Vec<NavLabel>,
     #[yaserde(child)]
-    content: String,
+    content: Content,
     #[yaserde(child, rename = "navPoint")]
     navpoint: Vec<NavPoint>, // Should be an option, but a library bug is interfering
 }
@@ -79,10 +89,12 @@ struct PageTarget {
     pagetarget_type: String,
     #[yaserde(attribute)]
     value: Option<String>,
+    #[yaserde(attribute, rename = "playOrder")]
+    play_order: String,
     #[yaserde(child, rename = "navLabel")]
     navlabel: Vec<NavLabel>,
     #[yaserde(child)]
-    content: String,
+    content: Content,
 }
 
 #[derive(YaSerialize)]
@@ -97,10 +109,14 @@ struct PageList {
 
 #[derive(YaSerialize)]
 struct NavTarget {
+    #[yaserde(attribute)]
+    id: String,
+    #[yaserde(attribute, rename = "playOrder")]
+    play_order: String,
     #[yaserde(child, rename = "navLabel")]
     navlabel: Vec<NavLabel>,
     #[yaserde(child)]
-    content: String,
+    content: Content,
 }
 
 #[derive(YaSerialize)]
@@ -248,18 +264,23 @@ fn convert_navpoint(
             idref,
             fragment,
             children,
+            ..
         } => NavPoint {
+            id: String::new(),
+            play_order: String::new(),
             navlabel: vec![NavLabel {
                 xml_lang: None,
                 text: label.clone(),
             }],
-            content: get_ncx_path_to_file_from_idref(
-                config,
-                &idref,
-                fragment.as_ref(),
-                opf_parent_path,
-                ncx_path_from_opf,
-            )?,
+            content: Content {
+                src: get_ncx_path_to_file_from_idref(
+                    config,
+                    &idref,
+                    fragment.as_ref(),
+                    opf_parent_path,
+                    ncx_path_from_opf,
+                )?,
+            },
             navpoint: match children {
                 None => Vec::new(),
                 Some(children) => {
@@ -281,7 +302,10 @@ fn convert_navpoint(
             idref,
             fragment,
             children,
+            ..
         } => NavPoint {
+            id: String::new(),
+            play_order: String::new(),
             navlabel: labels
                 .iter()
                 .map(|label| NavLabel {
@@ -289,13 +313,15 @@ fn convert_navpoint(
                     text: label.label.clone(),
                 })
                 .collect(),
-            content: get_ncx_path_to_file_from_idref(
-                config,
-                &idref,
-                fragment.as_ref(),
-                opf_parent_path,
-                ncx_path_from_opf,
-            )?,
+            content: Content {
+                src: get_ncx_path_to_file_from_idref(
+                    config,
+                    &idref,
+                    fragment.as_ref(),
+                    opf_parent_path,
+                    ncx_path_from_opf,
+                )?,
+            },
             navpoint: match children {
                 None => Vec::new(),
                 Some(children) => {
@@ -325,11 +351,19 @@ fn get_navmap(
     Ok(match &config.navmap {
         None => NavMap {
             navpoint: vec![NavPoint {
+                id: String::new(),
+                play_order: String::new(),
                 navlabel: vec![NavLabel {
                     xml_lang: None,
                     text: String::from(doctitle),
                 }],
-                content: String::from(first_linear_spine_href),
+                content: Content {
+                    src: get_ncx_path_to_file(
+                        opf_parent_path,
+                        ncx_path_from_opf,
+                        &PathBuf::from(first_linear_spine_href),
+                    )?,
+                },
                 navpoint: Vec::new(),
             }],
         },
@@ -373,17 +407,20 @@ fn get_pagelist(
                             id: id.clone(),
                             pagetarget_type: target_type.clone(),
                             value: value.clone(),
+                            play_order: String::new(),
                             navlabel: vec![NavLabel {
                                 xml_lang: None,
                                 text: label.clone(),
                             }],
-                            content: get_ncx_path_to_file_from_idref(
-                                config,
-                                &idref,
-                                fragment.as_ref(),
-                                opf_parent_path,
-                                ncx_path_from_opf,
-                            )?,
+                            content: Content {
+                                src: get_ncx_path_to_file_from_idref(
+                                    config,
+                                    &idref,
+                                    fragment.as_ref(),
+                                    opf_parent_path,
+                                    ncx_path_from_opf,
+                                )?,
+                            },
                         },
                         config::PageTarget::WithComplexLabels {
                             labels,
@@ -396,6 +433,7 @@ fn get_pagelist(
                             id: id.clone(),
                             pagetarget_type: target_type.clone(),
                             value: value.clone(),
+                            play_order: String::new(),
                             navlabel: labels
                                 .iter()
                                 .map(|label| NavLabel {
@@ -403,13 +441,15 @@ fn get_pagelist(
                                     text: label.label.clone(),
                                 })
                                 .collect(),
-                            content: get_ncx_path_to_file_from_idref(
-                                config,
-                                &idref,
-                                fragment.as_ref(),
-                                opf_parent_path,
-                                ncx_path_from_opf,
-                            )?,
+                            content: Content {
+                                src: get_ncx_path_to_file_from_idref(
+                                    config,
+                                    &idref,
+                                    fragment.as_ref(),
+                                    opf_parent_path,
+                                    ncx_path_from_opf,
+                                )?,
+                            },
                         },
                     });
                 }
@@ -433,23 +473,29 @@ fn convert_navlist(
                 idref,
                 fragment,
             } => NavTarget {
+                id: String::new(),
+                play_order: String::new(),
                 navlabel: vec![NavLabel {
                     xml_lang: None,
                     text: label.clone(),
                 }],
-                content: get_ncx_path_to_file_from_idref(
-                    config,
-                    &idref,
-                    fragment.as_ref(),
-                    opf_parent_path,
-                    ncx_path_from_opf,
-                )?,
+                content: Content {
+                    src: get_ncx_path_to_file_from_idref(
+                        config,
+                        &idref,
+                        fragment.as_ref(),
+                        opf_parent_path,
+                        ncx_path_from_opf,
+                    )?,
+                },
             },
             config::NavTarget::WithComplexLabels {
                 labels,
                 idref,
                 fragment,
             } => NavTarget {
+                id: String::new(),
+                play_order: String::new(),
                 navlabel: labels
                     .iter()
                     .map(|label| NavLabel {
@@ -457,13 +503,15 @@ fn convert_navlist(
                         text: label.label.clone(),
                     })
                     .collect(),
-                content: get_ncx_path_to_file_from_idref(
-                    config,
-                    &idref,
-                    fragment.as_ref(),
-                    opf_parent_path,
-                    ncx_path_from_opf,
-                )?,
+                content: Content {
+                    src: get_ncx_path_to_file_from_idref(
+                        config,
+                        &idref,
+                        fragment.as_ref(),
+                        opf_parent_path,
+                        ncx_path_from_opf,
+                    )?,
+                },
             },
         });
     }
@@ -515,6 +563,45 @@ fn get_navlists(
     }
 }
 
+fn number_navpoints(navpoints: &mut [NavPoint], ids: &mut Vec<String>, play_order: &mut usize) {
+    for navpoint in navpoints {
+        *play_order += 1;
+        navpoint.play_order = play_order.to_string();
+        navpoint.id = get_safe_id(&format!("navPoint-{}", play_order), ids);
+        ids.push(navpoint.id.clone());
+        number_navpoints(&mut navpoint.navpoint, ids, play_order);
+    }
+}
+
+fn number_nav_elements(ncx: &mut Ncx) {
+    // Reading systems order everything by playOrder, so it counts up through the navMap, the
+    // pageList and the navLists in document order; ids are generated around the pageTargets' own
+    let mut ids: Vec<String> = match &ncx.pagelist {
+        Some(pagelist) => pagelist
+            .pagetarget
+            .iter()
+            .map(|pagetarget| pagetarget.id.clone())
+            .collect(),
+        None => Vec::new(),
+    };
+    let mut play_order = 0;
+    number_navpoints(&mut ncx.navmap.navpoint, &mut ids, &mut play_order);
+    if let Some(pagelist) = &mut ncx.pagelist {
+        for pagetarget in &mut pagelist.pagetarget {
+            play_order += 1;
+            pagetarget.play_order = play_order.to_string();
+        }
+    }
+    for navlist in &mut ncx.navlist {
+        for navtarget in &mut navlist.navtarget {
+            play_order += 1;
+            navtarget.play_order = play_order.to_string();
+            navtarget.id = get_safe_id(&format!("navTarget-{}", play_order), &ids);
+            ids.push(navtarget.id.clone());
+        }
+    }
+}
+
 pub(crate) fn build_ncx_xml(
     config: &Epub2Config,
     opf_parent_path: &PathBuf,
@@ -525,7 +612,7 @@ pub(crate) fn build_ncx_xml(
 ) -> Result<String, String> {
     let clean_opf_parent_path = &fixed_clean(opf_parent_path);
 
-    let ncx = Ncx {
+    let mut ncx = Ncx {
         version: String::from("2005-1"),
         xmlns: String::from("http://www.daisy.org/z3986/2005/ncx/"),
         head: Head {
@@ -547,6 +634,7 @@ pub(crate) fn build_ncx_xml(
         pagelist: get_pagelist(config, clean_opf_parent_path, ncx_path_from_opf)?,
         navlist: get_navlists(config, clean_opf_parent_path, ncx_path_from_opf)?,
     };
+    number_nav_elements(&mut ncx);
 
     let yaserde_cfg = yaserde::ser::Config {
         perform_indent: true,
@@ -1,9 +1,17 @@
+use crate::epub::build::BuildReport;
 use crate::epub::epub2::config;
 use crate::epub::epub2::config::Epub2Config;
 use crate::epub::epub2::helpers::get_path_from_idref;
+use crate::epub::epub2::metadata::check_metadata_values;
+use crate::epub::epub2::spine::check_spine;
 
+use std::io::Write;
 use sys_locale::get_locale;
 use uuid::Uuid;
+use xml::attribute::OwnedAttribute;
+use xml::namespace::Namespace;
+use yaserde::ser::Serializer;
+use yaserde::YaSerialize;
 use yaserde_derive::YaSerialize;
 
 //////////////////
@@ -36,9 +44,9 @@ struct Language {
 
 #[derive(YaSerialize)]
 struct Creator {
-    #[yaserde(attribute, rename: "opf:file-as")]
+    #[yaserde(attribute, rename = "opf:file-as")]
     opf_file_as: Option<String>,
-    #[yaserde(attribute, rename: "opf:role")]
+    #[yaserde(attribute, rename = "opf:role")]
     opf_role: Option<String>,
     #[yaserde(attribute, rename = "xml:lang")]
     xml_lang: Option<String>,
@@ -72,9 +80,9 @@ struct Publisher {
 
 #[derive(YaSerialize)]
 struct Contributor {
-    #[yaserde(attribute, rename: "opf:file-as")]
+    #[yaserde(attribute, rename = "opf:file-as")]
     opf_file_as: Option<String>,
-    #[yaserde(attribute, rename: "opf:role")]
+    #[yaserde(attribute, rename = "opf:role")]
     opf_role: Option<String>,
     #[yaserde(attribute, rename = "xml:lang")]
     xml_lang: Option<String>,
@@ -84,7 +92,7 @@ struct Contributor {
 
 #[derive(YaSerialize)]
 struct Date {
-    #[yaserde(attribute, rename: "opf:event")]
+    #[yaserde(attribute, rename = "opf:event")]
     opf_event: Option<String>,
     #[yaserde(text)]
     body: String,
@@ -142,43 +150,78 @@ struct Meta {
     content: String,
 }
 
-#[derive(YaSerialize)]
 enum MetadataItem {
-    // THIS IS CURRENTLY BROKEN DUE TO YASERDE ENUM ISSUES; IT DOESN'T OUTPUT WELL-FORMED METADATA AT THIS TIME. COME BACK AND FIX IT BEFORE ANY REAL RELEASE.
-    #[yaserde(rename = "dc:title")]
     DcTitle(Title),
-    #[yaserde(rename = "dc:identifier")]
     DcIdentifier(Identifier),
-    #[yaserde(rename = "dc:language")]
     DcLanguage(Language),
-    #[yaserde(rename = "dc:creator")]
     DcCreator(Creator),
-    #[yaserde(rename = "dc:subject")]
     DcSubject(Subject),
-    #[yaserde(rename = "dc:description")]
     DcDescription(Description),
-    #[yaserde(rename = "dc:publisher")]
     DcPublisher(Publisher),
-    #[yaserde(rename = "dc:contributor")]
     DcContributor(Contributor),
-    #[yaserde(rename = "dc:date")]
     DcDate(Date),
-    #[yaserde(rename = "dc:type")]
     DcType(Type),
-    #[yaserde(rename = "dc:format")]
     DcFormat(Format),
-    #[yaserde(rename = "dc:source")]
     DcSource(Source),
-    #[yaserde(rename = "dc:relation")]
     DcRelation(Relation),
-    #[yaserde(rename = "dc:coverage")]
     DcCoverage(Coverage),
-    #[yaserde(rename = "dc:rights")]
     DcRights(Rights),
-    #[yaserde(rename = "meta")]
     Meta(Meta),
 }
 
+// Derived enums serialize as their variant names without the inner elements' attributes, so each
+// item is written as its inner element under the right name instead
+impl YaSerialize for MetadataItem {
+    fn serialize<W: Write>(&self, writer: &mut Serializer<W>) -> Result<(), String> {
+        let name = match self {
+            MetadataItem::DcTitle(_) => "dc:title",
+            MetadataItem::DcIdentifier(_) => "dc:identifier",
+            MetadataItem::DcLanguage(_) => "dc:language",
+            MetadataItem::DcCreator(_) => "dc:creator",
+            MetadataItem::DcSubject(_) => "dc:subject",
+            MetadataItem::DcDescription(_) => "dc:description",
+            MetadataItem::DcPublisher(_) => "dc:publisher",
+            MetadataItem::DcContributor(_) => "dc:contributor",
+            MetadataItem::DcDate(_) => "dc:date",
+            MetadataItem::DcType(_) => "dc:type",
+            MetadataItem::DcFormat(_) => "dc:format",
+            MetadataItem::DcSource(_) => "dc:source",
+            MetadataItem::DcRelation(_) => "dc:relation",
+            MetadataItem::DcCoverage(_) => "dc:coverage",
+            MetadataItem::DcRights(_) => "dc:rights",
+            MetadataItem::Meta(_) => "meta",
+        };
+        writer.set_start_event_name(Some(String::from(name)));
+        writer.set_skip_start_end(false);
+        match self {
+            MetadataItem::DcTitle(inner) => inner.serialize(writer),
+            MetadataItem::DcIdentifier(inner) => inner.serialize(writer),
+            MetadataItem::DcLanguage(inner) => inner.serialize(writer),
+            MetadataItem::DcCreator(inner) => inner.serialize(writer),
+            MetadataItem::DcSubject(inner) => inner.serialize(writer),
+            MetadataItem::DcDescription(inner) => inner.serialize(writer),
+            MetadataItem::DcPublisher(inner) => inner.serialize(writer),
+            MetadataItem::DcContributor(inner) => inner.serialize(writer),
+            MetadataItem::DcDate(inner) => inner.serialize(writer),
+            MetadataItem::DcType(inner) => inner.serialize(writer),
+            MetadataItem::DcFormat(inner) => inner.serialize(writer),
+            MetadataItem::DcSource(inner) => inner.serialize(writer),
+            MetadataItem::DcRelation(inner) => inner.serialize(writer),
+            MetadataItem::DcCoverage(inner) => inner.serialize(writer),
+            MetadataItem::DcRights(inner) => inner.serialize(writer),
+            MetadataItem::Meta(inner) => inner.serialize(writer),
+        }
+    }
+
+    fn serialize_attributes(
+        &self,
+        attributes: Vec<OwnedAttribute>,
+        namespace: Namespace,
+    ) -> Result<(Vec<OwnedAttribute>, Namespace), String> {
+        Ok((attributes, namespace))
+    }
+}
+
 #[derive(YaSerialize)]
 struct Metadata {
     #[yaserde(attribute, rename = "xmlns:dc")]
@@ -284,19 +327,48 @@ struct Package {
 //   Build   //
 ///////////////
 
+// The unique identifier's element id, which the package refers to, and its value, which the NCX
+// repeats as dtb:uid
+struct Uid {
+    id: String,
+    value: String,
+}
+
+fn get_default_language() -> String {
+    // POSIX locales like en_US.UTF-8 become language tags like en-US, and the C locale names none
+    match get_locale() {
+        Some(locale) => {
+            let tag = locale
+                .split(['.', '@'])
+                .next()
+                .unwrap_or("")
+                .replace('_', "-");
+            match tag.as_ref() {
+                "" | "C" | "POSIX" => String::from("en"),
+                _ => tag,
+            }
+        }
+        None => String::from("en"),
+    }
+}
+
 fn get_uid_and_title_and_metadata(
     config: &Epub2Config,
     safe_uid: &str,
-) -> Result<(String, String, Metadata), String> {
+    build_report: &mut BuildReport,
+) -> Result<(Uid, String, Metadata), String> {
     match &config.metadata {
         Some(config_metadata) => {
             let mut metadata = Vec::new();
 
+            // Check values before writing them through
+            check_metadata_values(config_metadata, build_report)?;
+
             // Sort all metadata from the config
 
             for item in config_metadata {
                 match item {
-                    config::Metadata::DcMetadata {name, content, id, scheme, file_as, role, event, lang} => {
+                    config::Metadata::DcMetadata {name, content, id, scheme, file_as, role, event, lang, ..} => {
                         match name.as_ref() {
                             "title" =>  metadata.push(MetadataItem::DcTitle(Title {
                                 xml_lang: lang.clone(),
@@ -363,7 +435,7 @@ fn get_uid_and_title_and_metadata(
                             _ => return Err(format!("Unrecognized DC metadata name: '{}'; if using custom metadata names, please use the attribute custom_name in place of name.", name)),
                         }
                     }
-                    config::Metadata::CustomMetadata {name, content} => metadata.push(MetadataItem::Meta(Meta {
+                    config::Metadata::CustomMetadata {name, content, ..} => metadata.push(MetadataItem::Meta(Meta {
                         name: name.clone(),
                         content: content.clone(),
                     })),
@@ -407,10 +479,7 @@ fn get_uid_and_title_and_metadata(
                 }
             }) {
                 metadata.push(MetadataItem::DcLanguage(Language {
-                    body: match get_locale() {
-                        Some(locale) => locale,
-                        None => String::from("en"),
-                    },
+                    body: get_default_language(),
                 }))
             };
 
@@ -423,12 +492,18 @@ fn get_uid_and_title_and_metadata(
                     false
                 }
             }) {
-                Some(MetadataItem::DcIdentifier(identifier)) => identifier.id.clone().unwrap(),
+                Some(MetadataItem::DcIdentifier(identifier)) => Uid {
+                    id: identifier.id.clone().unwrap(),
+                    value: identifier.body.clone(),
+                },
                 _ => {
                     match metadata.iter_mut().find(|item| if let MetadataItem::DcIdentifier(_) = item { true } else { false }) {
                         Some(MetadataItem::DcIdentifier(identifier)) => {
                             identifier.id = Some(String::from(safe_uid));
-                            String::from(safe_uid)
+                            Uid {
+                                id: String::from(safe_uid),
+                                value: identifier.body.clone(),
+                            }
                         },
                         _ => return Err(String::from("No identifier found after identifier's presence should have been ensured.")),
                     }
@@ -459,6 +534,7 @@ fn get_uid_and_title_and_metadata(
             Ok((uid, title, metadata))
         }
         None => {
+            let uid_value = format!("{}", Uuid::new_v4());
             let metadata = Metadata {
                 xmlns_dc: Some(String::from("http://purl.org/dc/elements/1.1/")),
                 xmlns_opf: Some(String::from("http://www.idpf.org/2007/opf")),
@@ -470,18 +546,20 @@ fn get_uid_and_title_and_metadata(
                     MetadataItem::DcIdentifier(Identifier {
                         id: Some(String::from(safe_uid)),
                         opf_scheme: Some(String::from("UUID")),
-                        body: format!("{}", Uuid::new_v4()),
+                        body: uid_value.clone(),
                     }),
                     MetadataItem::DcLanguage(Language {
-                        body: match get_locale() {
-                            Some(locale) => locale,
-                            None => String::from("en"),
-                        },
+                        body: get_default_language(),
                     }),
                 ],
             };
 
-            Ok((String::from(safe_uid), String::from("Untitled"), metadata))
+            let uid = Uid {
+                id: String::from(safe_uid),
+                value: uid_value,
+            };
+
+            Ok((uid, String::from("Untitled"), metadata))
         }
     }
 }
@@ -516,68 +594,21 @@ fn get_manifest(config: &Epub2Config, ncx_id: &str, ncx_path_from_opf: &str) ->
     Manifest { item: items_vec }
 }
 
-fn id_falls_back_to_types(config: &Epub2Config, id: &str, target_types: &Vec<&str>) -> bool {
-    match config.manifest.iter().find(|item| &item.id == id) {
-        Some(item) => {
-            if target_types.contains(&item.media_type.as_ref()) {
-                true
-            } else if item.fallback.is_some() {
-                id_falls_back_to_types(config, &item.fallback.as_ref().unwrap(), target_types)
-            } else {
-                false
-            }
-        }
-        None => false,
-    }
-}
-
 fn get_spine(config: &Epub2Config, ncx_id: &str) -> Result<Spine, String> {
-    let first_linearizable_manifest_item = match config.manifest.iter().find(|item| {
-        &item.media_type == "application/xhtml+xml"
-            || &item.media_type == "application/x-dtbook+xml"
-            || id_falls_back_to_types(
-                config,
-                &item.id,
-                &vec!["application/xhtml+xml", "application/x-dtbook+xml"],
-            )
-    }) {
-        Some(item) => item,
-        None => {
-            return Err(String::from(
-                "Manifest contains no items legally placeable within the spine.",
-            ))
-        }
-    };
-
-    let mut itemrefs = Vec::new();
-    match &config.spine {
-        Some(spine) => {
-            for itemref in spine {
-                let (idref, linear) = match itemref {
-                    config::Itemref::RawIdref(idref) => (idref, None),
-                    config::Itemref::CookedIdref { idref, linear } => (
-                        idref,
-                        match linear {
-                            Some(false) => Some(String::from("no")),
-                            _ => None,
-                        },
-                    ),
-                };
-                itemrefs.push(Itemref {
-                    linear: linear,
-                    idref: idref.clone(),
-                });
-            }
-        }
-        None => itemrefs.push(Itemref {
-            idref: first_linearizable_manifest_item.id.clone(),
-            linear: None,
-        }),
-    }
+    let itemref = check_spine(config)?
+        .into_iter()
+        .map(|(item, is_linear)| Itemref {
+            idref: item.id.clone(),
+            linear: match is_linear {
+                false => Some(String::from("no")),
+                true => None,
+            },
+        })
+        .collect();
 
     Ok(Spine {
         toc: String::from(ncx_id),
-        itemref: itemrefs,
+        itemref,
     })
 }
 
@@ -609,12 +640,13 @@ pub(crate) fn build_opf_xml_and_get_metadata(
     ncx_id: &str,
     ncx_path_from_opf: &str,
     safe_uid: &str,
+    build_report: &mut BuildReport,
 ) -> Result<(String, String, String, String), String> {
-    let (uid, title, metadata) = get_uid_and_title_and_metadata(&config, safe_uid)?;
+    let (uid, title, metadata) = get_uid_and_title_and_metadata(&config, safe_uid, build_report)?;
     let opf = Package {
         version: String::from("2.0"),
         xmlns: String::from("http://www.idpf.org/2007/opf"),
-        unique_identifier: uid.clone(),
+        unique_identifier: uid.id,
         metadata: metadata,
         manifest: get_manifest(&config, ncx_id, ncx_path_from_opf),
         spine: get_spine(&config, ncx_id)?,
@@ -631,7 +663,7 @@ pub(crate) fn build_opf_xml_and_get_metadata(
         .spine
         .itemref
         .iter()
-        .find(|itemref| itemref.linear.is_some())
+        .find(|itemref| itemref.linear.is_none())
     {
         None => {
             return Err(String::from(
@@ -641,5 +673,5 @@ pub(crate) fn build_opf_xml_and_get_metadata(
         Some(itemref) => get_path_from_idref(config, &itemref.idref, None)?,
     };
 
-    Ok((opf_xml, uid, title, first_linear_spine_href))
+    Ok((opf_xml, uid.value, title, first_linear_spine_href))
 }
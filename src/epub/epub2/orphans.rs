@@ -0,0 +1,267 @@
+use crate::epub::build::BuildReport;
+use crate::epub::epub2::config::{Epub2Config, Itemref, ManifestItem, NavList, NavPoint};
+use crate::epub::epub2::helpers::{get_cover_item, get_manifest_item, get_opf_path, is_spine_item};
+use crate::epub::epub2::variants::{get_nav_target_idref, get_page_target_idref};
+use crate::epub::validate::{
+    find_css_references, find_document_references, is_remote_href, resolve_href, ValidationIssue,
+};
+use crate::helpers::{fixed_clean, format_size, path_to_string};
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{metadata, read};
+use std::path::Path;
+
+pub(crate) struct UnlistedReference<'a> {
+    // Item whose content makes the reference
+    pub(crate) item: &'a ManifestItem,
+    // Path of the item and of the file it refers to, from the container root
+    pub(crate) item_path: String,
+    pub(crate) path: String,
+    pub(crate) reference: String,
+    pub(crate) is_nonmanifest: bool,
+}
+
+pub(crate) struct OrphanReport<'a> {
+    pub(crate) orphans: Vec<&'a ManifestItem>,
+    pub(crate) unlisted_references: Vec<UnlistedReference<'a>>,
+    pub(crate) orphan_bytes: u64,
+}
+
+//////////////////
+//   Scanning   //
+//////////////////
+
+fn collect_navpoint_idrefs<'a>(navpoints: &'a [NavPoint], idrefs: &mut Vec<&'a str>) {
+    for navpoint in navpoints {
+        let (idref, children) = match navpoint {
+            NavPoint::WithSimpleLabel {
+                idref, children, ..
+            } => (idref, children),
+            NavPoint::WithComplexLabels {
+                idref, children, ..
+            } => (idref, children),
+        };
+        idrefs.push(idref);
+        if let Some(children) = children {
+            collect_navpoint_idrefs(children, idrefs);
+        }
+    }
+}
+
+fn get_root_idrefs(config: &Epub2Config) -> Vec<&str> {
+    // Everything the OPF and NCX point at directly, as ids or inside paths
+    let mut idrefs = Vec::new();
+    match &config.spine {
+        Some(spine) => {
+            for itemref in spine {
+                match itemref {
+                    Itemref::RawIdref(idref) | Itemref::CookedIdref { idref, .. } => {
+                        idrefs.push(idref.as_ref())
+                    }
+                    Itemref::Glob { glob, .. } => {
+                        if let Some(paths) = config.manifest_glob_paths.get(glob) {
+                            idrefs.extend(paths.iter().map(String::as_str));
+                        }
+                    }
+                }
+            }
+        }
+        None => {
+            if let Some(item) = config
+                .manifest
+                .iter()
+                .find(|item| is_spine_item(config, item))
+            {
+                idrefs.push(&item.id);
+            }
+        }
+    }
+    for reference in config.guide.iter().flatten() {
+        idrefs.push(&reference.idref);
+    }
+    collect_navpoint_idrefs(config.navmap.as_deref().unwrap_or_default(), &mut idrefs);
+    for target in config.pagelist.iter().flatten() {
+        idrefs.push(get_page_target_idref(target));
+    }
+    for navlist in config.navlists.iter().flatten() {
+        let list = match navlist {
+            NavList::WithSimpleLabel { list, .. } => list,
+            NavList::WithComplexLabels { list, .. } => list,
+        };
+        idrefs.extend(list.iter().map(get_nav_target_idref));
+    }
+    if let Some(item) = get_cover_item(config) {
+        idrefs.push(&item.id);
+    }
+    idrefs
+}
+
+fn read_references(item: &ManifestItem) -> Vec<String> {
+    // Files which can't be read or parsed are reported by the build, so they just reference
+    // nothing here
+    let contents = match read(&item.outside_path) {
+        Ok(contents) => contents,
+        Err(_) => return Vec::new(),
+    };
+    match item.media_type.as_ref() {
+        "application/xhtml+xml" | "image/svg+xml" => {
+            find_document_references(&contents).unwrap_or_default()
+        }
+        "text/css" => find_css_references(&String::from_utf8_lossy(&contents)),
+        _ => Vec::new(),
+    }
+}
+
+fn get_inside_path(opf_parent_dir: &Path, inside_path_from_opf: &str) -> Result<String, String> {
+    path_to_string(fixed_clean(opf_parent_dir.join(inside_path_from_opf)))
+}
+
+pub(crate) fn find_orphans(config: &Epub2Config) -> Result<OrphanReport<'_>, String> {
+    // Follows references outwards from the spine, guide, NCX and cover, so that a resource is only
+    // kept by content which is itself reachable
+    let (_, opf_path) = get_opf_path(config);
+    let opf_parent_dir = Path::new(opf_path).parent().unwrap_or(Path::new(""));
+    let mut items_by_path = HashMap::new();
+    for item in &config.manifest {
+        items_by_path.insert(
+            get_inside_path(opf_parent_dir, &item.inside_path_from_opf)?,
+            item,
+        );
+    }
+    let mut nonmanifest_paths = HashSet::new();
+    for file in config.nonmanifest_files.iter().flatten() {
+        nonmanifest_paths.insert(path_to_string(fixed_clean(&file.inside_path))?);
+    }
+
+    let mut reached = HashSet::new();
+    let mut queue = Vec::new();
+    for idref in get_root_idrefs(config) {
+        if let Some(item) = get_manifest_item(config, idref) {
+            if reached.insert(&item.id) {
+                queue.push(item);
+            }
+        }
+    }
+    let mut unlisted_references = Vec::new();
+    let mut unlisted_paths = HashSet::new();
+    while let Some(item) = queue.pop() {
+        let item_path = get_inside_path(opf_parent_dir, &item.inside_path_from_opf)?;
+        let mut targets: Vec<&ManifestItem> = [&item.fallback, &item.fallback_style]
+            .into_iter()
+            .flatten()
+            .filter_map(|id| get_manifest_item(config, id))
+            .collect();
+        for reference in read_references(item) {
+            if is_remote_href(&reference) {
+                continue;
+            }
+            // References escaping the container are left to the validator
+            let path = match resolve_href(&item_path, &reference) {
+                Some(path) => path,
+                None => continue,
+            };
+            match items_by_path.get(&path) {
+                Some(target) => targets.push(target),
+                None => {
+                    if unlisted_paths.insert((&item.id, path.clone())) {
+                        unlisted_references.push(UnlistedReference {
+                            item,
+                            item_path: item_path.clone(),
+                            is_nonmanifest: nonmanifest_paths.contains(&path),
+                            path,
+                            reference,
+                        });
+                    }
+                }
+            }
+        }
+        for target in targets {
+            if reached.insert(&target.id) {
+                queue.push(target);
+            }
+        }
+    }
+    unlisted_references.sort_by(|a, b| {
+        (&a.item.outside_path, &a.reference).cmp(&(&b.item.outside_path, &b.reference))
+    });
+
+    let orphans: Vec<&ManifestItem> = config
+        .manifest
+        .iter()
+        .filter(|item| !reached.contains(&item.id))
+        .collect();
+    let orphan_bytes = orphans
+        .iter()
+        .filter_map(|item| metadata(&item.outside_path).ok())
+        .map(|file| file.len())
+        .sum();
+    Ok(OrphanReport {
+        orphans,
+        unlisted_references,
+        orphan_bytes,
+    })
+}
+
+///////////////////
+//   Reporting   //
+///////////////////
+
+pub(crate) fn describe_orphan(item: &ManifestItem) -> String {
+    format!(
+        "Manifest item {} ({}) isn't referenced by the spine, guide, NCX, cover or any content.",
+        item.id, item.outside_path
+    )
+}
+
+pub(crate) fn describe_unlisted_reference(reference: &UnlistedReference) -> String {
+    match reference.is_nonmanifest {
+        true => format!(
+            "{} refers to {}, which is a nonmanifest file; content may only use manifest items.",
+            reference.item.outside_path, reference.reference
+        ),
+        false => format!(
+            "{} refers to {}, which isn't in the manifest.",
+            reference.item.outside_path, reference.reference
+        ),
+    }
+}
+
+pub(crate) fn report_orphans(report: &OrphanReport, pruned: bool, build_report: &mut BuildReport) {
+    for reference in &report.unlisted_references {
+        build_report
+            .warnings
+            .push(ValidationIssue::warning(describe_unlisted_reference(
+                reference,
+            )));
+    }
+    if report.orphans.is_empty() {
+        return;
+    }
+    let size = format_size(report.orphan_bytes);
+    match pruned {
+        true => {
+            for item in &report.orphans {
+                build_report
+                    .notes
+                    .push(format!("Leaving out {} ({}).", item.id, item.outside_path));
+            }
+            build_report.notes.push(format!(
+                "Left {} unreferenced manifest item(s) out of the book, saving {}.",
+                report.orphans.len(),
+                size
+            ));
+        }
+        false => {
+            for item in &report.orphans {
+                build_report
+                    .warnings
+                    .push(ValidationIssue::warning(describe_orphan(item)));
+            }
+            build_report.warnings.push(ValidationIssue::warning(format!(
+                "{} unreferenced manifest item(s) take up {}; set prune_orphans = true to leave them out of the book.",
+                report.orphans.len(),
+                size
+            )));
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use crate::epub::epub2::config::{Epub2Config, ManifestItem};
+use crate::epub::epub2::helpers::get_opf_path;
+use crate::helpers::{fixed_clean, path_to_string};
+
+use std::path::{Path, PathBuf};
+
+pub(crate) enum PathEntry<'a> {
+    Container,
+    Opf,
+    Ncx,
+    ManifestItem(&'a ManifestItem),
+    // Index into the nonmanifest files
+    NonmanifestFile(usize),
+}
+
+pub(crate) struct PathProblem<'a> {
+    pub(crate) entry: PathEntry<'a>,
+    // Key of the offending value, for problems with a recipe entry
+    pub(crate) field: &'static str,
+    pub(crate) message: String,
+}
+
+pub(crate) fn get_ncx_path(config: &Epub2Config) -> (&str, PathBuf) {
+    // The NCX's path from the OPF, and its inside path
+    let (_, opf_path) = get_opf_path(config);
+    let opf_parent_dir = Path::new(opf_path).parent().unwrap_or(Path::new(""));
+    let ncx_path_from_opf = config
+        .ncx_meta
+        .as_ref()
+        .and_then(|meta| meta.manifest_path_from_opf.as_deref())
+        .unwrap_or("toc.ncx");
+    (ncx_path_from_opf, opf_parent_dir.join(ncx_path_from_opf))
+}
+
+pub(crate) fn check_inside_path_is_valid(inside_path: &PathBuf) -> Result<(), String> {
+    match inside_path.file_name() {
+        None => return Err(format!("Invalid path ending in '..': {:?}", inside_path)),
+        Some(filename) => match filename.to_str() {
+            None => return Err(format!("Invalid non-UTF-8 filename: {:?}", filename)),
+            Some(filename_str) => {
+                if filename_str.len() > 255 {
+                    return Err(format!(
+                        "Invalid filename of length >255 bytes: {}",
+                        filename_str
+                    ));
+                }
+            }
+        },
+    };
+
+    match inside_path.to_str() {
+        None => return Err(format!("Invalid non-UTF-8 path: {:?}", inside_path)),
+        Some(path_str) => {
+            if path_str.len() > 65535 {
+                return Err(format!("Invalid path of length >65535 bytes: {}", path_str));
+            }
+        }
+    };
+
+    for component in inside_path.iter() {
+        match component.to_str() {
+            None => {
+                return Err(format!(
+                    "Invalid non-UTF-8 path component (you shouldn't ever see this error): {:?}",
+                    component
+                ))
+            }
+            Some(component_str) => {
+                match component_str.find(&['/', '"', '*', ':', '<', '>', '?', '\\'][..]) {
+                    None => (),
+                    Some(index) => {
+                        return Err(format!(
+                            "Path {:?} contains invalid character '{}'.",
+                            inside_path,
+                            component_str.get(index..index + 1).unwrap()
+                        ))
+                    }
+                };
+                if component_str.ends_with('.') && component_str != "." && component_str != ".." {
+                    // This is a bit crude/awkward; plausibly there's a more elegant way to do this check
+                    return Err(format!("Path {:?} ends with '.'.", inside_path));
+                }
+            }
+        };
+    }
+
+    Ok(())
+}
+
+fn describe_entry(entry: &PathEntry, config: &Epub2Config) -> String {
+    match entry {
+        PathEntry::Container => String::from("the container file"),
+        PathEntry::Opf => String::from("the OPF"),
+        PathEntry::Ncx => String::from("the NCX"),
+        PathEntry::ManifestItem(item) => format!("manifest item {}", item.id),
+        PathEntry::NonmanifestFile(index) => match &config.nonmanifest_files {
+            Some(files) => format!("nonmanifest file {}", files[*index].outside_path),
+            None => String::from("a nonmanifest file"),
+        },
+    }
+}
+
+pub(crate) fn find_path_problems(config: &Epub2Config) -> Vec<PathProblem<'_>> {
+    let mut problems = Vec::new();
+    let (_, opf_path) = get_opf_path(config);
+    let opf_parent_dir = Path::new(opf_path).parent().unwrap_or(Path::new(""));
+
+    // Outside paths
+    for item in &config.manifest {
+        if !Path::new(&item.outside_path).is_file() {
+            problems.push(PathProblem {
+                entry: PathEntry::ManifestItem(item),
+                field: "outside_path",
+                message: format!("Outside path {} does not exist.", item.outside_path),
+            });
+        }
+    }
+    let nonmanifest_files = config.nonmanifest_files.as_deref().unwrap_or(&[]);
+    for (index, file) in nonmanifest_files.iter().enumerate() {
+        if !Path::new(&file.outside_path).exists() {
+            problems.push(PathProblem {
+                entry: PathEntry::NonmanifestFile(index),
+                field: "outside_path",
+                message: format!("Outside path {} does not exist.", file.outside_path),
+            });
+        }
+    }
+
+    // Inside paths, with the generated files first so that the recipe's own entries are the ones
+    // reported as clashing
+    let mut inside_paths: Vec<(PathEntry, &'static str, PathBuf)> = vec![
+        (
+            PathEntry::Container,
+            "path",
+            PathBuf::from("META-INF/container.xml"),
+        ),
+        (PathEntry::Opf, "path", PathBuf::from(opf_path)),
+        (
+            PathEntry::Ncx,
+            "manifest_path_from_opf",
+            get_ncx_path(config).1,
+        ),
+    ];
+    for item in &config.manifest {
+        inside_paths.push((
+            PathEntry::ManifestItem(item),
+            "inside_path_from_opf",
+            opf_parent_dir.join(&item.inside_path_from_opf),
+        ));
+    }
+    for (index, file) in nonmanifest_files.iter().enumerate() {
+        inside_paths.push((
+            PathEntry::NonmanifestFile(index),
+            "inside_path",
+            PathBuf::from(&file.inside_path),
+        ));
+    }
+
+    // Zip entries are looked up by their cleaned paths, and some file systems ignore case
+    let mut used_paths: Vec<(String, String)> = Vec::new();
+    for (entry, field, inside_path) in inside_paths {
+        let clean_path = match check_inside_path_is_valid(&inside_path)
+            .and_then(|_| path_to_string(fixed_clean(&inside_path)))
+        {
+            Ok(clean_path) => clean_path,
+            Err(message) => {
+                problems.push(PathProblem {
+                    entry,
+                    field,
+                    message,
+                });
+                continue;
+            }
+        };
+        match used_paths
+            .iter()
+            .find(|(used_path, _)| used_path.eq_ignore_ascii_case(&clean_path))
+        {
+            Some((_, user)) => problems.push(PathProblem {
+                entry,
+                field,
+                message: format!(
+                    "Inside path {} is already used by {}; inside paths must differ by more than case.",
+                    clean_path, user
+                ),
+            }),
+            None => used_paths.push((clean_path, describe_entry(&entry, config))),
+        }
+    }
+    problems
+}
+
+pub(crate) fn check_paths(config: &Epub2Config) -> Result<(), String> {
+    let problems: Vec<String> = find_path_problems(config)
+        .into_iter()
+        .map(|problem| format!("  {}", problem.message))
+        .collect();
+    match problems.is_empty() {
+        true => Ok(()),
+        false => Err(format!("Paths have problems:\n{}", problems.join("\n"))),
+    }
+}
@@ -0,0 +1,272 @@
+use crate::epub::epub2::config::{Epub2Config, Itemref, ManifestItem};
+use crate::epub::epub2::helpers::{get_manifest_item, is_spine_item};
+
+pub(crate) struct SpineProblem {
+    // Index of the spine entry the problem is with, if it's with one in particular
+    pub(crate) index: Option<usize>,
+    // Key of the offending value, for problems with a table entry's idref or glob
+    pub(crate) field: Option<&'static str>,
+    pub(crate) message: String,
+}
+
+pub(crate) struct SpineReport<'a> {
+    // Items in reading order, with whether each is linear
+    pub(crate) itemrefs: Vec<(&'a ManifestItem, bool)>,
+    pub(crate) problems: Vec<SpineProblem>,
+}
+
+pub(crate) fn find_spine_report(config: &Epub2Config) -> SpineReport<'_> {
+    // Without a spine, the book opens on the first item that can go in one
+    let mut report = SpineReport {
+        itemrefs: Vec::new(),
+        problems: Vec::new(),
+    };
+    let spine = match &config.spine {
+        Some(spine) => spine,
+        None => {
+            match config
+                .manifest
+                .iter()
+                .find(|item| is_spine_item(config, item))
+            {
+                Some(item) => report.itemrefs.push((item, true)),
+                None => report.problems.push(SpineProblem {
+                    index: None,
+                    field: None,
+                    message: String::from(
+                        "Manifest contains no items legally placeable within the spine.",
+                    ),
+                }),
+            }
+            return report;
+        }
+    };
+
+    for (index, itemref) in spine.iter().enumerate() {
+        let mut push = |field, message| {
+            report.problems.push(SpineProblem {
+                index: Some(index),
+                field,
+                message,
+            })
+        };
+        let (idrefs, field, linear) = match itemref {
+            Itemref::RawIdref(idref) => (vec![idref.clone()], None, &None),
+            Itemref::CookedIdref { idref, linear, .. } => {
+                (vec![idref.clone()], Some("idref"), linear)
+            }
+            Itemref::Glob { glob, linear, .. } => match config.manifest_glob_paths.get(glob) {
+                Some(paths) => (paths.clone(), None, linear),
+                None => {
+                    push(
+                        Some("glob"),
+                        format!("Spine glob '{}' does not match any manifest glob.", glob),
+                    );
+                    continue;
+                }
+            },
+        };
+        for idref in idrefs {
+            let item = match get_manifest_item(config, &idref) {
+                Some(item) => item,
+                None => {
+                    push(
+                        field,
+                        format!(
+                            "Spine idref {} not found in manifest as either an id or an inside path.",
+                            idref
+                        ),
+                    );
+                    continue;
+                }
+            };
+            if !is_spine_item(config, item) {
+                push(
+                    None,
+                    format!(
+                        "Spine item {} has media type {}, and doesn't fall back to XHTML or DTBook.",
+                        item.id, item.media_type
+                    ),
+                );
+            }
+            if report.itemrefs.iter().any(|(other, _)| other.id == item.id) {
+                push(
+                    None,
+                    format!("Spine item {} appears more than once.", item.id),
+                );
+                continue;
+            }
+            report.itemrefs.push((item, *linear != Some(false)));
+        }
+    }
+
+    // Reading systems start from the first linear item, so a spine of only auxiliary content
+    // leaves them nowhere to begin; a spine whose entries all failed to resolve has already been
+    // reported
+    let is_unresolved = report.itemrefs.is_empty() && !report.problems.is_empty();
+    if !is_unresolved && !report.itemrefs.iter().any(|(_, is_linear)| *is_linear) {
+        report.problems.push(SpineProblem {
+            index: None,
+            field: None,
+            message: String::from(
+                "Spine has no linear items; at least one itemref must not be marked linear = false.",
+            ),
+        });
+    }
+    report
+}
+
+pub(crate) fn check_spine(config: &Epub2Config) -> Result<Vec<(&ManifestItem, bool)>, String> {
+    // Every problem is listed at once, and the reading order returned if there are none
+    let report = find_spine_report(config);
+    let problems: Vec<String> = report
+        .problems
+        .into_iter()
+        .map(|problem| format!("  {}", problem.message))
+        .collect();
+    match problems.is_empty() {
+        true => Ok(report.itemrefs),
+        false => Err(format!("Spine has problems:\n{}", problems.join("\n"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epub::epub2::config::parse_epub2_recipe;
+    use crate::toml::{parse_config_with_unsaved_files, FileContents};
+
+    use std::env::temp_dir;
+    use std::process::id;
+
+    const MANIFEST: &str = r#"manifest = [
+  { outside_path = "cover.xhtml", inside_path_from_opf = "cover.xhtml", id = "cover" },
+  { outside_path = "chapter.xhtml", inside_path_from_opf = "chapter.xhtml", id = "chapter" },
+  { outside_path = "map.svg", inside_path_from_opf = "map.svg", id = "map", fallback = "cover" },
+  { outside_path = "style.css", inside_path_from_opf = "style.css", id = "style" },
+]
+"#;
+
+    fn parse_test_config(name: &str, manifest: &str, spine: &str) -> Epub2Config {
+        // The listed files don't need to exist, as media types come from their extensions
+        let path = temp_dir()
+            .join(format!("bookfactory-spine-{}-{}", name, id()))
+            .join("bookfactory.toml");
+        let text = format!(
+            "[book]\nschema_version = 2\nformat = \"epub2\"\n{}{}",
+            manifest, spine
+        );
+        let unsaved_contents = FileContents::from([(path.clone(), text)]);
+        let recipes =
+            parse_config_with_unsaved_files(path.display().to_string(), &unsaved_contents).unwrap();
+        parse_epub2_recipe(&recipes[0]).unwrap()
+    }
+
+    fn find_spine_messages(name: &str, spine: &str) -> Vec<String> {
+        find_spine_report(&parse_test_config(name, MANIFEST, spine))
+            .problems
+            .into_iter()
+            .map(|problem| problem.message)
+            .collect()
+    }
+
+    #[test]
+    fn valid_spine_gives_reading_order() {
+        let config = parse_test_config(
+            "valid",
+            MANIFEST,
+            r#"spine = [{ idref = "cover", linear = false }, "chapter", "map"]"#,
+        );
+        let report = find_spine_report(&config);
+        assert!(report.problems.is_empty());
+        assert_eq!(
+            report
+                .itemrefs
+                .iter()
+                .map(|(item, is_linear)| (item.id.as_str(), *is_linear))
+                .collect::<Vec<_>>(),
+            vec![("cover", false), ("chapter", true), ("map", true)]
+        );
+    }
+
+    #[test]
+    fn duplicates_are_reported_at_the_repeat() {
+        let config = parse_test_config(
+            "duplicates",
+            MANIFEST,
+            r#"spine = ["chapter", "cover", "chapter"]"#,
+        );
+        let report = find_spine_report(&config);
+        assert_eq!(report.itemrefs.len(), 2);
+        assert_eq!(report.problems.len(), 1);
+        assert_eq!(report.problems[0].index, Some(2));
+        assert_eq!(
+            report.problems[0].message,
+            "Spine item chapter appears more than once."
+        );
+    }
+
+    #[test]
+    fn spine_needs_a_linear_item() {
+        assert_eq!(
+            find_spine_messages(
+                "nonlinear",
+                r#"spine = [{ idref = "cover", linear = false }, { idref = "chapter", linear = false }]"#
+            ),
+            vec!["Spine has no linear items; at least one itemref must not be marked linear = false."]
+        );
+    }
+
+    #[test]
+    fn non_xhtml_items_need_a_fallback() {
+        assert_eq!(
+            find_spine_messages("stylesheet", r#"spine = ["chapter", "style"]"#),
+            vec!["Spine item style has media type text/css, and doesn't fall back to XHTML or DTBook."]
+        );
+    }
+
+    #[test]
+    fn missing_idrefs_are_reported_at_their_field() {
+        let config = parse_test_config(
+            "missing",
+            MANIFEST,
+            r#"spine = ["chapter", { idref = "epilogue" }]"#,
+        );
+        let report = find_spine_report(&config);
+        assert_eq!(report.problems.len(), 1);
+        assert_eq!(report.problems[0].index, Some(1));
+        assert_eq!(report.problems[0].field, Some("idref"));
+        assert_eq!(
+            report.problems[0].message,
+            "Spine idref epilogue not found in manifest as either an id or an inside path."
+        );
+    }
+
+    #[test]
+    fn missing_spine_starts_at_the_first_document() {
+        let manifest = MANIFEST.replace(
+            "  { outside_path = \"cover.xhtml\", inside_path_from_opf = \"cover.xhtml\", id = \"cover\" },\n",
+            "",
+        );
+        let manifest = manifest.replace(", fallback = \"cover\"", "");
+        let config = parse_test_config("default", &manifest, "");
+        let report = find_spine_report(&config);
+        assert!(report.problems.is_empty());
+        assert_eq!(report.itemrefs.len(), 1);
+        assert_eq!(report.itemrefs[0].0.id, "chapter");
+
+        let manifest = manifest.replace(
+            "  { outside_path = \"chapter.xhtml\", inside_path_from_opf = \"chapter.xhtml\", id = \"chapter\" },\n",
+            "",
+        );
+        let config = parse_test_config("empty", &manifest, "");
+        assert_eq!(
+            find_spine_report(&config)
+                .problems
+                .into_iter()
+                .map(|problem| problem.message)
+                .collect::<Vec<_>>(),
+            vec!["Manifest contains no items legally placeable within the spine."]
+        );
+    }
+}
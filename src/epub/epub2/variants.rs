@@ -0,0 +1,252 @@
+use crate::epub::epub2::config::{
+    Epub2Config, Itemref, ManifestItem, Metadata, NavList, NavPoint, NavTarget, PageTarget,
+    VariantTags,
+};
+use crate::epub::epub2::helpers::get_manifest_item;
+use crate::helpers::fixed_clean;
+
+use std::mem::take;
+
+impl VariantTags {
+    fn includes(&self, variant: Option<&str>) -> bool {
+        let in_only_in = match (&self.only_in, variant) {
+            (None, _) => true,
+            (Some(only_in), Some(variant)) => only_in.iter().any(|tag| tag == variant),
+            (Some(_), None) => false,
+        };
+        let in_except_in = match (&self.except_in, variant) {
+            (Some(except_in), Some(variant)) => except_in.iter().any(|tag| tag == variant),
+            _ => false,
+        };
+        in_only_in && !in_except_in
+    }
+
+    fn tags(&self) -> impl Iterator<Item = &String> {
+        self.only_in.iter().chain(self.except_in.iter()).flatten()
+    }
+}
+
+fn get_metadata_variants(item: &Metadata) -> &VariantTags {
+    match item {
+        Metadata::DcMetadata { variants, .. } => variants,
+        Metadata::CustomMetadata { variants, .. } => variants,
+    }
+}
+
+fn get_navpoint_variants_and_idref(navpoint: &NavPoint) -> (&VariantTags, &str) {
+    match navpoint {
+        NavPoint::WithSimpleLabel {
+            variants, idref, ..
+        } => (variants, idref),
+        NavPoint::WithComplexLabels {
+            variants, idref, ..
+        } => (variants, idref),
+    }
+}
+
+fn get_navpoint_children(navpoint: &mut NavPoint) -> &mut Option<Vec<NavPoint>> {
+    match navpoint {
+        NavPoint::WithSimpleLabel { children, .. } => children,
+        NavPoint::WithComplexLabels { children, .. } => children,
+    }
+}
+
+pub(crate) fn get_page_target_idref(target: &PageTarget) -> &str {
+    match target {
+        PageTarget::WithSimpleLabel { idref, .. } => idref,
+        PageTarget::WithComplexLabels { idref, .. } => idref,
+    }
+}
+
+pub(crate) fn get_nav_target_idref(target: &NavTarget) -> &str {
+    match target {
+        NavTarget::WithSimpleLabel { idref, .. } => idref,
+        NavTarget::WithComplexLabels { idref, .. } => idref,
+    }
+}
+
+fn get_navlist_targets(navlist: &mut NavList) -> &mut Vec<NavTarget> {
+    match navlist {
+        NavList::WithSimpleLabel { list, .. } => list,
+        NavList::WithComplexLabels { list, .. } => list,
+    }
+}
+
+fn collect_navpoint_tags<'a>(navpoints: &'a [NavPoint], tags: &mut Vec<&'a String>) {
+    for navpoint in navpoints {
+        let (variants, _) = get_navpoint_variants_and_idref(navpoint);
+        tags.extend(variants.tags());
+        if let NavPoint::WithSimpleLabel {
+            children: Some(children),
+            ..
+        }
+        | NavPoint::WithComplexLabels {
+            children: Some(children),
+            ..
+        } = navpoint
+        {
+            collect_navpoint_tags(children, tags);
+        }
+    }
+}
+
+fn check_variant_is_used(
+    config: &Epub2Config,
+    variant: &str,
+    recipe_name: &str,
+) -> Result<(), String> {
+    // Catches misspelled variants, which would otherwise silently build the untagged content
+    let mut tags: Vec<&String> = config
+        .manifest
+        .iter()
+        .flat_map(|item| item.variants.tags())
+        .collect();
+    if let Some(metadata) = &config.metadata {
+        tags.extend(
+            metadata
+                .iter()
+                .flat_map(|item| get_metadata_variants(item).tags()),
+        );
+    }
+    if let Some(spine) = &config.spine {
+        for itemref in spine {
+            match itemref {
+                Itemref::RawIdref(_) => (),
+                Itemref::CookedIdref { variants, .. } | Itemref::Glob { variants, .. } => {
+                    tags.extend(variants.tags())
+                }
+            }
+        }
+    }
+    if let Some(navmap) = &config.navmap {
+        collect_navpoint_tags(navmap, &mut tags);
+    }
+
+    match tags.iter().any(|tag| *tag == variant) {
+        true => Ok(()),
+        false => Err(format!(
+            "Variant {} is not named by any only_in or except_in tag in recipe {}.",
+            variant, recipe_name
+        )),
+    }
+}
+
+pub(crate) fn is_excluded_reference(config: &Epub2Config, reference: &str) -> bool {
+    // References to items left out of this variant are dropped; anything else unresolvable is
+    // still reported as an error when the book is built
+    get_manifest_item(config, reference).is_none()
+        && config.excluded_manifest.iter().any(|item| {
+            item.id == reference
+                || fixed_clean(&item.inside_path_from_opf) == fixed_clean(reference)
+        })
+}
+
+fn filter_navpoints(
+    config: &Epub2Config,
+    variant: Option<&str>,
+    navpoints: Vec<NavPoint>,
+) -> Vec<NavPoint> {
+    // A navpoint's children go with it
+    navpoints
+        .into_iter()
+        .filter(|navpoint| {
+            let (variants, idref) = get_navpoint_variants_and_idref(navpoint);
+            variants.includes(variant) && !is_excluded_reference(config, idref)
+        })
+        .map(|mut navpoint| {
+            let children = get_navpoint_children(&mut navpoint);
+            if let Some(child_navpoints) = children.take() {
+                *children = Some(filter_navpoints(config, variant, child_navpoints));
+            }
+            navpoint
+        })
+        .collect()
+}
+
+pub(crate) fn remove_excluded_manifest_items(
+    config: &mut Epub2Config,
+    variant: Option<&str>,
+    recipe_name: &str,
+) -> Result<(), String> {
+    if let Some(variant) = variant {
+        check_variant_is_used(config, variant, recipe_name)?;
+    }
+
+    let (kept_items, excluded_items): (Vec<ManifestItem>, Vec<ManifestItem>) =
+        take(&mut config.manifest)
+            .into_iter()
+            .partition(|item| item.variants.includes(variant));
+    config.manifest = kept_items;
+    for inside_paths in config.manifest_glob_paths.values_mut() {
+        inside_paths.retain(|inside_path| {
+            !excluded_items
+                .iter()
+                .any(|item| &item.inside_path_from_opf == inside_path)
+        });
+    }
+    config.excluded_manifest = excluded_items;
+
+    Ok(())
+}
+
+pub(crate) fn filter_by_variant(config: &mut Epub2Config, variant: Option<&str>) {
+    if let Some(metadata) = config.metadata.take() {
+        let (metadata, entry_indices) = metadata
+            .into_iter()
+            .enumerate()
+            .filter(|(_, item)| get_metadata_variants(item).includes(variant))
+            .map(|(index, item)| (item, index))
+            .unzip();
+        config.metadata = Some(metadata);
+        config.metadata_entry_indices = entry_indices;
+    }
+
+    if let Some(spine) = config.spine.take() {
+        let (spine, entry_indices) = spine
+            .into_iter()
+            .enumerate()
+            .filter(|(_, itemref)| match itemref {
+                Itemref::RawIdref(idref) => !is_excluded_reference(config, idref),
+                Itemref::CookedIdref {
+                    idref, variants, ..
+                } => variants.includes(variant) && !is_excluded_reference(config, idref),
+                Itemref::Glob { variants, .. } => variants.includes(variant),
+            })
+            .map(|(index, itemref)| (itemref, index))
+            .unzip();
+        config.spine = Some(spine);
+        config.spine_entry_indices = entry_indices;
+    }
+
+    if let Some(mut guide) = config.guide.take() {
+        guide.retain(|reference| !is_excluded_reference(config, &reference.idref));
+        config.guide = Some(guide);
+    }
+
+    // The NCX doesn't allow an empty navmap or page list, so an emptied navmap falls back to the
+    // default one and an emptied page list is left out
+    if let Some(navmap) = config.navmap.take() {
+        let navmap = filter_navpoints(config, variant, navmap);
+        config.navmap = (!navmap.is_empty()).then_some(navmap);
+    }
+
+    if let Some(pagelist) = config.pagelist.take() {
+        let (pagelist, entry_indices): (Vec<_>, _) = pagelist
+            .into_iter()
+            .enumerate()
+            .filter(|(_, target)| !is_excluded_reference(config, get_page_target_idref(target)))
+            .map(|(index, target)| (target, index))
+            .unzip();
+        config.pagelist = (!pagelist.is_empty()).then_some(pagelist);
+        config.pagelist_entry_indices = entry_indices;
+    }
+
+    if let Some(mut navlists) = config.navlists.take() {
+        for navlist in navlists.iter_mut() {
+            get_navlist_targets(navlist)
+                .retain(|target| !is_excluded_reference(config, get_nav_target_idref(target)));
+        }
+        navlists.retain_mut(|navlist| !get_navlist_targets(navlist).is_empty());
+        config.navlists = Some(navlists);
+    }
+}
@@ -0,0 +1,272 @@
+use crate::epub::media_type::{is_core_media_type, needs_fallback};
+
+use std::collections::{HashMap, HashSet};
+
+// The parts of a manifest item which fallbacks depend on, whether it comes from a recipe or an OPF
+pub(crate) struct FallbackItem<'a> {
+    pub(crate) id: &'a str,
+    pub(crate) media_type: &'a str,
+    pub(crate) fallback: Option<&'a str>,
+    pub(crate) fallback_style: Option<&'a str>,
+}
+
+pub(crate) enum ChainEnd<'a> {
+    // The last item has no fallback
+    Complete,
+    // The last item falls back to an id which isn't in the manifest
+    Unknown(&'a str),
+    // The last item is one seen earlier in the chain
+    Cycle,
+}
+
+pub(crate) struct FallbackChain<'a> {
+    // Indices of the items in the chain, starting with the item it's followed from
+    pub(crate) items: Vec<usize>,
+    pub(crate) end: ChainEnd<'a>,
+}
+
+pub(crate) struct FallbackProblem {
+    // Index of the item the problem is reported at
+    pub(crate) index: usize,
+    // Whether the problem is with the item's fallback style rather than its fallback
+    pub(crate) is_style: bool,
+    pub(crate) message: String,
+}
+
+pub(crate) struct FallbackGraph<'a> {
+    items: &'a [FallbackItem<'a>],
+    indices: HashMap<&'a str, usize>,
+}
+
+impl<'a> FallbackGraph<'a> {
+    pub(crate) fn new(items: &'a [FallbackItem<'a>]) -> Self {
+        let mut indices = HashMap::new();
+        for (index, item) in items.iter().enumerate() {
+            indices.entry(item.id).or_insert(index);
+        }
+        FallbackGraph { items, indices }
+    }
+
+    pub(crate) fn get_chain(&self, index: usize) -> FallbackChain<'a> {
+        // Follows fallbacks from the item until they run out, leave the manifest or loop
+        let mut items = vec![index];
+        loop {
+            let fallback = match self.items[items[items.len() - 1]].fallback {
+                Some(fallback) => fallback,
+                None => {
+                    return FallbackChain {
+                        items,
+                        end: ChainEnd::Complete,
+                    }
+                }
+            };
+            match self.indices.get(fallback) {
+                None => {
+                    return FallbackChain {
+                        items,
+                        end: ChainEnd::Unknown(fallback),
+                    }
+                }
+                Some(&next) => {
+                    let is_cycle = items.contains(&next);
+                    items.push(next);
+                    if is_cycle {
+                        return FallbackChain {
+                            items,
+                            end: ChainEnd::Cycle,
+                        };
+                    }
+                }
+            }
+        }
+    }
+
+    fn describe_chain(&self, chain: &FallbackChain) -> String {
+        let mut links: Vec<String> = chain
+            .items
+            .iter()
+            .map(|&index| {
+                format!(
+                    "{} ({})",
+                    self.items[index].id, self.items[index].media_type
+                )
+            })
+            .collect();
+        if let ChainEnd::Unknown(id) = chain.end {
+            links.push(format!("{} (missing)", id));
+        }
+        links.join(" -> ")
+    }
+
+    fn find_style_problem(&self, item: &FallbackItem) -> Option<String> {
+        let style_id = item.fallback_style?;
+        match self.indices.get(style_id) {
+            None => Some(format!(
+                "Manifest item {} has fallback style {}, which isn't in the manifest.",
+                item.id, style_id
+            )),
+            Some(&index) if self.items[index].media_type != "text/css" => Some(format!(
+                "Manifest item {} has fallback style {}, which is {} rather than a CSS stylesheet (text/css).",
+                item.id, style_id, self.items[index].media_type
+            )),
+            Some(_) => None,
+        }
+    }
+
+    pub(crate) fn find_problems(&self) -> Vec<FallbackProblem> {
+        // Each broken link or loop is reported once, at the item it starts from, rather than at
+        // every item whose chain passes through it
+        let mut problems = Vec::new();
+        let mut reported_cycles = HashSet::new();
+        for (index, item) in self.items.iter().enumerate() {
+            let chain = self.get_chain(index);
+            let mut push = |is_style, message| {
+                problems.push(FallbackProblem {
+                    index,
+                    is_style,
+                    message,
+                })
+            };
+            match chain.end {
+                ChainEnd::Unknown(id) if chain.items.len() == 1 => push(
+                    false,
+                    format!(
+                        "Manifest item {} falls back to {}, which isn't in the manifest: {}.",
+                        item.id,
+                        id,
+                        self.describe_chain(&chain)
+                    ),
+                ),
+                ChainEnd::Cycle if chain.items[chain.items.len() - 1] == index => {
+                    let mut members = chain.items[1..].to_vec();
+                    members.sort();
+                    if reported_cycles.insert(members) {
+                        push(
+                            false,
+                            format!(
+                                "Fallback chain of manifest item {} loops back on itself: {}.",
+                                item.id,
+                                self.describe_chain(&chain)
+                            ),
+                        );
+                    }
+                }
+                _ => (),
+            }
+
+            let reaches_core_type = chain
+                .items
+                .iter()
+                .any(|&link| is_core_media_type(self.items[link].media_type));
+            if needs_fallback(item.media_type) && !reaches_core_type {
+                match (item.fallback, &chain.end) {
+                    (None, _) => push(
+                        false,
+                        format!(
+                            "Manifest item {} has media type {}, which isn't an OPS core type, so it needs a fallback to one.",
+                            item.id, item.media_type
+                        ),
+                    ),
+                    (Some(_), ChainEnd::Complete) => push(
+                        false,
+                        format!(
+                            "Manifest item {} has media type {}, which isn't an OPS core type, and its fallback chain doesn't lead to one: {}.",
+                            item.id,
+                            item.media_type,
+                            self.describe_chain(&chain)
+                        ),
+                    ),
+                    // Broken chains are reported where they break
+                    (Some(_), _) => (),
+                }
+            }
+
+            if let Some(message) = self.find_style_problem(item) {
+                push(true, message);
+            }
+        }
+        problems
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item<'a>(
+        id: &'a str,
+        media_type: &'a str,
+        fallback: Option<&'a str>,
+        fallback_style: Option<&'a str>,
+    ) -> FallbackItem<'a> {
+        FallbackItem {
+            id,
+            media_type,
+            fallback,
+            fallback_style,
+        }
+    }
+
+    fn find_messages(items: &[FallbackItem]) -> Vec<String> {
+        FallbackGraph::new(items)
+            .find_problems()
+            .into_iter()
+            .map(|problem| problem.message)
+            .collect()
+    }
+
+    #[test]
+    fn chain_reaching_core_type_is_fine() {
+        let items = [
+            item("video", "video/mp4", Some("poster"), None),
+            item("poster", "image/png", None, None),
+        ];
+        assert!(find_messages(&items).is_empty());
+        assert_eq!(FallbackGraph::new(&items).get_chain(0).items, vec![0, 1]);
+    }
+
+    #[test]
+    fn missing_fallback_is_reported_where_the_chain_breaks() {
+        let items = [
+            item("a", "video/mp4", Some("b"), None),
+            item("b", "video/webm", Some("c"), None),
+        ];
+        assert_eq!(
+            find_messages(&items),
+            vec![
+                "Manifest item b falls back to c, which isn't in the manifest: b (video/webm) -> c (missing)."
+            ]
+        );
+    }
+
+    #[test]
+    fn cycle_is_reported_once() {
+        let items = [
+            item("a", "video/mp4", Some("b"), None),
+            item("b", "video/webm", Some("a"), None),
+        ];
+        assert_eq!(
+            find_messages(&items),
+            vec!["Fallback chain of manifest item a loops back on itself: a (video/mp4) -> b (video/webm) -> a (video/mp4)."]
+        );
+    }
+
+    #[test]
+    fn fallback_style_must_be_a_stylesheet_in_the_manifest() {
+        let items = [
+            item("chapter", "application/xhtml+xml", None, Some("missing")),
+            item("cover", "application/xhtml+xml", None, Some("chapter")),
+            item("styled", "application/xhtml+xml", None, Some("style")),
+            item("style", "text/css", None, None),
+        ];
+        let problems = FallbackGraph::new(&items).find_problems();
+        assert!(problems.iter().all(|problem| problem.is_style));
+        assert_eq!(
+            problems.into_iter().map(|problem| problem.message).collect::<Vec<_>>(),
+            vec![
+                "Manifest item chapter has fallback style missing, which isn't in the manifest.",
+                "Manifest item cover has fallback style chapter, which is application/xhtml+xml rather than a CSS stylesheet (text/css).",
+            ]
+        );
+    }
+}
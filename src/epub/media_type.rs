@@ -0,0 +1,486 @@
+use std::path::Path;
+
+////////////////////
+//   Core Types   //
+////////////////////
+
+// Types every OPS 2.0.1 reading system supports, so items of other types need a fallback chain
+// leading to one of them
+const CORE_MEDIA_TYPES: &[&str] = &[
+    "application/xhtml+xml",
+    "application/x-dtbook+xml",
+    "application/x-dtbncx+xml",
+    "application/xml",
+    "text/css",
+    "text/x-oeb1-document",
+    "text/x-oeb1-css",
+    "image/gif",
+    "image/jpeg",
+    "image/png",
+    "image/svg+xml",
+];
+
+pub(crate) fn is_core_media_type(media_type: &str) -> bool {
+    CORE_MEDIA_TYPES.contains(&media_type)
+}
+
+pub(crate) fn needs_fallback(media_type: &str) -> bool {
+    // Fonts aren't core types, but a reading system which can't use one just renders the text in
+    // another font, so they're never given fallbacks
+    let is_font = [&OPENTYPE, &WOFF, &WOFF2]
+        .iter()
+        .any(|signature| signature.media_types.contains(&media_type));
+    !is_core_media_type(media_type) && !is_font
+}
+
+////////////////////
+//   Extensions   //
+////////////////////
+
+pub(crate) fn media_type_from_extension<P: AsRef<Path>>(path: P) -> Option<&'static str> {
+    let extension = path.as_ref().extension()?.to_str()?.to_lowercase();
+    Some(match extension.as_ref() {
+        // OPS core types
+        "xhtml" | "xht" | "html" | "htm" => "application/xhtml+xml",
+        "dtb" | "dtbook" => "application/x-dtbook+xml",
+        "css" => "text/css",
+        "xml" => "application/xml",
+        "xpgt" => "application/adobe-page-template+xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" | "jpe" => "image/jpeg",
+        "gif" => "image/gif",
+
+        // Package files
+        "ncx" => "application/x-dtbncx+xml",
+        "opf" => "application/oebps-package+xml",
+
+        // Other images
+        "bmp" => "image/bmp",
+        "webp" => "image/webp",
+        "tif" | "tiff" => "image/tiff",
+
+        // Fonts
+        "otf" => "application/vnd.ms-opentype",
+        "ttf" => "application/x-font-ttf",
+        "woff" => "application/font-woff",
+        "woff2" => "font/woff2",
+
+        // Audio and video
+        "mp3" => "audio/mpeg",
+        "m4a" => "audio/mp4",
+        "mp4" | "m4v" => "video/mp4",
+        "ogg" | "oga" => "audio/ogg",
+
+        // Miscellaneous
+        "smil" => "application/smil+xml",
+        "js" => "text/javascript",
+        "pdf" => "application/pdf",
+        "txt" => "text/plain",
+        _ => return None,
+    })
+}
+
+////////////////////
+//   Signatures   //
+////////////////////
+
+pub(crate) struct ContentSignature {
+    pub(crate) description: &'static str,
+    // The first media type is the preferred one when inferring from content
+    pub(crate) media_types: &'static [&'static str],
+}
+
+const PNG: ContentSignature = ContentSignature {
+    description: "PNG image",
+    media_types: &["image/png"],
+};
+const JPEG: ContentSignature = ContentSignature {
+    description: "JPEG image",
+    media_types: &["image/jpeg"],
+};
+const GIF: ContentSignature = ContentSignature {
+    description: "GIF image",
+    media_types: &["image/gif"],
+};
+const BMP: ContentSignature = ContentSignature {
+    description: "BMP image",
+    media_types: &["image/bmp"],
+};
+const WEBP: ContentSignature = ContentSignature {
+    description: "WebP image",
+    media_types: &["image/webp"],
+};
+const TIFF: ContentSignature = ContentSignature {
+    description: "TIFF image",
+    media_types: &["image/tiff"],
+};
+const SVG: ContentSignature = ContentSignature {
+    description: "SVG image",
+    media_types: &["image/svg+xml"],
+};
+const OPENTYPE: ContentSignature = ContentSignature {
+    description: "OpenType/TrueType font",
+    media_types: &[
+        "application/vnd.ms-opentype",
+        "application/x-font-ttf",
+        "application/x-font-truetype",
+        "application/x-font-otf",
+        "application/x-font-opentype",
+        "application/font-sfnt",
+        "font/otf",
+        "font/ttf",
+        "font/sfnt",
+    ],
+};
+const WOFF: ContentSignature = ContentSignature {
+    description: "WOFF font",
+    media_types: &[
+        "application/font-woff",
+        "font/woff",
+        "application/x-font-woff",
+    ],
+};
+const WOFF2: ContentSignature = ContentSignature {
+    description: "WOFF2 font",
+    media_types: &["font/woff2", "application/font-woff2"],
+};
+const MP3: ContentSignature = ContentSignature {
+    description: "MP3 audio",
+    media_types: &["audio/mpeg", "audio/mp3"],
+};
+const MP4: ContentSignature = ContentSignature {
+    description: "MP4 media",
+    media_types: &["video/mp4", "audio/mp4", "audio/x-m4a", "audio/m4a"],
+};
+const OGG: ContentSignature = ContentSignature {
+    description: "Ogg media",
+    media_types: &["audio/ogg", "video/ogg", "application/ogg"],
+};
+const PDF: ContentSignature = ContentSignature {
+    description: "PDF document",
+    media_types: &["application/pdf"],
+};
+
+const SIGNATURES: &[&ContentSignature] = &[
+    &PNG, &JPEG, &GIF, &BMP, &WEBP, &TIFF, &SVG, &OPENTYPE, &WOFF, &WOFF2, &MP3, &MP4, &OGG, &PDF,
+];
+
+fn decode_text(head: &[u8]) -> String {
+    // Markup with a byte order mark may be UTF-16; anything else is read as UTF-8
+    let decode_utf16 = |bytes: &[u8], from_bytes: fn([u8; 2]) -> u16| {
+        let units: Vec<u16> = bytes
+            .chunks_exact(2)
+            .map(|pair| from_bytes([pair[0], pair[1]]))
+            .collect();
+        String::from_utf16_lossy(&units)
+    };
+    if let Some(rest) = head.strip_prefix(b"\xff\xfe") {
+        decode_utf16(rest, u16::from_le_bytes)
+    } else if let Some(rest) = head.strip_prefix(b"\xfe\xff") {
+        decode_utf16(rest, u16::from_be_bytes)
+    } else {
+        String::from_utf8_lossy(head.strip_prefix(b"\xef\xbb\xbf").unwrap_or(head)).into_owned()
+    }
+}
+
+pub(crate) fn starts_with_markup(head: &[u8]) -> bool {
+    decode_text(head).trim_start().starts_with('<')
+}
+
+fn get_first_element_name(text: &str) -> Option<&str> {
+    // Skips the XML declaration, processing instructions, comments and doctype, including any
+    // internal subset, to the first start tag
+    let mut rest = text.trim_start();
+    loop {
+        if let Some(after) = rest.strip_prefix("<?") {
+            rest = &after[after.find("?>")? + 2..];
+        } else if let Some(after) = rest.strip_prefix("<!--") {
+            rest = &after[after.find("-->")? + 3..];
+        } else if let Some(after) = rest.strip_prefix("<!") {
+            let end = after.find(['[', '>'])?;
+            rest = match after.as_bytes()[end] {
+                b'[' => {
+                    let subset_end = end + after[end..].find(']')?;
+                    &after[subset_end + after[subset_end..].find('>')? + 1..]
+                }
+                _ => &after[end + 1..],
+            };
+        } else if let Some(after) = rest.strip_prefix('<') {
+            let end = after
+                .find(|c: char| c.is_whitespace() || c == '>' || c == '/')
+                .unwrap_or(after.len());
+            return Some(&after[..end]);
+        } else {
+            return None;
+        }
+        rest = rest.trim_start();
+    }
+}
+
+fn looks_like_svg(head: &[u8]) -> bool {
+    let text = decode_text(head);
+    match get_first_element_name(&text) {
+        Some(name) => name.rsplit(':').next() == Some("svg"),
+        None => false,
+    }
+}
+
+fn is_mpeg_frame_header(head: &[u8]) -> bool {
+    // Eleven sync bits, then a version, layer, bitrate and sample rate which aren't reserved or
+    // invalid; the sync bits alone also match byte order marks and other binary data
+    let header = match head.get(0..4) {
+        Some(header) => header,
+        None => return false,
+    };
+    let version = (header[1] >> 3) & 0b11;
+    let layer = (header[1] >> 1) & 0b11;
+    let bitrate = header[2] >> 4;
+    let sample_rate = (header[2] >> 2) & 0b11;
+    header[0] == 0xff
+        && header[1] & 0xe0 == 0xe0
+        && version != 0b01
+        && layer != 0b00
+        && bitrate != 0b1111
+        && sample_rate != 0b11
+}
+
+pub(crate) fn sniff_content(head: &[u8]) -> Option<&'static ContentSignature> {
+    if head.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some(&PNG)
+    } else if head.starts_with(b"\xff\xd8\xff") {
+        Some(&JPEG)
+    } else if head.starts_with(b"GIF87a") || head.starts_with(b"GIF89a") {
+        Some(&GIF)
+    } else if head.len() >= 14 && head.starts_with(b"BM") && head[6..10] == [0, 0, 0, 0] {
+        Some(&BMP)
+    } else if head.len() >= 12 && &head[0..4] == b"RIFF" && &head[8..12] == b"WEBP" {
+        Some(&WEBP)
+    } else if head.starts_with(b"II*\x00") || head.starts_with(b"MM\x00*") {
+        Some(&TIFF)
+    } else if head.starts_with(b"OTTO")
+        || head.starts_with(b"\x00\x01\x00\x00")
+        || head.starts_with(b"ttcf")
+    {
+        Some(&OPENTYPE)
+    } else if head.starts_with(b"wOFF") {
+        Some(&WOFF)
+    } else if head.starts_with(b"wOF2") {
+        Some(&WOFF2)
+    } else if [&b"\xef\xbb\xbf"[..], b"\xff\xfe", b"\xfe\xff"]
+        .iter()
+        .any(|bom| head.starts_with(bom))
+    {
+        // Text with a byte order mark, which is only sniffed as far as being SVG or not
+        looks_like_svg(head).then_some(&SVG)
+    } else if head.starts_with(b"ID3") || is_mpeg_frame_header(head) {
+        Some(&MP3)
+    } else if head.len() >= 8 && &head[4..8] == b"ftyp" {
+        Some(&MP4)
+    } else if head.starts_with(b"OggS") {
+        Some(&OGG)
+    } else if head.starts_with(b"%PDF-") {
+        Some(&PDF)
+    } else if looks_like_svg(head) {
+        Some(&SVG)
+    } else {
+        None
+    }
+}
+
+pub(crate) fn signature_for_media_type(media_type: &str) -> Option<&'static ContentSignature> {
+    SIGNATURES
+        .iter()
+        .find(|signature| signature.media_types.contains(&media_type))
+        .copied()
+}
+
+////////////////////
+//   Dimensions   //
+////////////////////
+
+fn read_u16_be(bytes: &[u8], offset: usize) -> Option<u32> {
+    let field = bytes.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([field[0], field[1]]) as u32)
+}
+
+fn read_u16_le(bytes: &[u8], offset: usize) -> Option<u32> {
+    let field = bytes.get(offset..offset + 2)?;
+    Some(u16::from_le_bytes([field[0], field[1]]) as u32)
+}
+
+fn read_u32_be(bytes: &[u8], offset: usize) -> Option<u32> {
+    let field = bytes.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes([field[0], field[1], field[2], field[3]]))
+}
+
+fn read_u32_le(bytes: &[u8], offset: usize) -> Option<u32> {
+    let field = bytes.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes([field[0], field[1], field[2], field[3]]))
+}
+
+fn read_jpeg_dimensions(contents: &[u8]) -> Option<(u32, u32)> {
+    // Walks the segments up to the first start-of-frame marker, which holds the dimensions
+    let mut offset = 2;
+    loop {
+        if *contents.get(offset)? != 0xff {
+            return None;
+        }
+        while *contents.get(offset)? == 0xff {
+            offset += 1;
+        }
+        let marker = *contents.get(offset)?;
+        offset += 1;
+        match marker {
+            // Markers without a length
+            0x01 | 0xd0..=0xd8 => continue,
+            // Start of frame, apart from the huffman table, arithmetic coding and JPEG-LS markers
+            // which share the range
+            0xc0..=0xcf if marker != 0xc4 && marker != 0xc8 && marker != 0xcc => {
+                return Some((
+                    read_u16_be(contents, offset + 5)?,
+                    read_u16_be(contents, offset + 3)?,
+                ))
+            }
+            _ => offset += read_u16_be(contents, offset)? as usize,
+        }
+    }
+}
+
+fn read_webp_dimensions(contents: &[u8]) -> Option<(u32, u32)> {
+    match contents.get(12..16)? {
+        b"VP8 " => Some((
+            read_u16_le(contents, 26)? & 0x3fff,
+            read_u16_le(contents, 28)? & 0x3fff,
+        )),
+        b"VP8L" => {
+            let bits = read_u32_le(contents, 21)?;
+            Some(((bits & 0x3fff) + 1, ((bits >> 14) & 0x3fff) + 1))
+        }
+        b"VP8X" => Some((
+            (read_u32_le(contents, 24)? & 0xffffff) + 1,
+            (read_u32_le(contents, 27)? & 0xffffff) + 1,
+        )),
+        _ => None,
+    }
+}
+
+fn read_bmp_dimensions(contents: &[u8]) -> Option<(u32, u32)> {
+    // OS/2 bitmaps have 16-bit dimensions, and Windows ones have signed 32-bit dimensions, with a
+    // negative height for top-down images
+    match read_u32_le(contents, 14)? {
+        12 => Some((read_u16_le(contents, 18)?, read_u16_le(contents, 20)?)),
+        _ => Some((
+            (read_u32_le(contents, 18)? as i32).unsigned_abs(),
+            (read_u32_le(contents, 22)? as i32).unsigned_abs(),
+        )),
+    }
+}
+
+pub(crate) fn read_image_dimensions(
+    signature: &ContentSignature,
+    contents: &[u8],
+) -> Option<(u32, u32)> {
+    // Width and height in pixels, from the header of a raster image of the given signature; None
+    // for other signatures or for headers which are truncated or corrupt
+    match signature.media_types[0] {
+        "image/png" if contents.get(12..16) == Some(b"IHDR") => {
+            Some((read_u32_be(contents, 16)?, read_u32_be(contents, 20)?))
+        }
+        "image/jpeg" => read_jpeg_dimensions(contents),
+        "image/gif" => Some((read_u16_le(contents, 6)?, read_u16_le(contents, 8)?)),
+        "image/webp" => read_webp_dimensions(contents),
+        "image/bmp" => read_bmp_dimensions(contents),
+        _ => None,
+    }
+}
+
+pub(crate) fn is_raster_image(signature: &ContentSignature) -> bool {
+    [
+        "image/png",
+        "image/jpeg",
+        "image/gif",
+        "image/webp",
+        "image/bmp",
+    ]
+    .contains(&signature.media_types[0])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sniff(head: &[u8]) -> Option<&'static str> {
+        sniff_content(head).map(|signature| signature.description)
+    }
+
+    fn utf16le(text: &str) -> Vec<u8> {
+        let mut bytes = vec![0xff, 0xfe];
+        bytes.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+        bytes
+    }
+
+    #[test]
+    fn binary_signatures() {
+        assert_eq!(
+            sniff(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"),
+            Some(PNG.description)
+        );
+        assert_eq!(sniff(b"\xff\xd8\xff\xe0\0\x10JFIF"), Some(JPEG.description));
+        assert_eq!(sniff(b"GIF89a\x01\0\x01\0"), Some(GIF.description));
+        assert_eq!(sniff(b"RIFF\0\0\0\0WEBPVP8 "), Some(WEBP.description));
+        assert_eq!(sniff(b"wOF2\0\x01\0\0"), Some(WOFF2.description));
+        assert_eq!(sniff(b"\0\0\0\x18ftypmp42"), Some(MP4.description));
+        assert_eq!(sniff(b"%PDF-1.7"), Some(PDF.description));
+        assert_eq!(sniff(b"plain text"), None);
+        assert_eq!(sniff(b""), None);
+    }
+
+    #[test]
+    fn mp3_needs_a_whole_frame_header() {
+        assert_eq!(sniff(b"ID3\x04\0\0\0\0\0\0"), Some(MP3.description));
+        // MPEG-1 layer III, 128 kbit/s, 44.1 kHz
+        assert_eq!(sniff(b"\xff\xfb\x90\x64"), Some(MP3.description));
+        // Reserved version, reserved layer, invalid bitrate and reserved sample rate
+        assert_eq!(sniff(b"\xff\xeb\x90\x64"), None);
+        assert_eq!(sniff(b"\xff\xf9\x90\x64"), None);
+        assert_eq!(sniff(b"\xff\xfb\xf0\x64"), None);
+        assert_eq!(sniff(b"\xff\xfb\x9c\x64"), None);
+        assert_eq!(sniff(b"\xff\xfb"), None);
+    }
+
+    #[test]
+    fn utf16_markup_isnt_mp3() {
+        let xhtml = utf16le("<?xml version=\"1.0\" encoding=\"utf-16\"?><html xmlns=\"http://www.w3.org/1999/xhtml\"/>");
+        assert_eq!(sniff(&xhtml), None);
+        let svg = utf16le("<svg xmlns=\"http://www.w3.org/2000/svg\"/>");
+        assert_eq!(sniff(&svg), Some(SVG.description));
+        let mut svg = vec![0xfe, 0xff];
+        svg.extend("<svg/>".encode_utf16().flat_map(u16::to_be_bytes));
+        assert_eq!(sniff(&svg), Some(SVG.description));
+        assert_eq!(sniff(b"\xef\xbb\xbf<svg/>"), Some(SVG.description));
+    }
+
+    #[test]
+    fn svg_is_recognised_by_its_first_element() {
+        let comment = format!("<!-- {} -->", "x".repeat(2000));
+        let svg = format!(
+            "<?xml version=\"1.0\"?>\n{}\n<!DOCTYPE svg PUBLIC \"-//W3C//DTD SVG 1.1//EN\" \"http://www.w3.org/Graphics/SVG/1.1/DTD/svg11.dtd\" [\n  <!ENTITY a \"b\">\n]>\n<?xml-stylesheet href=\"a.css\"?>\n<svg:svg xmlns:svg=\"http://www.w3.org/2000/svg\"/>",
+            comment
+        );
+        assert_eq!(sniff(svg.as_bytes()), Some(SVG.description));
+        assert_eq!(sniff(b"  <svg>"), Some(SVG.description));
+
+        assert_eq!(sniff(b"<html><body><svg/></body></html>"), None);
+        assert_eq!(sniff(b"<svgx/>"), None);
+        assert_eq!(sniff(b"<!-- unterminated <svg/>"), None);
+        assert_eq!(sniff(b"text <svg/>"), None);
+    }
+
+    #[test]
+    fn markup_is_recognised_through_byte_order_marks() {
+        assert!(starts_with_markup(b"\xef\xbb\xbf  <?xml"));
+        assert!(starts_with_markup(&utf16le("\n<html/>")));
+        assert!(!starts_with_markup(b"\xff\xd8\xff"));
+    }
+}
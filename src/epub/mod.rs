@@ -1,7 +1,13 @@
 mod build;
 mod epub2;
 mod epub3;
+mod fallbacks;
+mod media_type;
+mod validate;
 mod zip;
 
-pub use self::build::zip_with_epub_mimetype;
+pub use self::build::{zip_with_epub_mimetype, BuildReport};
 pub use self::epub2::build::build_epub2;
+pub use self::epub2::epub2_config_schema;
+pub(crate) use self::epub2::{find_epub2_problems, get_manifest_item, Epub2Config};
+pub use self::validate::{validate_epub, IssueLocation, Severity, ValidationIssue};
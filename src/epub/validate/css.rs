@@ -0,0 +1,393 @@
+use crate::epub::validate::links::LinkTargets;
+use crate::epub::validate::opf::Package;
+use crate::epub::validate::{is_remote_href, EpubContents, Issues};
+
+use std::collections::HashSet;
+
+// The CSS2 properties which OPS 2.0.1 adopts, plus its own oeb-column-number; EPUB 2 reading
+// systems are free to ignore anything else
+const OPS_PROPERTIES: &[&str] = &[
+    // Box model
+    "margin",
+    "margin-top",
+    "margin-right",
+    "margin-bottom",
+    "margin-left",
+    "padding",
+    "padding-top",
+    "padding-right",
+    "padding-bottom",
+    "padding-left",
+    "border",
+    "border-top",
+    "border-right",
+    "border-bottom",
+    "border-left",
+    "border-color",
+    "border-top-color",
+    "border-right-color",
+    "border-bottom-color",
+    "border-left-color",
+    "border-style",
+    "border-top-style",
+    "border-right-style",
+    "border-bottom-style",
+    "border-left-style",
+    "border-width",
+    "border-top-width",
+    "border-right-width",
+    "border-bottom-width",
+    "border-left-width",
+    // Visual formatting
+    "display",
+    "position",
+    "top",
+    "right",
+    "bottom",
+    "left",
+    "float",
+    "clear",
+    "direction",
+    "unicode-bidi",
+    "width",
+    "min-width",
+    "max-width",
+    "height",
+    "min-height",
+    "max-height",
+    "line-height",
+    "vertical-align",
+    // Lists
+    "list-style",
+    "list-style-type",
+    "list-style-position",
+    "list-style-image",
+    // Paged media
+    "page-break-before",
+    "page-break-after",
+    "page-break-inside",
+    "orphans",
+    "widows",
+    // Colors and backgrounds
+    "color",
+    "background-color",
+    // Fonts
+    "font",
+    "font-family",
+    "font-size",
+    "font-style",
+    "font-variant",
+    "font-weight",
+    // Text
+    "text-indent",
+    "text-align",
+    "text-decoration",
+    "letter-spacing",
+    "word-spacing",
+    "text-transform",
+    "white-space",
+    // Tables
+    "caption-side",
+    "table-layout",
+    "border-collapse",
+    "border-spacing",
+    "empty-cells",
+    // OPS extension
+    "oeb-column-number",
+];
+
+/////////////////
+//   Parsing   //
+/////////////////
+
+fn blank_comments(css: &str) -> String {
+    // Comments become spaces, keeping their line breaks, so that positions in what's left still
+    // match the original; a comment opener inside a string is part of the string
+    let mut blanked = String::with_capacity(css.len());
+    let mut in_comment = false;
+    let mut quote = None;
+    let mut chars = css.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (in_comment, quote, c, chars.peek()) {
+            (false, Some(open_quote), _, _) => {
+                if c == open_quote {
+                    quote = None;
+                }
+                blanked.push(c);
+            }
+            (false, None, '"' | '\'', _) => {
+                quote = Some(c);
+                blanked.push(c);
+            }
+            (false, None, '/', Some('*')) | (true, _, '*', Some('/')) => {
+                chars.next();
+                in_comment = !in_comment;
+                blanked.push_str("  ");
+            }
+            (true, _, '\n', _) => blanked.push('\n'),
+            (true, _, _, _) => blanked.push(' '),
+            (false, None, _, _) => blanked.push(c),
+        }
+    }
+    blanked
+}
+
+fn unquote(value: &str) -> &str {
+    let value = value.trim();
+    for quote in ['"', '\''] {
+        if let Some(inner) = value
+            .strip_prefix(quote)
+            .and_then(|value| value.strip_suffix(quote))
+        {
+            return inner;
+        }
+    }
+    value
+}
+
+fn get_position(css: &str, offset: usize) -> (u64, u64) {
+    let line_start = css[..offset].rfind('\n').map_or(0, |index| index + 1);
+    (
+        css[..offset].matches('\n').count() as u64 + 1,
+        css[line_start..offset].chars().count() as u64 + 1,
+    )
+}
+
+fn find_located_references(css: &str) -> Vec<(String, usize)> {
+    // The targets of url() and of @import's string form, which is the only other way a stylesheet
+    // pulls in a file, with their byte offsets
+    let mut references = Vec::new();
+    let lowercase = css.to_ascii_lowercase();
+    for (start, _) in lowercase.match_indices("url(") {
+        let value_start = start + "url(".len();
+        if let Some(end) = css[value_start..].find(')') {
+            references.push((
+                String::from(unquote(&css[value_start..value_start + end])),
+                start,
+            ));
+        }
+    }
+    for (start, _) in lowercase.match_indices("@import") {
+        let value = css[start + "@import".len()..].trim_start();
+        if let Some(quote) = value.chars().next().filter(|c| *c == '"' || *c == '\'') {
+            if let Some(end) = value[1..].find(quote) {
+                references.push((String::from(&value[1..1 + end]), start));
+            }
+        }
+    }
+    references.retain(|(reference, _)| !reference.is_empty());
+    references.sort_by_key(|(_, start)| *start);
+    references
+}
+
+pub(crate) fn find_css_references(css: &str) -> Vec<String> {
+    find_located_references(&blank_comments(css))
+        .into_iter()
+        .map(|(reference, _)| reference)
+        .collect()
+}
+
+#[derive(PartialEq)]
+enum Block {
+    // The top level, or an @media rule, holding further rules
+    Rules,
+    // A style rule's declarations
+    Declarations,
+    // An @font-face or @page rule's descriptors, or anything unrecognized
+    Other,
+}
+
+fn push_property(css: &str, start: usize, end: usize, properties: &mut Vec<(String, usize)>) {
+    if let Some((name, _)) = css[start..end].split_once(':') {
+        let offset = start + name.len() - name.trim_start().len();
+        let name = name.trim();
+        if !name.is_empty() {
+            properties.push((name.to_ascii_lowercase(), offset));
+        }
+    }
+}
+
+fn find_located_properties(css: &str) -> Vec<(String, usize)> {
+    // Property names declared in style rules, with their byte offsets
+    let mut properties = Vec::new();
+    let mut blocks = vec![Block::Rules];
+    let mut segment_start = 0;
+    let mut quote = None;
+    for (index, c) in css.char_indices() {
+        if let Some(open_quote) = quote {
+            if c == open_quote {
+                quote = None;
+            }
+            continue;
+        }
+        match c {
+            '"' | '\'' => quote = Some(c),
+            '{' => {
+                let prelude = css[segment_start..index].trim().to_ascii_lowercase();
+                blocks.push(match blocks.last() {
+                    Some(Block::Rules) if prelude.starts_with("@media") => Block::Rules,
+                    Some(Block::Rules) if !prelude.starts_with('@') => Block::Declarations,
+                    _ => Block::Other,
+                });
+                segment_start = index + 1;
+            }
+            ';' | '}' => {
+                if blocks.last() == Some(&Block::Declarations) {
+                    push_property(css, segment_start, index, &mut properties);
+                }
+                if c == '}' && blocks.len() > 1 {
+                    blocks.pop();
+                }
+                segment_start = index + 1;
+            }
+            _ => (),
+        }
+    }
+    properties
+}
+
+//////////////////
+//   Checking   //
+//////////////////
+
+fn check_stylesheet(path: &str, css: &str, targets: &LinkTargets, issues: &mut Issues) {
+    let css = blank_comments(css);
+    for (reference, offset) in find_located_references(&css) {
+        let (line, column) = get_position(&css, offset);
+        if is_remote_href(&reference) {
+            issues.warning_at(
+                path,
+                line,
+                column,
+                format!(
+                    "Stylesheet refers to remote resource {}, which reading systems may not load.",
+                    reference
+                ),
+            );
+        } else {
+            targets.check_link(path, line, column, &reference, issues);
+        }
+    }
+
+    // Vendor-prefixed properties are aimed at particular reading systems on purpose
+    for (property, offset) in find_located_properties(&css) {
+        if !property.starts_with('-') && !OPS_PROPERTIES.contains(&property.as_ref()) {
+            let (line, column) = get_position(&css, offset);
+            issues.warning_at(
+                path,
+                line,
+                column,
+                format!(
+                    "Property {} isn't part of the OPS 2.0.1 CSS subset, so EPUB 2 reading systems may ignore it.",
+                    property
+                ),
+            );
+        }
+    }
+}
+
+pub(crate) fn check_stylesheets(
+    contents: &EpubContents,
+    package: &Package,
+    targets: &LinkTargets,
+    issues: &mut Issues,
+) {
+    let mut checked_paths = HashSet::new();
+    for item in &package.manifest {
+        if item.media_type != "text/css" {
+            continue;
+        }
+        let (path, css) = match &item.path {
+            Some(path) => match contents.get(path) {
+                Some(css) => (path, css),
+                None => continue,
+            },
+            None => continue,
+        };
+        if checked_paths.insert(path) {
+            check_stylesheet(path, &String::from_utf8_lossy(css), targets, issues);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epub::validate::tests::validate_test_epub;
+
+    fn find_properties(css: &str) -> Vec<(String, (u64, u64))> {
+        let css = blank_comments(css);
+        find_located_properties(&css)
+            .into_iter()
+            .map(|(property, offset)| (property, get_position(&css, offset)))
+            .collect()
+    }
+
+    #[test]
+    fn comments_are_blanked_in_place() {
+        let css = "p { /* a\n b */ color: red; }";
+        let blanked = blank_comments(css);
+        assert_eq!(blanked, "p {     \n      color: red; }");
+        assert_eq!(blanked.len(), css.len());
+    }
+
+    #[test]
+    fn comment_openers_in_strings_are_kept() {
+        let css = "p::before { content: \"/*\"; color: red; } /* note */ q { margin: 0 }";
+        assert_eq!(
+            blank_comments(css),
+            "p::before { content: \"/*\"; color: red; }            q { margin: 0 }"
+        );
+        assert_eq!(
+            find_properties(css),
+            vec![
+                (String::from("content"), (1, 13)),
+                (String::from("color"), (1, 28)),
+                (String::from("margin"), (1, 57)),
+            ]
+        );
+    }
+
+    #[test]
+    fn properties_are_found_in_style_rules_only() {
+        let css = concat!(
+            "@import \"other.css\";\n",
+            "@font-face { font-family: F; src: url(f.otf) }\n",
+            "@media screen {\n",
+            "  P { Color: red; background: url('a;b.png') }\n",
+            "}\n",
+        );
+        assert_eq!(
+            find_properties(css),
+            vec![
+                (String::from("color"), (4, 7)),
+                (String::from("background"), (4, 19)),
+            ]
+        );
+        assert_eq!(
+            find_css_references(css),
+            vec!["other.css", "f.otf", "a;b.png"]
+        );
+    }
+
+    #[test]
+    fn references_in_comments_are_ignored() {
+        assert_eq!(
+            find_css_references("/* url(old.png) */ p { background: url(\"new.png\") }"),
+            vec!["new.png"]
+        );
+    }
+
+    #[test]
+    fn stylesheets_are_checked_against_the_manifest_and_subset() {
+        let css = "p {\n  margin: 0;\n  box-shadow: none;\n  -webkit-hyphens: auto;\n}\nbody { background-image: url(images/paper.png) }";
+        assert_eq!(
+            validate_test_epub(&[("OEBPS/style.css", css)], &[]),
+            vec![
+                "error: OEBPS/style.css:6:26: Link images/paper.png refers to OEBPS/images/paper.png, which isn't in the manifest.",
+                "warning: OEBPS/style.css:3:3: Property box-shadow isn't part of the OPS 2.0.1 CSS subset, so EPUB 2 reading systems may ignore it.",
+                "warning: OEBPS/style.css:6:8: Property background-image isn't part of the OPS 2.0.1 CSS subset, so EPUB 2 reading systems may ignore it.",
+            ]
+        );
+    }
+}
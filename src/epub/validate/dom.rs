@@ -0,0 +1,152 @@
+use xml::common::Position;
+use xml::reader::{EventReader, ParserConfig, XmlEvent};
+
+// A minimal element tree, since validation needs line and column numbers that the
+// serialization-oriented libraries elsewhere in the crate don't keep
+
+pub(crate) struct Element {
+    pub(crate) namespace: Option<String>,
+    pub(crate) local_name: String,
+    pub(crate) attributes: Vec<Attribute>,
+    pub(crate) children: Vec<Node>,
+    pub(crate) line: u64,
+    pub(crate) column: u64,
+}
+
+pub(crate) struct Attribute {
+    pub(crate) namespace: Option<String>,
+    pub(crate) local_name: String,
+    pub(crate) value: String,
+}
+
+pub(crate) enum Node {
+    Element(Element),
+    Text(String),
+}
+
+pub(crate) struct XmlError {
+    pub(crate) line: u64,
+    pub(crate) column: u64,
+    pub(crate) message: String,
+}
+
+impl Element {
+    pub(crate) fn is(&self, namespace: &str, local_name: &str) -> bool {
+        self.namespace.as_deref() == Some(namespace) && self.local_name == local_name
+    }
+
+    pub(crate) fn attribute(&self, local_name: &str) -> Option<&str> {
+        // Unprefixed attributes are in no namespace, whatever the element's namespace
+        self.attributes
+            .iter()
+            .find(|attribute| attribute.namespace.is_none() && attribute.local_name == local_name)
+            .map(|attribute| attribute.value.as_ref())
+    }
+
+    pub(crate) fn elements(&self) -> impl Iterator<Item = &Element> {
+        self.children.iter().filter_map(|child| match child {
+            Node::Element(element) => Some(element),
+            Node::Text(_) => None,
+        })
+    }
+
+    pub(crate) fn children_named<'a>(
+        &'a self,
+        namespace: &'a str,
+        local_name: &'a str,
+    ) -> impl Iterator<Item = &'a Element> {
+        self.elements()
+            .filter(move |element| element.is(namespace, local_name))
+    }
+
+    pub(crate) fn child_named(&self, namespace: &str, local_name: &str) -> Option<&Element> {
+        self.elements()
+            .find(|element| element.is(namespace, local_name))
+    }
+
+    pub(crate) fn descendants(&self) -> Vec<&Element> {
+        // In document order, including the element itself
+        let mut descendants = vec![self];
+        for element in self.elements() {
+            descendants.append(&mut element.descendants());
+        }
+        descendants
+    }
+
+    pub(crate) fn text(&self) -> String {
+        let mut text = String::new();
+        for child in &self.children {
+            match child {
+                Node::Text(child_text) => text.push_str(child_text),
+                Node::Element(element) => text.push_str(&element.text()),
+            }
+        }
+        text
+    }
+}
+
+pub(crate) fn parse_xml_with_entities(
+    bytes: &[u8],
+    entities: &[(&str, u32)],
+) -> Result<Element, XmlError> {
+    let mut config = ParserConfig::new()
+        .cdata_to_characters(true)
+        .ignore_comments(true);
+    for (name, code_point) in entities {
+        if let Some(c) = char::from_u32(*code_point) {
+            config
+                .extra_entities
+                .insert(String::from(*name), c.to_string());
+        }
+    }
+    let mut reader = EventReader::new_with_config(bytes, config);
+    let mut open_elements: Vec<Element> = Vec::new();
+    loop {
+        let event = reader.next().map_err(|e| XmlError {
+            line: e.position().row + 1,
+            column: e.position().column + 1,
+            message: String::from(e.msg()),
+        })?;
+        let position = reader.position();
+        match event {
+            XmlEvent::StartElement {
+                name, attributes, ..
+            } => open_elements.push(Element {
+                namespace: name.namespace,
+                local_name: name.local_name,
+                attributes: attributes
+                    .into_iter()
+                    .map(|attribute| Attribute {
+                        namespace: attribute.name.namespace,
+                        local_name: attribute.name.local_name,
+                        value: attribute.value,
+                    })
+                    .collect(),
+                children: Vec::new(),
+                line: position.row + 1,
+                column: position.column + 1,
+            }),
+            XmlEvent::EndElement { .. } => {
+                // The reader guarantees matching start and end tags
+                let element = open_elements.pop().unwrap();
+                match open_elements.last_mut() {
+                    Some(parent) => parent.children.push(Node::Element(element)),
+                    None => return Ok(element),
+                }
+            }
+            XmlEvent::Characters(text) | XmlEvent::Whitespace(text) => {
+                if let Some(parent) = open_elements.last_mut() {
+                    parent.children.push(Node::Text(text));
+                }
+            }
+            XmlEvent::EndDocument => {
+                return Err(XmlError {
+                    line: position.row + 1,
+                    column: position.column + 1,
+                    message: String::from("Document has no root element"),
+                })
+            }
+            _ => (),
+        }
+    }
+}
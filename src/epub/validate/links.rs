@@ -0,0 +1,221 @@
+use crate::epub::validate::css::find_css_references;
+use crate::epub::validate::dom::{parse_xml_with_entities, Element};
+use crate::epub::validate::entities::XHTML_ENTITIES;
+use crate::epub::validate::opf::Package;
+use crate::epub::validate::{is_remote_href, resolve_href, split_fragment, Issues};
+
+use std::collections::{HashMap, HashSet};
+
+const XLINK_NAMESPACE: &str = "http://www.w3.org/1999/xlink";
+
+fn collect_ids(root: &Element) -> HashSet<String> {
+    root.descendants()
+        .into_iter()
+        .filter_map(|element| element.attribute("id"))
+        .map(String::from)
+        .collect()
+}
+
+pub(crate) fn find_document_ids(bytes: &[u8]) -> Option<HashSet<String>> {
+    // For checking fragments before a build, so documents which don't parse are left to the
+    // build's own checks
+    parse_xml_with_entities(bytes, XHTML_ENTITIES)
+        .ok()
+        .map(|root| collect_ids(&root))
+}
+
+fn find_element_references(element: &Element) -> Vec<String> {
+    // Every file an element pulls in or links to, including through its inline styles
+    let mut references = Vec::new();
+    for attribute in &element.attributes {
+        match (
+            attribute.namespace.as_deref(),
+            attribute.local_name.as_ref(),
+        ) {
+            (None, "src" | "href" | "data") | (Some(XLINK_NAMESPACE), "href") => {
+                references.push(attribute.value.clone())
+            }
+            (None, "style") => references.extend(find_css_references(&attribute.value)),
+            _ => (),
+        }
+    }
+    if element.local_name == "style" {
+        references.extend(find_css_references(&element.text()));
+    }
+    references
+}
+
+pub(crate) fn find_document_references(bytes: &[u8]) -> Option<Vec<String>> {
+    // Every file an XHTML or SVG document pulls in or links to, in document order
+    let root = parse_xml_with_entities(bytes, XHTML_ENTITIES).ok()?;
+    Some(
+        root.descendants()
+            .into_iter()
+            .flat_map(find_element_references)
+            .collect(),
+    )
+}
+
+pub(crate) struct LinkTargets<'a> {
+    package: &'a Package,
+    // Ids of every content document which parsed, by path from the container root
+    ids: HashMap<&'a str, HashSet<String>>,
+}
+
+impl<'a> LinkTargets<'a> {
+    pub(crate) fn new(package: &'a Package, documents: &'a HashMap<String, Element>) -> Self {
+        LinkTargets {
+            package,
+            ids: documents
+                .iter()
+                .map(|(path, root)| (path.as_ref(), collect_ids(root)))
+                .collect(),
+        }
+    }
+
+    pub(crate) fn check_link(
+        &self,
+        source: &str,
+        line: u64,
+        column: u64,
+        href: &str,
+        issues: &mut Issues,
+    ) {
+        // Targets must be in the manifest, and fragments must name an id in the target, as long as
+        // the target is a document whose ids are known
+        let path = match resolve_href(source, href) {
+            Some(path) => path,
+            None => {
+                issues.error_at(
+                    source,
+                    line,
+                    column,
+                    format!("Link {} points outside the container.", href),
+                );
+                return;
+            }
+        };
+        if self.package.get_item_by_path(&path).is_none() {
+            let message = format!(
+                "Link {} refers to {}, which isn't in the manifest.",
+                href, path
+            );
+            issues.unlisted_reference_at(source, line, column, path, message);
+            return;
+        }
+        let fragment = match split_fragment(href).1 {
+            Some(fragment) if !fragment.is_empty() => fragment,
+            _ => return,
+        };
+        if let Some(ids) = self.ids.get(path.as_str()) {
+            if !ids.contains(fragment) {
+                issues.error_at(
+                    source,
+                    line,
+                    column,
+                    format!(
+                        "Link {} refers to id {}, which {} doesn't contain.",
+                        href, fragment, path
+                    ),
+                );
+            }
+        }
+    }
+}
+
+pub(crate) fn check_guide_links(package: &Package, targets: &LinkTargets, issues: &mut Issues) {
+    for reference in &package.guide {
+        if !is_remote_href(&reference.href) {
+            targets.check_link(
+                &package.path,
+                reference.line,
+                reference.column,
+                &reference.href,
+                issues,
+            );
+        }
+    }
+}
+
+pub(crate) fn check_document_links(
+    documents: &HashMap<String, Element>,
+    targets: &LinkTargets,
+    issues: &mut Issues,
+) {
+    // Hyperlinks may lead anywhere on the web, but resources a document pulls in, such as images,
+    // objects, stylesheets and scripts, are checked the same way as stylesheet references
+    let mut paths: Vec<&String> = documents.keys().collect();
+    paths.sort();
+    for path in paths {
+        for element in documents[path].descendants() {
+            let is_hyperlink = matches!(element.local_name.as_ref(), "a" | "area");
+            for reference in find_element_references(element) {
+                match (is_remote_href(&reference), is_hyperlink) {
+                    (false, _) => {
+                        targets.check_link(path, element.line, element.column, &reference, issues)
+                    }
+                    (true, false) => issues.warning_at(
+                        path,
+                        element.line,
+                        element.column,
+                        format!(
+                            "Element <{}> refers to remote resource {}, which reading systems may not load.",
+                            element.local_name, reference
+                        ),
+                    ),
+                    (true, true) => (),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::epub::validate::tests::{get_test_file, validate_test_epub};
+
+    fn validate_with_change(file: &str, from: &str, to: &str) -> Vec<String> {
+        let contents = get_test_file(file).replace(from, to);
+        validate_test_epub(&[(file, &contents)], &[])
+    }
+
+    #[test]
+    fn document_link_fragments_must_match_an_id() {
+        assert_eq!(
+            validate_with_change("OEBPS/text/chapter.xhtml", "#note", "#nope"),
+            vec!["error: OEBPS/text/chapter.xhtml:4:33: Link notes.xhtml#nope refers to id nope, which OEBPS/text/notes.xhtml doesn't contain."]
+        );
+    }
+
+    #[test]
+    fn guide_fragments_must_match_an_id() {
+        assert_eq!(
+            validate_with_change("OEBPS/content.opf", "chapter.xhtml#start", "chapter.xhtml#end"),
+            vec!["error: OEBPS/content.opf:19:5: Link text/chapter.xhtml#end refers to id end, which OEBPS/text/chapter.xhtml doesn't contain."]
+        );
+    }
+
+    #[test]
+    fn page_list_fragments_must_match_an_id() {
+        assert_eq!(
+            validate_with_change("OEBPS/toc.ncx", "chapter.xhtml#start", "notes.xhtml#start"),
+            vec!["error: OEBPS/toc.ncx:14:7: Link text/notes.xhtml#start refers to id start, which OEBPS/text/notes.xhtml doesn't contain."]
+        );
+    }
+
+    #[test]
+    fn navigation_must_lead_to_manifest_items() {
+        assert_eq!(
+            validate_with_change("OEBPS/toc.ncx", r#"src="text/chapter.xhtml""#, r#"src="text/preface.xhtml""#),
+            vec!["error: OEBPS/toc.ncx:8:7: Link text/preface.xhtml refers to OEBPS/text/preface.xhtml, which isn't in the manifest."]
+        );
+    }
+
+    #[test]
+    fn fragments_of_documents_which_dont_parse_are_left_alone() {
+        // The document's own parse error is the only thing worth reporting
+        let issues = validate_with_change("OEBPS/text/notes.xhtml", "</p>", "");
+        assert_eq!(issues.len(), 1);
+        assert!(issues[0].contains("File isn't well-formed XML"));
+    }
+}
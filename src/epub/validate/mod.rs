@@ -0,0 +1,603 @@
+mod css;
+mod dom;
+mod entities;
+mod links;
+mod ncx;
+mod ocf;
+mod opf;
+mod xhtml;
+
+use crate::epub::validate::dom::{parse_xml_with_entities, Element};
+use crate::epub::validate::links::LinkTargets;
+
+pub(crate) use css::find_css_references;
+pub(crate) use links::{find_document_ids, find_document_references};
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{Cursor, Read};
+use zip::read::ZipArchive;
+
+////////////////
+//   Issues   //
+////////////////
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IssueLocation {
+    pub file: String,
+    // Lines and columns count from 1, and are missing for problems with a file as a whole
+    pub line: Option<u64>,
+    pub column: Option<u64>,
+}
+
+// What an issue is about, where a build treats it differently from other issues
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum IssueKind {
+    General,
+    // A problem with an XHTML document's markup, which lenient manifest items report as a warning
+    Markup,
+    // A reference to a file which isn't in the manifest, by path from the container root
+    UnlistedReference(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ValidationIssue {
+    pub severity: Severity,
+    pub location: Option<IssueLocation>,
+    pub message: String,
+    pub(crate) kind: IssueKind,
+}
+
+impl ValidationIssue {
+    pub(crate) fn warning(message: String) -> Self {
+        // For problems found outside the validator, which aren't tied to a place in the book
+        ValidationIssue {
+            severity: Severity::Warning,
+            location: None,
+            message,
+            kind: IssueKind::General,
+        }
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+impl fmt::Display for IssueLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.line, self.column) {
+            (Some(line), Some(column)) => write!(f, "{}:{}:{}", self.file, line, column),
+            (Some(line), None) => write!(f, "{}:{}", self.file, line),
+            _ => write!(f, "{}", self.file),
+        }
+    }
+}
+
+impl fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.location {
+            Some(location) => write!(f, "{}: {}: {}", self.severity, location, self.message),
+            None => write!(f, "{}: {}", self.severity, self.message),
+        }
+    }
+}
+
+pub(crate) struct Issues {
+    issues: Vec<ValidationIssue>,
+    // Files whose markup problems are reported as warnings rather than errors
+    lenient_files: HashSet<String>,
+}
+
+impl Issues {
+    fn new(lenient_files: HashSet<String>) -> Self {
+        Issues {
+            issues: Vec::new(),
+            lenient_files,
+        }
+    }
+
+    fn push(
+        &mut self,
+        severity: Severity,
+        kind: IssueKind,
+        location: Option<IssueLocation>,
+        message: String,
+    ) {
+        let severity = match (&kind, &location) {
+            (IssueKind::Markup, Some(location)) if self.lenient_files.contains(&location.file) => {
+                Severity::Warning
+            }
+            _ => severity,
+        };
+        self.issues.push(ValidationIssue {
+            severity,
+            location,
+            message,
+            kind,
+        });
+    }
+
+    pub(crate) fn error_in(&mut self, file: &str, message: String) {
+        self.push(
+            Severity::Error,
+            IssueKind::General,
+            Some(file_location(file)),
+            message,
+        );
+    }
+
+    pub(crate) fn error_at(&mut self, file: &str, line: u64, column: u64, message: String) {
+        self.push(
+            Severity::Error,
+            IssueKind::General,
+            Some(position_location(file, line, column)),
+            message,
+        );
+    }
+
+    pub(crate) fn warning_at(&mut self, file: &str, line: u64, column: u64, message: String) {
+        self.push(
+            Severity::Warning,
+            IssueKind::General,
+            Some(position_location(file, line, column)),
+            message,
+        );
+    }
+
+    pub(crate) fn markup_error_at(&mut self, file: &str, line: u64, column: u64, message: String) {
+        self.push(
+            Severity::Error,
+            IssueKind::Markup,
+            Some(position_location(file, line, column)),
+            message,
+        );
+    }
+
+    pub(crate) fn unlisted_reference_at(
+        &mut self,
+        file: &str,
+        line: u64,
+        column: u64,
+        path: String,
+        message: String,
+    ) {
+        self.push(
+            Severity::Error,
+            IssueKind::UnlistedReference(path),
+            Some(position_location(file, line, column)),
+            message,
+        );
+    }
+}
+
+fn file_location(file: &str) -> IssueLocation {
+    IssueLocation {
+        file: String::from(file),
+        line: None,
+        column: None,
+    }
+}
+
+fn position_location(file: &str, line: u64, column: u64) -> IssueLocation {
+    IssueLocation {
+        file: String::from(file),
+        line: Some(line),
+        column: Some(column),
+    }
+}
+
+//////////////////
+//   Contents   //
+//////////////////
+
+pub(crate) struct EpubContents {
+    // Uncompressed contents by path from the container root
+    pub(crate) files: HashMap<String, Vec<u8>>,
+}
+
+impl EpubContents {
+    pub(crate) fn get(&self, name: &str) -> Option<&Vec<u8>> {
+        self.files.get(name)
+    }
+
+    pub(crate) fn contains(&self, name: &str) -> bool {
+        self.files.contains_key(name)
+    }
+}
+
+fn read_epub_contents(epub: &[u8]) -> Result<EpubContents, String> {
+    let mut archive = ZipArchive::new(Cursor::new(epub)).map_err(|e| e.to_string())?;
+    let mut files = HashMap::new();
+    for index in 0..archive.len() {
+        let mut file = archive.by_index(index).map_err(|e| e.to_string())?;
+        if file.is_dir() {
+            continue;
+        }
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)
+            .map_err(|e| format!("Can't read {}: {}", file.name(), e))?;
+        files.insert(String::from(file.name()), contents);
+    }
+    Ok(EpubContents { files })
+}
+
+pub(crate) fn parse_file(
+    contents: &EpubContents,
+    name: &str,
+    issues: &mut Issues,
+) -> Option<Element> {
+    parse_file_with_entities(contents, name, &[], IssueKind::General, issues)
+}
+
+pub(crate) fn parse_file_with_entities(
+    contents: &EpubContents,
+    name: &str,
+    entities: &[(&str, u32)],
+    kind: IssueKind,
+    issues: &mut Issues,
+) -> Option<Element> {
+    let bytes = match contents.get(name) {
+        Some(bytes) => bytes,
+        None => {
+            issues.error_in(name, String::from("File is missing from the container."));
+            return None;
+        }
+    };
+    match parse_xml_with_entities(bytes, entities) {
+        Ok(root) => Some(root),
+        Err(e) => {
+            issues.push(
+                Severity::Error,
+                kind,
+                Some(position_location(name, e.line, e.column)),
+                format!("File isn't well-formed XML: {}", e.message),
+            );
+            None
+        }
+    }
+}
+
+///////////////
+//   Paths   //
+///////////////
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let escaped = match (bytes[index], bytes.get(index + 1..index + 3)) {
+            (b'%', Some(hex)) => std::str::from_utf8(hex)
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                index += 3;
+            }
+            None => {
+                decoded.push(bytes[index]);
+                index += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+pub(crate) fn split_fragment(href: &str) -> (&str, Option<&str>) {
+    match href.split_once('#') {
+        Some((path, fragment)) => (path, Some(fragment)),
+        None => (href, None),
+    }
+}
+
+pub(crate) fn is_remote_href(href: &str) -> bool {
+    // Anything starting with a URI scheme points outside the container
+    match href.find(':') {
+        Some(index) => href[..index]
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.'),
+        None => false,
+    }
+}
+
+pub(crate) fn resolve_href(base_file: &str, href: &str) -> Option<String> {
+    // Resolves a relative href, without its fragment, to a path from the container root; hrefs
+    // with only a fragment resolve to the base file, and hrefs which escape the container to nothing
+    let (path, _) = split_fragment(href);
+    if path.is_empty() {
+        return Some(String::from(base_file));
+    }
+    let mut components: Vec<String> = match base_file.rfind('/') {
+        Some(index) => base_file[..index].split('/').map(String::from).collect(),
+        None => Vec::new(),
+    };
+    for component in percent_decode(path).split('/') {
+        match component {
+            "" | "." => (),
+            ".." => {
+                components.pop()?;
+            }
+            _ => components.push(String::from(component)),
+        }
+    }
+    Some(components.join("/"))
+}
+
+////////////////////
+//   Validation   //
+////////////////////
+
+pub fn validate_epub(epub: &[u8]) -> Vec<ValidationIssue> {
+    validate_epub_leniently(epub, HashSet::new())
+}
+
+fn validate_epub_leniently(epub: &[u8], lenient_files: HashSet<String>) -> Vec<ValidationIssue> {
+    let mut issues = Issues::new(lenient_files);
+    ocf::check_mimetype(epub, &mut issues);
+    let contents = match read_epub_contents(epub) {
+        Ok(contents) => contents,
+        Err(e) => {
+            issues.push(
+                Severity::Error,
+                IssueKind::General,
+                None,
+                format!("Can't read the file as a zip archive: {}", e),
+            );
+            return issues.issues;
+        }
+    };
+
+    if let Some(opf_path) = ocf::check_container(&contents, &mut issues) {
+        if let Some(package) = opf::check_opf(&contents, &opf_path, &mut issues) {
+            let documents = xhtml::check_xhtml_documents(&contents, &package, &mut issues);
+            let targets = LinkTargets::new(&package, &documents);
+            ncx::check_ncx(&contents, &package, &targets, &mut issues);
+            links::check_guide_links(&package, &targets, &mut issues);
+            links::check_document_links(&documents, &targets, &mut issues);
+            css::check_stylesheets(&contents, &package, &targets, &mut issues);
+        }
+    }
+    issues.issues
+}
+
+fn is_reported(issue: &ValidationIssue, reported_references: &HashSet<(String, String)>) -> bool {
+    match (&issue.kind, &issue.location) {
+        (IssueKind::UnlistedReference(path), Some(location)) => {
+            reported_references.contains(&(location.file.clone(), path.clone()))
+        }
+        _ => false,
+    }
+}
+
+pub(crate) fn check_built_epub(
+    epub: &[u8],
+    lenient_files: HashSet<String>,
+    reported_references: &HashSet<(String, String)>,
+) -> Result<Vec<ValidationIssue>, String> {
+    // Warnings don't stop the build, so they're returned for the caller to show; references to
+    // files outside the manifest which the orphan report has already warned about, by the files
+    // making them and the paths they lead to, aren't reported again
+    let (errors, warnings): (Vec<ValidationIssue>, Vec<ValidationIssue>) =
+        validate_epub_leniently(epub, lenient_files)
+            .into_iter()
+            .filter(|issue| !is_reported(issue, reported_references))
+            .partition(|issue| issue.severity == Severity::Error);
+
+    match errors.is_empty() {
+        true => Ok(warnings),
+        false => Err(format!(
+            "Built book failed validation with {} error{}:\n{}",
+            errors.len(),
+            if errors.len() == 1 { "" } else { "s" },
+            errors
+                .iter()
+                .map(|issue| format!("  {}", issue))
+                .collect::<Vec<String>>()
+                .join("\n")
+        )),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::epub::zip::add_epub_mimetype;
+
+    use std::io::Write;
+    use zip::write::{FileOptions, ZipWriter};
+
+    // A small book which validates cleanly, for tests to change one file of at a time
+    const TEST_BOOK: &[(&str, &str)] = &[
+        (
+            "META-INF/container.xml",
+            r#"<?xml version="1.0"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles>
+    <rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+  </rootfiles>
+</container>"#,
+        ),
+        (
+            "OEBPS/content.opf",
+            r#"<?xml version="1.0"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="2.0" unique-identifier="BookId">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:title>T</dc:title>
+    <dc:language>en</dc:language>
+    <dc:identifier id="BookId">urn:isbn:9780306406157</dc:identifier>
+  </metadata>
+  <manifest>
+    <item id="ncx" href="toc.ncx" media-type="application/x-dtbncx+xml"/>
+    <item id="chapter" href="text/chapter.xhtml" media-type="application/xhtml+xml"/>
+    <item id="notes" href="text/notes.xhtml" media-type="application/xhtml+xml"/>
+    <item id="style" href="style.css" media-type="text/css"/>
+  </manifest>
+  <spine toc="ncx">
+    <itemref idref="chapter"/>
+    <itemref idref="notes"/>
+  </spine>
+  <guide>
+    <reference type="text" title="Start" href="text/chapter.xhtml#start"/>
+  </guide>
+</package>"#,
+        ),
+        (
+            "OEBPS/toc.ncx",
+            r#"<?xml version="1.0"?>
+<ncx xmlns="http://www.daisy.org/z3986/2005/ncx/" version="2005-1">
+  <head><meta name="dtb:uid" content="urn:isbn:9780306406157"/></head>
+  <docTitle><text>T</text></docTitle>
+  <navMap>
+    <navPoint id="chapter" playOrder="1">
+      <navLabel><text>Chapter</text></navLabel>
+      <content src="text/chapter.xhtml"/>
+    </navPoint>
+  </navMap>
+  <pageList>
+    <pageTarget id="page-1" type="normal" value="1" playOrder="2">
+      <navLabel><text>1</text></navLabel>
+      <content src="text/chapter.xhtml#start"/>
+    </pageTarget>
+  </pageList>
+</ncx>"#,
+        ),
+        (
+            "OEBPS/text/chapter.xhtml",
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml">
+<head><title>C</title><link rel="stylesheet" type="text/css" href="../style.css"/></head>
+<body><p id="start">Text with a <a href="notes.xhtml#note">note</a>.</p></body>
+</html>"#,
+        ),
+        (
+            "OEBPS/text/notes.xhtml",
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml">
+<head><title>N</title></head>
+<body><p id="note">A note.</p></body>
+</html>"#,
+        ),
+        ("OEBPS/style.css", "p { margin: 0; }"),
+    ];
+
+    pub(crate) fn get_test_file(name: &str) -> &'static str {
+        TEST_BOOK.iter().find(|(path, _)| *path == name).unwrap().1
+    }
+
+    pub(crate) fn make_test_epub(changes: &[(&str, &str)]) -> Vec<u8> {
+        // The test book with some files replaced or added
+        let mut buffer = Vec::new();
+        let mut zip_file = ZipWriter::new(Cursor::new(&mut buffer));
+        add_epub_mimetype(&mut zip_file).unwrap();
+        let unchanged = TEST_BOOK
+            .iter()
+            .filter(|(name, _)| !changes.iter().any(|(changed, _)| changed == name));
+        for (name, contents) in unchanged.chain(changes) {
+            zip_file.start_file(*name, FileOptions::default()).unwrap();
+            zip_file.write_all(contents.as_bytes()).unwrap();
+        }
+        zip_file.finish().unwrap();
+        drop(zip_file);
+        buffer
+    }
+
+    pub(crate) fn validate_test_epub(
+        changes: &[(&str, &str)],
+        lenient_files: &[&str],
+    ) -> Vec<String> {
+        let lenient_files = lenient_files
+            .iter()
+            .map(|file| String::from(*file))
+            .collect();
+        validate_epub_leniently(&make_test_epub(changes), lenient_files)
+            .iter()
+            .map(|issue| issue.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_book_is_valid() {
+        assert_eq!(validate_test_epub(&[], &[]), Vec::<String>::new());
+    }
+
+    #[test]
+    fn hrefs_resolve_from_their_file() {
+        assert_eq!(
+            resolve_href("OEBPS/text/chapter.xhtml", "../images/a%20b.png#x").as_deref(),
+            Some("OEBPS/images/a b.png")
+        );
+        assert_eq!(
+            resolve_href("OEBPS/text/chapter.xhtml", "./notes.xhtml").as_deref(),
+            Some("OEBPS/text/notes.xhtml")
+        );
+        assert_eq!(
+            resolve_href("OEBPS/text/chapter.xhtml", "#start").as_deref(),
+            Some("OEBPS/text/chapter.xhtml")
+        );
+    }
+
+    #[test]
+    fn hrefs_escaping_the_container_resolve_to_nothing() {
+        assert_eq!(
+            resolve_href("OEBPS/text/chapter.xhtml", "../../../x.png"),
+            None
+        );
+        assert_eq!(resolve_href("content.opf", "../x.png"), None);
+        assert_eq!(
+            resolve_href("OEBPS/content.opf", "../x.png").as_deref(),
+            Some("x.png")
+        );
+    }
+
+    #[test]
+    fn remote_hrefs_have_a_scheme() {
+        assert!(is_remote_href("https://example.com/a.png"));
+        assert!(is_remote_href("mailto:someone@example.com"));
+        assert!(!is_remote_href("images/a.png"));
+        assert!(!is_remote_href("a.xhtml#x:y"));
+    }
+
+    #[test]
+    fn links_outside_the_container_are_errors() {
+        let chapter = get_test_file("OEBPS/text/chapter.xhtml")
+            .replace("notes.xhtml#note", "../../../notes.xhtml");
+        assert_eq!(
+            validate_test_epub(&[("OEBPS/text/chapter.xhtml", &chapter)], &[]),
+            vec!["error: OEBPS/text/chapter.xhtml:4:33: Link ../../../notes.xhtml points outside the container."]
+        );
+    }
+
+    #[test]
+    fn references_already_reported_are_left_out() {
+        let chapter =
+            get_test_file("OEBPS/text/chapter.xhtml").replace("notes.xhtml#note", "extra.xhtml");
+        let epub = make_test_epub(&[("OEBPS/text/chapter.xhtml", &chapter)]);
+        let reported = HashSet::from([(
+            String::from("OEBPS/text/chapter.xhtml"),
+            String::from("OEBPS/text/extra.xhtml"),
+        )]);
+        assert_eq!(
+            check_built_epub(&epub, HashSet::new(), &reported),
+            Ok(Vec::new())
+        );
+        assert_eq!(
+            check_built_epub(&epub, HashSet::new(), &HashSet::new()),
+            Err(String::from("Built book failed validation with 1 error:\n  error: OEBPS/text/chapter.xhtml:4:33: Link extra.xhtml refers to OEBPS/text/extra.xhtml, which isn't in the manifest."))
+        );
+    }
+}
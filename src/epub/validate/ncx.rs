@@ -0,0 +1,217 @@
+use crate::epub::validate::dom::Element;
+use crate::epub::validate::links::LinkTargets;
+use crate::epub::validate::opf::Package;
+use crate::epub::validate::{is_remote_href, parse_file, EpubContents, Issues};
+
+use std::collections::HashSet;
+
+const NCX_NAMESPACE: &str = "http://www.daisy.org/z3986/2005/ncx/";
+
+fn check_label(element: &Element, ncx_path: &str, issues: &mut Issues) {
+    let has_label = element
+        .children_named(NCX_NAMESPACE, "navLabel")
+        .any(|label| label.child_named(NCX_NAMESPACE, "text").is_some());
+    if !has_label {
+        issues.error_at(
+            ncx_path,
+            element.line,
+            element.column,
+            format!("{} has no navLabel with text.", element.local_name),
+        );
+    }
+}
+
+fn check_content(element: &Element, targets: &LinkTargets, ncx_path: &str, issues: &mut Issues) {
+    let content = match element.child_named(NCX_NAMESPACE, "content") {
+        Some(content) => content,
+        None => {
+            issues.error_at(
+                ncx_path,
+                element.line,
+                element.column,
+                format!("{} has no content element.", element.local_name),
+            );
+            return;
+        }
+    };
+    let src = match content.attribute("src") {
+        Some(src) => src,
+        None => {
+            issues.error_at(
+                ncx_path,
+                content.line,
+                content.column,
+                String::from("Content element has no src attribute."),
+            );
+            return;
+        }
+    };
+    if is_remote_href(src) {
+        issues.error_at(
+            ncx_path,
+            content.line,
+            content.column,
+            format!("Content src {} must point inside the container.", src),
+        );
+        return;
+    }
+    targets.check_link(ncx_path, content.line, content.column, src, issues);
+}
+
+fn check_nav_element(
+    element: &Element,
+    targets: &LinkTargets,
+    ncx_path: &str,
+    ids: &mut HashSet<String>,
+    issues: &mut Issues,
+) {
+    // Shared by navPoint, pageTarget and navTarget, which all need an id, a playOrder, a label and
+    // a content target
+    match element.attribute("id") {
+        Some(id) => {
+            if !ids.insert(String::from(id)) {
+                issues.error_at(
+                    ncx_path,
+                    element.line,
+                    element.column,
+                    format!("NCX id {} is used more than once.", id),
+                );
+            }
+        }
+        None => issues.error_at(
+            ncx_path,
+            element.line,
+            element.column,
+            format!("{} has no id attribute.", element.local_name),
+        ),
+    }
+    match element.attribute("playOrder") {
+        Some(play_order) if play_order.parse::<u32>().is_ok_and(|order| order > 0) => (),
+        Some(play_order) => issues.error_at(
+            ncx_path,
+            element.line,
+            element.column,
+            format!("playOrder must be a positive integer, not {}.", play_order),
+        ),
+        None => issues.error_at(
+            ncx_path,
+            element.line,
+            element.column,
+            format!("{} has no playOrder attribute.", element.local_name),
+        ),
+    }
+    check_label(element, ncx_path, issues);
+    check_content(element, targets, ncx_path, issues);
+}
+
+fn check_navpoints(
+    parent: &Element,
+    targets: &LinkTargets,
+    ncx_path: &str,
+    ids: &mut HashSet<String>,
+    issues: &mut Issues,
+) {
+    for navpoint in parent.children_named(NCX_NAMESPACE, "navPoint") {
+        check_nav_element(navpoint, targets, ncx_path, ids, issues);
+        check_navpoints(navpoint, targets, ncx_path, ids, issues);
+    }
+}
+
+pub(crate) fn check_ncx(
+    contents: &EpubContents,
+    package: &Package,
+    targets: &LinkTargets,
+    issues: &mut Issues,
+) {
+    let ncx_item = match package.toc.as_ref().and_then(|toc| package.get_item(toc)) {
+        Some(item) => item,
+        None => return,
+    };
+    let ncx_path = match &ncx_item.path {
+        Some(path) if contents.contains(path) => path,
+        _ => return,
+    };
+    let root = match parse_file(contents, ncx_path, issues) {
+        Some(root) => root,
+        None => return,
+    };
+    if !root.is(NCX_NAMESPACE, "ncx") {
+        issues.error_at(
+            ncx_path,
+            root.line,
+            root.column,
+            format!("Root element must be ncx in namespace {}.", NCX_NAMESPACE),
+        );
+        return;
+    }
+
+    let uid = root
+        .child_named(NCX_NAMESPACE, "head")
+        .and_then(|head| {
+            head.children_named(NCX_NAMESPACE, "meta")
+                .find(|meta| meta.attribute("name") == Some("dtb:uid"))
+        })
+        .and_then(|meta| meta.attribute("content"));
+    match (uid, &package.unique_identifier) {
+        (None, _) => issues.error_at(
+            ncx_path,
+            root.line,
+            root.column,
+            String::from("NCX head has no dtb:uid meta."),
+        ),
+        (Some(uid), Some(identifier)) if uid.trim() != identifier => issues.error_at(
+            ncx_path,
+            root.line,
+            root.column,
+            format!(
+                "NCX dtb:uid {} doesn't match the package's unique identifier {}.",
+                uid, identifier
+            ),
+        ),
+        _ => (),
+    }
+
+    let has_doctitle = root
+        .child_named(NCX_NAMESPACE, "docTitle")
+        .is_some_and(|doctitle| doctitle.child_named(NCX_NAMESPACE, "text").is_some());
+    if !has_doctitle {
+        issues.error_at(
+            ncx_path,
+            root.line,
+            root.column,
+            String::from("NCX has no docTitle with text."),
+        );
+    }
+
+    let mut ids = HashSet::new();
+    match root.child_named(NCX_NAMESPACE, "navMap") {
+        Some(navmap) => {
+            if navmap.child_named(NCX_NAMESPACE, "navPoint").is_none() {
+                issues.error_at(
+                    ncx_path,
+                    navmap.line,
+                    navmap.column,
+                    String::from("navMap has no navPoints."),
+                );
+            }
+            check_navpoints(navmap, targets, ncx_path, &mut ids, issues);
+        }
+        None => issues.error_at(
+            ncx_path,
+            root.line,
+            root.column,
+            String::from("NCX has no navMap."),
+        ),
+    }
+    if let Some(pagelist) = root.child_named(NCX_NAMESPACE, "pageList") {
+        for pagetarget in pagelist.children_named(NCX_NAMESPACE, "pageTarget") {
+            check_nav_element(pagetarget, targets, ncx_path, &mut ids, issues);
+        }
+    }
+    for navlist in root.children_named(NCX_NAMESPACE, "navList") {
+        check_label(navlist, ncx_path, issues);
+        for navtarget in navlist.children_named(NCX_NAMESPACE, "navTarget") {
+            check_nav_element(navtarget, targets, ncx_path, &mut ids, issues);
+        }
+    }
+}
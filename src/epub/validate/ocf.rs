@@ -0,0 +1,242 @@
+use crate::epub::validate::{is_remote_href, parse_file, EpubContents, Issues};
+
+const CONTAINER_PATH: &str = "META-INF/container.xml";
+const CONTAINER_NAMESPACE: &str = "urn:oasis:names:tc:opendocument:xmlns:container";
+const OPF_MEDIA_TYPE: &str = "application/oebps-package+xml";
+const EPUB_MIMETYPE: &[u8] = b"application/epub+zip";
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<usize> {
+    let field = bytes.get(offset..offset + 2)?;
+    Some(u16::from_le_bytes([field[0], field[1]]) as usize)
+}
+
+pub(crate) fn check_mimetype(epub: &[u8], issues: &mut Issues) {
+    // Reading systems sniff the first local file header directly, so it's checked byte by byte
+    // rather than through the archive's central directory
+    if epub.get(0..4) != Some(b"PK\x03\x04") {
+        issues.error_in(
+            "mimetype",
+            String::from("The container doesn't start with a zip local file header."),
+        );
+        return;
+    }
+    let (compression, compressed_size, name_length, extra_length) = match (
+        read_u16(epub, 8),
+        epub.get(18..22),
+        read_u16(epub, 26),
+        read_u16(epub, 28),
+    ) {
+        (Some(compression), Some(size), Some(name_length), Some(extra_length)) => (
+            compression,
+            u32::from_le_bytes([size[0], size[1], size[2], size[3]]) as usize,
+            name_length,
+            extra_length,
+        ),
+        _ => {
+            issues.error_in(
+                "mimetype",
+                String::from("The container's first local file header is truncated."),
+            );
+            return;
+        }
+    };
+    let name = epub.get(30..30 + name_length);
+    if name != Some(b"mimetype") {
+        issues.error_in(
+            "mimetype",
+            format!(
+                "The first file in the container must be mimetype, not {}.",
+                String::from_utf8_lossy(name.unwrap_or_default())
+            ),
+        );
+        return;
+    }
+    if compression != 0 {
+        issues.error_in(
+            "mimetype",
+            String::from("The mimetype file must be stored without compression."),
+        );
+    }
+    if extra_length != 0 {
+        issues.error_in(
+            "mimetype",
+            format!(
+                "The mimetype file's local header must have no extra field, but has {} bytes of one.",
+                extra_length
+            ),
+        );
+    }
+    let data_start = 30 + name_length + extra_length;
+    // Sizes of zero are allowed, since they're moved to a data descriptor when written as a stream
+    let contents_match = epub.get(data_start..data_start + EPUB_MIMETYPE.len())
+        == Some(EPUB_MIMETYPE)
+        && (compressed_size == 0 || compressed_size == EPUB_MIMETYPE.len());
+    if compression == 0 && !contents_match {
+        issues.error_in(
+            "mimetype",
+            String::from("The mimetype file must contain exactly 'application/epub+zip'."),
+        );
+    }
+}
+
+pub(crate) fn check_container(contents: &EpubContents, issues: &mut Issues) -> Option<String> {
+    // Returns the path of the OPF which the rest of validation follows
+    let root = parse_file(contents, CONTAINER_PATH, issues)?;
+    if !root.is(CONTAINER_NAMESPACE, "container") {
+        issues.error_at(
+            CONTAINER_PATH,
+            root.line,
+            root.column,
+            format!(
+                "Root element must be container in namespace {}.",
+                CONTAINER_NAMESPACE
+            ),
+        );
+        return None;
+    }
+
+    let rootfiles: Vec<_> = match root.child_named(CONTAINER_NAMESPACE, "rootfiles") {
+        Some(rootfiles) => rootfiles
+            .children_named(CONTAINER_NAMESPACE, "rootfile")
+            .collect(),
+        None => Vec::new(),
+    };
+    let mut opf_path = None;
+    for rootfile in &rootfiles {
+        let full_path = match rootfile.attribute("full-path") {
+            Some(full_path) => full_path,
+            None => {
+                issues.error_at(
+                    CONTAINER_PATH,
+                    rootfile.line,
+                    rootfile.column,
+                    String::from("Rootfile has no full-path attribute."),
+                );
+                continue;
+            }
+        };
+        if full_path.starts_with('/') || is_remote_href(full_path) {
+            issues.error_at(
+                CONTAINER_PATH,
+                rootfile.line,
+                rootfile.column,
+                format!(
+                    "Rootfile path {} must be relative to the container root.",
+                    full_path
+                ),
+            );
+            continue;
+        }
+        if !contents.contains(full_path) {
+            issues.error_at(
+                CONTAINER_PATH,
+                rootfile.line,
+                rootfile.column,
+                format!("Rootfile {} is missing from the container.", full_path),
+            );
+            continue;
+        }
+        if rootfile.attribute("media-type") == Some(OPF_MEDIA_TYPE) && opf_path.is_none() {
+            opf_path = Some(String::from(full_path));
+        }
+    }
+
+    if opf_path.is_none() {
+        issues.error_at(
+            CONTAINER_PATH,
+            root.line,
+            root.column,
+            format!(
+                "No rootfile with media type {} found in the container.",
+                OPF_MEDIA_TYPE
+            ),
+        );
+    }
+    opf_path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epub::validate::tests::make_test_epub;
+
+    use std::collections::HashSet;
+
+    fn local_header(name: &str, compression: u16, extra: &[u8], data: &[u8]) -> Vec<u8> {
+        // The start of a zip file, as far as the first entry's data
+        let mut header = b"PK\x03\x04\x0a\x00\x00\x00".to_vec();
+        header.extend(compression.to_le_bytes());
+        header.extend([0; 8]);
+        header.extend((data.len() as u32).to_le_bytes());
+        header.extend((data.len() as u32).to_le_bytes());
+        header.extend((name.len() as u16).to_le_bytes());
+        header.extend((extra.len() as u16).to_le_bytes());
+        header.extend(name.as_bytes());
+        header.extend(extra);
+        header.extend(data);
+        header
+    }
+
+    fn find_mimetype_messages(epub: &[u8]) -> Vec<String> {
+        let mut issues = Issues::new(HashSet::new());
+        check_mimetype(epub, &mut issues);
+        issues
+            .issues
+            .into_iter()
+            .map(|issue| issue.message)
+            .collect()
+    }
+
+    #[test]
+    fn stored_mimetype_first_is_fine() {
+        assert!(find_mimetype_messages(&make_test_epub(&[])).is_empty());
+        assert!(
+            find_mimetype_messages(&local_header("mimetype", 0, &[], EPUB_MIMETYPE)).is_empty()
+        );
+    }
+
+    #[test]
+    fn compressed_mimetype_is_reported() {
+        assert_eq!(
+            find_mimetype_messages(&local_header("mimetype", 8, &[], b"K,(\xc8")),
+            vec!["The mimetype file must be stored without compression."]
+        );
+    }
+
+    #[test]
+    fn extra_field_is_reported() {
+        assert_eq!(
+            find_mimetype_messages(&local_header("mimetype", 0, &[0; 4], EPUB_MIMETYPE)),
+            vec!["The mimetype file's local header must have no extra field, but has 4 bytes of one."]
+        );
+    }
+
+    #[test]
+    fn wrong_contents_are_reported() {
+        assert_eq!(
+            find_mimetype_messages(&local_header("mimetype", 0, &[], b"application/zip")),
+            vec!["The mimetype file must contain exactly 'application/epub+zip'."]
+        );
+    }
+
+    #[test]
+    fn wrong_first_entry_is_reported() {
+        assert_eq!(
+            find_mimetype_messages(&local_header(CONTAINER_PATH, 0, &[], b"<container/>")),
+            vec!["The first file in the container must be mimetype, not META-INF/container.xml."]
+        );
+    }
+
+    #[test]
+    fn truncated_header_is_reported() {
+        let header = local_header("mimetype", 0, &[], EPUB_MIMETYPE);
+        assert_eq!(
+            find_mimetype_messages(&header[..20]),
+            vec!["The container's first local file header is truncated."]
+        );
+        assert_eq!(
+            find_mimetype_messages(b"%PDF-1.4"),
+            vec!["The container doesn't start with a zip local file header."]
+        );
+    }
+}
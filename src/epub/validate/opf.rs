@@ -0,0 +1,433 @@
+use crate::epub::fallbacks::{FallbackGraph, FallbackItem};
+use crate::epub::validate::dom::Element;
+use crate::epub::validate::{
+    is_remote_href, parse_file, resolve_href, split_fragment, EpubContents, Issues,
+};
+
+use std::collections::HashMap;
+
+const OPF_NAMESPACE: &str = "http://www.idpf.org/2007/opf";
+const DC_NAMESPACE: &str = "http://purl.org/dc/elements/1.1/";
+const NCX_MEDIA_TYPE: &str = "application/x-dtbncx+xml";
+
+pub(crate) struct ManifestItem {
+    pub(crate) id: String,
+    // Path from the container root, or None for remote or unresolvable hrefs
+    pub(crate) path: Option<String>,
+    pub(crate) media_type: String,
+    pub(crate) fallback: Option<String>,
+    pub(crate) fallback_style: Option<String>,
+    pub(crate) required_namespace: Option<String>,
+    pub(crate) line: u64,
+    pub(crate) column: u64,
+}
+
+pub(crate) struct GuideReference {
+    pub(crate) href: String,
+    pub(crate) line: u64,
+    pub(crate) column: u64,
+}
+
+pub(crate) struct Package {
+    pub(crate) path: String,
+    pub(crate) unique_identifier: Option<String>,
+    pub(crate) manifest: Vec<ManifestItem>,
+    pub(crate) toc: Option<String>,
+    pub(crate) guide: Vec<GuideReference>,
+}
+
+impl Package {
+    pub(crate) fn get_item(&self, id: &str) -> Option<&ManifestItem> {
+        self.manifest.iter().find(|item| item.id == id)
+    }
+
+    pub(crate) fn get_item_by_path(&self, path: &str) -> Option<&ManifestItem> {
+        self.manifest
+            .iter()
+            .find(|item| item.path.as_deref() == Some(path))
+    }
+}
+
+//////////////////
+//   Metadata   //
+//////////////////
+
+fn check_metadata(root: &Element, opf_path: &str, issues: &mut Issues) -> Option<String> {
+    // Returns the value of the identifier which unique-identifier points at
+    let metadata = match root.child_named(OPF_NAMESPACE, "metadata") {
+        Some(metadata) => metadata,
+        None => {
+            issues.error_at(
+                opf_path,
+                root.line,
+                root.column,
+                String::from("Package has no metadata element."),
+            );
+            return None;
+        }
+    };
+    // Metadata may be wrapped in dc-metadata, as in OEBPS 1.2
+    let dc_elements: Vec<&Element> = metadata
+        .descendants()
+        .into_iter()
+        .filter(|element| element.namespace.as_deref() == Some(DC_NAMESPACE))
+        .collect();
+
+    for required in ["title", "identifier", "language"] {
+        let present = dc_elements
+            .iter()
+            .any(|element| element.local_name == required && !element.text().trim().is_empty());
+        if !present {
+            issues.error_at(
+                opf_path,
+                metadata.line,
+                metadata.column,
+                format!("Metadata has no non-empty dc:{} element.", required),
+            );
+        }
+    }
+
+    let unique_identifier = match root.attribute("unique-identifier") {
+        Some(unique_identifier) => unique_identifier,
+        None => {
+            issues.error_at(
+                opf_path,
+                root.line,
+                root.column,
+                String::from("Package has no unique-identifier attribute."),
+            );
+            return None;
+        }
+    };
+    match dc_elements.iter().find(|element| {
+        element.local_name == "identifier" && element.attribute("id") == Some(unique_identifier)
+    }) {
+        Some(identifier) => Some(identifier.text().trim().to_string()),
+        None => {
+            issues.error_at(
+                opf_path,
+                root.line,
+                root.column,
+                format!(
+                    "Package's unique-identifier {} doesn't match the id of any dc:identifier.",
+                    unique_identifier
+                ),
+            );
+            None
+        }
+    }
+}
+
+//////////////////
+//   Manifest   //
+//////////////////
+
+fn read_manifest(
+    root: &Element,
+    contents: &EpubContents,
+    opf_path: &str,
+    issues: &mut Issues,
+) -> Vec<ManifestItem> {
+    let manifest = match root.child_named(OPF_NAMESPACE, "manifest") {
+        Some(manifest) => manifest,
+        None => {
+            issues.error_at(
+                opf_path,
+                root.line,
+                root.column,
+                String::from("Package has no manifest element."),
+            );
+            return Vec::new();
+        }
+    };
+
+    let mut items = Vec::new();
+    let mut items_by_path: HashMap<String, String> = HashMap::new();
+    for element in manifest.children_named(OPF_NAMESPACE, "item") {
+        let (id, href, media_type) = match (
+            element.attribute("id"),
+            element.attribute("href"),
+            element.attribute("media-type"),
+        ) {
+            (Some(id), Some(href), Some(media_type)) => (id, href, media_type),
+            _ => {
+                issues.error_at(
+                    opf_path,
+                    element.line,
+                    element.column,
+                    String::from("Manifest item must have id, href and media-type attributes."),
+                );
+                continue;
+            }
+        };
+        if items.iter().any(|item: &ManifestItem| item.id == id) {
+            issues.error_at(
+                opf_path,
+                element.line,
+                element.column,
+                format!("Manifest id {} is used more than once.", id),
+            );
+        }
+
+        let path = match is_remote_href(href) {
+            true => None,
+            false => resolve_href(opf_path, href),
+        };
+        match &path {
+            Some(path) => {
+                if !contents.contains(path) {
+                    issues.error_at(
+                        opf_path,
+                        element.line,
+                        element.column,
+                        format!(
+                            "Manifest item {} refers to {}, which is missing from the container.",
+                            id, path
+                        ),
+                    );
+                } else if let Some(other_id) = items_by_path.get(path) {
+                    issues.error_at(
+                        opf_path,
+                        element.line,
+                        element.column,
+                        format!(
+                            "Manifest items {} and {} both refer to {}.",
+                            other_id, id, path
+                        ),
+                    );
+                } else {
+                    items_by_path.insert(path.clone(), String::from(id));
+                }
+                if split_fragment(href).1.is_some() {
+                    issues.error_at(
+                        opf_path,
+                        element.line,
+                        element.column,
+                        format!("Manifest item {} href must not have a fragment.", id),
+                    );
+                }
+            }
+            None if is_remote_href(href) => issues.warning_at(
+                opf_path,
+                element.line,
+                element.column,
+                format!(
+                    "Manifest item {} refers to remote resource {}, which reading systems may not load.",
+                    id, href
+                ),
+            ),
+            None => issues.error_at(
+                opf_path,
+                element.line,
+                element.column,
+                format!(
+                    "Manifest item {} href {} points outside the container.",
+                    id, href
+                ),
+            ),
+        }
+
+        items.push(ManifestItem {
+            id: String::from(id),
+            path,
+            media_type: String::from(media_type),
+            fallback: element.attribute("fallback").map(String::from),
+            fallback_style: element.attribute("fallback-style").map(String::from),
+            required_namespace: element.attribute("required-namespace").map(String::from),
+            line: element.line,
+            column: element.column,
+        });
+    }
+
+    check_fallbacks(&items, opf_path, issues);
+    items
+}
+
+fn check_fallbacks(items: &[ManifestItem], opf_path: &str, issues: &mut Issues) {
+    let fallback_items: Vec<FallbackItem> = items
+        .iter()
+        .map(|item| FallbackItem {
+            id: &item.id,
+            media_type: &item.media_type,
+            fallback: item.fallback.as_deref(),
+            fallback_style: item.fallback_style.as_deref(),
+        })
+        .collect();
+    for problem in FallbackGraph::new(&fallback_items).find_problems() {
+        let item = &items[problem.index];
+        issues.error_at(opf_path, item.line, item.column, problem.message);
+    }
+}
+
+///////////////
+//   Spine   //
+///////////////
+
+fn read_spine(
+    root: &Element,
+    manifest: &[ManifestItem],
+    opf_path: &str,
+    issues: &mut Issues,
+) -> Option<String> {
+    let spine = match root.child_named(OPF_NAMESPACE, "spine") {
+        Some(spine) => spine,
+        None => {
+            issues.error_at(
+                opf_path,
+                root.line,
+                root.column,
+                String::from("Package has no spine element."),
+            );
+            return None;
+        }
+    };
+
+    let toc = spine.attribute("toc").map(String::from);
+    match &toc {
+        None => issues.error_at(
+            opf_path,
+            spine.line,
+            spine.column,
+            String::from("Spine has no toc attribute pointing at the NCX."),
+        ),
+        Some(toc) => match manifest.iter().find(|item| &item.id == toc) {
+            None => issues.error_at(
+                opf_path,
+                spine.line,
+                spine.column,
+                format!("Spine toc {} isn't in the manifest.", toc),
+            ),
+            Some(item) if item.media_type != NCX_MEDIA_TYPE => issues.error_at(
+                opf_path,
+                spine.line,
+                spine.column,
+                format!(
+                    "Spine toc {} has media type {} rather than {}.",
+                    toc, item.media_type, NCX_MEDIA_TYPE
+                ),
+            ),
+            Some(_) => (),
+        },
+    }
+
+    let mut itemref_count = 0;
+    for element in spine.children_named(OPF_NAMESPACE, "itemref") {
+        let idref = match element.attribute("idref") {
+            Some(idref) => idref,
+            None => {
+                issues.error_at(
+                    opf_path,
+                    element.line,
+                    element.column,
+                    String::from("Spine itemref has no idref attribute."),
+                );
+                continue;
+            }
+        };
+        if !manifest.iter().any(|item| item.id == idref) {
+            issues.error_at(
+                opf_path,
+                element.line,
+                element.column,
+                format!("Spine itemref {} isn't in the manifest.", idref),
+            );
+        }
+        if let Some(linear) = element.attribute("linear") {
+            if linear != "yes" && linear != "no" {
+                issues.error_at(
+                    opf_path,
+                    element.line,
+                    element.column,
+                    format!("Spine itemref linear must be yes or no, not {}.", linear),
+                );
+            }
+        }
+        itemref_count += 1;
+    }
+    if itemref_count == 0 {
+        issues.error_at(
+            opf_path,
+            spine.line,
+            spine.column,
+            String::from("Spine has no itemrefs."),
+        );
+    }
+    toc
+}
+
+///////////////
+//   Guide   //
+///////////////
+
+fn read_guide(root: &Element, opf_path: &str, issues: &mut Issues) -> Vec<GuideReference> {
+    // Hrefs are checked along with the content documents' links, once their ids are known
+    let guide = match root.child_named(OPF_NAMESPACE, "guide") {
+        Some(guide) => guide,
+        None => return Vec::new(),
+    };
+    let mut references = Vec::new();
+    for reference in guide.children_named(OPF_NAMESPACE, "reference") {
+        match (reference.attribute("type"), reference.attribute("href")) {
+            (Some(_), Some(href)) => references.push(GuideReference {
+                href: String::from(href),
+                line: reference.line,
+                column: reference.column,
+            }),
+            _ => issues.error_at(
+                opf_path,
+                reference.line,
+                reference.column,
+                String::from("Guide reference must have type and href attributes."),
+            ),
+        }
+    }
+    references
+}
+
+/////////////////
+//   Package   //
+/////////////////
+
+pub(crate) fn check_opf(
+    contents: &EpubContents,
+    opf_path: &str,
+    issues: &mut Issues,
+) -> Option<Package> {
+    let root = parse_file(contents, opf_path, issues)?;
+    if !root.is(OPF_NAMESPACE, "package") {
+        issues.error_at(
+            opf_path,
+            root.line,
+            root.column,
+            format!(
+                "Root element must be package in namespace {}.",
+                OPF_NAMESPACE
+            ),
+        );
+        return None;
+    }
+    if root.attribute("version") != Some("2.0") {
+        issues.warning_at(
+            opf_path,
+            root.line,
+            root.column,
+            format!(
+                "Package version is {} rather than 2.0, so it's only checked against EPUB 2 rules.",
+                root.attribute("version").unwrap_or("missing")
+            ),
+        );
+    }
+
+    let unique_identifier = check_metadata(&root, opf_path, issues);
+    let manifest = read_manifest(&root, contents, opf_path, issues);
+    let toc = read_spine(&root, &manifest, opf_path, issues);
+    let guide = read_guide(&root, opf_path, issues);
+
+    Some(Package {
+        path: String::from(opf_path),
+        unique_identifier,
+        manifest,
+        toc,
+        guide,
+    })
+}
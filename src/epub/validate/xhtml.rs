@@ -0,0 +1,443 @@
+use crate::epub::validate::dom::Element;
+use crate::epub::validate::entities::XHTML_ENTITIES;
+use crate::epub::validate::opf::{ManifestItem, Package};
+use crate::epub::validate::{parse_file_with_entities, EpubContents, IssueKind, Issues};
+
+use std::collections::{HashMap, HashSet};
+
+pub(crate) const XHTML_NAMESPACE: &str = "http://www.w3.org/1999/xhtml";
+const SVG_NAMESPACE: &str = "http://www.w3.org/2000/svg";
+const OPS_NAMESPACE: &str = "http://www.idpf.org/2007/ops";
+const XML_NAMESPACE: &str = "http://www.w3.org/XML/1998/namespace";
+
+////////////////////
+//   Vocabulary   //
+////////////////////
+
+// The XHTML 1.1 modules which OPS 2.0.1 adopts as its preferred vocabulary, leaving out forms,
+// frames, intrinsic events and the legacy presentational elements
+
+const COMMON_ATTRIBUTES: &[&str] = &["id", "class", "title", "style", "dir"];
+
+// Element name, whether it takes the common attributes, and the attributes particular to it
+const ELEMENTS: &[(&str, bool, &[&str])] = &[
+    // Structure
+    ("html", false, &["id", "dir", "version"]),
+    ("head", false, &["id", "dir", "profile"]),
+    ("title", false, &["id", "dir"]),
+    ("body", true, &[]),
+    // Text
+    ("abbr", true, &[]),
+    ("acronym", true, &[]),
+    ("address", true, &[]),
+    ("blockquote", true, &["cite"]),
+    ("br", true, &[]),
+    ("cite", true, &[]),
+    ("code", true, &[]),
+    ("dfn", true, &[]),
+    ("div", true, &[]),
+    ("em", true, &[]),
+    ("h1", true, &[]),
+    ("h2", true, &[]),
+    ("h3", true, &[]),
+    ("h4", true, &[]),
+    ("h5", true, &[]),
+    ("h6", true, &[]),
+    ("kbd", true, &[]),
+    ("p", true, &[]),
+    ("pre", true, &[]),
+    ("q", true, &["cite"]),
+    ("samp", true, &[]),
+    ("span", true, &[]),
+    ("strong", true, &[]),
+    ("var", true, &[]),
+    // Hypertext
+    (
+        "a",
+        true,
+        &[
+            "accesskey",
+            "charset",
+            "href",
+            "hreflang",
+            "rel",
+            "rev",
+            "tabindex",
+            "type",
+            "shape",
+            "coords",
+        ],
+    ),
+    // List
+    ("dl", true, &[]),
+    ("dt", true, &[]),
+    ("dd", true, &[]),
+    ("ol", true, &[]),
+    ("ul", true, &[]),
+    ("li", true, &[]),
+    // Object
+    (
+        "object",
+        true,
+        &[
+            "archive", "classid", "codebase", "codetype", "data", "declare", "height", "name",
+            "standby", "tabindex", "type", "usemap", "width",
+        ],
+    ),
+    (
+        "param",
+        false,
+        &["id", "name", "value", "valuetype", "type"],
+    ),
+    // Presentation
+    ("b", true, &[]),
+    ("big", true, &[]),
+    ("hr", true, &[]),
+    ("i", true, &[]),
+    ("small", true, &[]),
+    ("sub", true, &[]),
+    ("sup", true, &[]),
+    ("tt", true, &[]),
+    // Edit
+    ("del", true, &["cite", "datetime"]),
+    ("ins", true, &["cite", "datetime"]),
+    // Bidirectional text
+    ("bdo", true, &[]),
+    // Table
+    ("caption", true, &[]),
+    (
+        "col",
+        true,
+        &["align", "char", "charoff", "span", "valign", "width"],
+    ),
+    (
+        "colgroup",
+        true,
+        &["align", "char", "charoff", "span", "valign", "width"],
+    ),
+    (
+        "table",
+        true,
+        &[
+            "border",
+            "cellpadding",
+            "cellspacing",
+            "frame",
+            "rules",
+            "summary",
+            "width",
+        ],
+    ),
+    ("tbody", true, &["align", "char", "charoff", "valign"]),
+    ("thead", true, &["align", "char", "charoff", "valign"]),
+    ("tfoot", true, &["align", "char", "charoff", "valign"]),
+    ("tr", true, &["align", "char", "charoff", "valign"]),
+    (
+        "td",
+        true,
+        &[
+            "abbr", "align", "axis", "char", "charoff", "colspan", "headers", "rowspan", "scope",
+            "valign",
+        ],
+    ),
+    (
+        "th",
+        true,
+        &[
+            "abbr", "align", "axis", "char", "charoff", "colspan", "headers", "rowspan", "scope",
+            "valign",
+        ],
+    ),
+    // Image and client-side image map
+    (
+        "img",
+        true,
+        &[
+            "alt", "height", "ismap", "longdesc", "src", "usemap", "width",
+        ],
+    ),
+    ("map", true, &[]),
+    (
+        "area",
+        true,
+        &[
+            "accesskey",
+            "alt",
+            "coords",
+            "href",
+            "nohref",
+            "shape",
+            "tabindex",
+        ],
+    ),
+    // Meta-information, style sheet, link and base
+    (
+        "meta",
+        false,
+        &["id", "dir", "content", "http-equiv", "name", "scheme"],
+    ),
+    ("style", false, &["id", "dir", "media", "title", "type"]),
+    (
+        "link",
+        true,
+        &["charset", "href", "hreflang", "media", "rel", "rev", "type"],
+    ),
+    ("base", false, &["id", "href"]),
+    // Scripting, which reading systems are free to ignore
+    ("script", false, &["id", "charset", "defer", "src", "type"]),
+    ("noscript", true, &[]),
+];
+
+// Elements which may set xml:space, as their content's whitespace is significant
+const XML_SPACE_ELEMENTS: &[&str] = &["pre", "script", "style"];
+
+//////////////////
+//   Checking   //
+//////////////////
+
+struct DocumentCheck<'a> {
+    path: &'a str,
+    item: &'a ManifestItem,
+}
+
+impl DocumentCheck<'_> {
+    fn check_attributes(&self, element: &Element, rule: (bool, &[&str]), issues: &mut Issues) {
+        let (takes_common, particular) = rule;
+        for attribute in &element.attributes {
+            let allowed = match attribute.namespace.as_deref() {
+                None => {
+                    (takes_common && COMMON_ATTRIBUTES.contains(&attribute.local_name.as_ref()))
+                        || particular.contains(&attribute.local_name.as_ref())
+                }
+                Some(XML_NAMESPACE) => match attribute.local_name.as_ref() {
+                    "lang" => true,
+                    "space" => XML_SPACE_ELEMENTS.contains(&element.local_name.as_ref()),
+                    _ => false,
+                },
+                Some(_) => false,
+            };
+            if allowed {
+                continue;
+            }
+            let message = match attribute.namespace.as_deref() {
+                None if attribute.local_name.starts_with("on") => format!(
+                    "Event handler attribute {} on <{}> isn't allowed in OPS 2.0.1.",
+                    attribute.local_name, element.local_name
+                ),
+                None => format!(
+                    "Attribute {} isn't allowed on <{}> in OPS 2.0.1.",
+                    attribute.local_name, element.local_name
+                ),
+                Some(namespace) => format!(
+                    "Attribute {} in namespace {} isn't allowed on <{}> in OPS 2.0.1.",
+                    attribute.local_name, namespace, element.local_name
+                ),
+            };
+            issues.markup_error_at(self.path, element.line, element.column, message);
+        }
+    }
+
+    fn check_switch(&self, switch: &Element, issues: &mut Issues) {
+        // Cases hold XML islands for reading systems which support them, so only the default
+        // content has to be OPS
+        for child in switch.elements() {
+            match (child.namespace.as_deref(), child.local_name.as_ref()) {
+                (Some(OPS_NAMESPACE), "case") => (),
+                (Some(OPS_NAMESPACE), "default") => {
+                    for grandchild in child.elements() {
+                        self.check_element(grandchild, issues);
+                    }
+                }
+                _ => issues.markup_error_at(
+                    self.path,
+                    child.line,
+                    child.column,
+                    format!(
+                        "<{}> isn't allowed in ops:switch, which may only contain ops:case and ops:default.",
+                        child.local_name
+                    ),
+                ),
+            }
+        }
+    }
+
+    fn check_element(&self, element: &Element, issues: &mut Issues) {
+        match element.namespace.as_deref() {
+            Some(XHTML_NAMESPACE) => {
+                match ELEMENTS
+                    .iter()
+                    .find(|(name, _, _)| *name == element.local_name)
+                {
+                    Some((_, takes_common, particular)) => {
+                        self.check_attributes(element, (*takes_common, particular), issues)
+                    }
+                    None => issues.markup_error_at(
+                        self.path,
+                        element.line,
+                        element.column,
+                        format!(
+                            "Element <{}> isn't part of the OPS 2.0.1 vocabulary.",
+                            element.local_name
+                        ),
+                    ),
+                }
+                for child in element.elements() {
+                    self.check_element(child, issues);
+                }
+            }
+            // Inline SVG is allowed as is
+            Some(SVG_NAMESPACE) => (),
+            Some(OPS_NAMESPACE) if element.local_name == "switch" => {
+                self.check_switch(element, issues)
+            }
+            namespace if namespace.is_some() && namespace == self.item.required_namespace.as_deref() => (),
+            namespace => issues.markup_error_at(
+                self.path,
+                element.line,
+                element.column,
+                format!(
+                    "Element <{}> in namespace {} must be inside ops:switch, or declared as the manifest item's required-namespace.",
+                    element.local_name,
+                    namespace.unwrap_or("(none)")
+                ),
+            ),
+        }
+    }
+}
+
+fn check_xhtml_document(
+    contents: &EpubContents,
+    item: &ManifestItem,
+    path: &str,
+    issues: &mut Issues,
+) -> Option<Element> {
+    // Named entities are only declared by the XHTML DTD, so they're only known when the
+    // document declares it
+    let declares_dtd = contents
+        .get(path)
+        .is_some_and(|bytes| String::from_utf8_lossy(bytes).contains("-//W3C//DTD XHTML"));
+    let entities = match declares_dtd {
+        true => XHTML_ENTITIES,
+        false => &[],
+    };
+    let root = parse_file_with_entities(contents, path, entities, IssueKind::Markup, issues)?;
+    if !root.is(XHTML_NAMESPACE, "html") {
+        issues.markup_error_at(
+            path,
+            root.line,
+            root.column,
+            format!(
+                "Root element must be html in namespace {}.",
+                XHTML_NAMESPACE
+            ),
+        );
+        return None;
+    }
+    let children: Vec<&str> = root
+        .elements()
+        .map(|element| element.local_name.as_ref())
+        .collect();
+    if children != ["head", "body"] {
+        issues.markup_error_at(
+            path,
+            root.line,
+            root.column,
+            String::from("The html element must contain exactly a head followed by a body."),
+        );
+    }
+
+    let check = DocumentCheck { path, item };
+    check.check_element(&root, issues);
+    Some(root)
+}
+
+pub(crate) fn check_xhtml_documents(
+    contents: &EpubContents,
+    package: &Package,
+    issues: &mut Issues,
+) -> HashMap<String, Element> {
+    // Returns the documents which parsed, by path, for their links to be checked
+    let mut documents = HashMap::new();
+    let mut checked_paths = HashSet::new();
+    for item in &package.manifest {
+        if item.media_type != "application/xhtml+xml" {
+            continue;
+        }
+        let path = match &item.path {
+            Some(path) if contents.contains(path) => path,
+            _ => continue,
+        };
+        if checked_paths.insert(path) {
+            if let Some(root) = check_xhtml_document(contents, item, path, issues) {
+                documents.insert(path.clone(), root);
+            }
+        }
+    }
+    documents
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::epub::validate::tests::{get_test_file, validate_test_epub};
+
+    const NOTES: &str = "OEBPS/text/notes.xhtml";
+
+    fn validate_notes(body: &str, lenient_files: &[&str]) -> Vec<String> {
+        let notes = get_test_file(NOTES).replace(r#"<p id="note">A note.</p>"#, body);
+        validate_test_epub(&[(NOTES, &notes)], lenient_files)
+    }
+
+    #[test]
+    fn unclosed_tags_are_reported_with_their_location() {
+        let issues = validate_notes(r#"<p id="note">A note.</body>"#, &[]);
+        assert_eq!(issues.len(), 1);
+        assert!(issues[0].starts_with("error: OEBPS/text/notes.xhtml:4:"));
+        assert!(issues[0].contains("File isn't well-formed XML"));
+    }
+
+    #[test]
+    fn elements_and_attributes_outside_ops_are_reported() {
+        assert_eq!(
+            validate_notes(
+                r#"<p id="note" onclick="go()">A <marquee>note</marquee>.</p><video/>"#,
+                &[]
+            ),
+            vec![
+                "error: OEBPS/text/notes.xhtml:4:7: Event handler attribute onclick on <p> isn't allowed in OPS 2.0.1.",
+                "error: OEBPS/text/notes.xhtml:4:37: Element <marquee> isn't part of the OPS 2.0.1 vocabulary.",
+                "error: OEBPS/text/notes.xhtml:4:65: Element <video> isn't part of the OPS 2.0.1 vocabulary.",
+            ]
+        );
+    }
+
+    #[test]
+    fn named_entities_need_the_xhtml_dtd() {
+        let doctype = concat!(
+            r#"<!DOCTYPE html PUBLIC "-//W3C//DTD XHTML 1.1//EN" "#,
+            r#""http://www.w3.org/TR/xhtml11/DTD/xhtml11.dtd">"#,
+        );
+        let notes = get_test_file(NOTES).replace("A note.", "A&nbsp;note.");
+        assert_eq!(validate_test_epub(&[(NOTES, &notes)], &[]).len(), 1);
+        let notes = notes.replace("<html", &format!("{}\n<html", doctype));
+        assert_eq!(
+            validate_test_epub(&[(NOTES, &notes)], &[]),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn lenient_items_downgrade_markup_but_not_links() {
+        let body = r#"<p id="note"><blink>A</blink> <a href="missing.xhtml">note</a>.</p>"#;
+        assert_eq!(
+            validate_notes(body, &[NOTES]),
+            vec![
+                "warning: OEBPS/text/notes.xhtml:4:20: Element <blink> isn't part of the OPS 2.0.1 vocabulary.",
+                "error: OEBPS/text/notes.xhtml:4:37: Link missing.xhtml refers to OEBPS/text/missing.xhtml, which isn't in the manifest.",
+            ]
+        );
+        assert_eq!(
+            validate_notes(body, &[])[0],
+            "error: OEBPS/text/notes.xhtml:4:20: Element <blink> isn't part of the OPS 2.0.1 vocabulary."
+        );
+    }
+}
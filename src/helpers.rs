@@ -1,7 +1,133 @@
 use path_clean::PathClean;
+use std::cmp::Ordering;
 use std::path::{Path, PathBuf};
 
 pub(crate) fn fixed_clean<P: AsRef<Path>>(path: P) -> PathBuf {
     // Workaround from https://github.com/danreeves/path-clean/issues/4 pending crate update
     PathBuf::from(path.as_ref().to_string_lossy().replace("\\", "/")).clean()
 }
+
+pub(crate) fn path_to_string<P: AsRef<Path>>(path: P) -> Result<String, String> {
+    path.as_ref()
+        .to_str()
+        .map(|s| s.replace('\\', "/"))
+        .ok_or(format!("Invalid non-UTF-8 path: {:?}", path.as_ref()))
+}
+
+pub(crate) fn escape_xml_text(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+pub(crate) fn natural_cmp(a: &str, b: &str) -> Ordering {
+    // Compares runs of ASCII digits by numeric value, so that "chapter2" sorts before "chapter10"
+    let mut a_chars = a.chars().peekable();
+    let mut b_chars = b.chars().peekable();
+    loop {
+        match (a_chars.peek(), b_chars.peek()) {
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(a_char), Some(b_char)) => {
+                if a_char.is_ascii_digit() && b_char.is_ascii_digit() {
+                    let mut a_digits = String::new();
+                    while let Some(c) = a_chars.next_if(|c| c.is_ascii_digit()) {
+                        a_digits.push(c);
+                    }
+                    let mut b_digits = String::new();
+                    while let Some(c) = b_chars.next_if(|c| c.is_ascii_digit()) {
+                        b_digits.push(c);
+                    }
+                    let a_trimmed = a_digits.trim_start_matches('0');
+                    let b_trimmed = b_digits.trim_start_matches('0');
+                    let ordering = a_trimmed
+                        .len()
+                        .cmp(&b_trimmed.len())
+                        .then_with(|| a_trimmed.cmp(b_trimmed));
+                    if ordering != Ordering::Equal {
+                        return ordering;
+                    }
+                } else {
+                    let ordering = a_char.cmp(b_char);
+                    if ordering != Ordering::Equal {
+                        return ordering;
+                    }
+                    a_chars.next();
+                    b_chars.next();
+                }
+            }
+        }
+    }
+}
+
+pub(crate) fn format_size(bytes: u64) -> String {
+    match bytes {
+        0..=1023 => format!("{} bytes", bytes),
+        1024..=1048575 => format!("{:.1} KiB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MiB", bytes as f64 / 1048576.0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn natural_cmp_compares_digit_runs_by_value() {
+        assert_eq!(natural_cmp("chapter2", "chapter10"), Ordering::Less);
+        assert_eq!(natural_cmp("1.10", "1.9"), Ordering::Greater);
+        assert_eq!(
+            natural_cmp("n99999999999999999999999", "n100000000000000000000001"),
+            Ordering::Less
+        );
+    }
+
+    #[test]
+    fn natural_cmp_ignores_leading_zeros_until_the_end() {
+        // Equal values only decide the order once nothing else does, so the order stays total
+        assert_eq!(natural_cmp("chapter007", "chapter10"), Ordering::Less);
+        assert_eq!(natural_cmp("chapter_002a", "chapter_2b"), Ordering::Less);
+        assert_eq!(natural_cmp("chapter_002", "chapter_2"), Ordering::Less);
+        assert_eq!(natural_cmp("x00", "x0"), Ordering::Greater);
+    }
+
+    #[test]
+    fn natural_cmp_compares_each_digit_run_in_turn() {
+        assert_eq!(natural_cmp("a2b9", "a2b10"), Ordering::Less);
+        assert_eq!(natural_cmp("a10b1", "a9b20"), Ordering::Greater);
+        assert_eq!(natural_cmp("v1.2.10", "v1.10.2"), Ordering::Less);
+        assert_eq!(natural_cmp("part2", "part_1"), Ordering::Less);
+    }
+
+    #[test]
+    fn natural_cmp_puts_prefixes_first() {
+        assert_eq!(natural_cmp("chapter", "chapter1"), Ordering::Less);
+        assert_eq!(natural_cmp("chapter1", "chapter1a"), Ordering::Less);
+        assert_eq!(natural_cmp("chapter10", "chapter1"), Ordering::Greater);
+        assert_eq!(natural_cmp("", "a"), Ordering::Less);
+        assert_eq!(natural_cmp("chapter1", "chapter1"), Ordering::Equal);
+    }
+
+    #[test]
+    fn natural_cmp_sorts_file_names() {
+        let mut names = vec![
+            "ch10.xhtml",
+            "ch2.xhtml",
+            "ch02.xhtml",
+            "ch1.xhtml",
+            "appendix.xhtml",
+        ];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(
+            names,
+            vec![
+                "appendix.xhtml",
+                "ch1.xhtml",
+                "ch02.xhtml",
+                "ch2.xhtml",
+                "ch10.xhtml"
+            ]
+        );
+    }
+}
@@ -0,0 +1,5 @@
+mod plaintext;
+
+pub use plaintext::{
+    import_plain_text, write_imported_book, ImportedBook, ImportedChapter, PlainTextImportSettings,
+};
@@ -0,0 +1,630 @@
+use crate::helpers::escape_xml_text;
+use crate::toml::CURRENT_SCHEMA_VERSION;
+
+use regex::Regex;
+use std::fs::{create_dir_all, write};
+use std::path::{Path, PathBuf};
+use toml::map::Map;
+use toml::Value;
+
+//////////////////
+//   Settings   //
+//////////////////
+
+pub struct PlainTextImportSettings {
+    // Metadata (falls back to the Project Gutenberg header, if any)
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub language: Option<String>,
+
+    // Structure detection
+    pub chapter_patterns: Vec<String>,
+    pub scene_break_patterns: Vec<String>,
+
+    // Boilerplate
+    pub strip_boilerplate: bool,
+    pub boilerplate_header_end_patterns: Vec<String>,
+    pub boilerplate_footer_start_patterns: Vec<String>,
+
+    // Typography
+    pub typography: bool,
+}
+
+impl Default for PlainTextImportSettings {
+    fn default() -> Self {
+        PlainTextImportSettings {
+            title: None,
+            author: None,
+            language: None,
+            chapter_patterns: vec![
+                String::from(r"(?i)^chapter\s+([0-9]+|[ivxlcdm]+)\b"),
+                String::from(r"(?i)^(book|part|volume)\s+([0-9]+|[ivxlcdm]+)\b"),
+                String::from(r"^[IVXLCDM]+\.?$"),
+                String::from(r"(?i)^(prologue|epilogue|preface|introduction)\.?$"),
+            ],
+            scene_break_patterns: vec![
+                String::from(r"^([*#~]\s*)+$"),
+                String::from(r"^(-\s*){3,}$"),
+            ],
+            strip_boilerplate: true,
+            boilerplate_header_end_patterns: vec![String::from(
+                r"(?i)^\*\*\*\s*start of (the|this) project gutenberg",
+            )],
+            boilerplate_footer_start_patterns: vec![
+                String::from(r"(?i)^\*\*\*\s*end of (the|this) project gutenberg"),
+                String::from(r"(?i)^end of (the )?project gutenberg'?s"),
+            ],
+            typography: true,
+        }
+    }
+}
+
+////////////////
+//   Output   //
+////////////////
+
+pub struct ImportedChapter {
+    pub id: String,
+    pub title: String,
+    pub filename: String,
+    pub xhtml: String,
+}
+
+pub struct ImportedBook {
+    pub title: String,
+    pub author: Option<String>,
+    pub language: String,
+    pub chapters: Vec<ImportedChapter>,
+}
+
+/////////////////
+//   Helpers   //
+/////////////////
+
+fn compile_patterns(patterns: &[String]) -> Result<Vec<Regex>, String> {
+    patterns
+        .iter()
+        .map(|pattern| {
+            Regex::new(pattern).map_err(|e| format!("Invalid pattern '{}': {}", pattern, e))
+        })
+        .collect()
+}
+
+fn matches_any(patterns: &[Regex], line: &str) -> bool {
+    patterns.iter().any(|pattern| pattern.is_match(line))
+}
+
+fn get_gutenberg_header_field(lines: &[&str], field: &str) -> Option<String> {
+    let prefix = format!("{}:", field);
+    lines.iter().find_map(|line| {
+        line.trim()
+            .strip_prefix(&prefix)
+            .map(|value| String::from(value.trim()))
+            .filter(|value| !value.is_empty())
+    })
+}
+
+fn get_language_code(language: &str) -> Result<String, String> {
+    // Project Gutenberg headers name languages in English; these are the ISO 639-1 names, with
+    // ISO 639-2 codes for the historical languages Gutenberg also carries
+    let code = match language.to_lowercase().as_ref() {
+        "afar" => "aa",
+        "abkhazian" => "ab",
+        "avestan" => "ae",
+        "afrikaans" => "af",
+        "akan" => "ak",
+        "amharic" => "am",
+        "aragonese" => "an",
+        "arabic" => "ar",
+        "assamese" => "as",
+        "avaric" => "av",
+        "aymara" => "ay",
+        "azerbaijani" => "az",
+        "bashkir" => "ba",
+        "belarusian" => "be",
+        "bulgarian" => "bg",
+        "bislama" => "bi",
+        "bambara" => "bm",
+        "bengali" => "bn",
+        "tibetan" => "bo",
+        "breton" => "br",
+        "bosnian" => "bs",
+        "catalan" | "valencian" => "ca",
+        "chechen" => "ce",
+        "chamorro" => "ch",
+        "corsican" => "co",
+        "cree" => "cr",
+        "czech" => "cs",
+        "church slavic" | "church slavonic" | "old church slavonic" | "old slavonic" | "old bulgarian" => "cu",
+        "chuvash" => "cv",
+        "welsh" => "cy",
+        "danish" => "da",
+        "german" => "de",
+        "divehi" | "dhivehi" | "maldivian" => "dv",
+        "dzongkha" => "dz",
+        "ewe" => "ee",
+        "greek" | "modern greek" => "el",
+        "english" => "en",
+        "esperanto" => "eo",
+        "spanish" | "castilian" => "es",
+        "estonian" => "et",
+        "basque" => "eu",
+        "persian" | "farsi" => "fa",
+        "fulah" => "ff",
+        "finnish" => "fi",
+        "fijian" => "fj",
+        "faroese" => "fo",
+        "french" => "fr",
+        "western frisian" | "frisian" => "fy",
+        "irish" => "ga",
+        "gaelic" | "scottish gaelic" => "gd",
+        "galician" => "gl",
+        "guarani" => "gn",
+        "gujarati" => "gu",
+        "manx" => "gv",
+        "hausa" => "ha",
+        "hebrew" => "he",
+        "hindi" => "hi",
+        "hiri motu" => "ho",
+        "croatian" => "hr",
+        "haitian" | "haitian creole" => "ht",
+        "hungarian" => "hu",
+        "armenian" => "hy",
+        "herero" => "hz",
+        "interlingua" => "ia",
+        "indonesian" => "id",
+        "interlingue" | "occidental" => "ie",
+        "igbo" => "ig",
+        "sichuan yi" | "nuosu" => "ii",
+        "inupiaq" => "ik",
+        "ido" => "io",
+        "icelandic" => "is",
+        "italian" => "it",
+        "inuktitut" => "iu",
+        "japanese" => "ja",
+        "javanese" => "jv",
+        "georgian" => "ka",
+        "kongo" => "kg",
+        "kikuyu" | "gikuyu" => "ki",
+        "kuanyama" | "kwanyama" => "kj",
+        "kazakh" => "kk",
+        "kalaallisut" | "greenlandic" => "kl",
+        "khmer" | "central khmer" => "km",
+        "kannada" => "kn",
+        "korean" => "ko",
+        "kanuri" => "kr",
+        "kashmiri" => "ks",
+        "kurdish" => "ku",
+        "komi" => "kv",
+        "cornish" => "kw",
+        "kirghiz" | "kyrgyz" => "ky",
+        "latin" => "la",
+        "luxembourgish" | "letzeburgesch" => "lb",
+        "ganda" => "lg",
+        "limburgan" | "limburger" | "limburgish" => "li",
+        "lingala" => "ln",
+        "lao" => "lo",
+        "lithuanian" => "lt",
+        "luba-katanga" => "lu",
+        "latvian" => "lv",
+        "malagasy" => "mg",
+        "marshallese" => "mh",
+        "maori" => "mi",
+        "macedonian" => "mk",
+        "malayalam" => "ml",
+        "mongolian" => "mn",
+        "marathi" => "mr",
+        "malay" => "ms",
+        "maltese" => "mt",
+        "burmese" => "my",
+        "nauru" => "na",
+        "norwegian bokmål" | "bokmål" => "nb",
+        "north ndebele" => "nd",
+        "nepali" => "ne",
+        "ndonga" => "ng",
+        "dutch" | "flemish" => "nl",
+        "norwegian nynorsk" | "nynorsk" => "nn",
+        "norwegian" => "no",
+        "south ndebele" => "nr",
+        "navajo" | "navaho" => "nv",
+        "chichewa" | "chewa" | "nyanja" => "ny",
+        "occitan" => "oc",
+        "ojibwa" => "oj",
+        "oromo" => "om",
+        "oriya" => "or",
+        "ossetian" | "ossetic" => "os",
+        "panjabi" | "punjabi" => "pa",
+        "pali" => "pi",
+        "polish" => "pl",
+        "pashto" | "pushto" => "ps",
+        "portuguese" => "pt",
+        "quechua" => "qu",
+        "romansh" => "rm",
+        "rundi" => "rn",
+        "romanian" | "moldavian" | "moldovan" => "ro",
+        "russian" => "ru",
+        "kinyarwanda" => "rw",
+        "sanskrit" => "sa",
+        "sardinian" => "sc",
+        "sindhi" => "sd",
+        "northern sami" => "se",
+        "sango" => "sg",
+        "sinhala" | "sinhalese" => "si",
+        "slovak" => "sk",
+        "slovenian" => "sl",
+        "samoan" => "sm",
+        "shona" => "sn",
+        "somali" => "so",
+        "albanian" => "sq",
+        "serbian" => "sr",
+        "swati" => "ss",
+        "southern sotho" => "st",
+        "sundanese" => "su",
+        "swedish" => "sv",
+        "swahili" => "sw",
+        "tamil" => "ta",
+        "telugu" => "te",
+        "tajik" => "tg",
+        "thai" => "th",
+        "tigrinya" => "ti",
+        "turkmen" => "tk",
+        "tagalog" => "tl",
+        "tswana" => "tn",
+        "tonga" => "to",
+        "turkish" => "tr",
+        "tsonga" => "ts",
+        "tatar" => "tt",
+        "twi" => "tw",
+        "tahitian" => "ty",
+        "uighur" | "uyghur" => "ug",
+        "ukrainian" => "uk",
+        "urdu" => "ur",
+        "uzbek" => "uz",
+        "venda" => "ve",
+        "vietnamese" => "vi",
+        "volapük" | "volapuk" => "vo",
+        "walloon" => "wa",
+        "wolof" => "wo",
+        "xhosa" => "xh",
+        "yiddish" => "yi",
+        "yoruba" => "yo",
+        "zhuang" | "chuang" => "za",
+        "chinese" => "zh",
+        "zulu" => "zu",
+        "old english" => "ang",
+        "middle english" => "enm",
+        "old french" => "fro",
+        "middle french" => "frm",
+        "middle high german" => "gmh",
+        "old high german" => "goh",
+        "ancient greek" | "greek, ancient" => "grc",
+        "old norse" => "non",
+        "scots" => "sco",
+        "cebuano" => "ceb",
+        "iloko" => "ilo",
+        _ => {
+            return Err(format!(
+                "Language '{}' from the Project Gutenberg header has no known language code; give one with --language, e.g. --language en.",
+                language
+            ))
+        }
+    };
+    Ok(String::from(code))
+}
+
+fn strip_boilerplate<'a>(
+    lines: &[&'a str],
+    header_end_patterns: &[Regex],
+    footer_start_patterns: &[Regex],
+) -> Vec<&'a str> {
+    let start = match lines
+        .iter()
+        .position(|line| matches_any(header_end_patterns, line.trim()))
+    {
+        Some(header_end) => header_end + 1,
+        None => 0,
+    };
+    let end = match lines[start..]
+        .iter()
+        .position(|line| matches_any(footer_start_patterns, line.trim()))
+    {
+        Some(footer_start) => start + footer_start,
+        None => lines.len(),
+    };
+    lines[start..end].to_vec()
+}
+
+fn split_blocks<'a>(lines: &[&'a str]) -> Vec<Vec<&'a str>> {
+    let mut blocks = Vec::new();
+    let mut current_block = Vec::new();
+    for line in lines {
+        if line.trim().is_empty() {
+            if !current_block.is_empty() {
+                blocks.push(current_block);
+                current_block = Vec::new();
+            }
+        } else {
+            current_block.push(line.trim());
+        }
+    }
+    if !current_block.is_empty() {
+        blocks.push(current_block);
+    }
+    blocks
+}
+
+fn apply_typography(text: &str) -> String {
+    let text = text
+        .replace("---", "\u{2014}")
+        .replace("--", "\u{2014}")
+        .replace("...", "\u{2026}");
+
+    let mut typeset = String::with_capacity(text.len());
+    let mut previous_char: Option<char> = None;
+    for c in text.chars() {
+        let opens = match previous_char {
+            None => true,
+            Some(previous) => {
+                previous.is_whitespace() || "([{\u{2014}\u{2018}\u{201C}".contains(previous)
+            }
+        };
+        typeset.push(match (c, opens) {
+            ('"', true) => '\u{201C}',
+            ('"', false) => '\u{201D}',
+            ('\'', true) => '\u{2018}',
+            ('\'', false) => '\u{2019}',
+            _ => c,
+        });
+        previous_char = Some(c);
+    }
+    typeset
+}
+
+fn format_text(text: &str, settings: &PlainTextImportSettings) -> String {
+    match settings.typography {
+        true => escape_xml_text(&apply_typography(text)),
+        false => escape_xml_text(text),
+    }
+}
+
+fn build_chapter_xhtml(title: &str, language: &str, body: &[String]) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<!DOCTYPE html PUBLIC \"-//W3C//DTD XHTML 1.1//EN\" \"http://www.w3.org/TR/xhtml11/DTD/xhtml11.dtd\">\n<html xmlns=\"http://www.w3.org/1999/xhtml\" xml:lang=\"{}\">\n<head>\n  <title>{}</title>\n</head>\n<body>\n{}\n</body>\n</html>\n",
+        escape_xml_text(language),
+        escape_xml_text(title),
+        body.join("\n")
+    )
+}
+
+////////////////
+//   Import   //
+////////////////
+
+struct Section {
+    title: Option<String>,
+    heading_html: Option<String>,
+    body: Vec<String>,
+}
+
+pub fn import_plain_text(
+    text: &str,
+    settings: &PlainTextImportSettings,
+) -> Result<ImportedBook, String> {
+    let chapter_patterns = compile_patterns(&settings.chapter_patterns)?;
+    let scene_break_patterns = compile_patterns(&settings.scene_break_patterns)?;
+    let header_end_patterns = compile_patterns(&settings.boilerplate_header_end_patterns)?;
+    let footer_start_patterns = compile_patterns(&settings.boilerplate_footer_start_patterns)?;
+
+    let text = text.trim_start_matches('\u{FEFF}').replace("\r\n", "\n");
+    let all_lines: Vec<&str> = text.lines().collect();
+
+    // Metadata
+    let title = match &settings.title {
+        Some(title) => title.clone(),
+        None => get_gutenberg_header_field(&all_lines, "Title")
+            .unwrap_or_else(|| String::from("Untitled")),
+    };
+    let author = match &settings.author {
+        Some(author) => Some(author.clone()),
+        None => get_gutenberg_header_field(&all_lines, "Author"),
+    };
+    let language = match &settings.language {
+        Some(language) => language.clone(),
+        None => match get_gutenberg_header_field(&all_lines, "Language") {
+            Some(language) => get_language_code(&language)?,
+            None => String::from("en"),
+        },
+    };
+
+    // Structure
+    let lines = match settings.strip_boilerplate {
+        true => strip_boilerplate(&all_lines, &header_end_patterns, &footer_start_patterns),
+        false => all_lines,
+    };
+
+    let mut sections = vec![Section {
+        title: None,
+        heading_html: None,
+        body: Vec::new(),
+    }];
+    for block in split_blocks(&lines) {
+        let is_heading = block.len() <= 3
+            && matches_any(&chapter_patterns, block[0])
+            && !block[1..]
+                .iter()
+                .any(|line| matches_any(&chapter_patterns, line));
+        if is_heading {
+            sections.push(Section {
+                title: Some(block.join(" ")),
+                heading_html: Some(format!(
+                    "  <h2>{}</h2>",
+                    block
+                        .iter()
+                        .map(|line| format_text(line, settings))
+                        .collect::<Vec<String>>()
+                        .join("<br />")
+                )),
+                body: Vec::new(),
+            });
+        } else if block.len() == 1 && matches_any(&scene_break_patterns, block[0]) {
+            sections
+                .last_mut()
+                .unwrap()
+                .body
+                .push(String::from("  <hr class=\"scene-break\" />"));
+        } else {
+            sections.last_mut().unwrap().body.push(format!(
+                "  <p>{}</p>",
+                format_text(&block.join(" "), settings)
+            ));
+        }
+    }
+
+    // Headings without any body text are most likely table-of-contents entries
+    let mut chapters = Vec::new();
+    let mut chapter_number = 1;
+    for section in sections {
+        if section.body.is_empty() {
+            continue;
+        }
+        let (id, section_title) = match &section.title {
+            None => (String::from("front_matter"), title.clone()),
+            Some(section_title) => {
+                let id = format!("chapter_{:03}", chapter_number);
+                chapter_number += 1;
+                (id, section_title.clone())
+            }
+        };
+        let mut body = Vec::new();
+        if let Some(heading_html) = section.heading_html {
+            body.push(heading_html);
+        }
+        body.extend(section.body);
+        chapters.push(ImportedChapter {
+            filename: format!("{}.xhtml", id),
+            xhtml: build_chapter_xhtml(&section_title, &language, &body),
+            title: section_title,
+            id,
+        });
+    }
+
+    if chapters.is_empty() {
+        return Err(String::from("No text found to import."));
+    }
+
+    Ok(ImportedBook {
+        title,
+        author,
+        language,
+        chapters,
+    })
+}
+
+/////////////////
+//   Writing   //
+/////////////////
+
+fn build_recipe(book: &ImportedBook, recipe_name: &str) -> Result<String, String> {
+    let mut metadata = vec![Value::Table(Map::from_iter([
+        (String::from("name"), Value::String(String::from("title"))),
+        (String::from("content"), Value::String(book.title.clone())),
+    ]))];
+    if let Some(author) = &book.author {
+        metadata.push(Value::Table(Map::from_iter([
+            (String::from("name"), Value::String(String::from("creator"))),
+            (String::from("content"), Value::String(author.clone())),
+            (String::from("role"), Value::String(String::from("aut"))),
+        ])));
+    }
+    metadata.push(Value::Table(Map::from_iter([
+        (
+            String::from("name"),
+            Value::String(String::from("language")),
+        ),
+        (
+            String::from("content"),
+            Value::String(book.language.clone()),
+        ),
+    ])));
+
+    let mut manifest = Vec::new();
+    let mut spine = Vec::new();
+    let mut navmap = Vec::new();
+    for chapter in &book.chapters {
+        manifest.push(Value::Table(Map::from_iter([
+            (
+                String::from("outside_path"),
+                Value::String(format!("text/{}", chapter.filename)),
+            ),
+            (
+                String::from("inside_path_from_opf"),
+                Value::String(format!("text/{}", chapter.filename)),
+            ),
+            (
+                String::from("media_type"),
+                Value::String(String::from("application/xhtml+xml")),
+            ),
+            (String::from("id"), Value::String(chapter.id.clone())),
+        ])));
+        spine.push(Value::String(chapter.id.clone()));
+        navmap.push(Value::Table(Map::from_iter([
+            (String::from("label"), Value::String(chapter.title.clone())),
+            (String::from("idref"), Value::String(chapter.id.clone())),
+        ])));
+    }
+
+    let recipe = Map::from_iter([
+        (
+            String::from("schema_version"),
+            Value::Integer(CURRENT_SCHEMA_VERSION),
+        ),
+        (String::from("format"), Value::String(String::from("epub2"))),
+        (String::from("metadata"), Value::Array(metadata)),
+        (String::from("manifest"), Value::Array(manifest)),
+        (String::from("spine"), Value::Array(spine)),
+        (String::from("navmap"), Value::Array(navmap)),
+    ]);
+    let config = Value::Table(Map::from_iter([(
+        String::from(recipe_name),
+        Value::Table(recipe),
+    )]));
+
+    toml::to_string(&config).map_err(|e| e.to_string())
+}
+
+pub fn write_imported_book<P: AsRef<Path>>(
+    book: &ImportedBook,
+    out_dir: P,
+    recipe_name: &str,
+) -> Result<PathBuf, String> {
+    let config_path = out_dir.as_ref().join("bookfactory.toml");
+    if config_path.exists() {
+        return Err(format!(
+            "Refusing to overwrite existing config file {}.",
+            config_path.display()
+        ));
+    }
+
+    let text_dir = out_dir.as_ref().join("text");
+    create_dir_all(&text_dir).map_err(|e| e.to_string())?;
+    for chapter in &book.chapters {
+        write(text_dir.join(&chapter.filename), &chapter.xhtml).map_err(|e| e.to_string())?;
+    }
+    write(&config_path, build_recipe(book, recipe_name)?).map_err(|e| e.to_string())?;
+
+    Ok(config_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gutenberg_language_names_map_to_codes() {
+        assert_eq!(get_language_code("English").as_deref(), Ok("en"));
+        assert_eq!(get_language_code("Esperanto").as_deref(), Ok("eo"));
+        assert_eq!(get_language_code("VOLAPÜK").as_deref(), Ok("vo"));
+        assert_eq!(get_language_code("Old English").as_deref(), Ok("ang"));
+        assert!(get_language_code("Klingon").is_err());
+    }
+}
@@ -0,0 +1,3 @@
+mod scaffold;
+
+pub use scaffold::{write_scaffold, ScaffoldSettings, SCAFFOLD_FORMATS};
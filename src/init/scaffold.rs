@@ -0,0 +1,222 @@
+use crate::helpers::escape_xml_text;
+use crate::toml::CURRENT_SCHEMA_VERSION;
+
+use std::fs::{create_dir_all, write};
+use std::path::{Path, PathBuf};
+use toml::Value;
+use uuid::Uuid;
+
+//////////////////
+//   Settings   //
+//////////////////
+
+pub struct ScaffoldSettings {
+    pub recipe_name: String,
+    pub title: String,
+    pub author: Option<String>,
+    pub language: String,
+    pub format: String,
+}
+
+impl Default for ScaffoldSettings {
+    fn default() -> Self {
+        ScaffoldSettings {
+            recipe_name: String::from("book"),
+            title: String::from("Untitled"),
+            author: None,
+            language: String::from("en"),
+            format: String::from("epub2"),
+        }
+    }
+}
+
+pub const SCAFFOLD_FORMATS: &[&str] = &["epub2"];
+
+///////////////////
+//   Templates   //
+///////////////////
+
+fn toml_string(value: &str) -> String {
+    Value::String(String::from(value)).to_string()
+}
+
+fn build_recipe(settings: &ScaffoldSettings) -> String {
+    // Written by hand rather than serialized, so that the comments can explain each key
+    let name = &settings.recipe_name;
+    let author = match &settings.author {
+        Some(author) => format!(
+            "  {{ name = \"creator\", content = {}, role = \"aut\" }},\n",
+            toml_string(author)
+        ),
+        None => String::new(),
+    };
+    format!(
+        r#"# Each top-level table is a recipe; build this one with
+#   bookfactory build {name}.epub bookfactory.toml {name}
+# Run `bookfactory schema` or `bookfactory lsp` for editor completion of the keys below.
+[{header}]
+schema_version = {schema_version}
+format = {format}
+
+# Dublin Core elements by name, plus custom <meta> elements by custom_name
+metadata = [
+  {{ name = "title", content = {title} }},
+{author}  {{ name = "language", content = {language} }},
+  {{ name = "identifier", content = "urn:uuid:{uuid}", id = "BookId", scheme = "UUID" }},
+  {{ custom_name = "cover", content = "cover-image" }},
+]
+
+# Every file in the book. Outside paths are relative to this file and inside paths to the OPF;
+# media types are inferred from the file extensions.
+manifest = [
+  {{ outside_path = "images/cover.svg", inside_path_from_opf = "images/cover.svg", id = "cover-image" }},
+  {{ outside_path = "text/cover.xhtml", inside_path_from_opf = "text/cover.xhtml", id = "cover" }},
+  {{ outside_path = "styles/style.css", inside_path_from_opf = "styles/style.css", id = "style" }},
+  # Adds every chapter file, with ids taken from the file names
+  {{ glob = "text/chapter_*.xhtml" }},
+]
+
+# Reading order, by manifest id or inside path; the cover is shown by itself rather than as the
+# first page of the text
+spine = [
+  {{ idref = "cover", linear = false }},
+  {{ glob = "text/chapter_*.xhtml" }},
+]
+
+# Landmarks for reading systems
+guide = [
+  {{ type = "cover", title = "Cover", idref = "cover" }},
+  {{ type = "text", title = "Beginning", idref = "chapter_001" }},
+]
+
+# Table of contents
+[[{header}.navmap]]
+label = "Chapter 1"
+idref = "chapter_001"
+"#,
+        name = name,
+        header = match name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            true => name.clone(),
+            false => toml_string(name),
+        },
+        schema_version = CURRENT_SCHEMA_VERSION,
+        format = toml_string(&settings.format),
+        title = toml_string(&settings.title),
+        author = author,
+        language = toml_string(&settings.language),
+        uuid = Uuid::new_v4(),
+    )
+}
+
+fn build_xhtml(title: &str, language: &str, body: &str) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<!DOCTYPE html PUBLIC \"-//W3C//DTD XHTML 1.1//EN\" \"http://www.w3.org/TR/xhtml11/DTD/xhtml11.dtd\">\n<html xmlns=\"http://www.w3.org/1999/xhtml\" xml:lang=\"{}\">\n<head>\n  <title>{}</title>\n  <link rel=\"stylesheet\" type=\"text/css\" href=\"../styles/style.css\" />\n</head>\n<body>\n{}\n</body>\n</html>\n",
+        escape_xml_text(language),
+        escape_xml_text(title),
+        body
+    )
+}
+
+fn build_cover_svg(settings: &ScaffoldSettings) -> String {
+    let author = match &settings.author {
+        Some(author) => format!(
+            "\n  <text x=\"300\" y=\"460\" font-size=\"28\" text-anchor=\"middle\" fill=\"#f4f1ea\">{}</text>",
+            escape_xml_text(author)
+        ),
+        None => String::new(),
+    };
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<svg xmlns=\"http://www.w3.org/2000/svg\" version=\"1.1\" width=\"600\" height=\"800\" viewBox=\"0 0 600 800\">\n  <rect width=\"600\" height=\"800\" fill=\"#3b4a5a\" />\n  <text x=\"300\" y=\"380\" font-size=\"44\" text-anchor=\"middle\" fill=\"#f4f1ea\">{}</text>{}\n</svg>\n",
+        escape_xml_text(&settings.title),
+        author
+    )
+}
+
+const STYLESHEET: &str = "body {
+  margin: 0 5%;
+  font-family: serif;
+  line-height: 1.4;
+}
+
+h1 {
+  margin: 2em 0 1em;
+  text-align: center;
+}
+
+p {
+  margin: 0;
+  text-indent: 1.5em;
+}
+
+div.cover {
+  text-align: center;
+}
+
+div.cover img {
+  height: 100%;
+}
+";
+
+/////////////////
+//   Writing   //
+/////////////////
+
+pub fn write_scaffold<P: AsRef<Path>>(
+    settings: &ScaffoldSettings,
+    out_dir: P,
+) -> Result<PathBuf, String> {
+    if !SCAFFOLD_FORMATS.contains(&settings.format.as_ref()) {
+        return Err(format!(
+            "Format {} isn't supported by init; supported formats are {}.",
+            settings.format,
+            SCAFFOLD_FORMATS.join(", ")
+        ));
+    }
+    let out_dir = out_dir.as_ref();
+    let config_path = out_dir.join("bookfactory.toml");
+    if config_path.exists() {
+        return Err(format!(
+            "Refusing to overwrite existing config file {}.",
+            config_path.display()
+        ));
+    }
+
+    let cover_xhtml = build_xhtml(
+        "Cover",
+        &settings.language,
+        "  <div class=\"cover\"><img src=\"../images/cover.svg\" alt=\"Cover\" /></div>",
+    );
+    let chapter_xhtml = build_xhtml(
+        "Chapter 1",
+        &settings.language,
+        "  <h1>Chapter 1</h1>\n  <p>Write your first chapter here. Add more chapters as text/chapter_002.xhtml and so on; they're picked up by the manifest and spine globs in bookfactory.toml, and can be added to the table of contents with more navmap entries.</p>",
+    );
+    let files = [
+        (PathBuf::from("text/cover.xhtml"), cover_xhtml),
+        (PathBuf::from("text/chapter_001.xhtml"), chapter_xhtml),
+        (PathBuf::from("styles/style.css"), String::from(STYLESHEET)),
+        (PathBuf::from("images/cover.svg"), build_cover_svg(settings)),
+    ];
+    for (path, _) in &files {
+        if out_dir.join(path).exists() {
+            return Err(format!(
+                "Refusing to overwrite existing file {}.",
+                out_dir.join(path).display()
+            ));
+        }
+    }
+
+    for (path, contents) in files {
+        let path = out_dir.join(path);
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        write(&path, contents).map_err(|e| e.to_string())?;
+    }
+    write(&config_path, build_recipe(settings)).map_err(|e| e.to_string())?;
+
+    Ok(config_path)
+}
@@ -1,4 +1,7 @@
 pub mod epub;
 pub(crate) mod helpers;
+pub mod import;
+pub mod init;
+pub mod lsp;
 pub mod toml;
 pub mod zip;
@@ -0,0 +1,409 @@
+use crate::lsp::documents::{position_to_offset, Document};
+
+use lsp_types::{CompletionItem, CompletionItemKind, Documentation, Position};
+use regex::Regex;
+use serde_json::Value;
+
+////////////////////////////
+//   Completion Context   //
+////////////////////////////
+
+// Where the cursor is in a TOML config file, worked out from the text before it, since the file is
+// usually unparseable while it's being typed
+pub(crate) struct CompletionContext {
+    // Keys from the top level of the file down to the table or array the cursor is in
+    pub(crate) path: Vec<String>,
+    // Key whose value is being typed, or None when a key is being typed; array elements count as
+    // values of the array's key
+    pub(crate) value_key: Option<String>,
+    pub(crate) in_array: bool,
+    pub(crate) in_string: bool,
+}
+
+fn split_key(key: &str) -> Vec<String> {
+    key.split('.')
+        .map(|part| {
+            part.trim()
+                .trim_matches(|c| c == '"' || c == '\'')
+                .to_string()
+        })
+        .filter(|part| !part.is_empty())
+        .collect()
+}
+
+pub(crate) fn get_completion_context(text_before: &str) -> Option<CompletionContext> {
+    let header_regex =
+        Regex::new(r#"(?m)^[ \t]*\[\[?([A-Za-z0-9_\-. \t"']+)\]\]?[ \t]*(?:#.*)?$"#).unwrap();
+    let (mut path, body) = match header_regex.captures_iter(text_before).last() {
+        Some(captures) => (
+            split_key(&captures[1]),
+            &text_before[captures.get(0).unwrap().end()..],
+        ),
+        None => (Vec::new(), text_before),
+    };
+
+    // Open inline tables and arrays, with the keys whose values they are
+    let mut stack: Vec<(char, Vec<String>)> = Vec::new();
+    let mut key_text = String::new();
+    let mut value_key: Vec<String> = Vec::new();
+    let mut after_equals = false;
+    let mut in_string: Option<char> = None;
+    let mut in_comment = false;
+    let mut escaped = false;
+    for c in body.chars() {
+        if in_comment {
+            in_comment = c != '\n';
+            continue;
+        }
+        if let Some(quote) = in_string {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' && quote == '"' {
+                escaped = true;
+            } else if c == quote {
+                in_string = None;
+            }
+            if !after_equals {
+                key_text.push(c);
+            }
+            continue;
+        }
+        match c {
+            '#' => in_comment = true,
+            '"' | '\'' => {
+                in_string = Some(c);
+                if !after_equals {
+                    key_text.push(c);
+                }
+            }
+            '=' if !after_equals => {
+                value_key = split_key(&key_text);
+                key_text.clear();
+                after_equals = true;
+            }
+            '{' => {
+                let key = match stack.last() {
+                    Some(('[', _)) => Vec::new(),
+                    _ => value_key.clone(),
+                };
+                stack.push(('{', key));
+                after_equals = false;
+            }
+            '[' => {
+                let key = match stack.last() {
+                    Some(('[', _)) => Vec::new(),
+                    _ => value_key.clone(),
+                };
+                stack.push(('[', key));
+            }
+            '}' | ']' => {
+                stack.pop();
+                after_equals = true;
+            }
+            ',' if matches!(stack.last(), Some(('{', _))) => {
+                after_equals = false;
+                key_text.clear();
+            }
+            '\n' if stack.is_empty() => {
+                after_equals = false;
+                key_text.clear();
+            }
+            c if !after_equals => key_text.push(c),
+            _ => (),
+        }
+    }
+    if in_comment {
+        return None;
+    }
+
+    for (_, key) in &stack {
+        path.extend(key.iter().cloned());
+    }
+    let in_array = matches!(stack.last(), Some(('[', _)));
+    let value_key = match (in_array, after_equals) {
+        (true, _) => path.pop(),
+        (false, true) => {
+            // Dotted keys like "ncx_meta.manifest_id = " set keys of nested tables
+            let last = value_key.pop();
+            path.extend(value_key);
+            last
+        }
+        (false, false) => {
+            let mut key = split_key(&key_text);
+            key.pop();
+            path.extend(key);
+            None
+        }
+    };
+    Some(CompletionContext {
+        path,
+        value_key,
+        in_array,
+        in_string: in_string.is_some(),
+    })
+}
+
+////////////////
+//   Schema   //
+////////////////
+
+fn resolve_schema<'a>(root: &'a Value, schema: &'a Value, resolved: &mut Vec<&'a Value>) {
+    // Arrays stand in for their elements, as in array-of-tables headers
+    if let Some(Value::String(reference)) = schema.get("$ref") {
+        if let Some(target) = reference
+            .strip_prefix("#/definitions/")
+            .and_then(|name| root.get("definitions").and_then(|d| d.get(name)))
+        {
+            resolve_schema(root, target, resolved);
+        }
+        return;
+    }
+    for combinator in ["anyOf", "oneOf", "allOf"] {
+        if let Some(Value::Array(alternatives)) = schema.get(combinator) {
+            for alternative in alternatives {
+                resolve_schema(root, alternative, resolved);
+            }
+        }
+    }
+    if let Some(items) = schema.get("items") {
+        resolve_schema(root, items, resolved);
+    }
+    resolved.push(schema);
+}
+
+fn get_key_schemas<'a>(root: &'a Value, schemas: &[&'a Value], key: &str) -> Vec<&'a Value> {
+    let mut resolved = Vec::new();
+    for schema in schemas {
+        match schema
+            .get("properties")
+            .and_then(|properties| properties.get(key))
+        {
+            Some(property) => resolve_schema(root, property, &mut resolved),
+            None => {
+                if let Some(additional @ Value::Object(_)) = schema.get("additionalProperties") {
+                    resolve_schema(root, additional, &mut resolved);
+                }
+            }
+        }
+    }
+    resolved
+}
+
+fn get_path_schemas<'a>(root: &'a Value, path: &[String]) -> Vec<&'a Value> {
+    let mut schemas = vec![root];
+    for key in path {
+        schemas = get_key_schemas(root, &schemas, key);
+    }
+    schemas
+}
+
+fn get_description(root: &Value, schema: &Value) -> Option<String> {
+    let mut resolved = Vec::new();
+    resolve_schema(root, schema, &mut resolved);
+    std::iter::once(schema)
+        .chain(resolved)
+        .find_map(|schema| schema.get("description").and_then(|d| d.as_str()))
+        .map(String::from)
+}
+
+fn complete_keys(root: &Value, schemas: &[&Value]) -> Vec<CompletionItem> {
+    let mut items: Vec<CompletionItem> = Vec::new();
+    for schema in schemas {
+        if let Some(Value::Object(properties)) = schema.get("properties") {
+            for (key, property) in properties {
+                if items.iter().any(|item| &item.label == key) {
+                    continue;
+                }
+                items.push(CompletionItem {
+                    label: key.clone(),
+                    kind: Some(CompletionItemKind::PROPERTY),
+                    documentation: get_description(root, property).map(Documentation::String),
+                    ..Default::default()
+                });
+            }
+        }
+    }
+    items
+}
+
+fn quote(value: &str, context: &CompletionContext) -> String {
+    match context.in_string {
+        true => String::from(value),
+        false => format!("\"{}\"", value),
+    }
+}
+
+fn complete_values(schemas: &[&Value], context: &CompletionContext) -> Vec<CompletionItem> {
+    let mut items: Vec<CompletionItem> = Vec::new();
+    for schema in schemas {
+        let mut values: Vec<(String, String)> = Vec::new();
+        if let Some(Value::Array(options)) = schema.get("enum") {
+            values.extend(
+                options
+                    .iter()
+                    .filter_map(|option| option.as_str())
+                    .map(|option| (String::from(option), quote(option, context))),
+            );
+        }
+        let allows_boolean = match schema.get("type") {
+            Some(Value::String(schema_type)) => schema_type == "boolean",
+            Some(Value::Array(types)) => types.iter().any(|t| t == "boolean"),
+            _ => false,
+        };
+        if allows_boolean && !context.in_string {
+            values.extend(["true", "false"].map(|value| (value.to_string(), value.to_string())));
+        }
+        for (label, insert_text) in values {
+            if !items.iter().any(|item| item.label == label) {
+                items.push(CompletionItem {
+                    label,
+                    kind: Some(CompletionItemKind::VALUE),
+                    insert_text: Some(insert_text),
+                    ..Default::default()
+                });
+            }
+        }
+    }
+    items
+}
+
+/////////////
+//   Ids   //
+/////////////
+
+fn is_manifest_reference(context: &CompletionContext) -> bool {
+    match context.value_key.as_deref() {
+        Some("idref") | Some("fallback") | Some("fallback_style") => !context.in_array,
+        Some("spine") => context.in_array,
+        _ => false,
+    }
+}
+
+fn complete_ids(document: &Document, context: &CompletionContext) -> Vec<CompletionItem> {
+    // Items left out of the current variant can still be referenced
+    let recipe_name = context.path.first();
+    let mut items: Vec<CompletionItem> = Vec::new();
+    for parsed in &document.recipes {
+        if recipe_name.is_some_and(|name| name != &parsed.recipe.name) {
+            continue;
+        }
+        if let Some(config) = &parsed.config {
+            for item in config.manifest.iter().chain(&config.excluded_manifest) {
+                if items.iter().any(|existing| existing.label == item.id) {
+                    continue;
+                }
+                items.push(CompletionItem {
+                    label: item.id.clone(),
+                    kind: Some(CompletionItemKind::REFERENCE),
+                    detail: Some(format!(
+                        "{} ({})",
+                        item.inside_path_from_opf, item.media_type
+                    )),
+                    insert_text: Some(quote(&item.id, context)),
+                    ..Default::default()
+                });
+            }
+        }
+    }
+    items
+}
+
+/////////////////////
+//   Entry Point   //
+/////////////////////
+
+pub(crate) fn complete(
+    document: &Document,
+    schema: &Value,
+    position: Position,
+) -> Vec<CompletionItem> {
+    let offset = position_to_offset(&document.text, position);
+    let context = match get_completion_context(&document.text[..offset]) {
+        Some(context) => context,
+        None => return Vec::new(),
+    };
+    let schemas = get_path_schemas(schema, &context.path);
+    match &context.value_key {
+        None => complete_keys(schema, &schemas),
+        Some(key) => {
+            let mut items = match is_manifest_reference(&context) {
+                true => complete_ids(document, &context),
+                false => Vec::new(),
+            };
+            items.extend(complete_values(
+                &get_key_schemas(schema, &schemas, key),
+                &context,
+            ));
+            items
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epub::epub2_config_schema;
+    use crate::lsp::documents::offset_to_position;
+    use crate::lsp::documents::tests::{open_test_document, TEST_RECIPE};
+    use crate::toml::build_config_file_schema_value;
+
+    fn complete_at_end(name: &str, text: &str) -> Vec<String> {
+        // Completes at the end of text, with the configs parsed from the test recipe, as they
+        // are kept while a recipe is being edited
+        let (mut document, _) = open_test_document(name, TEST_RECIPE);
+        document.text = String::from(text);
+        let schema =
+            build_config_file_schema_value(vec![("epub2", epub2_config_schema())]).unwrap();
+        complete(&document, &schema, offset_to_position(text, text.len()))
+            .into_iter()
+            .map(|item| item.label)
+            .collect()
+    }
+
+    #[test]
+    fn context_follows_headers_inline_tables_and_arrays() {
+        let context =
+            get_completion_context("[book]\nmanifest = [\n  { outside_path = \"a\", id = \"")
+                .unwrap();
+        assert_eq!(context.path, vec!["book", "manifest"]);
+        assert_eq!(context.value_key.as_deref(), Some("id"));
+        assert!(!context.in_array);
+        assert!(context.in_string);
+
+        let context = get_completion_context("[book.navmap]\n# a = [\"\nchildren = [").unwrap();
+        assert_eq!(context.path, vec!["book", "navmap"]);
+        assert_eq!(context.value_key.as_deref(), Some("children"));
+        assert!(context.in_array);
+        assert!(!context.in_string);
+    }
+
+    #[test]
+    fn keys_come_from_the_schema() {
+        let labels = complete_at_end("completion-keys", "[book]\nformat = \"epub2\"\n");
+        for key in ["metadata", "manifest", "spine", "navmap", "image_limits"] {
+            assert!(labels.iter().any(|label| label == key), "{:?}", labels);
+        }
+        assert!(!labels.iter().any(|label| label == "outside_path"));
+    }
+
+    #[test]
+    fn spine_entries_complete_to_manifest_ids() {
+        let text = TEST_RECIPE.replace("spine = [\"chapter\", \"style\"]\n", "spine = [\"");
+        let labels = complete_at_end("completion-ids", &text);
+        assert_eq!(labels, vec!["chapter", "style"]);
+    }
+
+    #[test]
+    fn other_values_dont_complete_to_manifest_ids() {
+        let text = TEST_RECIPE.replace(
+            "spine = [\"chapter\", \"style\"]\n",
+            "guide = [{ title = \"",
+        );
+        let labels = complete_at_end("completion-other", &text);
+        assert!(
+            !labels.iter().any(|label| label == "chapter"),
+            "{:?}",
+            labels
+        );
+    }
+}
@@ -0,0 +1,108 @@
+use crate::epub::get_manifest_item;
+use crate::lsp::completion::get_completion_context;
+use crate::lsp::documents::{get_location_range, position_to_offset, Document};
+use crate::toml::{locate_path, PathSegment};
+
+use lsp_types::{Location, Position, Url};
+
+fn get_string_at(text: &str, offset: usize) -> Option<&str> {
+    // The quoted string on the cursor's line which contains the cursor
+    let line_start = text[..offset]
+        .rfind('\n')
+        .map(|index| index + 1)
+        .unwrap_or(0);
+    let line_end = text[offset..]
+        .find('\n')
+        .map(|index| offset + index)
+        .unwrap_or(text.len());
+    let line = &text[line_start..line_end];
+    let cursor = offset - line_start;
+
+    let mut string_start: Option<(usize, char)> = None;
+    for (index, c) in line.char_indices() {
+        match string_start {
+            None if c == '"' || c == '\'' => string_start = Some((index + 1, c)),
+            None if c == '#' => return None,
+            Some((start, quote)) if c == quote => {
+                if (start..=index).contains(&cursor) {
+                    return Some(&line[start..index]);
+                }
+                string_start = None;
+            }
+            _ => (),
+        }
+    }
+    None
+}
+
+pub(crate) fn find_definition(document: &Document, position: Position) -> Option<Location> {
+    // Goes from an idref to the manifest entry it refers to
+    let offset = position_to_offset(&document.text, position);
+    let reference = get_string_at(&document.text, offset)?;
+    let recipe_name = get_completion_context(&document.text[..offset])
+        .and_then(|context| context.path.first().cloned());
+
+    let mut candidates: Vec<_> = document.recipes.iter().collect();
+    candidates.sort_by_key(|parsed| Some(&parsed.recipe.name) != recipe_name.as_ref());
+    for parsed in candidates {
+        let item = match &parsed.config {
+            Some(config) => match get_manifest_item(config, reference) {
+                Some(item) => item,
+                None => continue,
+            },
+            None => continue,
+        };
+        let entry_path = vec![
+            PathSegment::Key(String::from("manifest")),
+            PathSegment::Index(item.entry_index),
+        ];
+        let id_path = [
+            entry_path.clone(),
+            vec![PathSegment::Key(String::from("id"))],
+        ]
+        .concat();
+        let location = match item.declared_id {
+            Some(_) => locate_path(&parsed.recipe, &id_path),
+            None => None,
+        }
+        .or_else(|| locate_path(&parsed.recipe, &entry_path))?;
+        return Some(Location {
+            uri: Url::from_file_path(&location.file).ok()?,
+            range: get_location_range(&parsed.recipe, &location),
+        });
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lsp::documents::offset_to_position;
+    use crate::lsp::documents::tests::{open_test_document, TEST_RECIPE};
+
+    fn find_definition_at(document: &Document, needle: &str) -> Option<(u32, u32)> {
+        // Looks up the definition with the cursor just inside the last occurrence of needle
+        let offset = document.text.rfind(needle).unwrap() + 1;
+        find_definition(document, offset_to_position(&document.text, offset))
+            .map(|location| (location.range.start.line, location.range.start.character))
+    }
+
+    #[test]
+    fn idrefs_lead_to_declared_ids() {
+        let (document, _) = open_test_document("definition-ids", TEST_RECIPE);
+        assert_eq!(find_definition_at(&document, "\"chapter\""), Some((10, 76)));
+    }
+
+    #[test]
+    fn generated_ids_lead_to_their_entries() {
+        let (document, _) = open_test_document("definition-generated", TEST_RECIPE);
+        assert_eq!(find_definition_at(&document, "\"style\""), Some((11, 2)));
+    }
+
+    #[test]
+    fn other_strings_have_no_definition() {
+        let (document, _) = open_test_document("definition-none", TEST_RECIPE);
+        assert_eq!(find_definition_at(&document, "\"epub2\""), None);
+        assert_eq!(find_definition_at(&document, "spine"), None);
+    }
+}
@@ -0,0 +1,328 @@
+use crate::epub::{find_epub2_problems, Epub2Config};
+use crate::toml::{
+    locate_path, parse_config_with_unsaved_files, FileContents, PathSegment, Recipe, RecipeProblem,
+    SourceLocation,
+};
+
+use lsp_types::{Diagnostic, DiagnosticSeverity, Position, Range};
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+pub(crate) struct ParsedRecipe {
+    pub(crate) recipe: Recipe,
+    pub(crate) config: Option<Epub2Config>,
+}
+
+pub(crate) struct Document {
+    pub(crate) text: String,
+    // Configs are kept from earlier parses while a recipe has errors, so completion keeps working
+    // as the user types
+    pub(crate) recipes: Vec<ParsedRecipe>,
+    // Files which this document's diagnostics were last published to
+    pub(crate) diagnosed_files: Vec<PathBuf>,
+}
+
+///////////////////
+//   Positions   //
+///////////////////
+
+// Editors count columns in UTF-16 code units, while spans are byte offsets
+
+pub(crate) fn offset_to_position(text: &str, offset: usize) -> Position {
+    let mut offset = offset.min(text.len());
+    while !text.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    let before = &text[..offset];
+    let line_start = before.rfind('\n').map(|index| index + 1).unwrap_or(0);
+    Position {
+        line: before.matches('\n').count() as u32,
+        character: before[line_start..].encode_utf16().count() as u32,
+    }
+}
+
+pub(crate) fn position_to_offset(text: &str, position: Position) -> usize {
+    let mut line_start = 0;
+    for _ in 0..position.line {
+        match text[line_start..].find('\n') {
+            Some(index) => line_start += index + 1,
+            None => return text.len(),
+        }
+    }
+    let mut units = 0;
+    for (index, c) in text[line_start..].char_indices() {
+        if c == '\n' || units >= position.character as usize {
+            return line_start + index;
+        }
+        units += c.len_utf16();
+    }
+    text.len()
+}
+
+pub(crate) fn get_location_contents(recipe: &Recipe, location: &SourceLocation) -> String {
+    match recipe.file_contents.get(&location.file) {
+        Some(contents) => contents.clone(),
+        None => read_to_string(&location.file).unwrap_or_default(),
+    }
+}
+
+pub(crate) fn get_location_range(recipe: &Recipe, location: &SourceLocation) -> Range {
+    // Spans of whole tables run over many lines, so ranges stop at the end of the first one
+    let contents = get_location_contents(recipe, location);
+    let start = location.span.start.min(contents.len());
+    let first_line_end = contents[start..]
+        .find('\n')
+        .map(|index| start + index)
+        .unwrap_or(contents.len());
+    Range {
+        start: offset_to_position(&contents, start),
+        end: offset_to_position(&contents, location.span.end.min(first_line_end)),
+    }
+}
+
+/////////////////////
+//   Diagnostics   //
+/////////////////////
+
+fn find_recipe_problems(recipe: &Recipe) -> (Vec<RecipeProblem>, Option<Epub2Config>) {
+    match recipe.format.as_ref() {
+        "epub2" => find_epub2_problems(recipe),
+        _ => (
+            vec![RecipeProblem {
+                path: vec![PathSegment::Key(String::from("format"))],
+                message: format!(
+                    "Format {} not recognized in recipe {}",
+                    recipe.format, recipe.name
+                ),
+                is_warning: false,
+            }],
+            None,
+        ),
+    }
+}
+
+fn make_diagnostic(range: Range, message: String, is_warning: bool) -> Diagnostic {
+    Diagnostic {
+        range,
+        severity: Some(match is_warning {
+            true => DiagnosticSeverity::WARNING,
+            false => DiagnosticSeverity::ERROR,
+        }),
+        source: Some(String::from("bookfactory")),
+        message,
+        ..Default::default()
+    }
+}
+
+pub(crate) fn parse_document(
+    path: &Path,
+    unsaved_contents: &FileContents,
+    previous_recipes: Vec<ParsedRecipe>,
+) -> (Vec<ParsedRecipe>, Vec<(PathBuf, Diagnostic)>) {
+    // Problems which can't be located within the config files are shown at the document's start
+    let document_start = Range::default();
+    let recipes =
+        match parse_config_with_unsaved_files(path.display().to_string(), unsaved_contents) {
+            Ok(recipes) => recipes,
+            Err(e) => {
+                let diagnostic = make_diagnostic(document_start, e, false);
+                return (previous_recipes, vec![(path.to_path_buf(), diagnostic)]);
+            }
+        };
+
+    let mut previous_recipes = previous_recipes;
+    let mut parsed_recipes = Vec::new();
+    let mut diagnostics: Vec<(PathBuf, Diagnostic)> = Vec::new();
+    for recipe in recipes {
+        let (problems, config) = find_recipe_problems(&recipe);
+        for problem in problems {
+            let (file, range) =
+                match locate_path(&recipe, &problem.path).or_else(|| locate_path(&recipe, &[])) {
+                    Some(location) => (
+                        location.file.clone(),
+                        get_location_range(&recipe, &location),
+                    ),
+                    None => (path.to_path_buf(), document_start),
+                };
+            let diagnostic = make_diagnostic(range, problem.message, problem.is_warning);
+            // Problems in keys inherited through 'extends' are found once per inheriting recipe
+            if !diagnostics.contains(&(file.clone(), diagnostic.clone())) {
+                diagnostics.push((file, diagnostic));
+            }
+        }
+
+        let config = config.or_else(|| {
+            previous_recipes
+                .iter()
+                .position(|previous| {
+                    previous.recipe.name == recipe.name && previous.recipe.format == recipe.format
+                })
+                .and_then(|index| previous_recipes.swap_remove(index).config)
+        });
+        parsed_recipes.push(ParsedRecipe { recipe, config });
+    }
+    (parsed_recipes, diagnostics)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, write};
+    use std::process::id;
+
+    pub(crate) const TEST_RECIPE: &str = r#"[book]
+schema_version = 2
+format = "epub2"
+metadata = [
+  { name = "title", content = "T" },
+  { name = "language", content = "en" },
+  { name = "identifier", content = "urn:isbn:9780306406157", id = "BookId" },
+  { name = "date", content = "2020-14-01" },
+]
+manifest = [
+  { outside_path = "chapter.xhtml", inside_path_from_opf = "chapter.xhtml", id = "chapter" },
+  { outside_path = "style.css", inside_path_from_opf = "style.css" },
+]
+spine = ["chapter", "style"]
+"#;
+
+    pub(crate) fn open_test_document(
+        name: &str,
+        text: &str,
+    ) -> (Document, Vec<(PathBuf, Diagnostic)>) {
+        // Recipes are kept unsaved, as they are while being edited, but the files they list are
+        // written out for the checks to read
+        let dir = temp_dir()
+            .join(format!("bookfactory-lsp-{}", id()))
+            .join(name);
+        create_dir_all(&dir).unwrap();
+        write(
+            dir.join("chapter.xhtml"),
+            concat!(
+                r#"<?xml version="1.0" encoding="utf-8"?>"#,
+                r#"<html xmlns="http://www.w3.org/1999/xhtml"><head><title>C</title>"#,
+                r#"<link rel="stylesheet" type="text/css" href="style.css"/></head>"#,
+                r#"<body><p id="start">Text</p></body></html>"#,
+            ),
+        )
+        .unwrap();
+        write(dir.join("style.css"), "p { margin: 0; }").unwrap();
+
+        let path = dir.join("bookfactory.toml");
+        let unsaved_contents = FileContents::from([(path.clone(), String::from(text))]);
+        let (recipes, diagnostics) = parse_document(&path, &unsaved_contents, Vec::new());
+        let document = Document {
+            text: String::from(text),
+            recipes,
+            diagnosed_files: Vec::new(),
+        };
+        (document, diagnostics)
+    }
+
+    fn describe_diagnostics(diagnostics: &[(PathBuf, Diagnostic)]) -> String {
+        diagnostics
+            .iter()
+            .map(|(_, diagnostic)| {
+                format!(
+                    "{}:{}: {:?}: {}",
+                    diagnostic.range.start.line,
+                    diagnostic.range.start.character,
+                    diagnostic.severity.unwrap(),
+                    diagnostic.message
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn positions_count_utf16_code_units() {
+        let text = "a = \"é𝄞\"\nb = 1";
+        let position = offset_to_position(text, text.find('"').unwrap() + 1 + "é𝄞".len());
+        assert_eq!(position, Position::new(0, 8));
+        assert_eq!(position_to_offset(text, position), text.rfind('"').unwrap());
+        assert_eq!(
+            position_to_offset(text, Position::new(1, 4)),
+            text.len() - 1
+        );
+    }
+
+    #[test]
+    fn build_checks_are_reported_at_their_entries() {
+        let (_, diagnostics) = open_test_document("build-checks", TEST_RECIPE);
+        assert_eq!(
+            describe_diagnostics(&diagnostics),
+            [
+                "7:19: Error: dc:date \"2020-14-01\" isn't a W3CDTF date, such as 2021, 2021-03 or 2021-03-14.",
+                "13:20: Error: Spine item style has media type text/css, and doesn't fall back to XHTML or DTBook.",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn entries_left_out_of_the_variant_are_mapped_back_to_the_recipe() {
+        let text = TEST_RECIPE
+            .replace(
+                "metadata = [\n",
+                "metadata = [\n  { name = \"date\", content = \"2020\", only_in = [\"x\"] },\n",
+            )
+            .replace(
+                "spine = [",
+                "spine = [{ idref = \"chapter\", only_in = [\"x\"] }, ",
+            );
+        let (_, diagnostics) = open_test_document("variants", &text);
+        assert_eq!(
+            describe_diagnostics(&diagnostics),
+            [
+                "8:19: Error: dc:date \"2020-14-01\" isn't a W3CDTF date, such as 2021, 2021-03 or 2021-03-14.",
+                "14:60: Error: Spine item style has media type text/css, and doesn't fall back to XHTML or DTBook.",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn path_and_id_clashes_are_reported_at_their_entries() {
+        let text = TEST_RECIPE.replace("2020-14-01", "2020").replace(
+            "spine = ",
+            concat!(
+                "nonmanifest_files = [\n",
+                "  { outside_path = \"style.css\", inside_path = \"OEBPS/./Chapter.xhtml\" },\n",
+                "]\n",
+                "pagelist = [\n",
+                "  { label = \"1\", id = \"page\", type = \"normal\", idref = \"chapter\" },\n",
+                "  { label = \"2\", id = \"page\", type = \"normal\", idref = \"chapter\" },\n",
+                "]\n",
+                "spine = ",
+            ),
+        );
+        let (_, diagnostics) = open_test_document("clashes", &text);
+        assert_eq!(
+            describe_diagnostics(&diagnostics),
+            [
+                "14:32: Error: Inside path OEBPS/Chapter.xhtml is already used by manifest item chapter; inside paths must differ by more than case.",
+                "18:17: Error: Id page is used more than once in the NCX.",
+                "20:20: Error: Spine item style has media type text/css, and doesn't fall back to XHTML or DTBook.",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn unparseable_recipes_keep_their_previous_configs() {
+        let (document, _) = open_test_document("keep-config", TEST_RECIPE);
+        let path = temp_dir()
+            .join(format!("bookfactory-lsp-{}", id()))
+            .join("keep-config")
+            .join("bookfactory.toml");
+        let broken = format!("{}manifest = [", TEST_RECIPE);
+        let unsaved_contents = FileContents::from([(path.clone(), broken)]);
+        let (recipes, diagnostics) = parse_document(&path, &unsaved_contents, document.recipes);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].1.range, Range::default());
+        assert!(recipes[0].config.is_some());
+    }
+}
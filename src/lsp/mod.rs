@@ -0,0 +1,6 @@
+mod completion;
+mod definition;
+mod documents;
+mod server;
+
+pub use server::run_language_server;
@@ -0,0 +1,221 @@
+use crate::epub::epub2_config_schema;
+use crate::helpers::fixed_clean;
+use crate::lsp::completion::complete;
+use crate::lsp::definition::find_definition;
+use crate::lsp::documents::{parse_document, Document};
+use crate::toml::{build_config_file_schema_value, FileContents};
+
+use lsp_server::{Connection, Message, Notification, Request, Response};
+use lsp_types::notification::{
+    DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, DidSaveTextDocument,
+    Notification as _, PublishDiagnostics,
+};
+use lsp_types::request::{Completion, GotoDefinition, Request as _};
+use lsp_types::{
+    CompletionOptions, CompletionParams, CompletionResponse, Diagnostic,
+    DidChangeTextDocumentParams, DidCloseTextDocumentParams, DidOpenTextDocumentParams,
+    DidSaveTextDocumentParams, GotoDefinitionParams, GotoDefinitionResponse, OneOf,
+    PublishDiagnosticsParams, ServerCapabilities, TextDocumentSyncCapability, TextDocumentSyncKind,
+    Url,
+};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+struct Server {
+    connection: Connection,
+    schema: Value,
+    documents: HashMap<PathBuf, Document>,
+}
+
+fn get_path(uri: &Url) -> Result<PathBuf, String> {
+    match uri.to_file_path() {
+        Ok(path) => Ok(fixed_clean(path)),
+        Err(_) => Err(format!("Only file URIs are supported, not {}.", uri)),
+    }
+}
+
+fn parse_params<P: DeserializeOwned>(params: Value) -> Result<P, String> {
+    serde_json::from_value(params).map_err(|e| e.to_string())
+}
+
+impl Server {
+    fn send(&self, message: Message) -> Result<(), String> {
+        self.connection
+            .sender
+            .send(message)
+            .map_err(|e| e.to_string())
+    }
+
+    fn publish_diagnostics(
+        &self,
+        file: PathBuf,
+        diagnostics: Vec<Diagnostic>,
+    ) -> Result<(), String> {
+        let uri = match Url::from_file_path(&file) {
+            Ok(uri) => uri,
+            Err(_) => return Err(format!("Can't make a URI from path {}.", file.display())),
+        };
+        let params = PublishDiagnosticsParams::new(uri, diagnostics, None);
+        self.send(Message::Notification(Notification::new(
+            PublishDiagnostics::METHOD.to_string(),
+            params,
+        )))
+    }
+
+    fn refresh(&mut self) -> Result<(), String> {
+        // Every open document is reparsed, since it may include or be included by the changed one
+        let unsaved_contents: FileContents = self
+            .documents
+            .iter()
+            .map(|(path, document)| (path.clone(), document.text.clone()))
+            .collect();
+        let mut published: HashMap<PathBuf, Vec<Diagnostic>> = HashMap::new();
+        let mut cleared: Vec<PathBuf> = Vec::new();
+        for (path, document) in self.documents.iter_mut() {
+            let previous_recipes = std::mem::take(&mut document.recipes);
+            let (recipes, diagnostics) = parse_document(path, &unsaved_contents, previous_recipes);
+            document.recipes = recipes;
+            cleared.append(&mut document.diagnosed_files);
+            for (file, diagnostic) in diagnostics {
+                if !document.diagnosed_files.contains(&file) {
+                    document.diagnosed_files.push(file.clone());
+                }
+                let file_diagnostics = published.entry(file).or_default();
+                if !file_diagnostics.contains(&diagnostic) {
+                    file_diagnostics.push(diagnostic);
+                }
+            }
+        }
+
+        for file in cleared {
+            published.entry(file).or_default();
+        }
+        for (file, diagnostics) in published {
+            self.publish_diagnostics(file, diagnostics)?;
+        }
+        Ok(())
+    }
+
+    fn handle_notification(&mut self, notification: Notification) -> Result<(), String> {
+        match notification.method.as_ref() {
+            DidOpenTextDocument::METHOD => {
+                let params: DidOpenTextDocumentParams = parse_params(notification.params)?;
+                let path = get_path(&params.text_document.uri)?;
+                self.documents.insert(
+                    path,
+                    Document {
+                        text: params.text_document.text,
+                        recipes: Vec::new(),
+                        diagnosed_files: Vec::new(),
+                    },
+                );
+                self.refresh()
+            }
+            DidChangeTextDocument::METHOD => {
+                // Changes are always whole documents, as requested in the server capabilities
+                let params: DidChangeTextDocumentParams = parse_params(notification.params)?;
+                let path = get_path(&params.text_document.uri)?;
+                if let (Some(document), Some(change)) = (
+                    self.documents.get_mut(&path),
+                    params.content_changes.into_iter().last(),
+                ) {
+                    document.text = change.text;
+                }
+                self.refresh()
+            }
+            DidSaveTextDocument::METHOD => {
+                let _params: DidSaveTextDocumentParams = parse_params(notification.params)?;
+                self.refresh()
+            }
+            DidCloseTextDocument::METHOD => {
+                let params: DidCloseTextDocumentParams = parse_params(notification.params)?;
+                let path = get_path(&params.text_document.uri)?;
+                if let Some(document) = self.documents.remove(&path) {
+                    for file in document.diagnosed_files {
+                        self.publish_diagnostics(file, Vec::new())?;
+                    }
+                }
+                self.refresh()
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn handle_request(&self, request: Request) -> Result<(), String> {
+        let response = match request.method.as_ref() {
+            Completion::METHOD => {
+                let params: CompletionParams = parse_params(request.params)?;
+                let position = params.text_document_position;
+                let items = match self.documents.get(&get_path(&position.text_document.uri)?) {
+                    Some(document) => complete(document, &self.schema, position.position),
+                    None => Vec::new(),
+                };
+                Response::new_ok(request.id, CompletionResponse::Array(items))
+            }
+            GotoDefinition::METHOD => {
+                let params: GotoDefinitionParams = parse_params(request.params)?;
+                let position = params.text_document_position_params;
+                let location = self
+                    .documents
+                    .get(&get_path(&position.text_document.uri)?)
+                    .and_then(|document| find_definition(document, position.position));
+                Response::new_ok(request.id, location.map(GotoDefinitionResponse::Scalar))
+            }
+            _ => Response::new_err(
+                request.id,
+                lsp_server::ErrorCode::MethodNotFound as i32,
+                format!("Unsupported request {}.", request.method),
+            ),
+        };
+        self.send(Message::Response(response))
+    }
+}
+
+pub fn run_language_server() -> Result<(), String> {
+    let (connection, io_threads) = Connection::stdio();
+    let capabilities = ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        completion_provider: Some(CompletionOptions {
+            trigger_characters: Some(["\"", "'", "[", "{", ",", "."].map(String::from).to_vec()),
+            ..Default::default()
+        }),
+        definition_provider: Some(OneOf::Left(true)),
+        ..Default::default()
+    };
+    let capabilities = serde_json::to_value(capabilities).map_err(|e| e.to_string())?;
+    connection
+        .initialize(capabilities)
+        .map_err(|e| e.to_string())?;
+
+    let mut server = Server {
+        connection,
+        schema: build_config_file_schema_value(vec![("epub2", epub2_config_schema())])?,
+        documents: HashMap::new(),
+    };
+    // Messages are read until the client asks for a shutdown
+    while let Ok(message) = server.connection.receiver.recv() {
+        let result = match message {
+            Message::Request(request) => {
+                if server
+                    .connection
+                    .handle_shutdown(&request)
+                    .map_err(|e| e.to_string())?
+                {
+                    break;
+                }
+                server.handle_request(request)
+            }
+            Message::Notification(notification) => server.handle_notification(notification),
+            Message::Response(_) => Ok(()),
+        };
+        // A bad message shouldn't take the server down, so errors are logged to stderr
+        if let Err(e) = result {
+            eprintln!("{}", e);
+        }
+    }
+
+    drop(server);
+    io_threads.join().map_err(|e| e.to_string())
+}
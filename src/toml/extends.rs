@@ -0,0 +1,508 @@
+use std::collections::HashMap;
+use toml::value::Table;
+use toml::Value;
+
+//////////////////////////
+//   Merge Strategies   //
+//////////////////////////
+
+enum ArrayMerge {
+    Append,
+    Replace,
+    Patch(String),
+}
+
+fn parse_array_merge(recipe_name: &str, key: &str, value: &Value) -> Result<ArrayMerge, String> {
+    let (strategy, patch_key) = match value {
+        Value::String(strategy) => (strategy.as_ref(), "id"),
+        Value::Table(table) => match (table.get("strategy"), table.get("key")) {
+            (Some(Value::String(strategy)), None) => (strategy.as_ref(), "id"),
+            (Some(Value::String(strategy)), Some(Value::String(patch_key))) => {
+                (strategy.as_ref(), patch_key.as_ref())
+            }
+            _ => {
+                return Err(format!(
+                    "Merge strategy for '{}' in recipe {} must be a string or a table with a 'strategy' string and an optional 'key' string.",
+                    key, recipe_name
+                ))
+            }
+        },
+        _ => {
+            return Err(format!(
+                "Merge strategy for '{}' in recipe {} must be a string or a table.",
+                key, recipe_name
+            ))
+        }
+    };
+
+    match strategy {
+        "append" => Ok(ArrayMerge::Append),
+        "replace" => Ok(ArrayMerge::Replace),
+        "patch" => Ok(ArrayMerge::Patch(String::from(patch_key))),
+        _ => Err(format!(
+            "Unrecognized merge strategy '{}' for '{}' in recipe {}; expected 'append', 'replace' or 'patch'.",
+            strategy, key, recipe_name
+        )),
+    }
+}
+
+fn parse_merge_strategies(
+    recipe_name: &str,
+    merge: Option<Value>,
+) -> Result<Vec<(String, ArrayMerge)>, String> {
+    match merge {
+        None => Ok(Vec::new()),
+        Some(Value::Table(table)) => table
+            .iter()
+            .map(|(key, value)| Ok((key.clone(), parse_array_merge(recipe_name, key, value)?)))
+            .collect(),
+        Some(_) => Err(format!(
+            "Recipe {} has a non-table 'merge' value.",
+            recipe_name
+        )),
+    }
+}
+
+/////////////////
+//   Origins   //
+/////////////////
+
+// Where an element of a merged array was written: the recipe whose own table held it, the keys
+// leading to the array within that table, and the element's index there
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct ElementOrigin {
+    pub(crate) recipe: String,
+    pub(crate) path: Vec<String>,
+    pub(crate) index: usize,
+}
+
+// For each array reached through tables alone, where each of its elements came from, nearest first;
+// an element patched by a child recipe came from both the child and its parent
+pub(crate) type ArrayOrigins = HashMap<Vec<String>, Vec<Vec<ElementOrigin>>>;
+
+fn record_origins(
+    table: &Table,
+    recipe_name: &str,
+    path: &mut Vec<String>,
+    origins: &mut ArrayOrigins,
+) {
+    for (key, value) in table {
+        path.push(key.clone());
+        match value {
+            Value::Array(array) => {
+                let elements = (0..array.len())
+                    .map(|index| {
+                        vec![ElementOrigin {
+                            recipe: String::from(recipe_name),
+                            path: path.clone(),
+                            index,
+                        }]
+                    })
+                    .collect();
+                origins.insert(path.clone(), elements);
+            }
+            Value::Table(table) => record_origins(table, recipe_name, path, origins),
+            _ => (),
+        }
+        path.pop();
+    }
+}
+
+#[derive(Default)]
+struct MergeOrigins {
+    // Keys leading to the tables being merged
+    path: Vec<String>,
+    // Origins of the parent's arrays, updated as the child's are merged into them
+    merged: ArrayOrigins,
+    child: ArrayOrigins,
+}
+
+impl MergeOrigins {
+    fn take_child_value(&mut self) {
+        // Everything under the current path now comes from the child
+        let path = &self.path;
+        self.merged
+            .retain(|array_path, _| !array_path.starts_with(path));
+        self.merged.extend(
+            self.child
+                .iter()
+                .filter(|(array_path, _)| array_path.starts_with(path))
+                .map(|(array_path, elements)| (array_path.clone(), elements.clone())),
+        );
+    }
+}
+
+/////////////////
+//   Merging   //
+/////////////////
+
+fn patch_array(
+    mut parent: Vec<Value>,
+    child: Vec<Value>,
+    patch_key: &str,
+    origins: &mut Vec<Vec<ElementOrigin>>,
+    child_origins: Vec<Vec<ElementOrigin>>,
+) -> Vec<Value> {
+    let mut child_origins = child_origins.into_iter();
+    for child_item in child {
+        let child_origin = child_origins.next().unwrap_or_default();
+        let parent_index = match &child_item {
+            Value::Table(child_table) => match child_table.get(patch_key) {
+                Some(child_key_value) => parent.iter().position(|parent_item| match parent_item {
+                    Value::Table(parent_table) => {
+                        parent_table.get(patch_key) == Some(child_key_value)
+                    }
+                    _ => false,
+                }),
+                None => None,
+            },
+            _ => None,
+        };
+        match (parent_index, child_item) {
+            (Some(index), Value::Table(child_table)) => {
+                if let Value::Table(parent_table) = parent.remove(index) {
+                    parent.insert(
+                        index,
+                        Value::Table(merge_tables(
+                            parent_table,
+                            child_table,
+                            &[],
+                            &mut MergeOrigins::default(),
+                        )),
+                    );
+                }
+                if let Some(origin) = origins.get_mut(index) {
+                    origin.splice(0..0, child_origin);
+                }
+            }
+            (_, child_item) => {
+                parent.push(child_item);
+                origins.push(child_origin);
+            }
+        }
+    }
+    parent
+}
+
+fn merge_tables(
+    mut parent: Table,
+    child: Table,
+    strategies: &[(String, ArrayMerge)],
+    origins: &mut MergeOrigins,
+) -> Table {
+    for (key, child_value) in child {
+        origins.path.push(key.clone());
+        let merged_value = match (parent.remove(&key), child_value) {
+            (Some(Value::Table(parent_table)), Value::Table(child_table)) => {
+                Value::Table(merge_tables(parent_table, child_table, &[], origins))
+            }
+            (Some(Value::Array(mut parent_array)), Value::Array(child_array)) => {
+                let child_origins = origins
+                    .child
+                    .get(&origins.path)
+                    .cloned()
+                    .unwrap_or_default();
+                match strategies
+                    .iter()
+                    .find(|(strategy_key, _)| strategy_key == &key)
+                {
+                    Some((_, ArrayMerge::Append)) => {
+                        parent_array.extend(child_array);
+                        origins
+                            .merged
+                            .entry(origins.path.clone())
+                            .or_default()
+                            .extend(child_origins);
+                        Value::Array(parent_array)
+                    }
+                    Some((_, ArrayMerge::Patch(patch_key))) => {
+                        let array_origins = origins.merged.entry(origins.path.clone()).or_default();
+                        Value::Array(patch_array(
+                            parent_array,
+                            child_array,
+                            patch_key,
+                            array_origins,
+                            child_origins,
+                        ))
+                    }
+                    Some((_, ArrayMerge::Replace)) | None => {
+                        origins.take_child_value();
+                        Value::Array(child_array)
+                    }
+                }
+            }
+            (_, child_value) => {
+                origins.take_child_value();
+                child_value
+            }
+        };
+        origins.path.pop();
+        parent.insert(key, merged_value);
+    }
+    parent
+}
+
+/////////////////////
+//   Inheritance   //
+/////////////////////
+
+#[derive(Clone)]
+pub(crate) struct ResolvedRecipe {
+    pub(crate) table: Table,
+    // Where the elements of the table's arrays were written
+    pub(crate) origins: ArrayOrigins,
+    // The recipe followed by the recipes it extends, nearest first
+    pub(crate) extends_chain: Vec<String>,
+}
+
+fn resolve_recipe_table(
+    top_level_table: &Table,
+    name: &str,
+    chain: &mut Vec<String>,
+) -> Result<ResolvedRecipe, String> {
+    if chain.iter().any(|link| link == name) {
+        return Err(format!(
+            "Recipe inheritance cycle: {} -> {}",
+            chain.join(" -> "),
+            name
+        ));
+    }
+    let mut recipe_table = match top_level_table.get(name) {
+        Some(Value::Table(table)) => table.clone(),
+        Some(_) => {
+            return Err(format!(
+                "Recipe {} extends {}, which is not a recipe table.",
+                chain.last().map(|s| s.as_ref()).unwrap_or(name),
+                name
+            ))
+        }
+        None => {
+            return Err(format!(
+                "Recipe {} extends unknown recipe {}.",
+                chain.last().map(|s| s.as_ref()).unwrap_or(name),
+                name
+            ))
+        }
+    };
+
+    chain.push(String::from(name));
+    let merge = recipe_table.remove("merge");
+    let extends = recipe_table.remove("extends");
+    let mut own_origins = ArrayOrigins::new();
+    record_origins(&recipe_table, name, &mut Vec::new(), &mut own_origins);
+    let resolved = match extends {
+        None => ResolvedRecipe {
+            table: recipe_table,
+            origins: own_origins,
+            extends_chain: vec![String::from(name)],
+        },
+        Some(Value::String(parent_name)) => {
+            let strategies = parse_merge_strategies(name, merge)?;
+            let parent = resolve_recipe_table(top_level_table, &parent_name, chain)?;
+            let mut origins = MergeOrigins {
+                path: Vec::new(),
+                merged: parent.origins,
+                child: own_origins,
+            };
+            let table = merge_tables(parent.table, recipe_table, &strategies, &mut origins);
+            ResolvedRecipe {
+                table,
+                origins: origins.merged,
+                extends_chain: [vec![String::from(name)], parent.extends_chain].concat(),
+            }
+        }
+        Some(_) => return Err(format!("Recipe {} has a non-string 'extends' value.", name)),
+    };
+    chain.pop();
+
+    Ok(resolved)
+}
+
+pub(crate) fn resolve_extends(
+    top_level_table: &Table,
+    name: &str,
+) -> Result<ResolvedRecipe, String> {
+    resolve_recipe_table(top_level_table, name, &mut Vec::new())
+}
+
+pub(crate) fn apply_overrides(
+    base: ResolvedRecipe,
+    mut overrides: Table,
+    format: &str,
+    recipe_name: &str,
+) -> Result<ResolvedRecipe, String> {
+    // Per-format overrides merge into the recipe the same way a child recipe merges into its parent;
+    // their arrays' origins were recorded under the 'overrides' key
+    let strategies = parse_merge_strategies(recipe_name, overrides.remove("merge"))?;
+    let overrides_path = [String::from("overrides"), String::from(format)];
+    let (override_origins, base_origins) = base
+        .origins
+        .into_iter()
+        .partition::<ArrayOrigins, _>(|(path, _)| path.starts_with(&overrides_path[..1]));
+    let mut origins = MergeOrigins {
+        path: Vec::new(),
+        merged: base_origins,
+        child: override_origins
+            .into_iter()
+            .filter(|(path, _)| path.starts_with(&overrides_path))
+            .map(|(path, elements)| (path[2..].to_vec(), elements))
+            .collect(),
+    };
+    let table = merge_tables(base.table, overrides, &strategies, &mut origins);
+    Ok(ResolvedRecipe {
+        table,
+        origins: origins.merged,
+        extends_chain: base.extends_chain,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(config: &str, name: &str) -> Result<Table, String> {
+        resolve_extends(&toml::from_str(config).unwrap(), name).map(|resolved| resolved.table)
+    }
+
+    fn get_strings(table: &Table, key: &str) -> Vec<String> {
+        table[key]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|value| match value {
+                Value::String(value) => value.clone(),
+                value => value["name"].as_str().unwrap().to_string(),
+            })
+            .collect()
+    }
+
+    const BASE: &str = r#"
+        [base]
+        format = "epub2"
+        spine = ["cover", "chapter_1"]
+        metadata = [
+          { name = "title", content = "Book" },
+          { name = "language", content = "en" },
+          { name = "identifier", content = "urn:isbn:9780306406157", id = "BookId" },
+        ]
+        ncx_meta = { manifest_id = "ncx", manifest_path_from_opf = "toc.ncx" }
+    "#;
+
+    #[test]
+    fn arrays_are_replaced_by_default() {
+        let config = format!(
+            "{}\n[child]\nextends = \"base\"\nspine = [\"chapter_1\"]",
+            BASE
+        );
+        let table = resolve(&config, "child").unwrap();
+        assert_eq!(get_strings(&table, "spine"), vec!["chapter_1"]);
+        assert_eq!(table["format"].as_str(), Some("epub2"));
+        assert!(!table.contains_key("extends"));
+    }
+
+    #[test]
+    fn arrays_can_be_replaced_explicitly_or_appended() {
+        let config = format!(
+            "{}\n[child]\nextends = \"base\"\nmerge = {{ spine = \"append\", metadata = \"replace\" }}\nspine = [\"afterword\"]\nmetadata = [{{ name = \"title\", content = \"Other\" }}]",
+            BASE
+        );
+        let table = resolve(&config, "child").unwrap();
+        assert_eq!(
+            get_strings(&table, "spine"),
+            vec!["cover", "chapter_1", "afterword"]
+        );
+        assert_eq!(get_strings(&table, "metadata"), vec!["title"]);
+        assert!(!table.contains_key("merge"));
+    }
+
+    #[test]
+    fn arrays_can_be_patched_by_key() {
+        let config = format!(
+            "{}\n[child]\nextends = \"base\"\nmerge = {{ metadata = {{ strategy = \"patch\", key = \"name\" }} }}\nmetadata = [{{ name = \"title\", content = \"Other\" }}, {{ name = \"date\", content = \"2021\" }}]",
+            BASE
+        );
+        let table = resolve(&config, "child").unwrap();
+        assert_eq!(
+            get_strings(&table, "metadata"),
+            vec!["title", "language", "identifier", "date"]
+        );
+        assert_eq!(table["metadata"][0]["content"].as_str(), Some("Other"));
+        assert_eq!(table["metadata"][2]["id"].as_str(), Some("BookId"));
+    }
+
+    #[test]
+    fn tables_are_merged_key_by_key() {
+        let config = format!(
+            "{}\n[child]\nextends = \"base\"\nncx_meta = {{ manifest_id = \"toc\" }}",
+            BASE
+        );
+        let ncx_meta = resolve(&config, "child").unwrap()["ncx_meta"].clone();
+        assert_eq!(ncx_meta["manifest_id"].as_str(), Some("toc"));
+        assert_eq!(ncx_meta["manifest_path_from_opf"].as_str(), Some("toc.ncx"));
+    }
+
+    #[test]
+    fn extends_chains_apply_in_order() {
+        let config = format!(
+            "{}\n[middle]\nextends = \"base\"\nmerge = {{ spine = \"append\" }}\nspine = [\"chapter_2\"]\n\n[child]\nextends = \"middle\"\nmerge = {{ spine = \"append\" }}\nformat = \"epub3\"\nspine = [\"chapter_3\"]",
+            BASE
+        );
+        let resolved = resolve_extends(&toml::from_str(&config).unwrap(), "child").unwrap();
+        assert_eq!(
+            get_strings(&resolved.table, "spine"),
+            vec!["cover", "chapter_1", "chapter_2", "chapter_3"]
+        );
+        assert_eq!(resolved.table["format"].as_str(), Some("epub3"));
+        assert_eq!(resolved.extends_chain, vec!["child", "middle", "base"]);
+    }
+
+    #[test]
+    fn cycles_are_errors() {
+        let config = "[a]\nextends = \"b\"\n\n[b]\nextends = \"c\"\n\n[c]\nextends = \"a\"";
+        assert_eq!(
+            resolve(config, "a"),
+            Err(String::from("Recipe inheritance cycle: a -> b -> c -> a"))
+        );
+        assert_eq!(
+            resolve("[a]\nextends = \"a\"", "a"),
+            Err(String::from("Recipe inheritance cycle: a -> a"))
+        );
+    }
+
+    #[test]
+    fn bad_parents_and_strategies_are_errors() {
+        assert_eq!(
+            resolve("[a]\nextends = \"missing\"", "a"),
+            Err(String::from("Recipe a extends unknown recipe missing."))
+        );
+        assert_eq!(
+            resolve("vars = 1\n[a]\nextends = \"vars\"", "a"),
+            Err(String::from(
+                "Recipe a extends vars, which is not a recipe table."
+            ))
+        );
+        assert_eq!(
+            resolve(
+                &format!("{}\n[a]\nextends = \"base\"\nmerge = {{ spine = \"prepend\" }}", BASE),
+                "a"
+            ),
+            Err(String::from(
+                "Unrecognized merge strategy 'prepend' for 'spine' in recipe a; expected 'append', 'replace' or 'patch'."
+            ))
+        );
+    }
+
+    #[test]
+    fn overrides_merge_like_children() {
+        let base = resolve_extends(&toml::from_str(BASE).unwrap(), "base").unwrap();
+        let overrides: Table =
+            toml::from_str("merge = { spine = \"append\" }\nspine = [\"notes\"]").unwrap();
+        let table = apply_overrides(base, overrides, "epub2", "base")
+            .unwrap()
+            .table;
+        assert_eq!(
+            get_strings(&table, "spine"),
+            vec!["cover", "chapter_1", "notes"]
+        );
+    }
+}
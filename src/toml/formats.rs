@@ -0,0 +1,85 @@
+use std::path::Path;
+use toml::value::Table;
+use toml::Value;
+
+// JSON and YAML configs are read through serde_json's value tree and converted into the same
+// TOML value tree that TOML configs produce, so everything downstream is format-neutral.
+
+fn json_to_toml(value: serde_json::Value, path: &Path) -> Result<Option<Value>, String> {
+    Ok(match value {
+        serde_json::Value::Null => None,
+        serde_json::Value::Bool(boolean) => Some(Value::Boolean(boolean)),
+        serde_json::Value::Number(number) => match number.as_i64() {
+            Some(integer) => Some(Value::Integer(integer)),
+            None => match number.as_f64() {
+                Some(float) => Some(Value::Float(float)),
+                None => {
+                    return Err(format!(
+                        "Number {} in config file {} is out of range.",
+                        number,
+                        path.display()
+                    ))
+                }
+            },
+        },
+        serde_json::Value::String(string) => Some(Value::String(string)),
+        serde_json::Value::Array(array) => {
+            let mut toml_array = Vec::new();
+            for item in array {
+                match json_to_toml(item, path)? {
+                    Some(toml_item) => toml_array.push(toml_item),
+                    None => {
+                        return Err(format!(
+                            "Config file {} contains a null array entry.",
+                            path.display()
+                        ))
+                    }
+                }
+            }
+            Some(Value::Array(toml_array))
+        }
+        serde_json::Value::Object(object) => {
+            // Null-valued keys are treated as absent
+            let mut table = Table::new();
+            for (key, item) in object {
+                if let Some(toml_item) = json_to_toml(item, path)? {
+                    table.insert(key, toml_item);
+                }
+            }
+            Some(Value::Table(table))
+        }
+    })
+}
+
+pub(crate) fn parse_config_file(path: &Path, contents: &str) -> Result<Table, String> {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_lowercase());
+    let tree = match extension.as_deref() {
+        Some("json") => json_to_toml(
+            serde_json::from_str(contents).map_err(|e| {
+                format!("Failed to parse JSON config file {}: {}", path.display(), e)
+            })?,
+            path,
+        )?,
+        Some("yaml") | Some("yml") => json_to_toml(
+            serde_yaml::from_str(contents).map_err(|e| {
+                format!("Failed to parse YAML config file {}: {}", path.display(), e)
+            })?,
+            path,
+        )?,
+        _ => Some(
+            toml::from_str(contents)
+                .map_err(|e| format!("Failed to parse config file {}: {}", path.display(), e))?,
+        ),
+    };
+
+    match tree {
+        Some(Value::Table(table)) => Ok(table),
+        _ => Err(format!(
+            "Config file {} does not have a table or object at its top level.",
+            path.display()
+        )),
+    }
+}
@@ -0,0 +1,269 @@
+use crate::helpers::fixed_clean;
+use crate::toml::formats::parse_config_file;
+use crate::toml::migrate::upgrade_file_table;
+
+use std::collections::{HashMap, HashSet};
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use toml::value::Table;
+use toml::Value;
+
+// Maps recipe names ("retail") and recipe keys ("retail.manifest") to the files that defined them
+pub(crate) type KeySources = HashMap<String, Vec<PathBuf>>;
+
+// Contents of each loaded config file, which may come from an editor's unsaved buffers
+pub(crate) type FileContents = HashMap<PathBuf, String>;
+
+pub(crate) fn describe_files(files: &[PathBuf]) -> String {
+    files
+        .iter()
+        .map(|file| file.display().to_string())
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+fn merge_file_table(
+    into: &mut Table,
+    from: Table,
+    file: &Path,
+    sources: &mut KeySources,
+    prefix: &[String],
+) -> Result<(), String> {
+    for (key, value) in from {
+        let mut key_path = prefix.to_vec();
+        key_path.push(key.clone());
+        let source_key = key_path[..key_path.len().min(2)].join(".");
+        let files = sources.entry(source_key.clone()).or_default();
+        if !files.iter().any(|existing| existing == file) {
+            files.push(file.to_path_buf());
+        }
+
+        if let (None, Value::Table(_)) = (into.get(&key), &value) {
+            into.insert(key.clone(), Value::Table(Table::new()));
+        }
+        match (into.get_mut(&key), value) {
+            (None, value) => {
+                into.insert(key, value);
+            }
+            (Some(Value::Table(existing_table)), Value::Table(new_table)) => {
+                merge_file_table(existing_table, new_table, file, sources, &key_path)?
+            }
+            (Some(Value::Array(existing_array)), Value::Array(new_array)) => {
+                existing_array.extend(new_array)
+            }
+            (Some(_), _) => {
+                let earlier_files: Vec<PathBuf> = sources[&source_key]
+                    .iter()
+                    .filter(|existing| existing.as_path() != file)
+                    .cloned()
+                    .collect();
+                return Err(match earlier_files.is_empty() {
+                    true => format!(
+                        "Key {} is defined more than once in config file {}.",
+                        key_path.join("."),
+                        file.display()
+                    ),
+                    false => format!(
+                        "Key {} in config file {} is already defined in {}.",
+                        key_path.join("."),
+                        file.display(),
+                        describe_files(&earlier_files)
+                    ),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+struct ConfigTree<'a> {
+    table: Table,
+    sources: KeySources,
+    contents: FileContents,
+    unsaved_contents: &'a FileContents,
+    // Files being loaded, outermost first, to catch include cycles
+    include_stack: Vec<PathBuf>,
+    // Files already loaded, so one included by several others is merged only once
+    loaded: HashSet<PathBuf>,
+}
+
+fn load_into(path: &Path, tree: &mut ConfigTree) -> Result<(), String> {
+    let unsaved_file = tree.unsaved_contents.get(&fixed_clean(path));
+    let canonical_path = match (path.canonicalize(), unsaved_file) {
+        (Ok(canonical_path), _) => canonical_path,
+        (Err(_), Some(_)) => fixed_clean(path),
+        (Err(e), None) => {
+            return Err(format!(
+                "Failed to read config file {}: {}",
+                path.display(),
+                e
+            ))
+        }
+    };
+    if tree.include_stack.contains(&canonical_path) {
+        return Err(format!(
+            "Config file {} includes itself, directly or indirectly.",
+            path.display()
+        ));
+    }
+    if !tree.loaded.insert(canonical_path.clone()) {
+        return Ok(());
+    }
+    tree.include_stack.push(canonical_path);
+
+    let file = match unsaved_file {
+        Some(file) => file.clone(),
+        None => read_to_string(path)
+            .map_err(|e| format!("Failed to read config file {}: {}", path.display(), e))?,
+    };
+    let mut file_table = parse_config_file(path, &file)?;
+    upgrade_file_table(&mut file_table, path)?;
+    tree.contents.insert(path.to_path_buf(), file);
+
+    let includes = match file_table.remove("include") {
+        None => Vec::new(),
+        Some(Value::String(include)) => vec![include],
+        Some(Value::Array(includes)) => includes
+            .into_iter()
+            .map(|include| match include {
+                Value::String(include) => Ok(include),
+                _ => Err(format!(
+                    "Config file {} has a non-string 'include' entry.",
+                    path.display()
+                )),
+            })
+            .collect::<Result<Vec<String>, String>>()?,
+        Some(_) => {
+            return Err(format!(
+                "Config file {} has an 'include' value which is neither a string nor an array of strings.",
+                path.display()
+            ))
+        }
+    };
+    let parent_dir = path.parent().unwrap_or(Path::new(""));
+    for include in includes {
+        load_into(&parent_dir.join(include), tree)?;
+    }
+
+    merge_file_table(&mut tree.table, file_table, path, &mut tree.sources, &[])?;
+    tree.include_stack.pop();
+
+    Ok(())
+}
+
+pub(crate) fn load_config_tree(
+    path: &Path,
+    unsaved_contents: &FileContents,
+) -> Result<(Table, KeySources, FileContents), String> {
+    let mut tree = ConfigTree {
+        table: Table::new(),
+        sources: KeySources::new(),
+        contents: FileContents::new(),
+        unsaved_contents,
+        include_stack: Vec::new(),
+        loaded: HashSet::new(),
+    };
+    load_into(path, &mut tree)?;
+    Ok((tree.table, tree.sources, tree.contents))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, write};
+    use std::process::id;
+
+    fn load(name: &str, files: &[(&str, &str)]) -> Result<Table, String> {
+        let dir = temp_dir().join(format!("bookfactory-include-{}-{}", name, id()));
+        create_dir_all(&dir).unwrap();
+        for (file, contents) in files {
+            write(dir.join(file), contents).unwrap();
+        }
+        load_config_tree(&dir.join(files[0].0), &FileContents::new())
+            .map(|(table, _, _)| table)
+            .map_err(|e| e.replace(&format!("{}/", dir.display()), ""))
+    }
+
+    #[test]
+    fn nested_includes_are_merged() {
+        let table = load(
+            "nested",
+            &[
+                (
+                    "main.toml",
+                    "include = \"a.toml\"\n[book]\nformat = \"epub2\"\n",
+                ),
+                ("a.toml", "include = \"b.toml\"\n[book]\nspine = [\"a\"]\n"),
+                ("b.toml", "[book]\nspine = [\"b\"]\n"),
+            ],
+        )
+        .unwrap();
+        let book = table["book"].as_table().unwrap();
+        assert_eq!(book["format"].as_str(), Some("epub2"));
+        let spine: Vec<&str> = book["spine"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|value| value.as_str().unwrap())
+            .collect();
+        assert_eq!(spine, vec!["b", "a"]);
+    }
+
+    #[test]
+    fn file_included_twice_is_loaded_once() {
+        let table = load(
+            "repeated",
+            &[
+                ("main.toml", "include = [\"a.toml\", \"b.toml\"]\n"),
+                ("a.toml", "include = \"common.toml\"\n[vars]\na = \"A\"\n"),
+                ("b.toml", "include = \"./common.toml\"\n[vars]\nb = \"B\"\n"),
+                (
+                    "common.toml",
+                    "[vars]\npublisher = \"P\"\n[book]\nspine = [\"c\"]\n",
+                ),
+            ],
+        )
+        .unwrap();
+        assert_eq!(table["vars"]["publisher"].as_str(), Some("P"));
+        assert_eq!(table["vars"]["b"].as_str(), Some("B"));
+        assert_eq!(table["book"]["spine"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn key_defined_in_two_files_names_both() {
+        let error = load(
+            "conflict",
+            &[
+                (
+                    "main.toml",
+                    "include = \"a.toml\"\n[vars]\npublisher = \"M\"\n",
+                ),
+                ("a.toml", "[vars]\npublisher = \"A\"\n"),
+            ],
+        )
+        .unwrap_err();
+        assert_eq!(
+            error,
+            "Key vars.publisher in config file main.toml is already defined in a.toml."
+        );
+    }
+
+    #[test]
+    fn include_cycles_are_reported() {
+        let error = load(
+            "cycle",
+            &[
+                ("main.toml", "include = \"a.toml\"\n"),
+                ("a.toml", "include = \"b.toml\"\n"),
+                ("b.toml", "include = \"a.toml\"\n"),
+            ],
+        )
+        .unwrap_err();
+        assert_eq!(
+            error,
+            "Config file a.toml includes itself, directly or indirectly."
+        );
+    }
+}
@@ -0,0 +1,347 @@
+use crate::toml::extends::ElementOrigin;
+use crate::toml::strict::PathSegment;
+use crate::toml::Recipe;
+
+use regex::Regex;
+use std::fs::read_to_string;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use toml_edit::{ImDocument, Item, TableLike};
+
+pub(crate) struct SourceLocation {
+    pub(crate) file: PathBuf,
+    pub(crate) line: usize,
+    pub(crate) column: usize,
+    // Byte offsets of the located key or entry within the file
+    pub(crate) span: Range<usize>,
+}
+
+// A path with array indices dropped, for searching files whose parsers don't keep positions
+#[derive(Clone, Copy)]
+enum SearchSegment<'a> {
+    Key(&'a str),
+    Element,
+}
+
+fn to_search_path(path: &[PathSegment]) -> Vec<SearchSegment<'_>> {
+    path.iter()
+        .map(|segment| match segment {
+            PathSegment::Key(key) => SearchSegment::Key(key),
+            PathSegment::Index(_) => SearchSegment::Element,
+        })
+        .collect()
+}
+
+///////////////////////
+//   Merged Recipe   //
+///////////////////////
+
+fn count_matches(value: &toml::Value, search_path: &[SearchSegment]) -> usize {
+    match (search_path.first(), value) {
+        (None, _) => 1,
+        (Some(SearchSegment::Key(key)), toml::Value::Table(table)) => match table.get(*key) {
+            Some(child) => count_matches(child, &search_path[1..]),
+            None => 0,
+        },
+        (Some(SearchSegment::Element), toml::Value::Array(array)) => array
+            .iter()
+            .map(|child| count_matches(child, &search_path[1..]))
+            .sum(),
+        _ => 0,
+    }
+}
+
+fn get_ordinal(value: &toml::Value, path: &[PathSegment]) -> usize {
+    // How many nodes with the same index-free path come before this one in the merged recipe
+    match (path.first(), value) {
+        (Some(PathSegment::Key(key)), toml::Value::Table(table)) => match table.get(key) {
+            Some(child) => get_ordinal(child, &path[1..]),
+            None => 0,
+        },
+        (Some(PathSegment::Index(index)), toml::Value::Array(array)) => {
+            let rest = to_search_path(&path[1..]);
+            let earlier: usize = array[..(*index).min(array.len())]
+                .iter()
+                .map(|child| count_matches(child, &rest))
+                .sum();
+            match array.get(*index) {
+                Some(child) => earlier + get_ordinal(child, &path[1..]),
+                None => earlier,
+            }
+        }
+        _ => 0,
+    }
+}
+
+//////////////////////
+//   Config Files   //
+//////////////////////
+
+fn read_config_file(recipe: &Recipe, file: &Path) -> Option<String> {
+    match recipe.file_contents.get(file) {
+        Some(contents) => Some(contents.clone()),
+        None => read_to_string(file).ok(),
+    }
+}
+
+fn get_line_and_column(contents: &str, offset: usize) -> (usize, usize) {
+    let before = &contents[..offset.min(contents.len())];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map(|index| index + 1).unwrap_or(0);
+    (line, before[line_start..].chars().count() + 1)
+}
+
+fn to_location(file: &Path, contents: &str, span: Range<usize>) -> SourceLocation {
+    let (line, column) = get_line_and_column(contents, span.start);
+    SourceLocation {
+        file: file.to_path_buf(),
+        line,
+        column,
+        span,
+    }
+}
+
+fn is_toml_file(file: &Path) -> bool {
+    !matches!(
+        file.extension().and_then(|extension| extension.to_str()),
+        Some("json") | Some("yaml") | Some("yml")
+    )
+}
+
+////////////////////
+//   TOML Files   //
+////////////////////
+
+fn get_item<'a>(table: &'a dyn TableLike, keys: &[String]) -> Option<&'a Item> {
+    let (first, rest) = keys.split_first()?;
+    let item = table.get(first)?;
+    match rest.is_empty() {
+        true => Some(item),
+        false => get_item(item.as_table_like()?, rest),
+    }
+}
+
+fn find_table_span(table: &dyn TableLike, path: &[PathSegment]) -> Option<Range<usize>> {
+    match path.split_first()? {
+        (PathSegment::Key(key), []) => table.get_key_value(key)?.0.span(),
+        (PathSegment::Key(key), rest) => find_item_span(table.get(key)?, rest),
+        (PathSegment::Index(_), _) => None,
+    }
+}
+
+fn find_item_span(item: &Item, path: &[PathSegment]) -> Option<Range<usize>> {
+    match path.split_first()? {
+        (PathSegment::Key(_), _) => find_table_span(item.as_table_like()?, path),
+        (PathSegment::Index(index), rest) => match item.as_array_of_tables() {
+            Some(array_of_tables) => {
+                let table = array_of_tables.get(*index)?;
+                match rest.is_empty() {
+                    true => table.span(),
+                    false => find_table_span(table, rest),
+                }
+            }
+            None => {
+                let value = item.as_array()?.get(*index)?;
+                match rest.is_empty() {
+                    true => value.span(),
+                    false => find_table_span(value.as_inline_table()?, rest),
+                }
+            }
+        },
+    }
+}
+
+fn get_toml_files<'a>(
+    recipe: &'a Recipe,
+    recipe_name: &str,
+    key: Option<&str>,
+) -> Vec<&'a PathBuf> {
+    // Files which defined the key in the named recipe's own table, in the order they were merged
+    let source_key = match key {
+        Some(key) => format!("{}.{}", recipe_name, key),
+        None => String::from(recipe_name),
+    };
+    recipe
+        .key_sources
+        .get(&source_key)
+        .into_iter()
+        .flatten()
+        .filter(|file| is_toml_file(file))
+        .collect()
+}
+
+fn find_in_recipe(
+    recipe: &Recipe,
+    recipe_name: &str,
+    path: &[PathSegment],
+) -> Option<SourceLocation> {
+    let key = match path.first() {
+        Some(PathSegment::Key(key)) => Some(key.as_str()),
+        _ => None,
+    };
+    let mut full_path = vec![PathSegment::Key(String::from(recipe_name))];
+    full_path.extend(path.iter().cloned());
+    get_toml_files(recipe, recipe_name, key)
+        .into_iter()
+        .find_map(|file| {
+            let contents = read_config_file(recipe, file)?;
+            let document = ImDocument::parse(contents.as_str()).ok()?;
+            let span = find_table_span(document.as_table(), &full_path)?;
+            Some(to_location(file, &contents, span))
+        })
+}
+
+fn find_element(
+    recipe: &Recipe,
+    origin: &ElementOrigin,
+    rest: &[PathSegment],
+) -> Option<SourceLocation> {
+    // A recipe's own array is the arrays of each file defining it appended in the order the files
+    // were merged
+    let mut array_keys = vec![origin.recipe.clone()];
+    array_keys.extend(origin.path.iter().cloned());
+    let mut index = origin.index;
+    for file in get_toml_files(
+        recipe,
+        &origin.recipe,
+        origin.path.first().map(|key| key.as_str()),
+    ) {
+        let contents = match read_config_file(recipe, file) {
+            Some(contents) => contents,
+            None => continue,
+        };
+        let document = match ImDocument::parse(contents.as_str()) {
+            Ok(document) => document,
+            Err(_) => continue,
+        };
+        let item = match get_item(document.as_table(), &array_keys) {
+            Some(item) => item,
+            None => continue,
+        };
+        let length = match item.as_array_of_tables() {
+            Some(array_of_tables) => array_of_tables.len(),
+            None => item.as_array().map(|array| array.len()).unwrap_or(0),
+        };
+        if index < length {
+            let mut element_path = vec![PathSegment::Index(index)];
+            element_path.extend(rest.iter().cloned());
+            let span = find_item_span(item, &element_path)?;
+            return Some(to_location(file, &contents, span));
+        }
+        index -= length;
+    }
+    None
+}
+
+fn find_toml_location(recipe: &Recipe, path: &[PathSegment]) -> Option<SourceLocation> {
+    // Array elements are looked for where they were written, trying the nearest of the recipes
+    // which contributed to a patched element first
+    let array_end = path
+        .iter()
+        .position(|segment| matches!(segment, PathSegment::Index(_)));
+    if let Some(array_end) = array_end {
+        let keys: Vec<String> = path[..array_end]
+            .iter()
+            .filter_map(|segment| match segment {
+                PathSegment::Key(key) => Some(key.clone()),
+                PathSegment::Index(_) => None,
+            })
+            .collect();
+        let origins = match &path[array_end] {
+            PathSegment::Index(index) => recipe
+                .array_origins
+                .get(&keys)
+                .and_then(|elements| elements.get(*index)),
+            PathSegment::Key(_) => None,
+        };
+        if let Some(origins) = origins {
+            return origins
+                .iter()
+                .find_map(|origin| find_element(recipe, origin, &path[array_end + 1..]));
+        }
+    }
+
+    // Other keys are looked for in the recipe's overrides for its format, which take precedence,
+    // then in each recipe of the 'extends' chain, nearest first
+    let mut override_path = vec![
+        PathSegment::Key(String::from("overrides")),
+        PathSegment::Key(recipe.format.clone()),
+    ];
+    override_path.extend(path.iter().cloned());
+    [override_path.as_slice(), path]
+        .iter()
+        .find_map(|candidate_path| {
+            recipe
+                .extends_chain
+                .iter()
+                .find_map(|recipe_name| find_in_recipe(recipe, recipe_name, candidate_path))
+        })
+}
+
+/////////////////////////////
+//   JSON and YAML Files   //
+/////////////////////////////
+
+fn collect_text_spans(
+    contents: &str,
+    search_path: &[SearchSegment],
+    spans: &mut Vec<Range<usize>>,
+) {
+    // JSON and YAML parsers don't keep positions, so fall back to finding the innermost key name
+    // in the text; this is approximate when the same key appears at several depths
+    let key = search_path.iter().rev().find_map(|segment| match segment {
+        SearchSegment::Key(key) => Some(*key),
+        SearchSegment::Element => None,
+    });
+    if let Some(key) = key {
+        let pattern = format!(
+            r#"(?m)(?:^|[\s{{,\-])(["']?{}["']?\s*:)"#,
+            regex::escape(key)
+        );
+        if let Ok(regex) = Regex::new(&pattern) {
+            spans.extend(
+                regex
+                    .captures_iter(contents)
+                    .filter_map(|captures| captures.get(1))
+                    .map(|found| found.range()),
+            );
+        }
+    }
+}
+
+fn find_text_location(recipe: &Recipe, path: &[PathSegment]) -> Option<SourceLocation> {
+    // Text search isn't scoped to a recipe, so matches are counted across the files in order, and
+    // the one at the node's position among nodes with the same index-free path is picked
+    let files = match path.first() {
+        Some(PathSegment::Key(key)) => recipe.sources.get(key).cloned().unwrap_or_default(),
+        _ => recipe.source_files(),
+    };
+    let search_path = to_search_path(path);
+    let ordinal = get_ordinal(&recipe.recipe, path);
+    let mut candidates = Vec::new();
+    for file in files.iter().filter(|file| !is_toml_file(file)) {
+        let contents = match read_config_file(recipe, file) {
+            Some(contents) => contents,
+            None => continue,
+        };
+        let mut spans = Vec::new();
+        collect_text_spans(&contents, &search_path, &mut spans);
+        candidates.extend(
+            spans
+                .into_iter()
+                .map(|span| to_location(file, &contents, span)),
+        );
+    }
+    match candidates.len() > ordinal {
+        true => Some(candidates.swap_remove(ordinal)),
+        false => candidates.pop(),
+    }
+}
+
+/////////////////////
+//   Entry Point   //
+/////////////////////
+
+pub(crate) fn locate_path(recipe: &Recipe, path: &[PathSegment]) -> Option<SourceLocation> {
+    find_toml_location(recipe, path).or_else(|| find_text_location(recipe, path))
+}
@@ -0,0 +1,475 @@
+use crate::toml::formats::parse_config_file;
+
+use std::fs::{read_to_string, write};
+use std::path::{Path, PathBuf};
+use toml::value::Table;
+use toml::Value;
+use toml_edit::{DocumentMut, InlineTable, Item, Key};
+
+// Recipes without a schema_version are from before versioning, i.e. version 1
+pub const CURRENT_SCHEMA_VERSION: i64 = 2;
+
+// Renames of keys within the entries of one of a recipe's arrays
+struct KeyRenames {
+    array: &'static str,
+    renames: &'static [(&'static str, &'static str)],
+}
+
+struct Migration {
+    to_version: i64,
+    key_renames: &'static [KeyRenames],
+}
+
+const MIGRATIONS: &[Migration] = &[Migration {
+    // Keys copied from OPF attribute names used kebab-case, unlike every other key
+    to_version: 2,
+    key_renames: &[
+        KeyRenames {
+            array: "rootfiles",
+            renames: &[("media-type", "media_type")],
+        },
+        KeyRenames {
+            array: "metadata",
+            renames: &[("file-as", "file_as")],
+        },
+        KeyRenames {
+            array: "manifest",
+            renames: &[
+                ("media-type", "media_type"),
+                ("fallback-style", "fallback_style"),
+                ("required-namespace", "required_namespace"),
+                ("required-modules", "required_modules"),
+            ],
+        },
+    ],
+}];
+
+fn is_recipe_key(key: &str) -> bool {
+    key != "vars" && key != "include"
+}
+
+fn get_schema_version(version: Option<i64>, recipe_name: &str, file: &Path) -> Result<i64, String> {
+    match version {
+        None => Ok(1),
+        Some(version) if (1..=CURRENT_SCHEMA_VERSION).contains(&version) => Ok(version),
+        Some(version) => Err(format!(
+            "Recipe {} in config file {} has schema_version {}, but this version of bookfactory only supports schema versions 1 to {}.",
+            recipe_name,
+            file.display(),
+            version,
+            CURRENT_SCHEMA_VERSION
+        )),
+    }
+}
+
+fn get_pending_migrations(version: i64) -> impl Iterator<Item = &'static Migration> {
+    MIGRATIONS
+        .iter()
+        .filter(move |migration| migration.to_version > version)
+}
+
+///////////////////
+//   In Memory   //
+///////////////////
+
+fn rename_table_keys(
+    table: &mut Table,
+    key_renames: &KeyRenames,
+    recipe_name: &str,
+    file: &Path,
+) -> Result<(), String> {
+    if let Some(Value::Array(entries)) = table.get_mut(key_renames.array) {
+        for entry in entries {
+            if let Value::Table(entry) = entry {
+                for (from, to) in key_renames.renames {
+                    if let Some(value) = entry.remove(*from) {
+                        if entry.contains_key(*to) {
+                            return Err(format!(
+                                "Recipe {} in config file {} has a {} entry with both '{}' and '{}'; please remove '{}'.",
+                                recipe_name, file.display(), key_renames.array, from, to, from
+                            ));
+                        }
+                        entry.insert(String::from(*to), value);
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn upgrade_file_table(file_table: &mut Table, file: &Path) -> Result<(), String> {
+    // Each config file's part of a recipe is upgraded from its own schema_version as it's loaded,
+    // so recipes split across included files may be migrated one file at a time
+    for (recipe_name, recipe) in file_table.iter_mut() {
+        if !is_recipe_key(recipe_name) {
+            continue;
+        }
+        if let Value::Table(recipe_table) = recipe {
+            let version = match recipe_table.remove("schema_version") {
+                None => None,
+                Some(Value::Integer(version)) => Some(version),
+                Some(_) => {
+                    return Err(format!(
+                        "Recipe {} in config file {} has a non-integer 'schema_version' value.",
+                        recipe_name,
+                        file.display()
+                    ))
+                }
+            };
+            let version = get_schema_version(version, recipe_name, file)?;
+            for migration in get_pending_migrations(version) {
+                for key_renames in migration.key_renames {
+                    rename_table_keys(recipe_table, key_renames, recipe_name, file)?;
+                    if let Some(Value::Table(overrides)) = recipe_table.get_mut("overrides") {
+                        for (_, format_overrides) in overrides.iter_mut() {
+                            if let Value::Table(format_overrides) = format_overrides {
+                                rename_table_keys(
+                                    format_overrides,
+                                    key_renames,
+                                    recipe_name,
+                                    file,
+                                )?;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+///////////////////
+//   Rewriting   //
+///////////////////
+
+// Entries are taken out and put back in their original order, since toml_edit can only add keys at
+// the end of a table; keys keep their comments and whitespace
+
+fn rename_table_entries(table: &mut toml_edit::Table, renames: &[(&str, &str)]) {
+    let keys: Vec<String> = table.iter().map(|(key, _)| String::from(key)).collect();
+    if !keys
+        .iter()
+        .any(|key| renames.iter().any(|(from, _)| from == key))
+    {
+        return;
+    }
+    for key in keys {
+        if let Some((old_key, item)) = table.remove_entry(&key) {
+            let new_key = match renames.iter().find(|(from, _)| *from == key) {
+                Some((_, to)) => Key::new(*to).with_leaf_decor(old_key.leaf_decor().clone()),
+                None => old_key,
+            };
+            table.insert_formatted(&new_key, item);
+        }
+    }
+}
+
+fn rename_inline_table_entries(table: &mut InlineTable, renames: &[(&str, &str)]) {
+    let keys: Vec<String> = table.iter().map(|(key, _)| String::from(key)).collect();
+    if !keys
+        .iter()
+        .any(|key| renames.iter().any(|(from, _)| from == key))
+    {
+        return;
+    }
+    for key in keys {
+        if let Some((old_key, value)) = table.remove_entry(&key) {
+            let new_key = match renames.iter().find(|(from, _)| *from == key) {
+                Some((_, to)) => Key::new(*to).with_leaf_decor(old_key.leaf_decor().clone()),
+                None => old_key,
+            };
+            table.insert_formatted(&new_key, value);
+        }
+    }
+}
+
+fn rename_document_keys(table: &mut dyn toml_edit::TableLike, key_renames: &KeyRenames) {
+    match table.get_mut(key_renames.array) {
+        Some(Item::ArrayOfTables(entries)) => {
+            for entry in entries.iter_mut() {
+                rename_table_entries(entry, key_renames.renames);
+            }
+        }
+        Some(Item::Value(toml_edit::Value::Array(entries))) => {
+            for entry in entries.iter_mut() {
+                if let toml_edit::Value::InlineTable(entry) = entry {
+                    rename_inline_table_entries(entry, key_renames.renames);
+                }
+            }
+        }
+        _ => (),
+    }
+}
+
+fn set_schema_version(recipe: &mut Item) {
+    // A new schema_version goes first in the recipe, where it's easiest to find
+    match recipe {
+        Item::Table(table) => {
+            let keys: Vec<String> = table.iter().map(|(key, _)| String::from(key)).collect();
+            let entries: Vec<(Key, Item)> = keys
+                .iter()
+                .filter_map(|key| table.remove_entry(key))
+                .filter(|(key, _)| key.get() != "schema_version")
+                .collect();
+            table.insert("schema_version", toml_edit::value(CURRENT_SCHEMA_VERSION));
+            for (key, item) in entries {
+                table.insert_formatted(&key, item);
+            }
+        }
+        Item::Value(toml_edit::Value::InlineTable(table)) => {
+            table.insert("schema_version", CURRENT_SCHEMA_VERSION.into());
+        }
+        _ => (),
+    }
+}
+
+fn migrate_document(document: &mut DocumentMut, file: &Path) -> Result<Vec<String>, String> {
+    let mut migrated_recipes = Vec::new();
+    for (recipe_name, recipe) in document.as_table_mut().iter_mut() {
+        let recipe_name = recipe_name.get();
+        if !is_recipe_key(recipe_name) {
+            continue;
+        }
+        let recipe_table = match recipe.as_table_like_mut() {
+            Some(table) => table,
+            None => continue,
+        };
+        let version = match recipe_table.get("schema_version") {
+            None => None,
+            Some(item) => match item.as_integer() {
+                Some(version) => Some(version),
+                None => {
+                    return Err(format!(
+                        "Recipe {} in config file {} has a non-integer 'schema_version' value.",
+                        recipe_name,
+                        file.display()
+                    ))
+                }
+            },
+        };
+        let version = get_schema_version(version, recipe_name, file)?;
+        if version == CURRENT_SCHEMA_VERSION {
+            continue;
+        }
+
+        for migration in get_pending_migrations(version) {
+            for key_renames in migration.key_renames {
+                rename_document_keys(recipe_table, key_renames);
+                if let Some(overrides) = recipe_table
+                    .get_mut("overrides")
+                    .and_then(|overrides| overrides.as_table_like_mut())
+                {
+                    for (_, format_overrides) in overrides.iter_mut() {
+                        if let Some(format_overrides) = format_overrides.as_table_like_mut() {
+                            rename_document_keys(format_overrides, key_renames);
+                        }
+                    }
+                }
+            }
+        }
+        set_schema_version(recipe);
+        migrated_recipes.push(String::from(recipe_name));
+    }
+    Ok(migrated_recipes)
+}
+
+//////////////
+//   Diff   //
+//////////////
+
+fn diff_lines(old: &str, new: &str, file: &Path) -> String {
+    // Unified diff with three lines of context, from the longest common subsequence of lines
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+    let mut lengths = vec![vec![0usize; new_lines.len() + 1]; old_lines.len() + 1];
+    for i in (0..old_lines.len()).rev() {
+        for j in (0..new_lines.len()).rev() {
+            lengths[i][j] = match old_lines[i] == new_lines[j] {
+                true => lengths[i + 1][j + 1] + 1,
+                false => lengths[i + 1][j].max(lengths[i][j + 1]),
+            };
+        }
+    }
+
+    // Each line is (old line number, new line number, marker, text)
+    let mut lines: Vec<(usize, usize, char, &str)> = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old_lines.len() || j < new_lines.len() {
+        if i < old_lines.len() && j < new_lines.len() && old_lines[i] == new_lines[j] {
+            lines.push((i, j, ' ', old_lines[i]));
+            i += 1;
+            j += 1;
+        } else if i < old_lines.len()
+            && (j == new_lines.len() || lengths[i + 1][j] >= lengths[i][j + 1])
+        {
+            lines.push((i, j, '-', old_lines[i]));
+            i += 1;
+        } else {
+            lines.push((i, j, '+', new_lines[j]));
+            j += 1;
+        }
+    }
+
+    let mut diff = format!("--- {0}\n+++ {0}\n", file.display());
+    let changed: Vec<usize> = (0..lines.len())
+        .filter(|index| lines[*index].2 != ' ')
+        .collect();
+    let mut index = 0;
+    while index < changed.len() {
+        // Changes less than seven lines apart share a hunk
+        let start = changed[index].saturating_sub(3);
+        let mut end = changed[index];
+        while index < changed.len() && changed[index] <= end + 6 {
+            end = changed[index];
+            index += 1;
+        }
+        let end = (end + 4).min(lines.len());
+        let hunk = &lines[start..end];
+        let old_count = hunk.iter().filter(|line| line.2 != '+').count();
+        let new_count = hunk.iter().filter(|line| line.2 != '-').count();
+        diff.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            hunk[0].0 + 1,
+            old_count,
+            hunk[0].1 + 1,
+            new_count
+        ));
+        for (_, _, marker, text) in hunk {
+            diff.push_str(&format!("{}{}\n", marker, text));
+        }
+    }
+    diff
+}
+
+/////////////////////
+//   Entry Point   //
+/////////////////////
+
+pub struct MigratedFile {
+    pub path: PathBuf,
+    pub recipes: Vec<String>,
+    pub diff: String,
+}
+
+pub fn migrate_config_file<P: AsRef<Path>>(path: P, dry_run: bool) -> Result<MigratedFile, String> {
+    let path = path.as_ref();
+    let contents = read_to_string(path)
+        .map_err(|e| format!("Failed to read config file {}: {}", path.display(), e))?;
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_lowercase());
+    if matches!(extension.as_deref(), Some("json" | "yaml" | "yml")) {
+        // Older JSON and YAML configs still load, since recipes are upgraded as they're read
+        return Err(format!(
+            "Config file {} isn't TOML; only TOML config files can be migrated in place.",
+            path.display()
+        ));
+    }
+    // Checks that the file is a valid config before rewriting it
+    parse_config_file(path, &contents)?;
+
+    let mut document: DocumentMut = contents
+        .parse()
+        .map_err(|e| format!("Failed to parse config file {}: {}", path.display(), e))?;
+    let recipes = migrate_document(&mut document, path)?;
+    let migrated = document.to_string();
+    let diff = match recipes.is_empty() {
+        true => String::new(),
+        false => diff_lines(&contents, &migrated, path),
+    };
+    if !dry_run && !recipes.is_empty() {
+        write(path, migrated).map_err(|e| e.to_string())?;
+    }
+
+    Ok(MigratedFile {
+        path: path.to_path_buf(),
+        recipes,
+        diff,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env::temp_dir;
+    use std::fs::create_dir_all;
+    use std::process::id;
+
+    const V1_RECIPE: &str = r#"# The book itself
+[book]
+format = "epub2"  # only format so far
+metadata = [
+  { name = "title", content = "T" },
+  # Sorted under its surname
+  { name = "creator", content = "A B", file-as = "B, A" },
+]
+manifest = [
+  { outside_path = "c.xhtml", inside_path_from_opf = "c.xhtml", media-type = "application/xhtml+xml" },
+]
+
+[book.overrides.epub2]
+rootfiles = [{ path = "content.opf", media-type = "application/oebps-package+xml" }]
+"#;
+
+    fn write_test_config(name: &str, contents: &str) -> PathBuf {
+        let dir = temp_dir().join(format!("bookfactory-migrate-{}-{}", name, id()));
+        create_dir_all(&dir).unwrap();
+        let path = dir.join("bookfactory.toml");
+        write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn old_keys_are_renamed_keeping_comments_and_order() {
+        let mut document: DocumentMut = V1_RECIPE.parse().unwrap();
+        let recipes = migrate_document(&mut document, Path::new("bookfactory.toml")).unwrap();
+        assert_eq!(recipes, vec!["book"]);
+        assert_eq!(
+            document.to_string(),
+            V1_RECIPE
+                .replace("[book]\n", "[book]\nschema_version = 2\n")
+                .replace("file-as", "file_as")
+                .replace("media-type", "media_type")
+        );
+    }
+
+    #[test]
+    fn current_files_are_left_alone() {
+        let contents = V1_RECIPE
+            .replace("[book]\n", "[book]\nschema_version = 2\n")
+            .replace("file-as", "file_as")
+            .replace("media-type", "media_type");
+        let path = write_test_config("current", &contents);
+        let migrated = migrate_config_file(&path, false).unwrap();
+        assert!(migrated.recipes.is_empty());
+        assert_eq!(migrated.diff, "");
+        assert_eq!(read_to_string(&path).unwrap(), contents);
+    }
+
+    #[test]
+    fn dry_runs_leave_the_file_alone() {
+        let path = write_test_config("dry-run", V1_RECIPE);
+        let migrated = migrate_config_file(&path, true).unwrap();
+        assert_eq!(migrated.recipes, vec!["book"]);
+        assert!(migrated.diff.contains("+schema_version = 2\n"));
+        assert_eq!(read_to_string(&path).unwrap(), V1_RECIPE);
+    }
+
+    #[test]
+    fn non_toml_files_are_refused_whatever_their_extension_case() {
+        let path = write_test_config("json", "").with_extension("JSON");
+        write(&path, r#"{ "book": { "format": "epub2" } }"#).unwrap();
+        match migrate_config_file(&path, true) {
+            Ok(_) => panic!("JSON config file was migrated"),
+            Err(e) => assert!(
+                e.ends_with("isn't TOML; only TOML config files can be migrated in place."),
+                "{}",
+                e
+            ),
+        }
+    }
+}
@@ -1,3 +1,20 @@
+mod extends;
+mod formats;
+mod include;
+mod locate;
+mod migrate;
 mod parse_config;
+mod schema;
+mod strict;
+mod vars;
 
+pub(crate) use include::FileContents;
+pub(crate) use locate::{locate_path, SourceLocation};
+pub use migrate::{migrate_config_file, MigratedFile, CURRENT_SCHEMA_VERSION};
+pub(crate) use parse_config::parse_config_with_unsaved_files;
 pub use parse_config::{parse_config, Recipe};
+pub use schema::build_config_file_schema;
+pub(crate) use schema::build_config_file_schema_value;
+pub(crate) use strict::{
+    check_recipe_against_schema, find_schema_problems, PathSegment, RecipeProblem,
+};
@@ -1,55 +1,248 @@
+use crate::toml::extends::{apply_overrides, resolve_extends, ArrayOrigins, ResolvedRecipe};
+use crate::toml::include::{describe_files, load_config_tree, FileContents, KeySources};
+use crate::toml::vars::interpolate_recipe;
+
+use std::collections::HashMap;
 use std::fmt::Display;
-use std::fs::read_to_string;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use toml::value::Table;
 use toml::Value;
 
 pub struct Recipe {
     pub name: String,
     pub format: String,
+    pub base_dir: PathBuf,
     pub recipe: Value,
+    // Config files which defined each of the recipe's keys, including keys inherited through 'extends'
+    pub sources: HashMap<String, Vec<PathBuf>>,
+    // This recipe's name followed by those of the recipes it extends, nearest first
+    pub extends_chain: Vec<String>,
+    // Whether unknown keys are errors rather than being ignored
+    pub strict: bool,
+    // Build variant selecting content tagged with only_in/except_in, if any
+    pub variant: Option<String>,
+    // Contents of the config files the recipe was loaded from
+    pub file_contents: HashMap<PathBuf, String>,
+    // Config files which defined each "recipe.key" of every recipe, in the order they were merged
+    pub(crate) key_sources: KeySources,
+    // Where each element of the recipe's arrays was written, through 'extends' and overrides
+    pub(crate) array_origins: ArrayOrigins,
+}
+
+impl Recipe {
+    pub(crate) fn source_files(&self) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = self.sources.values().flatten().cloned().collect();
+        files.sort();
+        files.dedup();
+        files
+    }
+
+    pub fn annotate_error(&self, error: String) -> String {
+        // Errors from deserializing the recipe name the offending key as "for key `manifest.id`"
+        let key = error
+            .rsplit_once("for key `")
+            .and_then(|(_, rest)| rest.split(['.', '`']).next());
+        let files = match key.and_then(|key| self.sources.get(key)) {
+            Some(files) => files.clone(),
+            None => self.source_files(),
+        };
+        format!(
+            "{} (in recipe {}, from {})",
+            error,
+            self.name,
+            describe_files(&files)
+        )
+    }
+}
+
+fn get_key_sources(sources: &KeySources, extends_chain: &[String], key: &str) -> Vec<PathBuf> {
+    // A child's own definition of the key comes first
+    let mut files: Vec<PathBuf> = Vec::new();
+    for recipe_name in extends_chain {
+        if let Some(key_files) = sources.get(&format!("{}.{}", recipe_name, key)) {
+            for file in key_files {
+                if !files.contains(file) {
+                    files.push(file.clone());
+                }
+            }
+        }
+    }
+    files
+}
+
+fn split_formats(
+    mut recipe: ResolvedRecipe,
+    name: &str,
+    recipe_files: &str,
+) -> Result<Vec<(String, ResolvedRecipe)>, String> {
+    // A recipe with a 'formats' array stands for one recipe per format, each with its overrides
+    let recipe_table = &mut recipe.table;
+    let overrides = match recipe_table.remove("overrides") {
+        None => Table::new(),
+        Some(Value::Table(overrides)) => overrides,
+        Some(_) => {
+            return Err(format!(
+                "Recipe {} in config file {} has a non-table 'overrides' value.",
+                name, recipe_files
+            ))
+        }
+    };
+    let formats = match (recipe_table.remove("format"), recipe_table.remove("formats")) {
+        (Some(Value::String(format)), None) => vec![format],
+        (None, Some(Value::Array(formats))) if !formats.is_empty() => formats
+            .into_iter()
+            .map(|format| match format {
+                Value::String(format) => Ok(format),
+                _ => Err(format!(
+                    "Recipe {} in config file {} has a non-string 'formats' entry.",
+                    name, recipe_files
+                )),
+            })
+            .collect::<Result<Vec<String>, String>>()?,
+        (Some(_), Some(_)) => {
+            return Err(format!(
+                "Recipe {} in config file {} has both 'format' and 'formats'; use one or the other.",
+                name, recipe_files
+            ))
+        }
+        _ => {
+            return Err(format!(
+                "Recipe {} in config file {} contains no 'format' string value or 'formats' array of strings.",
+                name, recipe_files
+            ))
+        }
+    };
+    for (index, format) in formats.iter().enumerate() {
+        if formats[..index].contains(format) {
+            return Err(format!(
+                "Recipe {} in config file {} lists format {} more than once.",
+                name, recipe_files, format
+            ));
+        }
+    }
+    if let Some(format) = overrides.keys().find(|format| !formats.contains(format)) {
+        return Err(format!(
+            "Recipe {} in config file {} has overrides for format {}, which isn't one of its formats.",
+            name, recipe_files, format
+        ));
+    }
+
+    formats
+        .into_iter()
+        .map(|format| {
+            let format_recipe = match overrides.get(&format) {
+                None => recipe.clone(),
+                Some(Value::Table(format_overrides)) => {
+                    apply_overrides(recipe.clone(), format_overrides.clone(), &format, name)?
+                }
+                Some(_) => {
+                    return Err(format!(
+                        "Recipe {} in config file {} has non-table overrides for format {}.",
+                        name, recipe_files, format
+                    ))
+                }
+            };
+            Ok((format, format_recipe))
+        })
+        .collect()
 }
 
 pub fn parse_config<P: AsRef<Path> + Display>(filename: P) -> Result<Vec<Recipe>, String> {
-    let file = read_to_string(&filename).map_err(|s| s.to_string())?;
-    let config_tree = toml::from_str(&file).map_err(|s| s.to_string())?;
-
-    if let Value::Table(top_level_table) = config_tree {
-        let mut recipes = Vec::new();
-        for (name, table) in top_level_table.iter() {
-            if let Value::Table(recipe_table) = table {
-                match recipe_table.get("format") {
-                    Some(Value::String(format)) => {
-                        let mut table_minus_format = recipe_table.clone();
-                        table_minus_format.remove("format");
-                        recipes.push(Recipe {
-                            name: name.clone(),
-                            format: format.clone(),
-                            recipe: Value::Table(table_minus_format),
-                        });
+    parse_config_with_unsaved_files(filename, &FileContents::new())
+}
+
+pub(crate) fn parse_config_with_unsaved_files<P: AsRef<Path> + Display>(
+    filename: P,
+    unsaved_contents: &FileContents,
+) -> Result<Vec<Recipe>, String> {
+    // Unsaved contents are keyed by cleaned path and take the place of the files on disk
+    let (top_level_table, key_sources, file_contents) =
+        load_config_tree(filename.as_ref(), unsaved_contents)?;
+
+    let config_dir = match filename.as_ref().parent() {
+        Some(parent) => parent.to_path_buf(),
+        None => PathBuf::new(),
+    };
+
+    let mut recipes = Vec::new();
+    for (name, table) in top_level_table.iter() {
+        if name == "vars" {
+            // Top-level variables shared by all recipes
+            continue;
+        }
+        let recipe_files = match key_sources.get(name) {
+            Some(files) => describe_files(files),
+            None => filename.to_string(),
+        };
+        if let Value::Table(_) = table {
+            let mut resolved = resolve_extends(&top_level_table, name)?;
+            interpolate_recipe(&mut resolved.table, top_level_table.get("vars"), name)?;
+            let extends_chain = resolved.extends_chain.clone();
+            let sources: HashMap<String, Vec<PathBuf>> = resolved
+                .table
+                .keys()
+                .map(|key| {
+                    (
+                        key.clone(),
+                        get_key_sources(&key_sources, &extends_chain, key),
+                    )
+                })
+                .collect();
+            for (format, format_recipe) in split_formats(resolved, name, &recipe_files)? {
+                let mut format_table = format_recipe.table;
+                let mut format_sources = sources.clone();
+                if let Some(override_files) = sources.get("overrides") {
+                    // Keys only set by an override come from wherever the overrides were defined
+                    for key in format_table.keys() {
+                        format_sources
+                            .entry(key.clone())
+                            .or_insert_with(|| override_files.clone());
+                    }
+                }
+                let base_dir = match format_table.remove("base_dir") {
+                    None => config_dir.clone(),
+                    Some(Value::String(base_dir)) => {
+                        // Relative to the config file which set it
+                        match format_sources["base_dir"]
+                            .first()
+                            .and_then(|file| file.parent())
+                        {
+                            Some(file_dir) => file_dir.join(base_dir),
+                            None => config_dir.join(base_dir),
+                        }
                     }
-                    _ => {
+                    Some(_) => {
                         return Err(format!(
-                            "Recipe {} in config file {} contains no 'format' string value.",
-                            name, filename
+                            "Recipe {} in config file {} has a non-string 'base_dir' value.",
+                            name,
+                            describe_files(&format_sources["base_dir"])
                         ))
                     }
-                }
-            } else {
-                return Err(format!(
-                    "Config file {} contains top-level element which is not a recipe header: {}",
-                    name, filename
-                ));
+                };
+                recipes.push(Recipe {
+                    name: name.clone(),
+                    format,
+                    base_dir,
+                    recipe: Value::Table(format_table),
+                    sources: format_sources,
+                    extends_chain: extends_chain.clone(),
+                    strict: false,
+                    variant: None,
+                    file_contents: file_contents.clone(),
+                    key_sources: key_sources.clone(),
+                    array_origins: format_recipe.origins,
+                });
             }
-        }
-        if !recipes.is_empty() {
-            Ok(recipes)
         } else {
-            Err(format!("Config file {} contains no recipes.", filename))
+            return Err(format!(
+                "Config file {} contains top-level element which is not a recipe header: {}",
+                recipe_files, name
+            ));
         }
+    }
+    if !recipes.is_empty() {
+        Ok(recipes)
     } else {
-        Err(format!(
-            "Config file {} has non-table top-level element. (This shouldn't be possible.)",
-            filename
-        ))
+        Err(format!("Config file {} contains no recipes.", filename))
     }
 }
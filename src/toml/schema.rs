@@ -0,0 +1,175 @@
+use crate::toml::migrate::CURRENT_SCHEMA_VERSION;
+
+use schemars::schema::RootSchema;
+use serde_json::{json, Map, Value};
+
+fn variables_schema() -> Value {
+    json!({
+        "description": "Variables available to ${name} interpolation in string values",
+        "type": "object",
+        "additionalProperties": { "type": ["string", "number", "boolean"] }
+    })
+}
+
+fn common_recipe_properties(format: &str, recipe_names: &[(String, String)]) -> Value {
+    // Keys handled while loading the config file, before the format's own config is parsed
+    let strategy = json!({ "type": "string", "enum": ["append", "replace", "patch"] });
+    let formats: Vec<&str> = recipe_names
+        .iter()
+        .map(|(format, _)| format.as_ref())
+        .collect();
+    let overrides: Map<String, Value> = recipe_names
+        .iter()
+        .map(|(format, name)| {
+            (
+                format.clone(),
+                json!({ "$ref": format!("#/definitions/{}", name) }),
+            )
+        })
+        .collect();
+    json!({
+        "schema_version": {
+            "description": "Version of the recipe format the recipe is written in; recipes without one are read as version 1 and upgraded as they're loaded",
+            "type": "integer",
+            "minimum": 1,
+            "maximum": CURRENT_SCHEMA_VERSION
+        },
+        "format": {
+            "description": "Output format of the recipe",
+            "type": "string",
+            "enum": [format]
+        },
+        "formats": {
+            "description": "Output formats of the recipe, built together",
+            "type": "array",
+            "items": { "type": "string", "enum": formats }
+        },
+        "overrides": {
+            "description": "Keys to merge into the recipe for one format only",
+            "type": "object",
+            "properties": overrides
+        },
+        "base_dir": {
+            "description": "Directory that outside paths are resolved against, relative to the config file which sets it",
+            "type": "string"
+        },
+        "extends": {
+            "description": "Name of a recipe to inherit keys from",
+            "type": "string"
+        },
+        "merge": {
+            "description": "How arrays combine with the ones inherited through 'extends'; replace by default",
+            "type": "object",
+            "additionalProperties": {
+                "anyOf": [
+                    strategy,
+                    {
+                        "type": "object",
+                        "required": ["strategy"],
+                        "properties": {
+                            "strategy": strategy,
+                            "key": {
+                                "description": "Key identifying entries to patch; defaults to id",
+                                "type": "string"
+                            }
+                        }
+                    }
+                ]
+            }
+        },
+        "vars": variables_schema()
+    })
+}
+
+fn disallow_unknown_keys(schema: &mut Value) {
+    // Lets editors flag misspelled keys, as strict mode does
+    match schema {
+        Value::Object(object) => {
+            if object.contains_key("properties") && !object.contains_key("additionalProperties") {
+                object.insert(String::from("additionalProperties"), Value::Bool(false));
+            }
+            for (_, child) in object.iter_mut() {
+                disallow_unknown_keys(child);
+            }
+        }
+        Value::Array(array) => {
+            for child in array {
+                disallow_unknown_keys(child);
+            }
+        }
+        _ => (),
+    }
+}
+
+pub(crate) fn build_config_file_schema_value(
+    format_schemas: Vec<(&str, RootSchema)>,
+) -> Result<Value, String> {
+    let mut definitions = Map::new();
+    let mut recipe_schemas = Vec::new();
+    for (format, format_schema) in format_schemas {
+        let mut recipe_schema = match serde_json::to_value(format_schema) {
+            Ok(Value::Object(object)) => object,
+            Ok(_) => return Err(format!("Schema for format {} is not an object.", format)),
+            Err(e) => return Err(e.to_string()),
+        };
+        if let Some(Value::Object(format_definitions)) = recipe_schema.remove("definitions") {
+            definitions.extend(format_definitions);
+        }
+        recipe_schema.remove("$schema");
+        let name = match recipe_schema.remove("title") {
+            Some(Value::String(title)) => title,
+            _ => String::from(format),
+        };
+        // Recipes may inherit any key through 'extends', so none is required of a recipe on its own
+        recipe_schema.remove("required");
+        recipe_schemas.push((String::from(format), name, recipe_schema));
+    }
+
+    let recipe_names: Vec<(String, String)> = recipe_schemas
+        .iter()
+        .map(|(format, name, _)| (format.clone(), name.clone()))
+        .collect();
+    let mut recipe_references = Vec::new();
+    for (format, name, mut recipe_schema) in recipe_schemas {
+        if let (Some(Value::Object(properties)), Value::Object(common_properties)) = (
+            recipe_schema.get_mut("properties"),
+            common_recipe_properties(&format, &recipe_names),
+        ) {
+            properties.extend(common_properties);
+        }
+        recipe_references.push(json!({ "$ref": format!("#/definitions/{}", name) }));
+        definitions.insert(name, Value::Object(recipe_schema));
+    }
+    let recipe_schema = match recipe_references.len() {
+        1 => recipe_references.remove(0),
+        _ => json!({ "anyOf": recipe_references }),
+    };
+    definitions.insert(String::from("Recipe"), recipe_schema);
+
+    let mut schema = json!({
+        "$schema": "http://json-schema.org/draft-07/schema#",
+        "title": "BookFactory config file",
+        "description": "Recipes keyed by name, alongside shared variables and included config files",
+        "type": "object",
+        "properties": {
+            "include": {
+                "description": "Config files to merge into this one, relative to it",
+                "anyOf": [
+                    { "type": "string" },
+                    { "type": "array", "items": { "type": "string" } }
+                ]
+            },
+            "vars": variables_schema()
+        },
+        "additionalProperties": { "$ref": "#/definitions/Recipe" },
+        "definitions": definitions
+    });
+    disallow_unknown_keys(&mut schema);
+
+    Ok(schema)
+}
+
+pub fn build_config_file_schema(format_schemas: Vec<(&str, RootSchema)>) -> Result<String, String> {
+    let schema = build_config_file_schema_value(format_schemas)?;
+    serde_json::to_string_pretty(&schema).map_err(|e| e.to_string())
+}
@@ -0,0 +1,678 @@
+use crate::toml::include::describe_files;
+use crate::toml::locate::locate_path;
+use crate::toml::Recipe;
+
+use schemars::schema::{InstanceType, RootSchema, Schema, SchemaObject, SingleOrVec};
+use std::fmt::{self, Display};
+use toml::Value;
+
+#[derive(Clone)]
+pub(crate) enum PathSegment {
+    Key(String),
+    Index(usize),
+}
+
+#[derive(PartialEq)]
+enum ProblemKind {
+    UnknownKey,
+    MissingKey,
+    WrongType,
+}
+
+struct Problem {
+    kind: ProblemKind,
+    path: Vec<PathSegment>,
+    message: String,
+}
+
+fn describe_path(path: &[PathSegment]) -> String {
+    let mut described = String::new();
+    for segment in path {
+        match segment {
+            PathSegment::Key(key) => {
+                if !described.is_empty() {
+                    described.push('.');
+                }
+                described.push_str(key);
+            }
+            PathSegment::Index(index) => described.push_str(&format!("[{}]", index)),
+        }
+    }
+    described
+}
+
+/////////////////////
+//   Suggestions   //
+/////////////////////
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b_chars: Vec<char> = b.chars().collect();
+    let mut previous_row: Vec<usize> = (0..=b_chars.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut row = vec![i + 1];
+        for (j, b_char) in b_chars.iter().enumerate() {
+            let substitution_cost = if a_char == *b_char { 0 } else { 1 };
+            row.push(
+                (previous_row[j] + substitution_cost)
+                    .min(previous_row[j + 1] + 1)
+                    .min(row[j] + 1),
+            );
+        }
+        previous_row = row;
+    }
+    previous_row[b_chars.len()]
+}
+
+fn suggest_key<'a>(key: &str, known_keys: &[&'a str]) -> Option<&'a str> {
+    // Catches both typos and the wrong separator or case, e.g. fallback_style or navMap
+    let normalize = |key: &str| key.to_lowercase().replace('-', "_");
+    if let Some(known_key) = known_keys
+        .iter()
+        .find(|known_key| normalize(known_key) == normalize(key))
+    {
+        return Some(known_key);
+    }
+    known_keys
+        .iter()
+        .map(|known_key| (edit_distance(key, known_key), *known_key))
+        .filter(|(distance, _)| *distance <= (key.chars().count() / 3).max(1))
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, known_key)| known_key)
+}
+
+////////////////////////
+//   Schema Walking   //
+////////////////////////
+
+struct ValueType<'a>(&'a Value);
+
+impl Display for ValueType<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match self.0 {
+            Value::String(_) => "a string",
+            Value::Integer(_) => "an integer",
+            Value::Float(_) => "a number",
+            Value::Boolean(_) => "a boolean",
+            Value::Datetime(_) => "a datetime",
+            Value::Array(_) => "an array",
+            Value::Table(_) => "a table",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+fn describe_instance_type(instance_type: &InstanceType) -> &'static str {
+    match instance_type {
+        InstanceType::Null => "absent",
+        InstanceType::Boolean => "a boolean",
+        InstanceType::Object => "a table",
+        InstanceType::Array => "an array",
+        InstanceType::Number => "a number",
+        InstanceType::String => "a string",
+        InstanceType::Integer => "an integer",
+    }
+}
+
+fn value_has_instance_type(value: &Value, instance_type: &InstanceType) -> bool {
+    matches!(
+        (value, instance_type),
+        (Value::String(_), InstanceType::String)
+            | (Value::Integer(_), InstanceType::Integer)
+            | (Value::Integer(_), InstanceType::Number)
+            | (Value::Float(_), InstanceType::Number)
+            | (Value::Boolean(_), InstanceType::Boolean)
+            | (Value::Array(_), InstanceType::Array)
+            | (Value::Table(_), InstanceType::Object)
+    )
+}
+
+fn get_instance_types(schema: &SchemaObject) -> Vec<&InstanceType> {
+    match &schema.instance_type {
+        None => Vec::new(),
+        Some(SingleOrVec::Single(instance_type)) => vec![instance_type],
+        Some(SingleOrVec::Vec(instance_types)) => instance_types.iter().collect(),
+    }
+}
+
+struct SchemaWalker<'a> {
+    root: &'a RootSchema,
+    strict: bool,
+}
+
+impl<'a> SchemaWalker<'a> {
+    fn resolve(&self, schema: &'a Schema) -> Option<(&'a SchemaObject, Option<&'a str>)> {
+        match schema {
+            Schema::Bool(_) => None,
+            Schema::Object(object) => {
+                // Documented untagged enum variants come out as a lone allOf around the variant's
+                // reference, so that the description has somewhere to go
+                let all_of = object.subschemas.as_ref().and_then(|s| s.all_of.as_deref());
+                match (&object.reference, all_of) {
+                    (Some(reference), _) => {
+                        let name = reference.trim_start_matches("#/definitions/");
+                        match self.root.definitions.get(name) {
+                            Some(definition) => self
+                                .resolve(definition)
+                                .map(|(resolved, _)| (resolved, Some(name))),
+                            None => None,
+                        }
+                    }
+                    (None, Some([only_schema])) => self.resolve(only_schema),
+                    (None, _) => Some((object, None)),
+                }
+            }
+        }
+    }
+
+    fn check(
+        &self,
+        value: &Value,
+        schema: &'a Schema,
+        path: &mut Vec<PathSegment>,
+        problems: &mut Vec<Problem>,
+    ) {
+        let (object, name) = match self.resolve(schema) {
+            Some(resolved) => resolved,
+            None => return,
+        };
+
+        if let Some(any_of) = object.subschemas.as_ref().and_then(|s| s.any_of.as_ref()) {
+            self.check_any_of(value, any_of, name, path, problems);
+            return;
+        }
+
+        let instance_types = get_instance_types(object);
+        if !instance_types.is_empty()
+            && !instance_types
+                .iter()
+                .any(|instance_type| value_has_instance_type(value, instance_type))
+        {
+            let expected: Vec<&str> = instance_types
+                .iter()
+                .filter(|instance_type| ***instance_type != InstanceType::Null)
+                .map(|instance_type| describe_instance_type(instance_type))
+                .collect();
+            problems.push(Problem {
+                kind: ProblemKind::WrongType,
+                path: path.clone(),
+                message: format!(
+                    "{} should be {}, but is {}.",
+                    describe_path(path),
+                    expected.join(" or "),
+                    ValueType(value)
+                ),
+            });
+            return;
+        }
+
+        match value {
+            Value::Table(table) => {
+                if let Some(object_validation) = &object.object {
+                    let known_keys: Vec<&str> = object_validation
+                        .properties
+                        .keys()
+                        .map(|key| key.as_ref())
+                        .collect();
+                    for (key, child) in table {
+                        path.push(PathSegment::Key(key.clone()));
+                        match object_validation.properties.get(key) {
+                            Some(child_schema) => self.check(child, child_schema, path, problems),
+                            None if self.strict => problems.push(Problem {
+                                kind: ProblemKind::UnknownKey,
+                                path: path.clone(),
+                                message: match suggest_key(key, &known_keys) {
+                                    Some(suggestion) => format!(
+                                        "Unknown key '{}'; did you mean '{}'?",
+                                        describe_path(path),
+                                        suggestion
+                                    ),
+                                    None => format!("Unknown key '{}'.", describe_path(path)),
+                                },
+                            }),
+                            None => (),
+                        }
+                        path.pop();
+                    }
+                    for required_key in &object_validation.required {
+                        if !table.contains_key(required_key) {
+                            problems.push(Problem {
+                                kind: ProblemKind::MissingKey,
+                                path: path.clone(),
+                                message: match path.is_empty() {
+                                    true => format!("Missing required key '{}'.", required_key),
+                                    false => format!(
+                                        "{} is missing required key '{}'.",
+                                        describe_path(path),
+                                        required_key
+                                    ),
+                                },
+                            });
+                        }
+                    }
+                }
+            }
+            Value::Array(array) => {
+                if let Some(SingleOrVec::Single(item_schema)) =
+                    object.array.as_ref().and_then(|a| a.items.as_ref())
+                {
+                    for (index, child) in array.iter().enumerate() {
+                        path.push(PathSegment::Index(index));
+                        self.check(child, item_schema, path, problems);
+                        path.pop();
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn describe_form(&self, schema: &'a Schema) -> Option<String> {
+        let (object, _) = self.resolve(schema)?;
+        match &object.object {
+            Some(object_validation) if !object_validation.required.is_empty() => Some(format!(
+                "a table with {}",
+                object_validation
+                    .required
+                    .iter()
+                    .map(|key| format!("'{}'", key))
+                    .collect::<Vec<String>>()
+                    .join(" and ")
+            )),
+            _ => get_instance_types(object)
+                .first()
+                .map(|instance_type| String::from(describe_instance_type(instance_type))),
+        }
+    }
+
+    fn check_any_of(
+        &self,
+        value: &Value,
+        any_of: &'a [Schema],
+        name: Option<&str>,
+        path: &mut Vec<PathSegment>,
+        problems: &mut Vec<Problem>,
+    ) {
+        // Options show up as a choice between the inner type and null; untagged enums as a choice
+        // between their variants, where the closest variant's problems are the useful ones
+        let alternatives: Vec<&Schema> = any_of
+            .iter()
+            .filter(|alternative| match self.resolve(alternative) {
+                Some((object, _)) => {
+                    object.instance_type != Some(SingleOrVec::Single(Box::new(InstanceType::Null)))
+                }
+                None => true,
+            })
+            .collect();
+        if alternatives.len() == 1 {
+            self.check(value, alternatives[0], path, problems);
+            return;
+        }
+
+        let mut best: Option<(usize, usize, Vec<Problem>)> = None;
+        for alternative in &alternatives {
+            let mut alternative_problems = Vec::new();
+            self.check(value, alternative, path, &mut alternative_problems);
+            if alternative_problems.is_empty() {
+                return;
+            }
+            let matched_keys = match (value, self.resolve(alternative)) {
+                (Value::Table(table), Some((object, _))) => match &object.object {
+                    Some(object_validation) => table
+                        .keys()
+                        .filter(|key| object_validation.properties.contains_key(*key))
+                        .count(),
+                    None => 0,
+                },
+                _ => 0,
+            };
+            // Type mismatches of the whole value rule an alternative out more than anything else
+            let mismatches = match alternative_problems.iter().any(|problem| {
+                problem.kind == ProblemKind::WrongType && problem.path.len() == path.len()
+            }) {
+                true => usize::MAX,
+                false => alternative_problems.len(),
+            };
+            let is_better = match &best {
+                None => true,
+                Some((best_mismatches, best_matched_keys, _)) => {
+                    (mismatches, usize::MAX - matched_keys)
+                        < (*best_mismatches, usize::MAX - best_matched_keys)
+                }
+            };
+            if is_better {
+                best = Some((mismatches, matched_keys, alternative_problems));
+            }
+        }
+
+        let forms: Vec<String> = alternatives
+            .iter()
+            .filter_map(|alternative| self.describe_form(alternative))
+            .collect();
+        let forms_note = format!(
+            "{} entries take one of these forms: {}.",
+            name.unwrap_or("These"),
+            forms.join("; ")
+        );
+        match best {
+            Some((usize::MAX, _, _)) => problems.push(Problem {
+                kind: ProblemKind::WrongType,
+                path: path.clone(),
+                message: format!(
+                    "{} is {}. {}",
+                    describe_path(path),
+                    ValueType(value),
+                    forms_note
+                ),
+            }),
+            Some((_, _, best_problems)) => {
+                for mut problem in best_problems {
+                    if problem.kind == ProblemKind::MissingKey && problem.path.len() == path.len() {
+                        problem.message = format!("{} {}", problem.message, forms_note);
+                    }
+                    problems.push(problem);
+                }
+            }
+            None => (),
+        }
+    }
+}
+
+/////////////////////
+//   Entry Point   //
+/////////////////////
+
+pub(crate) struct RecipeProblem {
+    pub(crate) path: Vec<PathSegment>,
+    pub(crate) message: String,
+    pub(crate) is_warning: bool,
+}
+
+pub(crate) fn find_schema_problems(
+    recipe: &Recipe,
+    schema: &RootSchema,
+    report_unknown_keys: bool,
+) -> Vec<RecipeProblem> {
+    // Unknown keys are errors in strict mode and warnings otherwise
+    let walker = SchemaWalker {
+        root: schema,
+        strict: report_unknown_keys,
+    };
+    let mut problems = Vec::new();
+    let root_schema = Schema::Object(schema.schema.clone());
+    walker.check(&recipe.recipe, &root_schema, &mut Vec::new(), &mut problems);
+    problems
+        .into_iter()
+        .map(|problem| RecipeProblem {
+            is_warning: problem.kind == ProblemKind::UnknownKey && !recipe.strict,
+            path: problem.path,
+            message: problem.message,
+        })
+        .collect()
+}
+
+pub(crate) fn check_recipe_against_schema(
+    recipe: &Recipe,
+    schema: &RootSchema,
+) -> Result<(), String> {
+    // Missing keys and mistyped values are always reported here, since they make better errors
+    // than the deserializer's
+    let problems = find_schema_problems(recipe, schema, recipe.strict);
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    let described_problems: Vec<String> = problems
+        .iter()
+        .map(|problem| match locate_path(recipe, &problem.path) {
+            Some(location) => format!(
+                "  {}:{}:{}: {}",
+                location.file.display(),
+                location.line,
+                location.column,
+                problem.message
+            ),
+            None => format!(
+                "  {}: {}",
+                describe_files(&recipe.source_files()),
+                problem.message
+            ),
+        })
+        .collect();
+    Err(format!(
+        "Recipe {} has {} config problem{}:\n{}",
+        recipe.name,
+        problems.len(),
+        if problems.len() == 1 { "" } else { "s" },
+        described_problems.join("\n")
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epub::epub2_config_schema;
+    use crate::toml::parse_config;
+
+    use std::fs::{create_dir_all, write};
+
+    fn check_strictly(name: &str, recipe: &str) -> String {
+        check_recipe_strictly(name, recipe, "book")
+    }
+
+    fn check_recipe_strictly(name: &str, recipe: &str, recipe_name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("bookfactory-strict-{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        let path = dir.join(format!("{}.toml", name)).display().to_string();
+        write(&path, recipe).unwrap();
+        let mut recipe = parse_config(&path)
+            .unwrap()
+            .into_iter()
+            .find(|recipe| recipe.name == recipe_name)
+            .unwrap();
+        recipe.strict = true;
+        check_recipe_against_schema(&recipe, &epub2_config_schema())
+            .unwrap_err()
+            .replace(&path, "recipe.toml")
+    }
+
+    const MANIFEST: &str = r#"manifest = [
+  { outside_path = "a.xhtml", inside_path_from_opf = "a.xhtml", id = "a" },
+]
+"#;
+
+    #[test]
+    fn reports_unknown_keys_in_manifest_entries() {
+        let problems = check_strictly(
+            "manifest_unknown",
+            r#"[book]
+format = "epub2"
+manifest = [
+  { outside_path = "a.xhtml", inside_path_from_opf = "a.xhtml", bogus = 1 },
+  { glob = "text/*.xhtml", id_prefx = "text-" },
+]
+"#,
+        );
+        assert!(
+            problems.contains("recipe.toml:4:65: Unknown key 'manifest[0].bogus'."),
+            "{}",
+            problems
+        );
+        assert!(
+            problems.contains(
+                "recipe.toml:5:28: Unknown key 'manifest[1].id_prefx'; did you mean 'id_prefix'?"
+            ),
+            "{}",
+            problems
+        );
+    }
+
+    #[test]
+    fn reports_wrong_types_in_manifest_entries() {
+        let problems = check_strictly(
+            "manifest_type",
+            r#"[book]
+format = "epub2"
+manifest = [
+  { outside_path = "a.xhtml", inside_path_from_opf = "a.xhtml", id = 5 },
+]
+"#,
+        );
+        assert!(
+            problems.contains(
+                "recipe.toml:4:65: manifest[0].id should be a string, but is an integer."
+            ),
+            "{}",
+            problems
+        );
+        assert!(!problems.contains("untagged enum"), "{}", problems);
+    }
+
+    #[test]
+    fn reports_problems_in_spine_entries() {
+        let problems = check_strictly(
+            "spine",
+            &format!(
+                r#"[book]
+format = "epub2"
+{}spine = [
+  {{ idref = "a", linaer = false }},
+  {{ idref = "a", linear = "no" }},
+]
+"#,
+                MANIFEST
+            ),
+        );
+        assert!(
+            problems.contains(
+                "recipe.toml:7:18: Unknown key 'spine[0].linaer'; did you mean 'linear'?"
+            ),
+            "{}",
+            problems
+        );
+        assert!(
+            problems.contains(
+                "recipe.toml:8:18: spine[1].linear should be a boolean, but is a string."
+            ),
+            "{}",
+            problems
+        );
+    }
+
+    #[test]
+    fn reports_problems_in_nested_navmap_entries() {
+        let problems = check_strictly(
+            "navmap",
+            &format!(
+                r#"[book]
+format = "epub2"
+{}
+[[book.navmap]]
+label = "A"
+idref = "a"
+children = [
+  {{ label = "B", idref = "a", fragmnet = "b" }},
+  {{ label = "C", idref = 3 }},
+]
+"#,
+                MANIFEST
+            ),
+        );
+        assert!(problems.contains(
+            "recipe.toml:11:31: Unknown key 'navmap[0].children[0].fragmnet'; did you mean 'fragment'?"
+        ), "{}", problems);
+        assert!(problems.contains(
+            "recipe.toml:12:18: navmap[0].children[1].idref should be a string, but is an integer."
+        ), "{}", problems);
+    }
+
+    const BASE: &str = r#"[base]
+format = "epub2"
+manifest = [
+  { outside_path = "a.xhtml", inside_path_from_opf = "a.xhtml", id = "a", bogus = 1 },
+  { outside_path = "b.xhtml", inside_path_from_opf = "b.xhtml", id = "b" },
+]
+spine = ["a"]
+"#;
+
+    #[test]
+    fn locates_elements_of_replaced_arrays_in_the_child() {
+        let problems = check_recipe_strictly(
+            "extends_replace",
+            &format!(
+                r#"{}
+[child]
+extends = "base"
+manifest = [
+  {{ outside_path = "c.xhtml", inside_path_from_opf = "c.xhtml", id = 5 }},
+]
+"#,
+                BASE
+            ),
+            "child",
+        );
+        assert_eq!(
+            problems,
+            "Recipe child has 1 config problem:\n  recipe.toml:12:65: manifest[0].id should be a string, but is an integer."
+        );
+    }
+
+    #[test]
+    fn locates_elements_of_appended_and_patched_arrays_where_each_key_was_written() {
+        let problems = check_recipe_strictly(
+            "extends_patch",
+            &format!(
+                r#"{}
+[child]
+extends = "base"
+merge = {{ manifest = "patch" }}
+manifest = [
+  {{ id = "b", bogus = 2 }},
+  {{ outside_path = "c.xhtml", inside_path_from_opf = "c.xhtml", id = "c", bogus = 3 }},
+]
+"#,
+                BASE
+            ),
+            "child",
+        );
+        assert!(
+            problems.contains("recipe.toml:4:75: Unknown key 'manifest[0].bogus'."),
+            "{}",
+            problems
+        );
+        assert!(
+            problems.contains("recipe.toml:13:15: Unknown key 'manifest[1].bogus'."),
+            "{}",
+            problems
+        );
+        assert!(
+            problems.contains("recipe.toml:14:75: Unknown key 'manifest[2].bogus'."),
+            "{}",
+            problems
+        );
+    }
+
+    #[test]
+    fn locates_elements_added_by_overrides() {
+        let problems = check_strictly(
+            "overrides",
+            &format!(
+                r#"[book]
+formats = ["epub2"]
+{}spine = ["a"]
+
+[book.overrides.epub2]
+merge = {{ spine = "append" }}
+spine = [{{ idref = "a", linaer = false }}]
+"#,
+                MANIFEST
+            ),
+        );
+        assert!(
+            problems.contains(
+                "recipe.toml:10:25: Unknown key 'spine[1].linaer'; did you mean 'linear'?"
+            ),
+            "{}",
+            problems
+        );
+    }
+}
@@ -0,0 +1,309 @@
+use std::collections::HashMap;
+use std::env;
+use toml::value::Table;
+use toml::Value;
+
+//////////////////
+//   Resolver   //
+//////////////////
+
+struct VarResolver<'a> {
+    recipe_name: &'a str,
+    raw_vars: HashMap<String, String>,
+    resolved_vars: HashMap<String, String>,
+    resolution_stack: Vec<String>,
+}
+
+impl<'a> VarResolver<'a> {
+    fn lookup(&mut self, name: &str) -> Result<String, String> {
+        if let Some(env_name) = name.strip_prefix("env:") {
+            return env::var(env_name).map_err(|_| {
+                format!(
+                    "Environment variable {} used in recipe {} is not set.",
+                    env_name, self.recipe_name
+                )
+            });
+        }
+        if let Some(value) = self.resolved_vars.get(name) {
+            return Ok(value.clone());
+        }
+        if self.resolution_stack.iter().any(|var| var == name) {
+            return Err(format!(
+                "Variable cycle in recipe {}: {} -> {}",
+                self.recipe_name,
+                self.resolution_stack.join(" -> "),
+                name
+            ));
+        }
+        let raw_value = match self.raw_vars.get(name) {
+            Some(value) => value.clone(),
+            None => {
+                return Err(format!(
+                    "Undefined variable '${{{}}}' in recipe {}.",
+                    name, self.recipe_name
+                ))
+            }
+        };
+
+        self.resolution_stack.push(String::from(name));
+        let value = self.interpolate(&raw_value)?;
+        self.resolution_stack.pop();
+        self.resolved_vars.insert(String::from(name), value.clone());
+
+        Ok(value)
+    }
+
+    fn interpolate(&mut self, text: &str) -> Result<String, String> {
+        let mut interpolated = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(index) = rest.find('$') {
+            interpolated.push_str(&rest[..index]);
+            rest = &rest[index..];
+            if let Some(after_escape) = rest.strip_prefix("$${") {
+                interpolated.push_str("${");
+                rest = after_escape;
+            } else if let Some(after_opening) = rest.strip_prefix("${") {
+                let closing_index = after_opening.find('}').ok_or(format!(
+                    "Unterminated '${{' in value '{}' in recipe {}; use '$${{' for a literal '${{'.",
+                    text, self.recipe_name
+                ))?;
+                interpolated.push_str(&self.lookup(&after_opening[..closing_index])?);
+                rest = &after_opening[closing_index + 1..];
+            } else {
+                interpolated.push('$');
+                rest = &rest[1..];
+            }
+        }
+        interpolated.push_str(rest);
+        Ok(interpolated)
+    }
+
+    fn interpolate_value(&mut self, value: &mut Value) -> Result<(), String> {
+        match value {
+            Value::String(text) => *text = self.interpolate(text)?,
+            Value::Array(array) => {
+                for item in array {
+                    self.interpolate_value(item)?;
+                }
+            }
+            Value::Table(table) => {
+                for (_, item) in table.iter_mut() {
+                    self.interpolate_value(item)?;
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+//////////////////////
+//   Entry Points   //
+//////////////////////
+
+fn collect_vars(
+    vars: Option<&Value>,
+    recipe_name: &str,
+    into: &mut HashMap<String, String>,
+) -> Result<(), String> {
+    match vars {
+        None => Ok(()),
+        Some(Value::Table(table)) => {
+            for (name, value) in table {
+                let value = match value {
+                    Value::String(value) => value.clone(),
+                    Value::Integer(value) => value.to_string(),
+                    Value::Float(value) => value.to_string(),
+                    Value::Boolean(value) => value.to_string(),
+                    _ => {
+                        return Err(format!(
+                            "Variable {} used by recipe {} must be a string, number or boolean.",
+                            name, recipe_name
+                        ))
+                    }
+                };
+                into.insert(name.clone(), value);
+            }
+            Ok(())
+        }
+        Some(_) => Err(format!(
+            "'vars' used by recipe {} must be a table.",
+            recipe_name
+        )),
+    }
+}
+
+pub(crate) fn interpolate_recipe(
+    recipe_table: &mut Table,
+    global_vars: Option<&Value>,
+    recipe_name: &str,
+) -> Result<(), String> {
+    // Recipe-level vars take precedence over top-level ones
+    let mut raw_vars = HashMap::new();
+    collect_vars(global_vars, recipe_name, &mut raw_vars)?;
+    collect_vars(
+        recipe_table.remove("vars").as_ref(),
+        recipe_name,
+        &mut raw_vars,
+    )?;
+
+    let mut resolver = VarResolver {
+        recipe_name,
+        raw_vars,
+        resolved_vars: HashMap::new(),
+        resolution_stack: Vec::new(),
+    };
+    for (_, value) in recipe_table.iter_mut() {
+        resolver.interpolate_value(value)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interpolate(recipe: &str, global_vars: Option<&str>) -> Result<Table, String> {
+        let mut recipe_table: Table = toml::from_str(recipe).unwrap();
+        let global_vars: Option<Value> = global_vars.map(|vars| toml::from_str(vars).unwrap());
+        interpolate_recipe(&mut recipe_table, global_vars.as_ref(), "book")?;
+        Ok(recipe_table)
+    }
+
+    fn get_string<'a>(table: &'a Table, key: &str) -> &'a str {
+        table[key].as_str().unwrap()
+    }
+
+    #[test]
+    fn vars_are_interpolated_everywhere() {
+        let table = interpolate(
+            r#"
+            vars = { dir = "text", count = 2, draft = true }
+            title = "Draft ${draft}"
+            manifest = [{ glob = "${dir}/*.xhtml", id_prefix = "${dir}-${count}-" }]
+            "#,
+            None,
+        )
+        .unwrap();
+        assert_eq!(get_string(&table, "title"), "Draft true");
+        let entry = &table["manifest"][0];
+        assert_eq!(entry["glob"].as_str(), Some("text/*.xhtml"));
+        assert_eq!(entry["id_prefix"].as_str(), Some("text-2-"));
+        assert!(!table.contains_key("vars"));
+    }
+
+    #[test]
+    fn recipe_vars_override_top_level_vars() {
+        let table = interpolate(
+            r#"
+            vars = { edition = "second" }
+            title = "${title} (${edition} edition)"
+            "#,
+            Some(
+                r#"title = "Book"
+edition = "first""#,
+            ),
+        )
+        .unwrap();
+        assert_eq!(get_string(&table, "title"), "Book (second edition)");
+    }
+
+    #[test]
+    fn environment_variables_are_read() {
+        env::set_var("BOOKFACTORY_VARS_TEST_PUBLISHER", "Example Press");
+        let table = interpolate(
+            r#"publisher = "${env:BOOKFACTORY_VARS_TEST_PUBLISHER}""#,
+            None,
+        )
+        .unwrap();
+        assert_eq!(get_string(&table, "publisher"), "Example Press");
+
+        assert_eq!(
+            interpolate(r#"publisher = "${env:BOOKFACTORY_VARS_TEST_UNSET}""#, None),
+            Err(String::from(
+                "Environment variable BOOKFACTORY_VARS_TEST_UNSET used in recipe book is not set."
+            ))
+        );
+    }
+
+    #[test]
+    fn escaped_and_lone_dollars_are_kept() {
+        let table = interpolate(
+            r#"
+            vars = { name = "x" }
+            literal = "$${name} costs $5, ${name}"
+            "#,
+            None,
+        )
+        .unwrap();
+        assert_eq!(get_string(&table, "literal"), "${name} costs $5, x");
+    }
+
+    #[test]
+    fn nested_references_are_resolved() {
+        let table = interpolate(
+            r#"
+            vars = { root = "books", series = "${root}/saga", volume = "${series}/one" }
+            out = "${volume}.epub"
+            "#,
+            None,
+        )
+        .unwrap();
+        assert_eq!(get_string(&table, "out"), "books/saga/one.epub");
+    }
+
+    #[test]
+    fn undefined_and_unterminated_references_are_errors() {
+        assert_eq!(
+            interpolate(r#"title = "${missing}""#, None),
+            Err(String::from(
+                "Undefined variable '${missing}' in recipe book."
+            ))
+        );
+        assert_eq!(
+            interpolate(
+                r#"
+                vars = { a = "${b}" }
+                title = "${a}"
+                "#,
+                None
+            ),
+            Err(String::from("Undefined variable '${b}' in recipe book."))
+        );
+        assert_eq!(
+            interpolate(r#"title = "${open""#, None),
+            Err(String::from(
+                "Unterminated '${' in value '${open' in recipe book; use '$${' for a literal '${'."
+            ))
+        );
+    }
+
+    #[test]
+    fn cycles_are_errors() {
+        assert_eq!(
+            interpolate(
+                r#"
+                vars = { a = "${b}", b = "${a}" }
+                title = "${a}"
+                "#,
+                None
+            ),
+            Err(String::from("Variable cycle in recipe book: a -> b -> a"))
+        );
+    }
+
+    #[test]
+    fn vars_must_be_scalars_in_a_table() {
+        assert_eq!(
+            interpolate(r#"vars = { list = ["a"] }"#, None),
+            Err(String::from(
+                "Variable list used by recipe book must be a string, number or boolean."
+            ))
+        );
+        assert_eq!(
+            interpolate(r#"vars = "a""#, None),
+            Err(String::from("'vars' used by recipe book must be a table."))
+        );
+    }
+}
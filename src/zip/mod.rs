@@ -1,3 +1,4 @@
 mod zip;
 
+pub use self::zip::CompressedFileCache;
 pub(crate) use self::zip::{zip_buffer, zip_path};
@@ -1,5 +1,6 @@
 use crate::helpers::fixed_clean;
 
+use std::collections::HashMap;
 use std::fmt::Debug;
 use std::fs::{metadata, read, read_dir};
 use std::io::{Cursor, Seek, Write};
@@ -9,6 +10,8 @@ use zip::read::ZipArchive;
 use zip::write::{FileOptions, ZipWriter};
 use zip::CompressionMethod;
 
+const COMPRESSED_ENTRY_NAME: &str = "compressed";
+
 fn p_to_string<P: AsRef<Path> + Clone + Debug>(p: P) -> Result<String, String> {
     Ok(String::from(
         p.as_ref()
@@ -18,49 +21,77 @@ fn p_to_string<P: AsRef<Path> + Clone + Debug>(p: P) -> Result<String, String> {
     ))
 }
 
-fn add_file_with_optional_deflate<P: AsRef<Path> + Clone + Debug, Z: Write + Seek>(
+fn compress_with_optional_deflate(file_contents: &[u8]) -> Result<Vec<u8>, String> {
+    // Produces a single-entry archive, so that the entry can be raw-copied into any number of
+    // zip files without compressing it again
+    let mut compressed_buffer = Vec::new();
+    for compression_method in [CompressionMethod::Deflated, CompressionMethod::Stored] {
+        compressed_buffer.clear();
+        let mut compression_writer = ZipWriter::new(Cursor::new(&mut compressed_buffer));
+        compression_writer
+            .start_file(
+                COMPRESSED_ENTRY_NAME,
+                FileOptions::default().compression_method(compression_method),
+            )
+            .map_err(|e| e.to_string())?;
+        compression_writer
+            .write_all(file_contents)
+            .map_err(|e| e.to_string())?;
+        compression_writer.finish().map_err(|e| e.to_string())?;
+        drop(compression_writer);
+
+        let mut compression_reader =
+            ZipArchive::new(Cursor::new(&compressed_buffer)).map_err(|e| e.to_string())?;
+        let compressed_size = compression_reader
+            .by_index_raw(0)
+            .map_err(|e| e.to_string())?
+            .compressed_size();
+        if compressed_size < file_contents.len() as u64 {
+            break;
+        }
+    }
+
+    Ok(compressed_buffer)
+}
+
+fn add_compressed_file<P: AsRef<Path> + Clone + Debug, Z: Write + Seek>(
     zip_file: &mut ZipWriter<Z>,
-    file_contents: Vec<u8>,
+    compressed_file: &[u8],
     inside_path: P,
 ) -> Result<(), String> {
-    let mut compression_test_buffer = Vec::new();
-    let mut compression_test_writer = ZipWriter::new(Cursor::new(&mut compression_test_buffer));
-
-    let uncompressed_size = file_contents.len() as u64;
-
-    compression_test_writer
-        .start_file(p_to_string(&inside_path)?, FileOptions::default())
+    let mut compressed_reader =
+        ZipArchive::new(Cursor::new(compressed_file)).map_err(|e| e.to_string())?;
+    let file_in_zip = compressed_reader
+        .by_index_raw(0)
         .map_err(|e| e.to_string())?;
-    compression_test_writer
-        .write(&file_contents)
+    zip_file
+        .raw_copy_file_rename(file_in_zip, p_to_string(inside_path)?)
         .map_err(|e| e.to_string())?;
-    compression_test_writer
-        .finish()
-        .map_err(|e| e.to_string())?;
-    drop(compression_test_writer);
 
-    let mut compression_test_reader =
-        ZipArchive::new(Cursor::new(&mut compression_test_buffer)).map_err(|e| e.to_string())?;
-    let file_in_zip = compression_test_reader
-        .by_name(&p_to_string(&inside_path)?)
-        .map_err(|e| e.to_string())?;
-    let compressed_size = file_in_zip.compressed_size();
+    Ok(())
+}
 
-    if compressed_size < uncompressed_size {
-        zip_file
-            .raw_copy_file(file_in_zip)
-            .map_err(|e| e.to_string())?;
-    } else {
-        zip_file
-            .start_file(
-                p_to_string(inside_path)?,
-                FileOptions::default().compression_method(CompressionMethod::Stored),
-            )
-            .map_err(|e| e.to_string())?;
-        zip_file.write(&file_contents).map_err(|e| e.to_string())?;
+/// Input files read and compressed once, for reuse by every book built from them
+#[derive(Default)]
+pub struct CompressedFileCache {
+    compressed_files: HashMap<PathBuf, Vec<u8>>,
+}
+
+impl CompressedFileCache {
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    Ok(())
+    fn get_or_compress(&mut self, outside_path: &Path) -> Result<&[u8], String> {
+        if !self.compressed_files.contains_key(outside_path) {
+            let file_contents = read(outside_path).map_err(|e| e.to_string())?;
+            self.compressed_files.insert(
+                outside_path.to_path_buf(),
+                compress_with_optional_deflate(&file_contents)?,
+            );
+        }
+        Ok(&self.compressed_files[outside_path])
+    }
 }
 
 pub(crate) fn zip_path<
@@ -71,8 +102,10 @@ pub(crate) fn zip_path<
     zip_file: &mut ZipWriter<Z>,
     outside_path: P,
     inside_path: Option<Q>,
+    file_cache: &mut CompressedFileCache,
 ) -> Result<(), String> {
-    let path_metadata = metadata(&outside_path).map_err(|e| e.to_string())?;
+    let path_metadata =
+        metadata(&outside_path).map_err(|e| format!("Failed to read {:?}: {}", outside_path, e))?;
     let true_inside_path = fixed_clean(match inside_path {
         Some(path) => path.as_ref().to_path_buf(),
         None => PathBuf::from(outside_path.as_ref().file_name().ok_or(format!(
@@ -82,8 +115,8 @@ pub(crate) fn zip_path<
     });
 
     if path_metadata.is_file() {
-        let file_contents = read(outside_path).map_err(|e| e.to_string())?;
-        add_file_with_optional_deflate(zip_file, file_contents, true_inside_path)?;
+        let compressed_file = file_cache.get_or_compress(outside_path.as_ref())?;
+        add_compressed_file(zip_file, compressed_file, true_inside_path)?;
     } else if path_metadata.is_dir() {
         for dir_entry in read_dir(outside_path).map_err(|e| e.to_string())? {
             let entry_outside_path = dir_entry.map_err(|e| e.to_string())?.path();
@@ -92,7 +125,12 @@ pub(crate) fn zip_path<
                 "Ill-formed path ending in '..': {:?}",
                 entry_outside_path
             ))?);
-            zip_path(zip_file, entry_outside_path, Some(entry_inside_path))?;
+            zip_path(
+                zip_file,
+                entry_outside_path,
+                Some(entry_inside_path),
+                file_cache,
+            )?;
         }
     }
 
@@ -104,5 +142,6 @@ pub(crate) fn zip_buffer<P: AsRef<Path> + Clone + Debug, Z: Write + Seek>(
     buffer: Vec<u8>,
     inside_path: P,
 ) -> Result<(), String> {
-    add_file_with_optional_deflate(zip_file, buffer, fixed_clean(inside_path))
+    let compressed_file = compress_with_optional_deflate(&buffer)?;
+    add_compressed_file(zip_file, &compressed_file, fixed_clean(inside_path))
 }